@@ -0,0 +1,59 @@
+//! Runs arbitrary ROMs with arbitrary keypad input for a bounded number of
+//! cycles, checking the machine never panics and stays in a sane state.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use chip_8::{Chip8, Font, Layout, Platform};
+use libfuzzer_sys::fuzz_target;
+
+// Upper bounds so each input runs quickly
+const MAX_FRAMES: usize = 64;
+const MAX_INSTRUCTIONS_PER_FRAME: u8 = 32;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    platform: u8,
+    seed: u64,
+    instructions_per_frame: u8,
+    // Keypad state for each frame, one bit per key
+    frames: Vec<u16>,
+    rom: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let platform = Platform::ALL[input.platform as usize % Platform::ALL.len()];
+    let layout = Layout::for_platform(platform);
+
+    let mut cpu = Chip8::with_layout(layout).expect("Platform layouts are valid");
+    cpu.set_font(Font::for_platform(platform))
+        .expect("Platform fonts fit their layouts");
+    cpu.set_seed(input.seed);
+
+    if cpu.load_rom(&input.rom).is_err() {
+        return;
+    }
+
+    let instructions_per_frame = input.instructions_per_frame % MAX_INSTRUCTIONS_PER_FRAME + 1;
+
+    for &keys in input.frames.iter().take(MAX_FRAMES) {
+        for key in 0x0..=0xF {
+            cpu.set_key(key, keys & (1 << key) != 0);
+        }
+
+        for _ in 0..instructions_per_frame {
+            let before = (cpu.pc(), *cpu.registers(), cpu.i(), cpu.sp());
+
+            if cpu.step().is_err() {
+                // Faulting instructions have no effect
+                assert_eq!(before, (cpu.pc(), *cpu.registers(), cpu.i(), cpu.sp()));
+                return;
+            }
+
+            assert_eq!(cpu.memory().len(), layout.memory_size);
+            assert!(cpu.sp() < cpu.stack().len());
+        }
+
+        cpu.tick_timers();
+    }
+});
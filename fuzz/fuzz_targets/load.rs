@@ -0,0 +1,62 @@
+//! Feeds arbitrary layouts, fonts and ROM files (including malformed zip and
+//! gzip archives) through the loader, checking it rejects bad input cleanly.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use chip_8::rom;
+use chip_8::{Chip8, Font, Layout};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    memory_size: u32,
+    load_address: u16,
+    entry_point: u16,
+    font_address: u16,
+    font: Option<Vec<u8>>,
+    file: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let layout = Layout {
+        memory_size: input.memory_size as usize,
+        load_address: input.load_address,
+        entry_point: input.entry_point,
+        font_address: input.font_address,
+    };
+
+    let mut cpu = match Chip8::with_layout(layout) {
+        Ok(cpu) => cpu,
+        Err(_) => return,
+    };
+
+    if let Some(font) = &input.font {
+        match Font::from_bytes(font) {
+            Ok(font) => {
+                if cpu.set_font(font).is_err() {
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+    }
+
+    let rom = match rom::extract_rom(&input.file, None) {
+        Ok(rom) => rom,
+        Err(_) => return,
+    };
+
+    if cpu.load_rom(&rom).is_ok() {
+        let start = layout.load_address as usize;
+        assert_eq!(&cpu.memory()[start..start + rom.len()], &rom[..]);
+        assert_eq!(cpu.pc(), layout.entry_point);
+
+        // A few instructions to make sure a freshly loaded machine can run
+        for _ in 0..16 {
+            if cpu.step().is_err() {
+                break;
+            }
+        }
+    }
+});
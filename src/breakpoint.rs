@@ -0,0 +1,153 @@
+//! Breakpoints with conditions, and log points that print instead of
+//! stopping.
+//!
+//! A breakpoint at an address is checked whenever PC reaches it, and acts if
+//! its condition, an [`Expr`], holds. A breakpoint without an address is a
+//! watch on its condition instead, checked before every instruction and
+//! acting each time the condition starts to hold, so `dt == 0` stops once as
+//! the timer runs out rather than on every instruction after.
+
+use crate::chip8::Chip8;
+use crate::expr::{Expr, LogMessage};
+
+/// What a breakpoint does when it's hit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hit {
+    /// Pause the program.
+    Stop,
+    /// Print the rendered log message and carry on.
+    Log(String),
+}
+
+/// A breakpoint or log point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    /// Where to check the condition, or `None` to check before every
+    /// instruction.
+    pub address: Option<u16>,
+    pub condition: Option<Expr>,
+    /// Printed instead of stopping, making this a log point.
+    pub log: Option<LogMessage>,
+    hits: u64,
+    // Whether the condition held last time, for breakpoints without an address
+    held: bool,
+}
+
+impl Breakpoint {
+    /// Creates a breakpoint that hasn't been reached yet.
+    pub fn new(
+        address: Option<u16>,
+        condition: Option<Expr>,
+        log: Option<LogMessage>,
+    ) -> Breakpoint {
+        Breakpoint {
+            address,
+            condition,
+            log,
+            hits: 0,
+            held: false,
+        }
+    }
+
+    /// Times the breakpoint has been reached, the `hit_count` conditions
+    /// see. Reaching it counts whether or not the condition held.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Checks the breakpoint before `cpu` executes its next instruction.
+    pub fn check(&mut self, cpu: &Chip8) -> Option<Hit> {
+        if matches!(self.address, Some(address) if address != cpu.pc()) {
+            return None;
+        }
+
+        self.hits += 1;
+        let holds = match &self.condition {
+            Some(condition) => condition.is_true(cpu, self.hits),
+            None => true,
+        };
+        let hit = match self.address {
+            Some(_) => holds,
+            None => holds && !self.held,
+        };
+        self.held = holds;
+        if !hit {
+            return None;
+        }
+
+        Some(match &self.log {
+            Some(log) => Hit::Log(log.render(cpu, self.hits)),
+            None => Hit::Stop,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+    use crate::symbols::Symbols;
+
+    fn expr(text: &str) -> Option<Expr> {
+        Some(Expr::parse(text, &Symbols::default()).unwrap())
+    }
+
+    // Checks `breakpoint` before each of `steps` steps of a loop that counts
+    // up V0 every other step, returning the steps it hit on
+    fn hits(breakpoint: &mut Breakpoint, steps: usize) -> Vec<(usize, Hit)> {
+        // 0x200: ADD V0, 1; JP 0x200
+        let mut cpu = cpu_with(&[0x7001, 0x1200]);
+
+        let mut hits = Vec::new();
+        for step in 0..steps {
+            if let Some(hit) = breakpoint.check(&cpu) {
+                hits.push((step, hit));
+            }
+            cpu.step().unwrap();
+        }
+        hits
+    }
+
+    #[test]
+    fn stops_each_time_pc_reaches_the_address() {
+        let mut breakpoint = Breakpoint::new(Some(0x202), None, None);
+
+        assert_eq!(
+            hits(&mut breakpoint, 6),
+            [(1, Hit::Stop), (3, Hit::Stop), (5, Hit::Stop)]
+        );
+        assert_eq!(breakpoint.hits(), 3);
+    }
+
+    #[test]
+    fn conditions_see_the_hit_count() {
+        let mut every_third = Breakpoint::new(Some(0x202), expr("hit_count % 3 == 0"), None);
+
+        assert_eq!(
+            hits(&mut every_third, 16),
+            [(5, Hit::Stop), (11, Hit::Stop)]
+        );
+        // Counted whether or not the condition held
+        assert_eq!(every_third.hits(), 8);
+    }
+
+    #[test]
+    fn log_points_render_instead_of_stopping() {
+        let log = LogMessage::parse("v0 is {v0}", &Symbols::default()).unwrap();
+        let mut log_point = Breakpoint::new(Some(0x200), expr("v0 == 4"), Some(log));
+
+        assert_eq!(
+            hits(&mut log_point, 16),
+            [(8, Hit::Log("v0 is 4".to_string()))]
+        );
+    }
+
+    #[test]
+    fn watches_stop_as_their_condition_starts_to_hold() {
+        // Holds while V0 is 2, then again while it's 6
+        let mut watch = Breakpoint::new(None, expr("v0 % 4 == 2"), None);
+
+        assert_eq!(hits(&mut watch, 16), [(3, Hit::Stop), (11, Hit::Stop)]);
+        assert_eq!(watch.hits(), 16);
+    }
+}
@@ -0,0 +1,386 @@
+//! Reading subroutine calls off the stack and checking they nest properly.
+//!
+//! `CALL` stores the return address at `stack[sp]` and then increments SP, so
+//! the stack holds at most [`MAX_DEPTH`] calls, in `stack[..sp]`. [`frames`]
+//! walks them innermost first, and a
+//! [`StackChecker`] watches the machine step by step for code that is about
+//! to misuse the stack, reporting each problem once as a [`StackWarning`]
+//! without stopping the program.
+
+use crate::chip8::Chip8;
+use crate::symbols::Symbols;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+/// Calls the stack can hold.
+pub const MAX_DEPTH: usize = 16;
+
+/// Depth at which calls are reported as deep recursion.
+pub const DEEP_CALLS: usize = MAX_DEPTH - 3;
+
+/// A subroutine call waiting to return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the `CALL`.
+    pub call: u16,
+    /// Where the subroutine returns to, as stored on the stack.
+    pub return_address: u16,
+    /// The subroutine called, `None` if the return address doesn't follow a
+    /// `CALL`, meaning the stack has been overwritten.
+    pub entry: Option<u16>,
+}
+
+/// The calls on the stack, innermost first.
+pub fn frames(cpu: &Chip8) -> Vec<Frame> {
+    let sp = cpu.sp().min(MAX_DEPTH);
+
+    cpu.stack()[..sp]
+        .iter()
+        .rev()
+        .map(|&return_address| {
+            let call = return_address.wrapping_sub(2);
+            let instruction = instruction_at(cpu, call);
+
+            Frame {
+                call,
+                return_address,
+                entry: (instruction >> 12 == 0x2).then_some(instruction & 0xFFF),
+            }
+        })
+        .collect()
+}
+
+/// A backtrace of `cpu` like a debugger's, one line per frame starting with
+/// the current instruction, naming addresses by label where `symbols` can.
+///
+/// ```text
+/// #0  0x2F4 <draw_score+0x4> in draw_score
+/// #1  0x208 <main+0x8> in top level
+/// ```
+pub fn backtrace(cpu: &Chip8, symbols: &Symbols) -> String {
+    let frames = frames(cpu);
+    let name = |address: u16| match symbols.location(address) {
+        Some(location) => format!("{:#05X} <{}>", address, location),
+        None => format!("{:#05X}", address),
+    };
+    let subroutine = |frame: Option<&Frame>| match frame {
+        None => "top level".to_string(),
+        Some(Frame {
+            entry: Some(entry), ..
+        }) => match symbols.label(*entry) {
+            Some(label) => label.to_string(),
+            None => format!("{:#05X}", entry),
+        },
+        Some(Frame { entry: None, .. }) => "?? (stack overwritten)".to_string(),
+    };
+
+    let addresses = std::iter::once(cpu.pc()).chain(frames.iter().map(|frame| frame.call));
+    let mut text = String::new();
+    for (depth, address) in addresses.enumerate() {
+        let _ = writeln!(
+            text,
+            "#{:<2} {} in {}",
+            depth,
+            name(address),
+            subroutine(frames.get(depth))
+        );
+    }
+
+    text
+}
+
+/// Stack misuse spotted by a [`StackChecker`], before the instruction at
+/// `address` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StackWarning {
+    /// `RET` with no call to return from.
+    UnbalancedRet { address: u16 },
+    /// `RET` to an address that doesn't follow a `CALL`.
+    CorruptReturn { address: u16, return_address: u16 },
+    /// `CALL` taking the stack to `depth`, close to [`MAX_DEPTH`].
+    DeepRecursion { address: u16, depth: usize },
+    /// A jump from inside the subroutine at `entry` to `target`, before the
+    /// subroutine or into another one, leaving its call on the stack.
+    JumpOutOfSubroutine {
+        address: u16,
+        target: u16,
+        entry: u16,
+    },
+}
+
+impl StackWarning {
+    /// Address of the instruction the warning is about.
+    pub fn address(&self) -> u16 {
+        match *self {
+            StackWarning::UnbalancedRet { address }
+            | StackWarning::CorruptReturn { address, .. }
+            | StackWarning::DeepRecursion { address, .. }
+            | StackWarning::JumpOutOfSubroutine { address, .. } => address,
+        }
+    }
+}
+
+impl fmt::Display for StackWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackWarning::UnbalancedRet { address } => {
+                write!(f, "RET at {:#05X} with no call to return from", address)
+            }
+            StackWarning::CorruptReturn {
+                address,
+                return_address,
+            } => write!(
+                f,
+                "RET at {:#05X} returns to {:#05X}, which doesn't follow a CALL",
+                address, return_address
+            ),
+            StackWarning::DeepRecursion { address, depth } => write!(
+                f,
+                "CALL at {:#05X} nests {} calls deep, the stack holds {}",
+                address, depth, MAX_DEPTH
+            ),
+            StackWarning::JumpOutOfSubroutine {
+                address,
+                target,
+                entry,
+            } => write!(
+                f,
+                "Jump at {:#05X} to {:#05X} leaves the subroutine at {:#05X} without returning",
+                address, target, entry
+            ),
+        }
+    }
+}
+
+/// Watches for stack misuse.
+///
+/// Call [`StackChecker::check`] before every [`Chip8::step`].
+#[derive(Debug, Default)]
+pub struct StackChecker {
+    // Every subroutine called so far
+    entries: BTreeSet<u16>,
+    // Instructions already warned about, so each is only reported once
+    reported: BTreeSet<u16>,
+}
+
+impl StackChecker {
+    /// Creates a checker that hasn't seen any calls.
+    pub fn new() -> StackChecker {
+        StackChecker::default()
+    }
+
+    /// Checks the instruction `cpu` is about to execute, returning a
+    /// warning the first time it misuses the stack.
+    pub fn check(&mut self, cpu: &Chip8) -> Option<StackWarning> {
+        let address = cpu.pc();
+        let instruction = instruction_at(cpu, address);
+        let sp = cpu.sp();
+
+        let warning = match instruction >> 12 {
+            0x0 if instruction == 0x00EE => match frames(cpu).first() {
+                None => Some(StackWarning::UnbalancedRet { address }),
+                Some(frame) if frame.entry.is_none() => Some(StackWarning::CorruptReturn {
+                    address,
+                    return_address: frame.return_address,
+                }),
+                Some(_) => None,
+            },
+            0x2 => {
+                self.entries.insert(instruction & 0xFFF);
+                if sp + 1 >= DEEP_CALLS && sp < MAX_DEPTH {
+                    Some(StackWarning::DeepRecursion {
+                        address,
+                        depth: sp + 1,
+                    })
+                } else {
+                    None
+                }
+            }
+            0x1 | 0xB => {
+                let target = match instruction >> 12 {
+                    0x1 => instruction & 0xFFF,
+                    _ => (instruction & 0xFFF).wrapping_add(cpu.v(0) as u16),
+                };
+                match frames(cpu).first().and_then(|frame| frame.entry) {
+                    Some(entry)
+                        if target < entry
+                            || (target != entry && self.entries.contains(&target)) =>
+                    {
+                        Some(StackWarning::JumpOutOfSubroutine {
+                            address,
+                            target,
+                            entry,
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match warning {
+            Some(warning) if self.reported.insert(address) => Some(warning),
+            _ => None,
+        }
+    }
+}
+
+fn instruction_at(cpu: &Chip8, address: u16) -> u16 {
+    let address = address as usize;
+
+    match cpu.memory().get(address..address + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // 0x200: CALL 0x204; RET
+    // 0x204: CALL 0x208; JP 0x200
+    // 0x208: JP 0x204
+    const PROGRAM: [u16; 5] = [0x2204, 0x00EE, 0x2208, 0x1200, 0x1204];
+
+    // PROGRAM run into 0x208, two calls deep, checked all the way
+    fn nested() -> (Chip8, StackChecker) {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut checker = StackChecker::new();
+        for _ in 0..2 {
+            assert_eq!(checker.check(&cpu), None);
+            cpu.step().unwrap();
+        }
+
+        (cpu, checker)
+    }
+
+    #[test]
+    fn frames_are_innermost_first() {
+        let (cpu, _) = nested();
+
+        assert_eq!(
+            frames(&cpu),
+            [
+                Frame {
+                    call: 0x204,
+                    return_address: 0x206,
+                    entry: Some(0x208)
+                },
+                Frame {
+                    call: 0x200,
+                    return_address: 0x202,
+                    entry: Some(0x204)
+                },
+            ]
+        );
+        assert!(frames(&cpu_with(&PROGRAM)).is_empty());
+    }
+
+    #[test]
+    fn backtrace_names_each_frame() {
+        let (cpu, _) = nested();
+        let symbols = Symbols::parse("label 0x200 main\nlabel 0x208 inner").unwrap();
+
+        assert_eq!(
+            backtrace(&cpu, &symbols),
+            "#0  0x208 <inner> in inner\n\
+             #1  0x204 <main+0x4> in 0x204\n\
+             #2  0x200 <main> in top level\n"
+        );
+    }
+
+    #[test]
+    fn warns_about_jumps_out_of_subroutines() {
+        let (mut cpu, mut checker) = nested();
+
+        // Jumping back into the caller leaves a call on the stack
+        assert_eq!(
+            checker.check(&cpu),
+            Some(StackWarning::JumpOutOfSubroutine {
+                address: 0x208,
+                target: 0x204,
+                entry: 0x208
+            })
+        );
+
+        // As does jumping to before the subroutine, from another instruction
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.set_pc(0x206);
+        assert!(matches!(
+            checker.check(&cpu),
+            Some(StackWarning::JumpOutOfSubroutine { target: 0x200, .. })
+        ));
+    }
+
+    #[test]
+    fn warns_about_corrupt_returns() {
+        let (mut cpu, mut checker) = nested();
+
+        cpu.set_pc(0x202);
+        cpu.stack_mut()[1] = 0x300;
+        assert_eq!(
+            checker.check(&cpu),
+            Some(StackWarning::CorruptReturn {
+                address: 0x202,
+                return_address: 0x300
+            })
+        );
+    }
+
+    #[test]
+    fn warns_about_unbalanced_returns() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut checker = StackChecker::new();
+
+        cpu.set_pc(0x202);
+        assert_eq!(
+            checker.check(&cpu),
+            Some(StackWarning::UnbalancedRet { address: 0x202 })
+        );
+    }
+
+    #[test]
+    fn each_instruction_is_only_reported_once() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut checker = StackChecker::new();
+        cpu.set_pc(0x202);
+
+        assert!(checker.check(&cpu).is_some());
+        assert_eq!(checker.check(&cpu), None);
+    }
+
+    #[test]
+    fn the_stack_holds_sixteen_calls() {
+        // 0x200: CALL 0x200, forever
+        let mut cpu = cpu_with(&[0x2200]);
+        let mut checker = StackChecker::new();
+
+        let mut warned_at = None;
+        for depth in 1..=MAX_DEPTH {
+            if let Some(warning) = checker.check(&cpu) {
+                warned_at.get_or_insert((depth, warning));
+            }
+            cpu.step().unwrap();
+        }
+        // Warned on the call reaching DEEP_CALLS
+        assert_eq!(
+            warned_at,
+            Some((
+                DEEP_CALLS,
+                StackWarning::DeepRecursion {
+                    address: 0x200,
+                    depth: DEEP_CALLS
+                }
+            ))
+        );
+
+        let frames = frames(&cpu);
+        assert_eq!(frames.len(), MAX_DEPTH);
+        assert!(frames.iter().all(|frame| frame.entry == Some(0x200)));
+        assert!(cpu.step().is_err());
+    }
+}
@@ -0,0 +1,418 @@
+//! Cheats: finding where a program keeps a value, freezing memory and
+//! patch codes remembered for each ROM.
+//!
+//! A patch code is a list of bytes to write, given as `ADDR:VALUE` pairs in
+//! hex separated by commas, such as `3F0:05,3F1:00`. Enabled codes and
+//! frozen addresses are written every frame, so the program can't change
+//! them back.
+//!
+//! Codes are kept in a plain text cheat file shared by every ROM, one per
+//! line with the CRC-32 of the ROM it's for, whether it's on and its name.
+//! Blank lines and lines starting with `#` are ignored, so names may
+//! contain one, and are kept when the file is written back:
+//!
+//! ```text
+//! # pong.ch8
+//! 6B3E2AF1 on 3F0:09 Always 9 points
+//! 6B3E2AF1 off 2A4:12,2A5:00 Skip the title screen
+//! ```
+
+use crate::chip8::Chip8;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt::{self, Write};
+
+/// Errors raised while parsing cheat codes and files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CheatError {
+    /// A code wasn't a list of `ADDR:VALUE` pairs.
+    InvalidCode { code: String },
+    /// A line of a cheat file couldn't be parsed.
+    InvalidRecord { line: usize, message: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::InvalidCode { code } => write!(
+                f,
+                "Invalid cheat code {}, expected ADDR:VALUE pairs like 3F0:05,3F1:00",
+                code
+            ),
+            CheatError::InvalidRecord { line, message } => {
+                write!(f, "Cheat file line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl error::Error for CheatError {}
+
+/// The CRC-32 identifying a ROM in cheat files.
+pub fn rom_hash(rom: &[u8]) -> u32 {
+    crc32fast::hash(rom)
+}
+
+/// A byte a code writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Patch {
+    pub address: u16,
+    pub value: u8,
+}
+
+/// A named patch code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub patches: Vec<Patch>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Parses a code such as `3F0:05,3F1:00`.
+    pub fn parse_code(code: &str) -> Result<Vec<Patch>, CheatError> {
+        let invalid = || CheatError::InvalidCode {
+            code: code.to_string(),
+        };
+        let hex = |text: &str| {
+            let text = text.trim_start_matches("0x").trim_start_matches("0X");
+            u16::from_str_radix(text, 16).ok()
+        };
+
+        code.split(',')
+            .map(|pair| {
+                let (address, value) = pair.split_once(':').ok_or_else(invalid)?;
+                match (hex(address), hex(value)) {
+                    (Some(address), Some(value)) if value <= 0xFF => Ok(Patch {
+                        address,
+                        value: value as u8,
+                    }),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect()
+    }
+
+    /// The code in the form [`Cheat::parse_code`] reads.
+    pub fn code(&self) -> String {
+        let pairs: Vec<String> = self
+            .patches
+            .iter()
+            .map(|patch| format!("{:03X}:{:02X}", patch.address, patch.value))
+            .collect();
+
+        pairs.join(",")
+    }
+}
+
+/// The contents of a cheat file: codes for any number of ROMs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheatFile {
+    roms: BTreeMap<u32, Vec<Cheat>>,
+    // The file's lines in order, so comments survive rewriting it
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    // A comment or blank line, as written
+    Text(String),
+    // The next of a ROM's codes
+    Cheat(u32),
+}
+
+impl CheatFile {
+    /// Parses a cheat file in the format described above.
+    pub fn parse(text: &str) -> Result<CheatFile, CheatError> {
+        let mut file = CheatFile::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: &str| CheatError::InvalidRecord {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            if line.trim().is_empty() || line.trim().starts_with('#') {
+                file.lines.push(Line::Text(line.to_string()));
+                continue;
+            }
+            let line = line.trim();
+
+            let mut fields = line.splitn(4, char::is_whitespace);
+            let (hash, enabled, code) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(hash), Some(enabled), Some(code)) => (hash, enabled, code),
+                _ => return Err(invalid("expected <ROM CRC-32> on|off <code> <name>")),
+            };
+            let hash = u32::from_str_radix(hash, 16).map_err(|_| invalid("invalid CRC-32"))?;
+            let enabled = match enabled {
+                "on" => true,
+                "off" => false,
+                _ => return Err(invalid("expected on or off")),
+            };
+            let patches = Cheat::parse_code(code).map_err(|e| invalid(&e.to_string()))?;
+
+            file.lines.push(Line::Cheat(hash));
+            file.roms.entry(hash).or_default().push(Cheat {
+                name: fields.next().unwrap_or("").trim().to_string(),
+                patches,
+                enabled,
+            });
+        }
+
+        Ok(file)
+    }
+
+    /// The file as text, in the format [`CheatFile::parse`] reads.
+    ///
+    /// Comments and blank lines stay where they were. Codes fill the lines
+    /// their ROM's codes were read from, with new ones written after the
+    /// ROM's last code, or at the end for a ROM the file didn't have.
+    pub fn to_text(&self) -> String {
+        let mut slots: BTreeMap<u32, usize> = BTreeMap::new();
+        for line in &self.lines {
+            if let Line::Cheat(hash) = line {
+                *slots.entry(*hash).or_default() += 1;
+            }
+        }
+
+        let mut text = String::new();
+        let mut written: BTreeMap<u32, usize> = BTreeMap::new();
+        for line in &self.lines {
+            match line {
+                Line::Text(line) => {
+                    let _ = writeln!(text, "{}", line);
+                }
+                Line::Cheat(hash) => {
+                    let cheats = self.cheats(*hash);
+                    let count = written.entry(*hash).or_default();
+                    *count += 1;
+                    // The ROM's last line also takes any codes added since
+                    let end = if *count == slots[hash] {
+                        cheats.len()
+                    } else {
+                        *count
+                    };
+                    for cheat in cheats.get(*count - 1..end).unwrap_or(&[]) {
+                        write_cheat(&mut text, *hash, cheat);
+                    }
+                }
+            }
+        }
+
+        for (hash, cheats) in &self.roms {
+            if !slots.contains_key(hash) {
+                for cheat in cheats {
+                    write_cheat(&mut text, *hash, cheat);
+                }
+            }
+        }
+
+        text
+    }
+
+    /// The codes for the ROM with CRC-32 `hash`.
+    pub fn cheats(&self, hash: u32) -> &[Cheat] {
+        self.roms.get(&hash).map_or(&[], Vec::as_slice)
+    }
+
+    /// The codes for the ROM with CRC-32 `hash`, to change.
+    pub fn cheats_mut(&mut self, hash: u32) -> &mut Vec<Cheat> {
+        self.roms.entry(hash).or_default()
+    }
+}
+
+fn write_cheat(text: &mut String, hash: u32, cheat: &Cheat) {
+    let enabled = if cheat.enabled { "on" } else { "off" };
+    let _ = writeln!(
+        text,
+        "{:08X} {} {} {}",
+        hash,
+        enabled,
+        cheat.code(),
+        cheat.name
+    );
+}
+
+/// Writes the enabled codes in `cheats` and the `frozen` addresses to
+/// memory, leaving out addresses outside it.
+pub fn apply(cpu: &mut Chip8, cheats: &[Cheat], frozen: &BTreeMap<u16, u8>) {
+    let enabled = cheats
+        .iter()
+        .filter(|cheat| cheat.enabled)
+        .flat_map(|cheat| &cheat.patches)
+        .map(|patch| (patch.address, patch.value));
+    let memory = cpu.memory_mut();
+
+    for (address, value) in enabled.chain(frozen.iter().map(|(&address, &value)| (address, value)))
+    {
+        if let Some(byte) = memory.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+}
+
+/// How a [`Search`] compares each byte with its value last time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+/// Narrows down the addresses that might hold a value, comparing memory
+/// now with the last time it was searched.
+#[derive(Debug, Clone)]
+pub struct Search {
+    candidates: Vec<u16>,
+    previous: Vec<u8>,
+}
+
+impl Search {
+    /// Starts a search with every address in memory a candidate.
+    pub fn new(cpu: &Chip8) -> Search {
+        Search {
+            candidates: (0..cpu.memory().len())
+                .map(|address| address as u16)
+                .collect(),
+            previous: cpu.memory().to_vec(),
+        }
+    }
+
+    /// Keeps the candidates matching `comparison`, returning how many are
+    /// left.
+    pub fn filter(&mut self, cpu: &Chip8, comparison: Comparison) -> usize {
+        let memory = cpu.memory();
+        let previous = &self.previous;
+
+        self.candidates.retain(|&address| {
+            let (now, before) = (memory[address as usize], previous[address as usize]);
+            match comparison {
+                Comparison::Equal(value) => now == value,
+                Comparison::Changed => now != before,
+                Comparison::Unchanged => now == before,
+                Comparison::Increased => now > before,
+                Comparison::Decreased => now < before,
+            }
+        });
+        self.previous.copy_from_slice(memory);
+
+        self.candidates.len()
+    }
+
+    /// The addresses still in the running, lowest first.
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// What the byte at `address` held when last searched.
+    pub fn previous(&self, address: u16) -> u8 {
+        self.previous[address as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // 0x200: ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x200
+    const COUNTER: [u16; 4] = [0x7001, 0xA300, 0xF055, 0x1200];
+
+    fn cheat(name: &str, code: &str, enabled: bool) -> Cheat {
+        Cheat {
+            name: name.to_string(),
+            patches: Cheat::parse_code(code).unwrap(),
+            enabled,
+        }
+    }
+
+    #[test]
+    fn search_narrows_to_the_changing_address() {
+        let mut cpu = cpu_with(&COUNTER);
+        let mut search = Search::new(&cpu);
+        cpu.run_frame(4).unwrap();
+        search.filter(&cpu, Comparison::Changed);
+        cpu.run_frame(4).unwrap();
+        assert_eq!(search.filter(&cpu, Comparison::Increased), 1);
+        assert_eq!(search.candidates(), &[0x300]);
+        assert_eq!(search.previous(0x300), 2);
+    }
+
+    #[test]
+    fn codes_are_written_in_canonical_form() {
+        let mut file =
+            CheatFile::parse("# counter\n0000abcd on 300:63,0x301:7 Counter at 99\n").unwrap();
+        file.cheats_mut(0xABCD)
+            .push(cheat("Level #3", "302:01", false));
+        let text =
+            "# counter\n0000ABCD on 300:63,301:07 Counter at 99\n0000ABCD off 302:01 Level #3\n";
+        assert_eq!(file.to_text(), text);
+
+        let reread = CheatFile::parse(&file.to_text()).unwrap();
+        assert_eq!(reread.cheats(0xABCD), file.cheats(0xABCD));
+        assert_eq!(reread.to_text(), text);
+    }
+
+    #[test]
+    fn codes_apply_to_their_rom_only() {
+        let rom: Vec<u8> = COUNTER.iter().flat_map(|op| op.to_be_bytes()).collect();
+        let hash = rom_hash(&rom);
+        let mut file = CheatFile::default();
+        file.cheats_mut(hash)
+            .push(cheat("On", "300:63,301:07", true));
+        file.cheats_mut(hash).push(cheat("Off", "302:01", false));
+        assert!(file.cheats(0).is_empty());
+
+        // Frozen addresses outside memory are ignored
+        let mut cpu = cpu_with(&COUNTER);
+        let frozen: BTreeMap<u16, u8> = [(0x303, 0xAA), (0xFFFF, 1)].iter().copied().collect();
+        apply(&mut cpu, file.cheats(hash), &frozen);
+        assert_eq!(&cpu.memory()[0x300..0x304], &[0x63, 0x07, 0x00, 0xAA]);
+    }
+
+    #[test]
+    fn malformed_codes_are_rejected() {
+        assert_eq!(
+            Cheat::parse_code("300:100").unwrap_err().to_string(),
+            "Invalid cheat code 300:100, expected ADDR:VALUE pairs like 3F0:05,3F1:00"
+        );
+        assert_eq!(
+            CheatFile::parse("12 maybe 300:01").unwrap_err().to_string(),
+            "Cheat file line 1: expected on or off"
+        );
+    }
+
+    #[test]
+    fn rewriting_keeps_comments() {
+        let text = "\
+# pong.ch8
+00000001 on 300:01 First
+00000001 off 301:02 Second
+
+# tetris.ch8
+00000002 on 302:03 Lines
+";
+        let mut file = CheatFile::parse(text).unwrap();
+        assert_eq!(file.to_text(), text);
+
+        // Removed codes leave their comments, new ones follow the ROM's last code
+        file.cheats_mut(1).remove(0);
+        file.cheats_mut(1).push(cheat("Third", "303:04", true));
+        file.cheats_mut(3).push(cheat("New ROM", "304:05", true));
+        assert_eq!(
+            file.to_text(),
+            "\
+# pong.ch8
+00000001 off 301:02 Second
+00000001 on 303:04 Third
+
+# tetris.ch8
+00000002 on 302:03 Lines
+00000003 on 304:05 New ROM
+"
+        );
+    }
+}
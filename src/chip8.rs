@@ -0,0 +1,1729 @@
+use crate::framebuffer::{Framebuffer, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::font::{Font, BIG_GLYPH_SIZE, SMALL_FONT_SIZE};
+use crate::layout::Layout;
+use crate::limits::{Limits, StopReason};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::error;
+use std::fmt;
+use std::ops::Range;
+use std::time::Instant;
+
+// Instructions between checks of the wall-clock limit, reading the clock is slow
+const TIME_CHECK_INTERVAL: u64 = 256;
+
+/// Errors raised while loading or executing a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The ROM contains no data.
+    EmptyRom,
+    /// The ROM doesn't fit in memory.
+    RomTooLarge { size: usize, max: usize },
+    /// The memory layout is inconsistent.
+    InvalidLayout(&'static str),
+    /// Loading the ROM would overwrite the font.
+    RomOverlapsFont,
+    /// A custom font has the wrong number of bytes.
+    InvalidFontSize(usize),
+    /// The font doesn't fit in memory at the layout's font address.
+    FontOutsideMemory,
+    /// The entry point doesn't point into the loaded ROM.
+    EntryPointOutsideRom(u16),
+    /// The instruction at `address` isn't a valid CHIP-8 instruction.
+    UnknownInstruction { address: u16, instruction: u16 },
+    /// The program counter points past the end of memory.
+    PcOutOfBounds(u16),
+    /// `CALL` at `address` with every stack slot in use.
+    StackOverflow { address: u16 },
+    /// `RET` at `address` with nothing on the stack.
+    StackUnderflow { address: u16 },
+    /// The instruction at `address` accesses `len` bytes from `i`, past the end of memory.
+    MemoryOutOfBounds { address: u16, i: u16, len: usize },
+    /// Execution went over the machine's [`Limits`].
+    Stopped(StopReason),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::EmptyRom => write!(f, "ROM appears empty"),
+            Error::RomTooLarge { size, max } => write!(
+                f,
+                "ROM is too large ({} bytes), max size is {:#X} bytes",
+                size, max
+            ),
+            Error::InvalidLayout(reason) => write!(f, "Invalid memory layout: {}", reason),
+            Error::RomOverlapsFont => write!(f, "ROM would overwrite the font"),
+            Error::InvalidFontSize(size) => write!(
+                f,
+                "Font is {} bytes, expected 80 bytes of small glyphs optionally followed by 100 or 160 bytes of big glyphs",
+                size
+            ),
+            Error::FontOutsideMemory => write!(f, "Font doesn't fit in memory"),
+            Error::EntryPointOutsideRom(address) => {
+                write!(f, "Entry point {:#05X} is outside the ROM", address)
+            }
+            Error::UnknownInstruction {
+                address,
+                instruction,
+            } => write!(
+                f,
+                "Reached unimplemented instruction {:#06X} at {:#05X}",
+                instruction, address
+            ),
+            Error::PcOutOfBounds(address) => {
+                write!(f, "Program counter {:#05X} is outside memory", address)
+            }
+            Error::StackOverflow { address } => {
+                write!(f, "Stack overflow, CALL at {:#05X} with a full stack", address)
+            }
+            Error::StackUnderflow { address } => {
+                write!(f, "Stack underflow, RET at {:#05X} with an empty stack", address)
+            }
+            Error::MemoryOutOfBounds { address, i, len } => write!(
+                f,
+                "Instruction at {:#05X} accesses {} bytes from I = {:#05X}, past the end of memory",
+                address, len, i
+            ),
+            Error::Stopped(reason) => write!(f, "Execution stopped: {}", reason),
+        }
+    }
+}
+
+impl Error {
+    /// The address of the instruction that failed, for errors raised while
+    /// running.
+    pub fn address(&self) -> Option<u16> {
+        match self {
+            Error::UnknownInstruction { address, .. }
+            | Error::StackOverflow { address }
+            | Error::StackUnderflow { address }
+            | Error::MemoryOutOfBounds { address, .. } => Some(*address),
+            Error::PcOutOfBounds(address) => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Current values of the delay and sound timers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+}
+
+/// How a program finished, see [`Chip8::exit_reason`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitReason {
+    /// `00FD` (SUPER-CHIP EXIT) at `address`.
+    Exit { address: u16 },
+    /// `1nnn` at `address` jumping to itself, how most test ROMs finish.
+    SelfJump { address: u16 },
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Exit { address } => write!(f, "exited at {:#05X}", address),
+            ExitReason::SelfJump { address } => {
+                write!(f, "halted on a jump to itself at {:#05X}", address)
+            }
+        }
+    }
+}
+
+/// A CHIP-8 machine: memory, registers, timers, keypad and framebuffer.
+///
+/// The machine has no notion of real time. Frontends call [`Chip8::run_frame`]
+/// (or [`Chip8::step`] and [`Chip8::tick_timers`]) at their own pace, feed key
+/// state in with [`Chip8::set_key`] and present [`Chip8::framebuffer`].
+#[derive(Clone)]
+pub struct Chip8 {
+    // Where the program and font live in memory
+    layout: Layout,
+
+    // RAM, 4kb in the default layout
+    ram: Vec<u8>,
+
+    // Hex digit glyphs stored at the layout's font address
+    font: Font,
+
+    // General purpose registers
+    v: [u8; 16],
+
+    // Index register
+    i: u16,
+
+    // Program counter
+    pc: u16,
+
+    // Stack and stack pointer
+    stack: [u16; 16],
+    sp: usize,
+
+    // Delay and sound timers
+    dt: u8,
+    st: u8,
+
+    // Display
+    framebuffer: Framebuffer,
+
+    // Hex keypad, true while a key is held
+    keys: [bool; 16],
+
+    // Key an Fx0A saw go down, stored in Vx once it's released
+    pending_key: Option<u8>,
+
+    // Source for Cxnn, seeded from entropy unless set
+    rng: StdRng,
+
+    // Length of the loaded ROM
+    rom_size: usize,
+
+    // Sandboxing, with the instructions executed and time since the limits were set
+    limits: Limits,
+    cycles: u64,
+    started: Instant,
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
+}
+
+impl Chip8 {
+    /// Creates a machine with the default layout, cleared memory and the font loaded.
+    pub fn new() -> Chip8 {
+        Chip8::with_layout(Layout::default()).expect("Default layout is valid")
+    }
+
+    /// Creates a machine with a custom memory layout.
+    pub fn with_layout(layout: Layout) -> Result<Chip8, Error> {
+        layout.validate()?;
+
+        // Initialise empty Chip8
+        let mut cpu = Chip8 {
+            layout,
+            ram: vec![0; layout.memory_size],
+            font: Font::default(),
+            v: [0; 16],
+            i: 0,
+            pc: layout.entry_point,
+            stack: [0; 16],
+            sp: 0,
+            dt: 0,
+            st: 0,
+            framebuffer: Framebuffer::new(),
+            keys: [false; 16],
+            pending_key: None,
+            rng: StdRng::from_entropy(),
+            rom_size: 0,
+            limits: Limits::default(),
+            cycles: 0,
+            started: Instant::now(),
+        };
+
+        // Write font, to 0x050 - 0x09F by default
+        cpu.set_font(Font::default())?;
+
+        // Return cpu
+        Ok(cpu)
+    }
+
+    /// Copies a ROM into memory at the layout's load address.
+    ///
+    /// Fails without touching memory if the ROM doesn't fit, would overwrite
+    /// the font or doesn't contain the entry point.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        self.layout.validate_rom(rom.len(), self.font.size())?;
+        self.write_ram(rom, self.layout.load_address);
+        self.rom_size = rom.len();
+
+        Ok(())
+    }
+
+    /// Replaces the font, writing it to memory at the layout's font address.
+    ///
+    /// Call this before [`Chip8::load_rom`] so the ROM can be checked against
+    /// the space the font takes up.
+    pub fn set_font(&mut self, font: Font) -> Result<(), Error> {
+        let start = self.layout.font_address as usize;
+
+        if start + font.size() > self.layout.memory_size {
+            return Err(Error::FontOutsideMemory);
+        }
+
+        // Clear out any big glyphs of the previous font
+        for byte in &mut self.ram[start..start + self.font.size()] {
+            *byte = 0;
+        }
+
+        self.ram[start..start + SMALL_FONT_SIZE].copy_from_slice(font.small());
+        self.ram[start + SMALL_FONT_SIZE..start + font.size()].copy_from_slice(font.big());
+        self.font = font;
+
+        Ok(())
+    }
+
+    /// Size in bytes of the loaded ROM, 0 if none has been loaded.
+    pub fn rom_size(&self) -> usize {
+        self.rom_size
+    }
+
+    /// The font in use.
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+
+    /// The memory layout this machine was created with.
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Restricts how the program may run from now on, see [`Limits`].
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.started = Instant::now();
+    }
+
+    /// The limits the program runs under.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Why the program has finished, if it has.
+    ///
+    /// A program is finished once PC is on a `00FD` or a jump to itself.
+    /// Neither moves PC, so stepping a finished program only runs the timers down.
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        let address = self.pc;
+
+        match self.read_instruction(address) {
+            Ok(0x00FD) => Some(ExitReason::Exit { address }),
+            Ok(instruction) if address < 0x1000 && instruction == 0x1000 | address => {
+                Some(ExitReason::SelfJump { address })
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs `instructions_per_frame` instructions, then ticks the timers once.
+    pub fn run_frame(&mut self, instructions_per_frame: u32) -> Result<(), Error> {
+        for _ in 0..instructions_per_frame {
+            self.step()?;
+        }
+        self.tick_timers();
+
+        Ok(())
+    }
+
+    /// Decrements the delay and sound timers, to be called at 60Hz.
+    pub fn tick_timers(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+    }
+
+    /// Reseeds the random number generator used by `Cxnn`, making runs reproducible.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// The display contents.
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    /// Sets whether `key` (0x0 - 0xF) on the hex keypad is held down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is greater than 0xF.
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        assert!(key <= 0xF, "Invalid hex value passed to set_key()");
+        self.keys[key as usize] = pressed;
+    }
+
+    /// Whether `key` (0x0 - 0xF) is currently held down.
+    pub fn key(&self, key: u8) -> bool {
+        self.keys[(key & 0xF) as usize]
+    }
+
+    /// The delay and sound timers.
+    pub fn timers(&self) -> Timers {
+        Timers {
+            delay: self.dt,
+            sound: self.st,
+        }
+    }
+
+    /// Overwrites the delay and sound timers.
+    pub fn set_timers(&mut self, timers: Timers) {
+        self.dt = timers.delay;
+        self.st = timers.sound;
+    }
+
+    /// Value of general purpose register V`register` (0x0 - 0xF).
+    pub fn v(&self, register: usize) -> u8 {
+        self.v[register]
+    }
+
+    /// Sets general purpose register V`register` (0x0 - 0xF).
+    pub fn set_v(&mut self, register: usize, value: u8) {
+        self.v[register] = value;
+    }
+
+    /// All general purpose registers, V0 to VF.
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    /// The index register.
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    /// Sets the index register.
+    pub fn set_i(&mut self, value: u16) {
+        self.i = value;
+    }
+
+    /// The program counter.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Sets the program counter.
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    /// The stack pointer.
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Sets the stack pointer.
+    pub fn set_sp(&mut self, value: usize) {
+        self.sp = value;
+    }
+
+    /// The call stack. Only entries below [`Chip8::sp`] are in use.
+    pub fn stack(&self) -> &[u16; 16] {
+        &self.stack
+    }
+
+    /// Mutable access to the call stack.
+    pub fn stack_mut(&mut self) -> &mut [u16; 16] {
+        &mut self.stack
+    }
+
+    /// The whole of memory, including the font and interpreter area.
+    pub fn memory(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Mutable access to the whole of memory.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn write_ram(&mut self, array: &[u8], start_address: u16) {
+        let start = start_address as usize;
+        self.ram[start..start + array.len()].copy_from_slice(array);
+    }
+
+    fn read_instruction(&self, start_address: u16) -> Result<u16, Error> {
+        let start = start_address as usize;
+
+        // Instructions are two bytes long
+        if start + 1 >= self.ram.len() {
+            return Err(Error::PcOutOfBounds(start_address));
+        }
+
+        let upper = self.ram[start] as u16;
+        let lower = self.ram[start + 1] as u16;
+
+        Ok((upper << 8) | lower)
+    }
+
+    // Memory indices of `len` bytes from I, checking they're all inside memory
+    fn i_range(&self, len: usize) -> Result<Range<usize>, Error> {
+        let start = self.i as usize;
+
+        if start + len > self.ram.len() {
+            return Err(Error::MemoryOutOfBounds {
+                // PC has already moved past the instruction
+                address: self.pc.wrapping_sub(2),
+                i: self.i,
+                len,
+            });
+        }
+
+        Ok(start..start + len)
+    }
+
+    /// Fetches, decodes and executes a single instruction.
+    ///
+    /// Faulting instructions have no effect, leaving PC pointing at them.
+    pub fn step(&mut self) -> Result<(), Error> {
+        let address = self.pc;
+        let result = self.check_limits().and_then(|_| self.execute(address));
+
+        match result {
+            Ok(()) => self.cycles += 1,
+            // Leave PC on the faulting instruction
+            Err(_) => self.pc = address,
+        }
+
+        result
+    }
+
+    // Fails if running the next instruction would go over the limits
+    fn check_limits(&self) -> Result<(), Error> {
+        if let Some(max_cycles) = self.limits.max_cycles {
+            if self.cycles >= max_cycles {
+                return Err(Error::Stopped(StopReason::CycleLimit(max_cycles)));
+            }
+        }
+
+        if let Some(max_time) = self.limits.max_time {
+            if self.cycles.is_multiple_of(TIME_CHECK_INTERVAL) && self.started.elapsed() > max_time
+            {
+                return Err(Error::Stopped(StopReason::TimeLimit(max_time)));
+            }
+        }
+
+        // A jump to itself with the timers stopped will never do anything else
+        if self.limits.detect_infinite_loops && self.dt == 0 && self.st == 0 {
+            if let Some(ExitReason::SelfJump { address }) = self.exit_reason() {
+                return Err(Error::Stopped(StopReason::InfiniteLoop { address }));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fails if `halt_on_self_modify` is set and `range` overlaps the ROM
+    fn check_write(&self, range: &Range<usize>) -> Result<(), Error> {
+        let rom_start = self.layout.load_address as usize;
+        let rom_end = rom_start + self.rom_size;
+
+        if self.limits.halt_on_self_modify && range.start < rom_end && rom_start < range.end {
+            return Err(Error::Stopped(StopReason::SelfModifyingWrite {
+                // PC has already moved past the instruction
+                address: self.pc.wrapping_sub(2),
+                target: range.start.max(rom_start) as u16,
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn execute(&mut self, address: u16) -> Result<(), Error> {
+        // Fetch
+        let instruction = self.read_instruction(address)?;
+        self.pc = address.wrapping_add(2);
+
+        // Get instruction arguments
+        let op = (instruction & 0b1111_0000_0000_0000) >> 12;
+        let x = ((instruction & 0b0000_1111_0000_0000) >> 8) as usize;
+        let y = ((instruction & 0b0000_0000_1111_0000) >> 4) as usize;
+        let n = instruction & 0b0000_0000_0000_1111;
+        let nn = (instruction & 0b0000_0000_1111_1111) as u8;
+        let nnn = instruction & 0b0000_1111_1111_1111;
+
+        // Decode
+        match op {
+            0x0 => {
+                match instruction {
+                    0x00E0 => {
+                        // CLS
+                        self.cls();
+                    }
+
+                    0x00EE => {
+                        // RET
+                        self.ret()?;
+                    }
+
+                    0x00FD => {
+                        // EXIT
+                        self.exit();
+                    }
+                    _ => {
+                        return Err(Error::UnknownInstruction {
+                            address,
+                            instruction,
+                        })
+                    }
+                }
+            }
+
+            0x1 => {
+                // JP
+                self.jp(nnn);
+            }
+
+            0x2 => {
+                // CALL
+                self.call(nnn)?;
+            }
+
+            0x3 => {
+                // SE immediate
+                self.se_imm(x, nn);
+            }
+
+            0x4 => {
+                // SNE immediate
+                self.sne_imm(x, nn);
+            }
+
+            0x5 => {
+                // SE
+                self.se(x, y)
+            }
+
+            0x6 => {
+                // LD immediate
+                self.ld_imm(x, nn);
+            }
+
+            0x7 => {
+                // ADD immediate
+                self.add_imm(x, nn);
+            }
+
+            0x8 => {
+                match n {
+                    0x0 => {
+                        // LD
+                        self.ld(x, y);
+                    }
+
+                    0x1 => {
+                        // OR
+                        self.or(x, y);
+                    }
+
+                    0x2 => {
+                        // AND
+                        self.and(x, y);
+                    }
+
+                    0x3 => {
+                        // XOR
+                        self.xor(x, y);
+                    }
+
+                    0x4 => {
+                        // ADD
+                        self.add(x, y);
+                    }
+
+                    0x5 => {
+                        // SUB
+                        self.sub(x, y);
+                    }
+
+                    0x6 => {
+                        // SHR
+                        self.shr(x);
+                    }
+
+                    0x7 => {
+                        // SUBN
+                        self.subn(x, y);
+                    }
+
+                    0xE => {
+                        // SHL
+                        self.shl(x);
+                    }
+
+                    _ => {
+                        return Err(Error::UnknownInstruction {
+                            address,
+                            instruction,
+                        })
+                    }
+                }
+            }
+
+            0x9 => {
+                // SNE
+                self.sne(x, y);
+            }
+
+            0xA => {
+                // LD Index
+                self.ld_i_imm(nnn);
+            }
+
+            0xB => {
+                // JP to nnn + v0
+                self.jp_offset(nnn);
+            }
+
+            0xC => {
+                // RAND
+                self.rand(x, nn);
+            }
+
+            0xD => {
+                // DRW
+                self.drw(x, y, n)?;
+            }
+
+            0xE => match nn {
+                0x9E => {
+                    // SKP
+                    self.skp(x);
+                }
+
+                0xA1 => {
+                    // SKNP
+                    self.sknp(x);
+                }
+                _ => {
+                    return Err(Error::UnknownInstruction {
+                        address,
+                        instruction,
+                    })
+                }
+            },
+
+            0xF => {
+                match nn {
+                    0x07 => {
+                        // LD Vx, DT
+                        self.ld_from_dt(x);
+                    }
+
+                    0x0A => {
+                        // LD Vx, K
+                        self.ld_key(x);
+                    }
+
+                    0x15 => {
+                        // LD DT, Vx
+                        self.ld_dt(x);
+                    }
+
+                    0x18 => {
+                        // LD ST, Vx
+                        self.ld_st(x);
+                    }
+
+                    0x1E => {
+                        // ADD I
+                        self.add_i(x);
+                    }
+
+                    0x29 => {
+                        // LD Font
+                        self.ld_f(x);
+                    }
+
+                    0x30 if !self.font.big().is_empty() => {
+                        // LD HF, only with a font that has big glyphs
+                        self.ld_hf(x);
+                    }
+
+                    0x33 => {
+                        // LD bcd
+                        self.ld_bcd(x)?;
+                    }
+
+                    0x55 => {
+                        // LD into I
+                        self.ld_into_i(x)?;
+                    }
+
+                    0x65 => {
+                        // LD from I
+                        self.ld_from_i(x)?;
+                    }
+
+                    _ => {
+                        return Err(Error::UnknownInstruction {
+                            address,
+                            instruction,
+                        })
+                    }
+                }
+            }
+
+            _ => {
+                return Err(Error::UnknownInstruction {
+                    address,
+                    instruction,
+                })
+            }
+        }
+
+        // Execute
+
+        Ok(())
+    }
+
+    fn cls(&mut self) {
+        // 00E0 - CLS
+        // Clears the display
+        self.framebuffer.clear();
+    }
+
+    fn ret(&mut self) -> Result<(), Error> {
+        // 00EE - RET
+        // Decrement SP, Set PC to top value in Stack
+        let address = self.pc.wrapping_sub(2);
+
+        if self.sp == 0 {
+            return Err(Error::StackUnderflow { address });
+        }
+        // Only reachable by setting SP by hand
+        if self.sp > self.stack.len() {
+            return Err(Error::StackOverflow { address });
+        }
+
+        self.sp -= 1;
+        self.pc = self.stack[self.sp];
+
+        Ok(())
+    }
+
+    fn exit(&mut self) {
+        // 00FD - EXIT (SUPER-CHIP)
+        // Exit the interpreter, here by staying on this instruction for good
+        self.pc = self.pc.wrapping_sub(2);
+    }
+
+    fn jp(&mut self, address: u16) {
+        // 1nnn - JP nnn
+        // Sets program counter to nnn
+        self.pc = address;
+    }
+
+    fn call(&mut self, address: u16) -> Result<(), Error> {
+        // 2nnn - CALL nnn
+        // Push current PC to stack, Increment SP, Set program counter to nnn
+        if self.sp >= self.stack.len() {
+            return Err(Error::StackOverflow {
+                address: self.pc.wrapping_sub(2),
+            });
+        }
+
+        self.stack[self.sp] = self.pc;
+        self.sp += 1;
+        self.pc = address;
+
+        Ok(())
+    }
+
+    fn se_imm(&mut self, target_register: usize, imm_value: u8) {
+        // 3xnn - SE Vx, nn
+        // Skip next instruction if Vx = nn
+        if self.v[target_register] == imm_value {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn sne_imm(&mut self, target_register: usize, imm_value: u8) {
+        // 4xnn - SNE Vx, nn
+        // Skip next instruction if Vx != nn
+        if self.v[target_register] != imm_value {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn se(&mut self, x_register: usize, y_register: usize) {
+        // 5xy0 - SE Vx, Vy
+        // Skip next instruction if Vx = Vy
+        if self.v[x_register] == self.v[y_register] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn ld_imm(&mut self, target_register: usize, imm_value: u8) {
+        // 6xnn - LD Vx, nn
+        // Loads the immediate value nn into register Vx
+        self.v[target_register] = imm_value;
+    }
+
+    fn add_imm(&mut self, x_register: usize, imm_value: u8) {
+        // 7xnn - ADD Vx, nn
+        // Adds the value nn to register Vx and stores it in Vx
+        // Note: doesn't affect overflow flag
+        self.v[x_register] = self.v[x_register].wrapping_add(imm_value);
+    }
+
+    fn ld(&mut self, x_register: usize, y_register: usize) {
+        // 8xy0 - LD Vx, Vy
+        // Set Vx = Vy
+        self.v[x_register] = self.v[y_register];
+    }
+
+    fn or(&mut self, x_register: usize, y_register: usize) {
+        // 8xy1 - OR Vx, Vy
+        // Set Vx = Vx OR Vy
+        self.v[x_register] |= self.v[y_register];
+    }
+
+    fn and(&mut self, x_register: usize, y_register: usize) {
+        // 8xy2 - AND Vx, Vy
+        // Set Vx = Vx AND Vy
+        self.v[x_register] &= self.v[y_register];
+    }
+
+    fn xor(&mut self, x_register: usize, y_register: usize) {
+        // 8xy3 - XOR Vx, Vy
+        // Set Vx = Vx XOR Vy
+        self.v[x_register] ^= self.v[y_register];
+    }
+
+    fn add(&mut self, x_register: usize, y_register: usize) {
+        // 8xy4 - ADD Vx, Vy
+        // Set Vx = Vx + Vy
+        // Set VF = carry
+        // Note: VF is written last so it holds the flag when x is F
+        let (value, overflow) = self.v[x_register].overflowing_add(self.v[y_register]);
+
+        self.v[x_register] = value;
+        self.v[0xF] = overflow as u8;
+    }
+
+    fn sub(&mut self, x_register: usize, y_register: usize) {
+        // 8xy5 - SUB Vx, Vy
+        // Set Vx = Vx - Vy
+        // Set VF = Not borrow (Vx >= Vy)
+        let not_borrow = self.v[x_register] >= self.v[y_register];
+
+        self.v[x_register] = self.v[x_register].wrapping_sub(self.v[y_register]);
+        self.v[0xF] = not_borrow as u8;
+    }
+
+    fn shr(&mut self, x_register: usize) {
+        // 8xy6 - SHR Vx
+        // Set Vx = Vx >> 1
+        // Set VF = LSB of X = 1
+        let lsb = self.v[x_register] & 0b0000_0001;
+
+        self.v[x_register] >>= 1;
+        self.v[0xF] = lsb;
+    }
+
+    fn subn(&mut self, x_register: usize, y_register: usize) {
+        // 8xy7 - SUBN Vx, Vy
+        // Set Vx = Vy - Vx
+        // Set VF = Not borrow (Vy >= Vx)
+        let not_borrow = self.v[y_register] >= self.v[x_register];
+
+        self.v[x_register] = self.v[y_register].wrapping_sub(self.v[x_register]);
+        self.v[0xF] = not_borrow as u8;
+    }
+
+    fn shl(&mut self, x_register: usize) {
+        // 8xyE - SHL Vx
+        // Set Vx = Vx << 1
+        // Set VF = MSB of X = 1
+        let msb = self.v[x_register] >> 7;
+
+        self.v[x_register] <<= 1;
+        self.v[0xF] = msb;
+    }
+
+    fn sne(&mut self, x_register: usize, y_register: usize) {
+        // 9xy0 - SNE Vx, Vy
+        // Skip next instruction if Vx != Vy
+        if self.v[x_register] != self.v[y_register] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn ld_i_imm(&mut self, address: u16) {
+        // Annn - LD I, addr
+        // Sets Index register to addr
+        self.i = address;
+    }
+
+    fn jp_offset(&mut self, address: u16) {
+        // Bnnn - JP to nnn + V0
+        // Set PC to nnn + V0
+        self.pc = address + (self.v[0x0] as u16);
+    }
+
+    fn rand(&mut self, x_register: usize, imm_value: u8) {
+        // Cxnn - RAND Vx, nn
+        // Set Vx to a random 8-bit number ANDed with nn
+        let rand_val: u8 = self.rng.gen();
+        self.v[x_register] = rand_val & imm_value;
+    }
+
+    fn drw(&mut self, x_register: usize, y_register: usize, n_bytes: u16) -> Result<(), Error> {
+        // Dxyn - DRW Vx, Vy, n
+        // Display n-byte sprite starting at memory location I at (Vx, Vy)
+        // Set VF = collision
+
+        // Get starting coordinates with appropriate wrapping
+        let x_coord = self.v[x_register] as usize % DISPLAY_WIDTH;
+        let y_coord = self.v[y_register] as usize % DISPLAY_HEIGHT;
+
+        // Sprites are clipped at the bottom of the display, so only visible rows are read
+        let rows = (n_bytes as usize).min(DISPLAY_HEIGHT - y_coord);
+        let sprite_start = self.i_range(rows)?.start;
+
+        // Reset VF
+        self.v[0xF] = 0;
+        let mut set_vf = false;
+
+        // For every row of sprite data
+        for n in 0..rows {
+            // Load data from ram
+            let sprite_data: u8 = self.ram[sprite_start + n];
+
+            // For each bit in sprite row
+            for bit in 0..8 {
+                // Sprites are clipped at the side of the display
+                if x_coord + bit >= DISPLAY_WIDTH {
+                    break;
+                }
+
+                // Mask MSB and if set, flip pixel on display
+                if (sprite_data << bit) & 0b1000_0000 != 0 {
+                    // set_vf will be true if any writes turned a pixel off
+                    set_vf |= self.framebuffer.flip_pixel(x_coord + bit, y_coord + n);
+                }
+            }
+        }
+
+        // If needed, set VF
+        if set_vf {
+            self.v[0xF] = 1;
+        }
+
+        Ok(())
+    }
+
+    fn skp(&mut self, x_register: usize) {
+        // Ex9E - SKP Vx
+        // Skip next instruction if key with value Vx is pressed
+        if self.keys[(self.v[x_register] & 0xF) as usize] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn sknp(&mut self, x_register: usize) {
+        // ExA1 - SKNP Vx
+        // Skip next instruction if key with value Vx is not pressed
+        if !self.keys[(self.v[x_register] & 0xF) as usize] {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn ld_from_dt(&mut self, x_register: usize) {
+        // Fx07 - LD Vx, DT
+        // Set Vx = delay timer value
+        self.v[x_register] = self.dt;
+    }
+
+    fn ld_key(&mut self, x_register: usize) {
+        // Fx0A - LD Vx, K
+        // Wait for a key press and store the value of the key in Vx
+
+        // As on the COSMAC VIP, the key is only taken once it's released again,
+        // so a held key doesn't satisfy a run of Fx0As. Other keys pressed in
+        // the meantime are ignored.
+        match self.pending_key {
+            Some(key) if !self.keys[key as usize] => {
+                self.v[x_register] = key;
+                self.pending_key = None;
+                return;
+            }
+            Some(_) => {}
+            None => {
+                self.pending_key = self
+                    .keys
+                    .iter()
+                    .position(|&pressed| pressed)
+                    .map(|key| key as u8);
+            }
+        }
+
+        // Not released yet, repeat this instruction
+        self.pc = self.pc.wrapping_sub(2);
+    }
+
+    fn ld_dt(&mut self, x_register: usize) {
+        // Fx15 - LD DT, Vx
+        // Set delay timer = Vx
+        self.dt = self.v[x_register];
+    }
+
+    fn ld_st(&mut self, x_register: usize) {
+        // Fx18 - LD ST, Vx
+        // Set sound timer = Vx
+        self.st = self.v[x_register];
+    }
+
+    fn add_i(&mut self, x_register: usize) {
+        // Fx1E - ADD I, Vx
+        // Set I = I + Vx
+
+        self.i = self.i.wrapping_add(self.v[x_register] as u16);
+    }
+
+    fn ld_f(&mut self, x_register: usize) {
+        // Fx29 - LD F, Vx
+        // Set I = location of sprite for digit Vx
+
+        // Index = base(0x050 by default) + Vx * offset(0x5), only the low nibble is a digit
+        self.i = self.layout.font_address + (self.v[x_register] & 0xF) as u16 * 0x005;
+    }
+
+    fn ld_hf(&mut self, x_register: usize) {
+        // Fx30 - LD HF, Vx (SUPER-CHIP)
+        // Set I = location of big 8x10 sprite for digit Vx
+
+        // Big glyphs follow the small ones. A small font can end right at the
+        // top of a 64kb memory, then I wraps around like it does for ADD I
+        let base = self
+            .layout
+            .font_address
+            .wrapping_add(SMALL_FONT_SIZE as u16);
+        let offset = (self.v[x_register] & 0xF) as u16 * BIG_GLYPH_SIZE as u16;
+        self.i = base.wrapping_add(offset);
+    }
+
+    fn ld_bcd(&mut self, x_register: usize) -> Result<(), Error> {
+        // Fx33 - LD B, Vx
+        // Store BCD representation of Vx in memory locations I, I+1 and I+2
+
+        let range = self.i_range(3)?;
+        self.check_write(&range)?;
+
+        let start_address = range.start;
+        let mut value = self.v[x_register];
+
+        // Store least significant digit in I+2
+        self.ram[start_address + 2] = value % 10;
+        value /= 10;
+
+        // Store second digit in I+1
+        self.ram[start_address + 1] = value % 10;
+        value /= 10;
+
+        // Store most significant digit in I
+        self.ram[start_address] = value % 10;
+
+        Ok(())
+    }
+
+    fn ld_into_i(&mut self, x_register: usize) -> Result<(), Error> {
+        // Fx55 - LD [I], Vx
+        // Stores registers V0 to Vx into memory starting at I
+
+        let range = self.i_range(x_register + 1)?;
+        self.check_write(&range)?;
+
+        let start_address = range.start;
+
+        for i in 0..=x_register {
+            self.ram[start_address + i] = self.v[i];
+        }
+
+        Ok(())
+    }
+
+    fn ld_from_i(&mut self, x_register: usize) -> Result<(), Error> {
+        // Fx65 - LD Vx, [I]
+        // Reads registers V0 to Vx from memory starting at I
+
+        let start_address = self.i_range(x_register + 1)?.start;
+
+        for i in 0..=x_register {
+            self.v[i] = self.ram[start_address + i];
+        }
+
+        Ok(())
+    }
+}
+
+// Machine with `program` loaded at 0x200, for tests throughout the crate
+#[cfg(test)]
+pub(crate) fn cpu_with(program: &[u16]) -> Chip8 {
+    let rom: Vec<u8> = program.iter().flat_map(|op| op.to_be_bytes()).collect();
+    let mut cpu = Chip8::new();
+    cpu.load_rom(&rom).unwrap();
+    cpu
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit_pixels(cpu: &Chip8) -> usize {
+        cpu.framebuffer()
+            .rows()
+            .map(|row| row.iter().filter(|&&p| p).count())
+            .sum()
+    }
+
+    #[test]
+    fn load_rom_rejects_empty_and_oversized_roms() {
+        let mut cpu = Chip8::new();
+
+        assert_eq!(cpu.load_rom(&[]), Err(Error::EmptyRom));
+        assert_eq!(
+            cpu.load_rom(&[0; 3585]),
+            Err(Error::RomTooLarge {
+                size: 3585,
+                max: 3584
+            })
+        );
+        assert_eq!(cpu.load_rom(&[0; 3584]), Ok(()));
+    }
+
+    #[test]
+    fn step_reports_unknown_instructions() {
+        let mut cpu = cpu_with(&[0x00E0, 0xFFFF]);
+
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(
+            cpu.step(),
+            Err(Error::UnknownInstruction {
+                address: 0x202,
+                instruction: 0xFFFF
+            })
+        );
+    }
+
+    #[test]
+    fn cls_clears_display() {
+        let mut cpu = cpu_with(&[0xD005, 0x00E0]);
+        cpu.i = crate::FONT_ADDRESS;
+
+        cpu.step().unwrap();
+        assert!(lit_pixels(&cpu) > 0);
+        cpu.step().unwrap();
+        assert_eq!(lit_pixels(&cpu), 0);
+    }
+
+    #[test]
+    fn call_and_ret() {
+        // 0x200: CALL 0x206, 0x206: RET
+        let mut cpu = cpu_with(&[0x2206, 0x0000, 0x0000, 0x00EE]);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+        assert_eq!(cpu.sp, 1);
+        assert_eq!(cpu.stack[0], 0x202);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn stack_faults_leave_pc_on_the_instruction() {
+        let mut cpu = cpu_with(&[0x00EE]);
+        assert_eq!(cpu.step(), Err(Error::StackUnderflow { address: 0x200 }));
+        assert_eq!(cpu.pc, 0x200);
+
+        // 0x200: CALL 0x200, forever. Every slot holds a call.
+        let mut cpu = cpu_with(&[0x2200]);
+        for _ in 0..16 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.sp, 16);
+        assert_eq!(cpu.step(), Err(Error::StackOverflow { address: 0x200 }));
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.sp, 16);
+    }
+
+    #[test]
+    fn memory_faults_leave_state_untouched() {
+        // LD I, 0xFFE; LD [I], V2
+        let mut cpu = cpu_with(&[0xAFFE, 0xF255]);
+        cpu.step().unwrap();
+
+        assert_eq!(
+            cpu.step(),
+            Err(Error::MemoryOutOfBounds {
+                address: 0x202,
+                i: 0xFFE,
+                len: 3
+            })
+        );
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(&cpu.ram[0xFFE..], &[0, 0]);
+
+        // Running off the end of memory
+        let mut cpu = cpu_with(&[0x1FFF]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(Error::PcOutOfBounds(0xFFF)));
+    }
+
+    #[test]
+    fn drw_only_reads_visible_rows() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0xFFF;
+        cpu.ram[0xFFF] = 0xFF;
+        cpu.v[0x1] = 31;
+
+        // The second row is clipped, so isn't read from past the end of memory
+        cpu.drw(0x0, 0x1, 2).unwrap();
+        assert_eq!(lit_pixels(&cpu), 8);
+
+        cpu.v[0x1] = 30;
+        assert!(cpu.drw(0x0, 0x1, 2).is_err());
+        assert_eq!(lit_pixels(&cpu), 8);
+    }
+
+    #[test]
+    fn cycle_limit_stops_execution() {
+        // ADD V0, 1; JP 0x200
+        let mut cpu = cpu_with(&[0x7001, 0x1200]);
+        cpu.set_limits(Limits {
+            max_cycles: Some(5),
+            ..Limits::default()
+        });
+
+        assert_eq!(
+            cpu.run_frame(10),
+            Err(Error::Stopped(StopReason::CycleLimit(5)))
+        );
+        assert_eq!(cpu.cycles(), 5);
+        assert_eq!(cpu.v[0x0], 3);
+    }
+
+    #[test]
+    fn self_jump_is_an_infinite_loop_once_timers_stop() {
+        // LD V0, 2; LD DT, V0; JP 0x204
+        let mut cpu = cpu_with(&[0x6002, 0xF015, 0x1204]);
+        cpu.set_limits(Limits {
+            detect_infinite_loops: true,
+            ..Limits::default()
+        });
+
+        // Still waiting on the delay timer
+        cpu.run_frame(3).unwrap();
+        cpu.run_frame(3).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        assert_eq!(
+            cpu.run_frame(3),
+            Err(Error::Stopped(StopReason::InfiniteLoop { address: 0x204 }))
+        );
+    }
+
+    #[test]
+    fn exit_and_self_jump_finish_the_program() {
+        // LD V0, 1; EXIT
+        let mut cpu = cpu_with(&[0x6001, 0x00FD]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.exit_reason(), Some(ExitReason::Exit { address: 0x202 }));
+
+        // Exiting is for good
+        cpu.run_frame(5).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.cycles(), 6);
+
+        // JP 0x202; JP 0x202
+        let mut cpu = cpu_with(&[0x1202, 0x1202]);
+        assert_eq!(cpu.exit_reason(), None);
+        cpu.step().unwrap();
+        assert_eq!(
+            cpu.exit_reason(),
+            Some(ExitReason::SelfJump { address: 0x202 })
+        );
+    }
+
+    #[test]
+    fn self_modifying_writes_stop_when_asked() {
+        // LD I, 0x200; LD [I], V0
+        let program = [0xA200, 0xF055];
+
+        let mut cpu = cpu_with(&program);
+        cpu.run_frame(2).unwrap();
+        assert_eq!(cpu.ram[0x200], 0);
+
+        let mut cpu = cpu_with(&program);
+        cpu.set_limits(Limits {
+            halt_on_self_modify: true,
+            ..Limits::default()
+        });
+        assert_eq!(
+            cpu.run_frame(2),
+            Err(Error::Stopped(StopReason::SelfModifyingWrite {
+                address: 0x202,
+                target: 0x200
+            }))
+        );
+        assert_eq!(cpu.ram[0x200], 0xA2);
+    }
+
+    #[test]
+    fn jp_and_jp_offset() {
+        let mut cpu = Chip8::new();
+
+        cpu.jp(0x345);
+        assert_eq!(cpu.pc, 0x345);
+
+        cpu.v[0x0] = 0x10;
+        cpu.jp_offset(0x300);
+        assert_eq!(cpu.pc, 0x310);
+    }
+
+    #[test]
+    fn skips() {
+        let mut cpu = Chip8::new();
+        cpu.v[0x1] = 0x42;
+        cpu.v[0x2] = 0x42;
+        cpu.v[0x3] = 0x07;
+
+        let pc = cpu.pc;
+        cpu.se_imm(0x1, 0x42);
+        assert_eq!(cpu.pc, pc + 2);
+        cpu.se_imm(0x1, 0x43);
+        assert_eq!(cpu.pc, pc + 2);
+
+        cpu.sne_imm(0x1, 0x43);
+        assert_eq!(cpu.pc, pc + 4);
+        cpu.sne_imm(0x1, 0x42);
+        assert_eq!(cpu.pc, pc + 4);
+
+        cpu.se(0x1, 0x2);
+        assert_eq!(cpu.pc, pc + 6);
+        cpu.se(0x1, 0x3);
+        assert_eq!(cpu.pc, pc + 6);
+
+        cpu.sne(0x1, 0x3);
+        assert_eq!(cpu.pc, pc + 8);
+        cpu.sne(0x1, 0x2);
+        assert_eq!(cpu.pc, pc + 8);
+    }
+
+    #[test]
+    fn loads_and_add_imm() {
+        let mut cpu = Chip8::new();
+
+        cpu.ld_imm(0x4, 0xFE);
+        assert_eq!(cpu.v[0x4], 0xFE);
+
+        // Wraps without touching VF
+        cpu.add_imm(0x4, 0x03);
+        assert_eq!(cpu.v[0x4], 0x01);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.ld(0x5, 0x4);
+        assert_eq!(cpu.v[0x5], 0x01);
+
+        cpu.ld_i_imm(0xABC);
+        assert_eq!(cpu.i, 0xABC);
+    }
+
+    #[test]
+    fn bitwise_ops() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0b1100;
+        cpu.v[0x2] = 0b1010;
+        cpu.or(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0b1110);
+
+        cpu.v[0x1] = 0b1100;
+        cpu.and(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0b1000);
+
+        cpu.v[0x1] = 0b1100;
+        cpu.xor(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0b0110);
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0xF0;
+        cpu.v[0x2] = 0x0F;
+        cpu.add(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0xFF);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.v[0x2] = 0x02;
+        cpu.add(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0x01);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn sub_sets_not_borrow() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0x10;
+        cpu.v[0x2] = 0x01;
+        cpu.sub(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0x0F);
+        assert_eq!(cpu.v[0xF], 1);
+
+        cpu.v[0x1] = 0x01;
+        cpu.v[0x2] = 0x02;
+        cpu.sub(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0xFF);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn subn_sets_not_borrow() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0x01;
+        cpu.v[0x2] = 0x10;
+        cpu.subn(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0x0F);
+        assert_eq!(cpu.v[0xF], 1);
+
+        cpu.v[0x1] = 0x02;
+        cpu.v[0x2] = 0x01;
+        cpu.subn(0x1, 0x2);
+        assert_eq!(cpu.v[0x1], 0xFF);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn shr_shifts_lsb_into_vf() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0b1000_0011;
+        cpu.shr(0x1);
+        assert_eq!(cpu.v[0x1], 0b0100_0001);
+        assert_eq!(cpu.v[0xF], 1);
+
+        cpu.v[0x1] = 0b1000_0010;
+        cpu.shr(0x1);
+        assert_eq!(cpu.v[0x1], 0b0100_0001);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn shl_shifts_msb_into_vf() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0x1] = 0b1100_0001;
+        cpu.shl(0x1);
+        assert_eq!(cpu.v[0x1], 0b1000_0010);
+        assert_eq!(cpu.v[0xF], 1);
+
+        cpu.v[0x1] = 0b0100_0001;
+        cpu.shl(0x1);
+        assert_eq!(cpu.v[0x1], 0b1000_0010);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn flag_wins_when_vf_is_the_target() {
+        let mut cpu = Chip8::new();
+
+        cpu.v[0xF] = 0xFF;
+        cpu.v[0x1] = 0x02;
+        cpu.add(0xF, 0x1);
+        assert_eq!(cpu.v[0xF], 1);
+
+        cpu.v[0xF] = 0x01;
+        cpu.v[0x1] = 0x02;
+        cpu.sub(0xF, 0x1);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.v[0xF] = 0b1000_0000;
+        cpu.shl(0xF);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn rand_is_masked() {
+        let mut cpu = Chip8::new();
+
+        for _ in 0..32 {
+            cpu.rand(0x1, 0x0F);
+            assert_eq!(cpu.v[0x1] & 0xF0, 0);
+        }
+        cpu.rand(0x1, 0x00);
+        assert_eq!(cpu.v[0x1], 0);
+    }
+
+    #[test]
+    fn drw_draws_and_detects_collision() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0x300;
+        cpu.ram[0x300] = 0b1010_0000;
+        cpu.v[0x1] = 10;
+        cpu.v[0x2] = 5;
+
+        cpu.drw(0x1, 0x2, 1).unwrap();
+        assert!(cpu.framebuffer().pixel(10, 5));
+        assert!(!cpu.framebuffer().pixel(11, 5));
+        assert!(cpu.framebuffer().pixel(12, 5));
+        assert_eq!(cpu.v[0xF], 0);
+
+        // Drawing the same sprite again erases it
+        cpu.drw(0x1, 0x2, 1).unwrap();
+        assert_eq!(lit_pixels(&cpu), 0);
+        assert_eq!(cpu.v[0xF], 1);
+
+        // No collision when only unlit pixels are flipped
+        cpu.drw(0x1, 0x2, 1).unwrap();
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn drw_wraps_start_and_clips_sprite() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0x300;
+        cpu.ram[0x300] = 0xFF;
+        cpu.ram[0x301] = 0xFF;
+
+        // Start coordinates wrap around the display
+        cpu.v[0x1] = 64 + 2;
+        cpu.v[0x2] = 32 + 3;
+        cpu.drw(0x1, 0x2, 1).unwrap();
+        assert!(cpu.framebuffer().pixel(2, 3));
+        assert_eq!(lit_pixels(&cpu), 8);
+
+        // But the sprite itself is clipped at the edges
+        cpu.cls();
+        cpu.v[0x1] = 60;
+        cpu.v[0x2] = 31;
+        cpu.drw(0x1, 0x2, 2).unwrap();
+        assert_eq!(lit_pixels(&cpu), 4);
+        assert!(!cpu.framebuffer().pixel(0, 31));
+        assert!(!cpu.framebuffer().pixel(60, 0));
+    }
+
+    #[test]
+    fn key_skips() {
+        let mut cpu = Chip8::new();
+        cpu.v[0x1] = 0xA;
+
+        let pc = cpu.pc;
+        cpu.skp(0x1);
+        assert_eq!(cpu.pc, pc);
+        cpu.sknp(0x1);
+        assert_eq!(cpu.pc, pc + 2);
+
+        cpu.set_key(0xA, true);
+        cpu.skp(0x1);
+        assert_eq!(cpu.pc, pc + 4);
+        cpu.sknp(0x1);
+        assert_eq!(cpu.pc, pc + 4);
+    }
+
+    #[test]
+    fn ld_key_waits_for_press_and_release() {
+        // LD V3, K
+        let mut cpu = cpu_with(&[0xF30A]);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        // Holding the key isn't enough
+        cpu.set_key(0x7, true);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        // Nor is pressing another one meanwhile
+        cpu.set_key(0x2, true);
+        cpu.set_key(0x7, false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.v[0x3], 0x7);
+    }
+
+    #[test]
+    fn timers() {
+        let mut cpu = Chip8::new();
+        cpu.v[0x1] = 2;
+
+        cpu.ld_dt(0x1);
+        cpu.ld_st(0x1);
+        assert_eq!(cpu.timers(), Timers { delay: 2, sound: 2 });
+
+        cpu.tick_timers();
+        cpu.ld_from_dt(0x2);
+        assert_eq!(cpu.v[0x2], 1);
+
+        // Timers stop at zero
+        cpu.tick_timers();
+        cpu.tick_timers();
+        assert_eq!(cpu.timers(), Timers::default());
+    }
+
+    #[test]
+    fn add_i() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0x100;
+        cpu.v[0x1] = 0x20;
+
+        cpu.add_i(0x1);
+        assert_eq!(cpu.i, 0x120);
+    }
+
+    #[test]
+    fn ld_f_and_ld_hf_point_at_glyphs() {
+        let mut cpu = Chip8::new();
+        cpu.v[0x1] = 0xA;
+
+        cpu.ld_f(0x1);
+        assert_eq!(cpu.i, crate::FONT_ADDRESS + 0xA * 5);
+        assert_eq!(&cpu.ram[cpu.i as usize..][..5], &cpu.font.small()[50..55]);
+
+        cpu.set_font(Font::builtin("xochip").unwrap()).unwrap();
+        cpu.ld_hf(0x1);
+        assert_eq!(&cpu.ram[cpu.i as usize..][..10], &cpu.font.big()[100..110]);
+
+        // The small font may fill the top of memory, leaving no room for big glyphs
+        let mut cpu = Chip8::with_layout(Layout {
+            memory_size: 0x10000,
+            font_address: (0x10000 - SMALL_FONT_SIZE) as u16,
+            ..Layout::default()
+        })
+        .unwrap();
+        cpu.v[0x1] = 0xF;
+        cpu.ld_hf(0x1);
+        assert_eq!(cpu.i, 0xF * BIG_GLYPH_SIZE as u16);
+    }
+
+    #[test]
+    fn ld_bcd() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0x300;
+        cpu.v[0x1] = 137;
+
+        cpu.ld_bcd(0x1).unwrap();
+        assert_eq!(&cpu.ram[0x300..0x303], &[1, 3, 7]);
+    }
+
+    #[test]
+    fn ld_into_and_from_i() {
+        let mut cpu = Chip8::new();
+        cpu.i = 0x300;
+        cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        cpu.ld_into_i(0x2).unwrap();
+        assert_eq!(&cpu.ram[0x300..0x304], &[1, 2, 3, 0]);
+
+        cpu.v = [0; 16];
+        cpu.ld_from_i(0x1).unwrap();
+        assert_eq!(&cpu.v[..3], &[1, 2, 0]);
+    }
+}
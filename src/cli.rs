@@ -0,0 +1,822 @@
+use crate::constants::*;
+use chip_8::octo;
+use chip_8::patch;
+use chip_8::symbols::Symbols;
+use chip_8::trace::{TraceFilter, TraceFormat};
+use chip_8::{Font, Layout, Limits, Platform, PROGRAM_START};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: chip-8 [WINDOW OPTIONS] [OPTIONS] <ROM>
+       chip-8 snapshot --frames <LIST> [SNAPSHOT OPTIONS] [OPTIONS] <ROM>
+       chip-8 headless [HEADLESS OPTIONS] [OPTIONS] <ROM>
+       chip-8 dump-trace [--symbols <PATH>] <TRACE>
+       chip-8 assemble [ASSEMBLE OPTIONS] <SOURCE>
+       chip-8 dap
+
+ROM is a CHIP-8 program, a .gz/.zip archive holding one, or - to read from stdin.
+A ROM ending in .8o is Octo source, assembled before it's run, and its labels and
+source lines are used as the symbols unless --symbols is given.
+
+Window options:
+    --monitor                 Read commands to inspect and edit memory from the
+                              terminal, type help for a list
+
+In the window, M shows memory over the display, scrolled with the arrow keys and
+Page Up/Down. While paused, click a byte and type hex digits to change it.
+With --draw-log, I outlines the sprites drawn last frame. Click a pixel to find
+the DRW that last flipped it, or step through the frame's draws with [ and ].
+With --cheats, F1-F9 turn the ROM's first nine cheat codes on and off.
+
+The snapshot command runs the ROM headlessly and records the display at the
+given frames as goldens named <ROM name>.<frame>.txt/.png, or checks the display
+against previously recorded goldens with --verify.
+
+Snapshot options:
+    --frames <LIST>           Comma separated frames to capture, e.g. 60,120,600
+    --input <PATH>            Input script, lines of <frame> down|up <hex key>
+    --dir <PATH>              Directory goldens are written to or read from,
+                              defaults to the current directory
+    --format <FORMAT>         text (default), png or both
+    --verify                  Compare against the goldens instead of writing them
+    --seed <N>                Random number seed, defaults to 0
+    --ipf <N>                 Instructions per frame
+
+The headless command runs the ROM without a window until it finishes, by
+executing 00FD or jumping to itself, then prints its registers. It exits with 0,
+or the value of --exit-register, once the ROM finishes and with 1 if the ROM
+fails or is still running after --frames.
+
+Headless options:
+    --frames <N>              Frames to run for before giving up, defaults to 3600
+    --input <PATH>            Input script, as for snapshot
+    --seed <N>                Random number seed, defaults to 0
+    --ipf <N>                 Instructions per frame
+    --exit-register <X>       Register (0-F) holding the exit code
+
+The assemble command writes Octo source out as a ROM.
+
+Assemble options:
+    -o, --output <PATH>       ROM to write, defaults to the source's name with .ch8
+    --write-symbols <PATH>    Also write the labels and source lines as a symbol
+                              file, for --symbols
+    --load-address <ADDR>     Address the ROM will be loaded at, defaults to 0x200
+
+The dap command is a Debug Adapter Protocol server for editors, talking over
+stdin and stdout. The ROM is run headlessly, as given by the launch request's
+arguments:
+
+    program                   Path to the ROM, or .8o Octo source to assemble
+    symbols                   Symbol file mapping source lines to addresses,
+                              as for --symbols
+    platform                  Memory layout preset, as for --platform
+    patch                     IPS or BPS patch, as for --patch
+    noPatch                   Don't look for a patch next to the ROM, as for
+                              --no-patch
+    maxCycles                 Stop after this many instructions, as for
+                              --max-cycles
+    maxTime                   Stop after this many seconds, as for --max-time
+    detectLoops               As for --detect-loops
+    haltOnSelfModify          As for --halt-on-self-modify
+    input                     Input script, as for snapshot
+    stopOnEntry               Stop before the first instruction
+
+Options:
+    --entry <NAME>            ROM to run from a zip archive holding several
+    --patch <PATH>            Apply an IPS or BPS patch to the ROM before loading
+                              it. Defaults to a .bps or .ips file with the same
+                              name as the ROM next to it
+    --no-patch                Don't look for a patch next to the ROM
+    --platform <NAME>         Memory layout preset: vip (default), eti660,
+                              dream6800, schip or xochip
+    --load-address <ADDR>     Address the ROM is loaded at
+    --entry-point <ADDR>      Initial program counter, defaults to the load address
+    --font-address <ADDR>     Address of the hex font
+    --memory-size <BYTES>     Size of memory, up to 0x10000
+    --font <NAME>             Hex font: chip48 (default), vip, dream6800, eti660,
+                              schip or xochip. Defaults to the platform's font
+                              when --platform is given
+    --font-file <PATH>        Load a custom font: 80 bytes of 4x5 glyphs, optionally
+                              followed by 100 or 160 bytes of 8x10 glyphs
+    -h, --help                Print this message
+
+Sandbox options, for running untrusted ROMs:
+    --max-cycles <N>          Stop after executing N instructions
+    --max-time <SECONDS>      Stop after running for this long
+    --detect-loops            Stop on a jump to itself with the timers stopped
+    --halt-on-self-modify     Stop when the ROM writes over its own code
+
+Debugging options, not supported by snapshot:
+    --trace <PATH>            Log every instruction executed to PATH
+    --trace-format <FORMAT>   text (default) or binary, which dump-trace turns
+                              back into text
+    --trace-addresses <RANGE> Only trace instructions at START-END, e.g. 0x200-0x2FF
+    --trace-cycles <RANGE>    Only trace instructions executed at cycles START-END,
+                              either end may be left out, e.g. 1000-
+    --profile <PATH>          Write a report of hot addresses, instruction kinds,
+                              subroutine time and draws to PATH on exit
+    --coverage <PATH>         Write the addresses executed and skip outcomes seen
+                              to PATH on exit
+    --coverage-format <FORMAT>
+                              listing (default), an annotated disassembly of
+                              the ROM, or lcov against the source lines in the
+                              symbols, or with one line per ROM word without
+    --draw-log <PATH>         Log every DRW with its position, sprite and collision
+                              to PATH, and enable the sprite inspector
+    --symbols <PATH>          Symbol file naming addresses in traces, reports,
+                              errors and monitor commands, with lines of
+                              label <ADDR> <NAME> and line <ADDR> <FILE>:<LINE>
+    --script <PATH>           Run a Rhai script, which can register closures with
+                              on_frame(f), on_exec(ADDR, f), on_write(ADDR, f)
+                              and on_key(f), read and change registers and
+                              memory, press keys and take screenshots
+    --cheats <PATH>           Cheat file of patch codes kept for each ROM, created
+                              when codes are added. Enabled codes are written to
+                              memory every frame, toggle codes 1-9 with F1-F9
+    --stack-checks            Warn about RET without a CALL, calls nesting close
+                              to the stack limit and jumps out of subroutines
+    --gdb <PORT>              Wait for GDB to connect to localhost on PORT before
+                              running, e.g. target remote :PORT
+
+Addresses and sizes may be given in decimal or as hex with a 0x prefix.";
+
+pub enum Command {
+    Run(Options, RunOptions),
+    Snapshot(Options, SnapshotOptions),
+    Headless(Options, HeadlessOptions),
+    DumpTrace(PathBuf, Symbols),
+    Assemble(AssembleOptions),
+    Dap,
+    Help,
+}
+
+pub struct AssembleOptions {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub symbols: Option<PathBuf>,
+    pub load_address: u16,
+}
+
+#[derive(Default)]
+pub struct RunOptions {
+    pub monitor: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Text,
+    Png,
+    Both,
+}
+
+pub struct SnapshotOptions {
+    pub frames: Vec<u32>,
+    pub input: Option<PathBuf>,
+    pub dir: PathBuf,
+    pub format: SnapshotFormat,
+    pub verify: bool,
+    pub seed: u64,
+    pub instructions_per_frame: u32,
+}
+
+pub struct HeadlessOptions {
+    pub frames: u32,
+    pub input: Option<PathBuf>,
+    pub seed: u64,
+    pub instructions_per_frame: u32,
+    pub exit_register: Option<usize>,
+}
+
+pub struct TraceOptions {
+    pub path: PathBuf,
+    pub format: TraceFormat,
+    pub filter: TraceFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Listing,
+    Lcov,
+}
+
+#[derive(Clone)]
+pub struct CoverageOptions {
+    pub path: PathBuf,
+    pub format: CoverageFormat,
+    // Named as the file covered in lcov reports without source lines
+    pub rom_path: PathBuf,
+    // Source files in the symbols are named relative to this
+    pub source_dir: PathBuf,
+}
+
+// Tools watching every instruction, see `Instruments`
+#[derive(Default)]
+pub struct InstrumentOptions {
+    pub trace: Option<TraceOptions>,
+    pub profile: Option<PathBuf>,
+    pub coverage: Option<CoverageOptions>,
+    pub draw_log: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub cheats: Option<PathBuf>,
+    pub stack_checks: bool,
+    pub gdb_port: Option<u16>,
+    pub symbols: Symbols,
+}
+
+impl InstrumentOptions {
+    pub fn is_empty(&self) -> bool {
+        self.trace.is_none()
+            && self.profile.is_none()
+            && self.coverage.is_none()
+            && self.draw_log.is_none()
+            && self.script.is_none()
+            && self.cheats.is_none()
+            && !self.stack_checks
+            && self.gdb_port.is_none()
+            && self.symbols.is_empty()
+    }
+}
+
+pub struct Options {
+    pub rom_path: PathBuf,
+    pub entry: Option<String>,
+    pub patch: Option<PathBuf>,
+    pub layout: Layout,
+    pub font: Font,
+    pub limits: Limits,
+    pub instruments: InstrumentOptions,
+}
+
+// Parses the command line, returning an error message on invalid input
+pub fn parse_args() -> Result<Command, String> {
+    let mut args = env::args().skip(1).peekable();
+
+    match args.peek().map(String::as_str) {
+        Some("snapshot") => {
+            args.next();
+            return parse_snapshot_args(args);
+        }
+        Some("headless") => {
+            args.next();
+            return parse_headless_args(args);
+        }
+        Some("dump-trace") => {
+            args.next();
+            let mut path = None;
+            let mut symbols = Symbols::default();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--symbols" => symbols = read_symbols(&next_value(&mut args, &arg)?)?,
+                    "-h" | "--help" => return Ok(Command::Help),
+                    _ if arg.starts_with('-') && arg != "-" => {
+                        return Err(format!("Unknown option {}", arg))
+                    }
+                    _ => path = Some(PathBuf::from(arg)),
+                }
+            }
+            let path = path.ok_or("Missing value for dump-trace")?;
+            return Ok(Command::DumpTrace(path, symbols));
+        }
+        Some("assemble") => {
+            args.next();
+            return parse_assemble_args(args);
+        }
+        Some("dap") => {
+            args.next();
+            return match args.next().as_deref() {
+                None => Ok(Command::Dap),
+                Some("-h") | Some("--help") => Ok(Command::Help),
+                Some(arg) => Err(format!("Unknown option {}", arg)),
+            };
+        }
+        _ => {}
+    }
+
+    let mut machine = MachineArgs::default();
+    let mut run = RunOptions::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--monitor" => run.monitor = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => {
+                if !machine.parse(&arg, &mut args)? {
+                    return Err(format!("Unknown option {}", arg));
+                }
+            }
+        }
+    }
+
+    let options = machine.finish()?;
+    if run.monitor && options.rom_path == Path::new("-") {
+        return Err("--monitor reads from stdin, so the ROM can't".to_string());
+    }
+
+    Ok(Command::Run(options, run))
+}
+
+fn parse_assemble_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut source = None;
+    let mut output = None;
+    let mut symbols = None;
+    let mut load_address = PROGRAM_START;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--write-symbols" => symbols = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--load-address" => load_address = next_address(&mut args, &arg)?,
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => source = Some(PathBuf::from(arg)),
+        }
+    }
+
+    let source: PathBuf = source.ok_or("No source given")?;
+    let output = output.unwrap_or_else(|| source.with_extension("ch8"));
+    if output == source {
+        return Err("The ROM would overwrite the source, give another with --output".to_string());
+    }
+
+    Ok(Command::Assemble(AssembleOptions {
+        source,
+        output,
+        symbols,
+        load_address,
+    }))
+}
+
+fn parse_snapshot_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut machine = MachineArgs::default();
+    let mut snapshot = SnapshotOptions {
+        frames: Vec::new(),
+        input: None,
+        dir: PathBuf::from("."),
+        format: SnapshotFormat::Text,
+        verify: false,
+        seed: 0,
+        instructions_per_frame: INSTRUCT_PER_SEC / FRAME_RATE,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => {
+                let value = next_value(&mut args, &arg)?;
+                snapshot.frames = value
+                    .split(',')
+                    .map(|frame| frame.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("Invalid frame list {}", value))?;
+            }
+            "--input" => snapshot.input = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--dir" => snapshot.dir = PathBuf::from(next_value(&mut args, &arg)?),
+            "--format" => {
+                snapshot.format = match next_value(&mut args, &arg)?.as_str() {
+                    "text" => SnapshotFormat::Text,
+                    "png" => SnapshotFormat::Png,
+                    "both" => SnapshotFormat::Both,
+                    format => return Err(format!("Unknown snapshot format {}", format)),
+                }
+            }
+            "--verify" => snapshot.verify = true,
+            "--seed" => snapshot.seed = next_number(&mut args, &arg)? as u64,
+            "--ipf" => snapshot.instructions_per_frame = next_ipf(&mut args, &arg)?,
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => {
+                if !machine.parse(&arg, &mut args)? {
+                    return Err(format!("Unknown option {}", arg));
+                }
+            }
+        }
+    }
+
+    if snapshot.frames.is_empty() {
+        return Err("No frames given to snapshot".to_string());
+    }
+
+    let options = machine.finish()?;
+    if !options.instruments.is_empty() {
+        return Err("Debugging options aren't supported by snapshot".to_string());
+    }
+
+    Ok(Command::Snapshot(options, snapshot))
+}
+
+fn parse_headless_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut machine = MachineArgs::default();
+    let mut headless = HeadlessOptions {
+        frames: 60 * FRAME_RATE,
+        input: None,
+        seed: 0,
+        instructions_per_frame: INSTRUCT_PER_SEC / FRAME_RATE,
+        exit_register: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => headless.frames = next_u32(&mut args, &arg)?,
+            "--input" => headless.input = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+            "--seed" => headless.seed = next_number(&mut args, &arg)? as u64,
+            "--ipf" => headless.instructions_per_frame = next_ipf(&mut args, &arg)?,
+            "--exit-register" => {
+                let value = next_value(&mut args, &arg)?;
+                let register = value.trim_start_matches(['v', 'V']);
+                headless.exit_register = match usize::from_str_radix(register, 16) {
+                    Ok(register) if register <= 0xF => Some(register),
+                    _ => return Err(format!("Invalid register {} for {}", value, arg)),
+                };
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => {
+                if !machine.parse(&arg, &mut args)? {
+                    return Err(format!("Unknown option {}", arg));
+                }
+            }
+        }
+    }
+
+    Ok(Command::Headless(machine.finish()?, headless))
+}
+
+// Options shared by every command, describing the machine and ROM
+#[derive(Default)]
+struct MachineArgs {
+    rom_path: Option<PathBuf>,
+    entry: Option<String>,
+    patch: Option<PathBuf>,
+    no_patch: bool,
+    platform: Option<Platform>,
+    font: Option<Font>,
+    load_address: Option<u16>,
+    entry_point: Option<u16>,
+    font_address: Option<u16>,
+    memory_size: Option<usize>,
+    limits: Limits,
+    trace: Option<PathBuf>,
+    trace_format: Option<TraceFormat>,
+    trace_filter: TraceFilter,
+    profile: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    coverage_format: Option<CoverageFormat>,
+    draw_log: Option<PathBuf>,
+    script: Option<PathBuf>,
+    cheats: Option<PathBuf>,
+    stack_checks: bool,
+    gdb_port: Option<u16>,
+    symbols: Option<Symbols>,
+    symbols_path: Option<PathBuf>,
+}
+
+impl MachineArgs {
+    // Consumes `arg` and its value, returning false if it isn't a machine option
+    fn parse(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        match arg {
+            "--entry" => self.entry = Some(next_value(args, arg)?),
+            "--patch" => self.patch = Some(PathBuf::from(next_value(args, arg)?)),
+            "--no-patch" => self.no_patch = true,
+            "--platform" => self.platform = Some(next_value(args, arg)?.parse()?),
+            "--font" => {
+                let name = next_value(args, arg)?;
+                self.font = Some(Font::builtin(&name).ok_or(format!("Unknown font {}", name))?);
+            }
+            "--font-file" => {
+                let path = next_value(args, arg)?;
+                let data =
+                    fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                self.font = Some(Font::from_bytes(&data).map_err(|e| e.to_string())?);
+            }
+            "--load-address" => self.load_address = Some(next_address(args, arg)?),
+            "--entry-point" => self.entry_point = Some(next_address(args, arg)?),
+            "--font-address" => self.font_address = Some(next_address(args, arg)?),
+            "--memory-size" => self.memory_size = Some(next_number(args, arg)?),
+            "--max-cycles" => self.limits.max_cycles = Some(next_number(args, arg)? as u64),
+            "--max-time" => {
+                let value = next_value(args, arg)?;
+                let time = value
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| format!("Invalid time {} for {}", value, arg))?;
+                self.limits.max_time = Some(time);
+            }
+            "--detect-loops" => self.limits.detect_infinite_loops = true,
+            "--halt-on-self-modify" => self.limits.halt_on_self_modify = true,
+            "--trace" => self.trace = Some(PathBuf::from(next_value(args, arg)?)),
+            "--profile" => self.profile = Some(PathBuf::from(next_value(args, arg)?)),
+            "--draw-log" => self.draw_log = Some(PathBuf::from(next_value(args, arg)?)),
+            "--symbols" => {
+                let path = next_value(args, arg)?;
+                self.symbols = Some(read_symbols(&path)?);
+                self.symbols_path = Some(PathBuf::from(path));
+            }
+            "--script" => self.script = Some(PathBuf::from(next_value(args, arg)?)),
+            "--cheats" => self.cheats = Some(PathBuf::from(next_value(args, arg)?)),
+            "--stack-checks" => self.stack_checks = true,
+            "--gdb" => {
+                let port = next_number(args, arg)?;
+                if port == 0 || port > u16::MAX as usize {
+                    return Err(format!("Invalid port {} for {}", port, arg));
+                }
+                self.gdb_port = Some(port as u16);
+            }
+            "--coverage" => self.coverage = Some(PathBuf::from(next_value(args, arg)?)),
+            "--coverage-format" => {
+                self.coverage_format = match next_value(args, arg)?.as_str() {
+                    "listing" => Some(CoverageFormat::Listing),
+                    "lcov" => Some(CoverageFormat::Lcov),
+                    format => return Err(format!("Unknown coverage format {}", format)),
+                }
+            }
+            "--trace-format" => {
+                self.trace_format = match next_value(args, arg)?.as_str() {
+                    "text" => Some(TraceFormat::Text),
+                    "binary" => Some(TraceFormat::Binary),
+                    format => return Err(format!("Unknown trace format {}", format)),
+                }
+            }
+            "--trace-addresses" => {
+                let range = next_range(args, arg)?;
+                if *range.start() > u16::MAX as usize {
+                    return Err(format!("Address range for {} is out of range", arg));
+                }
+                let end = (*range.end()).min(u16::MAX as usize);
+                self.trace_filter.addresses = Some(*range.start() as u16..=end as u16);
+            }
+            "--trace-cycles" => {
+                let range = next_range(args, arg)?;
+                self.trace_filter.cycles = Some(*range.start() as u64..=*range.end() as u64);
+            }
+            "-" => self.rom_path = Some(PathBuf::from(arg)),
+            _ if arg.starts_with('-') => return Ok(false),
+            _ => self.rom_path = Some(PathBuf::from(arg)),
+        }
+
+        Ok(true)
+    }
+
+    fn finish(self) -> Result<Options, String> {
+        // Start from the platform preset and apply any overrides
+        let mut layout = Layout::for_platform(self.platform.unwrap_or(Platform::CosmacVip));
+        if let Some(address) = self.load_address {
+            layout.load_address = address;
+            layout.entry_point = address;
+        }
+        if let Some(address) = self.entry_point {
+            layout.entry_point = address;
+        }
+        if let Some(address) = self.font_address {
+            layout.font_address = address;
+        }
+        if let Some(size) = self.memory_size {
+            layout.memory_size = size;
+        }
+
+        // Only switch to a historical font when asked for a platform, the modern
+        // font is what most ROMs expect
+        let font = match (self.font, self.platform) {
+            (Some(font), _) => font,
+            (None, Some(platform)) => Font::for_platform(platform),
+            (None, None) => Font::default(),
+        };
+
+        let tracing = self.trace_format.is_some() || self.trace_filter != TraceFilter::default();
+        if tracing && self.trace.is_none() {
+            return Err("Trace options given without --trace".to_string());
+        }
+
+        let trace = match self.trace {
+            Some(path) => Some(TraceOptions {
+                path,
+                format: self.trace_format.unwrap_or(TraceFormat::Text),
+                filter: self.trace_filter,
+            }),
+            None => None,
+        };
+
+        let rom_path = self.rom_path.ok_or("No ROM given")?;
+
+        if self.patch.is_some() && self.no_patch {
+            return Err("--patch and --no-patch can't be used together".to_string());
+        }
+        let patch = match self.patch {
+            Some(path) => Some(path),
+            None if self.no_patch || rom_path == Path::new("-") => None,
+            None => patch::find_patch(&rom_path),
+        };
+
+        if self.coverage_format.is_some() && self.coverage.is_none() {
+            return Err("Coverage options given without --coverage".to_string());
+        }
+        let coverage = match self.coverage {
+            Some(path) => Some(CoverageOptions {
+                path,
+                format: self.coverage_format.unwrap_or(CoverageFormat::Listing),
+                // Octo source is reported as the ROM it assembles to
+                rom_path: if octo::is_source(&rom_path) {
+                    rom_path.with_extension("ch8")
+                } else {
+                    rom_path.clone()
+                },
+                // Next to the symbol file, or the source that was assembled
+                source_dir: self
+                    .symbols_path
+                    .as_deref()
+                    .unwrap_or(&rom_path)
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .to_path_buf(),
+            }),
+            None => None,
+        };
+
+        Ok(Options {
+            rom_path,
+            entry: self.entry,
+            patch,
+            layout,
+            font,
+            limits: self.limits,
+            instruments: InstrumentOptions {
+                trace,
+                profile: self.profile,
+                coverage,
+                draw_log: self.draw_log,
+                script: self.script,
+                cheats: self.cheats,
+                stack_checks: self.stack_checks,
+                gdb_port: self.gdb_port,
+                symbols: self.symbols.unwrap_or_default(),
+            },
+        })
+    }
+}
+
+fn read_symbols(path: &str) -> Result<Symbols, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", option))
+}
+
+fn next_number(args: &mut impl Iterator<Item = String>, option: &str) -> Result<usize, String> {
+    let value = next_value(args, option)?;
+    parse_number(&value, option)
+}
+
+fn next_u32(args: &mut impl Iterator<Item = String>, option: &str) -> Result<u32, String> {
+    let value = next_number(args, option)?;
+    u32::try_from(value).map_err(|_| format!("{} for {} is out of range", value, option))
+}
+
+// Instructions run each frame, which can't be none
+fn next_ipf(args: &mut impl Iterator<Item = String>, option: &str) -> Result<u32, String> {
+    match next_u32(args, option)? {
+        0 => Err(format!("{} must be at least 1", option)),
+        ipf => Ok(ipf),
+    }
+}
+
+pub fn parse_number(value: &str, option: &str) -> Result<usize, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.map_err(|_| format!("Invalid number {} for {}", value, option))
+}
+
+// Parses an inclusive range START-END, where either end may be left out
+fn next_range(
+    args: &mut impl Iterator<Item = String>,
+    option: &str,
+) -> Result<RangeInclusive<usize>, String> {
+    let value = next_value(args, option)?;
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid range {} for {}, expected START-END", value, option))?;
+
+    let start = match start.trim() {
+        "" => 0,
+        start => parse_number(start, option)?,
+    };
+    let end = match end.trim() {
+        "" => usize::MAX,
+        end => parse_number(end, option)?,
+    };
+
+    if start > end {
+        return Err(format!("Empty range {} for {}", value, option));
+    }
+
+    Ok(start..=end)
+}
+
+fn next_address(args: &mut impl Iterator<Item = String>, option: &str) -> Result<u16, String> {
+    let value = next_number(args, option)?;
+
+    if value > u16::MAX as usize {
+        return Err(format!(
+            "Address {:#X} for {} is out of range",
+            value, option
+        ));
+    }
+
+    Ok(value as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(str::to_string)
+    }
+
+    fn machine(line: &str) -> Result<Options, String> {
+        match parse_headless_args(args(line))? {
+            Command::Headless(options, _) => Ok(options),
+            _ => panic!("{} isn't a headless command", line),
+        }
+    }
+
+    fn headless(line: &str) -> Result<HeadlessOptions, String> {
+        match parse_headless_args(args(line))? {
+            Command::Headless(_, options) => Ok(options),
+            _ => panic!("{} isn't a headless command", line),
+        }
+    }
+
+    fn snapshot(line: &str) -> Result<SnapshotOptions, String> {
+        match parse_snapshot_args(args(line))? {
+            Command::Snapshot(_, options) => Ok(options),
+            _ => panic!("{} isn't a snapshot command", line),
+        }
+    }
+
+    #[test]
+    fn headless_counts_fit_in_32_bits() {
+        let options = headless("--frames 0xFFFFFFFF --ipf 30 rom.ch8").unwrap();
+        assert_eq!(options.frames, u32::MAX);
+        assert_eq!(options.instructions_per_frame, 30);
+
+        assert_eq!(
+            headless("--frames 4294967296 rom.ch8").err(),
+            Some("4294967296 for --frames is out of range".to_string())
+        );
+        assert_eq!(
+            headless("--ipf 0x100000000 rom.ch8").err(),
+            Some("4294967296 for --ipf is out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn instructions_per_frame_cant_be_zero() {
+        assert_eq!(
+            headless("--ipf 0 rom.ch8").err(),
+            Some("--ipf must be at least 1".to_string())
+        );
+        assert_eq!(
+            snapshot("--frames 1 --ipf 0 rom.ch8").err(),
+            Some("--ipf must be at least 1".to_string())
+        );
+        assert_eq!(
+            snapshot("--frames 1 --ipf 4294967296 rom.ch8").err(),
+            Some("4294967296 for --ipf is out of range".to_string())
+        );
+        assert_eq!(
+            snapshot("--frames 1 --ipf 1 rom.ch8")
+                .unwrap()
+                .instructions_per_frame,
+            1
+        );
+    }
+
+    #[test]
+    fn max_time_must_fit_in_a_duration() {
+        let options = machine("--max-time 1.5 rom.ch8").unwrap();
+        assert_eq!(options.limits.max_time, Some(Duration::from_millis(1500)));
+
+        // Values past Duration's range are rejected rather than panicking
+        for time in &["1e20", "-1", "inf", "NaN"] {
+            assert_eq!(
+                machine(&format!("--max-time {} rom.ch8", time)).err(),
+                Some(format!("Invalid time {} for --max-time", time))
+            );
+        }
+    }
+}
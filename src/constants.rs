@@ -3,3 +3,8 @@ pub const WINDOW_HEIGHT: u32 = 320;
 
 pub const FRAME_RATE: u32 = 60;
 pub const INSTRUCT_PER_SEC: u32 = 700;
+
+// Runtime speed controls
+pub const MAX_INSTRUCT_PER_FRAME: u32 = 1000;
+pub const FAST_FORWARD_FACTOR: u32 = 4;
+pub const SLOW_MOTION_FACTOR: u32 = 4;
@@ -0,0 +1,394 @@
+//! Which parts of a ROM have been executed.
+//!
+//! [`Coverage`] counts how often each address is executed and, for the skip
+//! instructions (`SE`, `SNE`, `SKP` and `SKNP`), how often the skip was and
+//! wasn't taken. It can be written out as an annotated listing of the ROM:
+//!
+//! ```text
+//!       hits addr op
+//!          1 0200 6001  LD V0, 0x01
+//!          1 0202 3001  SE V0, 0x01  ; skipped 1, not skipped 0 !
+//!      ##### 0204 00E0  CLS
+//! ```
+//!
+//! or as an lcov tracefile. Words with a source line in the symbols, such as
+//! assembled Octo, are reported against that line of their source file.
+//! Otherwise line N is the Nth word of the ROM, so the report lines up with a
+//! listing of the ROM with one instruction per line.
+
+use crate::chip8::Chip8;
+use crate::disasm::disassemble_with;
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::Path;
+
+/// How often a skip instruction did and didn't skip.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub skipped: u64,
+    pub not_skipped: u64,
+}
+
+impl BranchCounts {
+    /// Whether both outcomes have been seen.
+    pub fn is_covered(&self) -> bool {
+        self.skipped > 0 && self.not_skipped > 0
+    }
+}
+
+/// Records which addresses and skip outcomes a program exercises.
+///
+/// Call [`Coverage::record`] before every [`Chip8::step`].
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: BTreeMap<u16, u64>,
+    branches: BTreeMap<u16, BranchCounts>,
+    // A skip waiting to see where PC ends up, and the cycle it ran on
+    pending_skip: Option<(u16, u64)>,
+}
+
+impl Coverage {
+    /// Creates an empty record.
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Records the instruction `cpu` is about to execute, and the outcome of
+    /// the previous instruction if it was a skip.
+    pub fn record(&mut self, cpu: &Chip8) {
+        // A skip moves PC on by 4 instead of 2. Only trust PC if exactly one
+        // instruction has run since, it may have been moved by hand otherwise.
+        if let Some((address, cycle)) = self.pending_skip.take() {
+            if cpu.cycles() == cycle + 1 {
+                let counts = self.branches.entry(address).or_default();
+                if cpu.pc() == address.wrapping_add(4) {
+                    counts.skipped += 1;
+                } else {
+                    counts.not_skipped += 1;
+                }
+            }
+        }
+
+        let pc = cpu.pc();
+        let instruction = match cpu.memory().get(pc as usize..pc as usize + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            // Past the end of memory, the step will fail
+            None => return,
+        };
+
+        *self.hits.entry(pc).or_insert(0) += 1;
+        if is_skip(instruction) {
+            self.pending_skip = Some((pc, cpu.cycles()));
+        }
+    }
+
+    /// Times the instruction at `address` was executed.
+    pub fn hits(&self, address: u16) -> u64 {
+        self.hits.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Skip outcomes seen at `address`, if a skip has run there.
+    pub fn branch(&self, address: u16) -> Option<BranchCounts> {
+        self.branches.get(&address).copied()
+    }
+
+    /// Every address that has been executed.
+    pub fn executed(&self) -> impl Iterator<Item = u16> + '_ {
+        self.hits.keys().copied()
+    }
+
+    /// An annotated listing of `rom`, loaded at `load_address`, with hit
+    /// counts and skip outcomes. Words that never ran are marked `#####` and
+    /// skips missing an outcome with `!`. Labels from `symbols` head the
+    /// words they name.
+    pub fn listing(&self, rom: &[u8], load_address: u16, symbols: &Symbols) -> String {
+        let addresses = self.rom_addresses(rom, load_address);
+        let executed = addresses.iter().filter(|&&a| self.hits(a) > 0).count();
+        let branches: Vec<BranchCounts> =
+            addresses.iter().filter_map(|&a| self.branch(a)).collect();
+        let outcomes: usize = branches
+            .iter()
+            .map(|b| (b.skipped > 0) as usize + (b.not_skipped > 0) as usize)
+            .sum();
+
+        let mut listing = String::new();
+        let _ = writeln!(
+            listing,
+            "; {} of {} words executed, {} of {} skip outcomes seen",
+            executed,
+            addresses.len(),
+            outcomes,
+            2 * branches.len()
+        );
+        let _ = writeln!(listing, "{:>10} addr op", "hits");
+
+        for address in addresses {
+            let offset = (address - load_address) as usize;
+            let instruction = match rom.get(offset..offset + 2) {
+                Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+                // A lone byte at the end of the ROM
+                None => (rom[offset] as u16) << 8,
+            };
+
+            if let Some(label) = symbols.label(address) {
+                let _ = writeln!(listing, "{}:", label);
+            }
+            let hits = match self.hits(address) {
+                0 => "#####".to_string(),
+                hits => hits.to_string(),
+            };
+            let _ = write!(
+                listing,
+                "{:>10} {:04X} {:04X}  {}",
+                hits,
+                address,
+                instruction,
+                disassemble_with(instruction, symbols)
+            );
+            if let Some(branch) = self.branch(address) {
+                let _ = write!(
+                    listing,
+                    "  ; skipped {}, not skipped {}{}",
+                    branch.skipped,
+                    branch.not_skipped,
+                    if branch.is_covered() { "" } else { " !" }
+                );
+            }
+            listing.push('\n');
+        }
+
+        listing
+    }
+
+    /// An lcov tracefile for `rom`, loaded at `load_address`. Words with a
+    /// source line in `symbols` are reported against it, their files named
+    /// relative to `source_dir`. Without any source lines, `rom_name` is the
+    /// file covered and line N is the Nth word of the ROM.
+    pub fn lcov(
+        &self,
+        rom_name: &str,
+        rom: &[u8],
+        load_address: u16,
+        symbols: &Symbols,
+        source_dir: &Path,
+    ) -> String {
+        let addresses = self.rom_addresses(rom, load_address);
+        let from_source = addresses.iter().any(|&a| symbols.source_line(a).is_some());
+
+        // The lines of each file covered
+        let mut files: BTreeMap<String, Record> = BTreeMap::new();
+        for &address in &addresses {
+            let (file, line) = match symbols.source_line(address) {
+                Some(source) => {
+                    let path = source_dir.join(&source.file);
+                    (path.display().to_string(), source.line)
+                }
+                // Code the assembler added, like the jump to main
+                None if from_source => continue,
+                None => (
+                    rom_name.to_string(),
+                    ((address - load_address) / 2 + 1) as u32,
+                ),
+            };
+
+            let record = files.entry(file).or_default();
+            // A line assembled into several words ran as often as the
+            // busiest of them
+            let hits = record.lines.entry(line).or_insert(0);
+            *hits = (*hits).max(self.hits(address));
+            if let Some(counts) = self.branch(address) {
+                record.branches.push((line, counts));
+            }
+        }
+
+        let mut report = String::new();
+        let _ = writeln!(report, "TN:");
+        for (file, record) in &files {
+            record.write(&mut report, file);
+        }
+
+        report
+    }
+
+    // Every word of the ROM, plus any odd addresses that were executed
+    fn rom_addresses(&self, rom: &[u8], load_address: u16) -> Vec<u16> {
+        let start = load_address as usize;
+        let end = start + rom.len();
+
+        let mut addresses: BTreeSet<u16> = (start..end).step_by(2).map(|a| a as u16).collect();
+        addresses.extend(
+            self.hits
+                .range(load_address..)
+                .map(|(&address, _)| address)
+                .take_while(|&address| (address as usize) < end),
+        );
+
+        addresses.into_iter().collect()
+    }
+}
+
+// The lines and skips of one file in an lcov report
+#[derive(Default)]
+struct Record {
+    lines: BTreeMap<u32, u64>,
+    branches: Vec<(u32, BranchCounts)>,
+}
+
+impl Record {
+    fn write(&self, report: &mut String, file: &str) {
+        let _ = writeln!(report, "SF:{}", file);
+        for (line, hits) in &self.lines {
+            let _ = writeln!(report, "DA:{},{}", line, hits);
+        }
+
+        let mut branches_hit = 0;
+        for (line, counts) in &self.branches {
+            for (index, count) in [counts.skipped, counts.not_skipped].iter().enumerate() {
+                let _ = writeln!(report, "BRDA:{},0,{},{}", line, index, count);
+                if *count > 0 {
+                    branches_hit += 1;
+                }
+            }
+        }
+
+        let _ = writeln!(report, "BRF:{}", 2 * self.branches.len());
+        let _ = writeln!(report, "BRH:{}", branches_hit);
+        let _ = writeln!(report, "LF:{}", self.lines.len());
+        let _ = writeln!(
+            report,
+            "LH:{}",
+            self.lines.values().filter(|&&h| h > 0).count()
+        );
+        let _ = writeln!(report, "end_of_record");
+    }
+}
+
+// SE Vx, nn; SNE Vx, nn; SE Vx, Vy; SNE Vx, Vy; SKP Vx; SKNP Vx
+fn is_skip(instruction: u16) -> bool {
+    match instruction >> 12 {
+        0x3 | 0x4 | 0x5 | 0x9 => true,
+        0xE => matches!(instruction & 0xFF, 0x9E | 0xA1),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // LD V0, 0x01; SE V0, 0x01; CLS; SNE V0, 0x01; JP 0x208
+    const PROGRAM: [u16; 5] = [0x6001, 0x3001, 0x00E0, 0x4001, 0x1208];
+
+    // Covers `steps` instructions of `program`, returning its ROM too
+    fn cover(program: &[u16], steps: usize) -> (Coverage, Vec<u8>) {
+        let mut cpu = cpu_with(program);
+        let mut coverage = Coverage::new();
+        for _ in 0..steps {
+            coverage.record(&cpu);
+            cpu.step().unwrap();
+        }
+
+        let rom = program.iter().flat_map(|op| op.to_be_bytes()).collect();
+        (coverage, rom)
+    }
+
+    #[test]
+    fn counts_hits() {
+        let (coverage, _) = cover(&PROGRAM, 5);
+
+        assert_eq!(coverage.hits(0x200), 1);
+        assert_eq!(coverage.hits(0x204), 0);
+        assert_eq!(coverage.hits(0x208), 2);
+        assert_eq!(
+            coverage.executed().collect::<Vec<_>>(),
+            [0x200, 0x202, 0x206, 0x208]
+        );
+    }
+
+    #[test]
+    fn records_skip_outcomes() {
+        let (coverage, _) = cover(&PROGRAM, 5);
+
+        assert_eq!(
+            coverage.branch(0x202),
+            Some(BranchCounts {
+                skipped: 1,
+                not_skipped: 0
+            })
+        );
+        assert_eq!(
+            coverage.branch(0x206),
+            Some(BranchCounts {
+                skipped: 0,
+                not_skipped: 1
+            })
+        );
+        assert_eq!(coverage.branch(0x200), None);
+        assert!(!coverage.branch(0x202).unwrap().is_covered());
+    }
+
+    #[test]
+    fn skips_are_only_judged_by_the_next_step() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut coverage = Coverage::new();
+        cpu.step().unwrap();
+
+        // PC moved by hand rather than by the skip
+        coverage.record(&cpu);
+        cpu.set_pc(0x206);
+        coverage.record(&cpu);
+        assert_eq!(coverage.branch(0x202), None);
+    }
+
+    #[test]
+    fn listing_marks_what_never_ran() {
+        let (coverage, rom) = cover(&PROGRAM, 5);
+        let symbols = Symbols::parse("label 0x204 unused").unwrap();
+        let listing = coverage.listing(&rom, 0x200, &symbols);
+
+        assert!(listing.starts_with("; 4 of 5 words executed, 2 of 4 skip outcomes seen\n"));
+        assert!(listing.contains("unused:\n     ##### 0204 00E0  CLS\n"));
+        assert!(listing.contains("SE V0, 0x01  ; skipped 1, not skipped 0 !\n"));
+        assert!(listing.contains("         2 0208 1208  JP 0x208\n"));
+    }
+
+    #[test]
+    fn lcov_counts_rom_words_without_source_lines() {
+        let (coverage, rom) = cover(&PROGRAM, 5);
+        let lcov = coverage.lcov("test.ch8", &rom, 0x200, &Symbols::default(), Path::new(""));
+
+        assert!(lcov.starts_with("TN:\nSF:test.ch8\nDA:1,1\nDA:2,1\nDA:3,0\n"));
+        assert!(lcov.contains("BRDA:2,0,0,1\nBRDA:2,0,1,0\n"));
+        assert!(lcov.contains("BRF:4\nBRH:2\nLF:5\nLH:4\nend_of_record\n"));
+    }
+
+    #[test]
+    fn lcov_reports_source_lines() {
+        // JP 0x202; main: LD V0, 0x01; SE V0, 0x01; CLS; JP 0x208
+        let program = [0x1202, 0x6001, 0x3001, 0x00E0, 0x1208];
+        let (coverage, rom) = cover(&program, 4);
+        let symbols = Symbols::parse(
+            "line 0x202 game.8o:3\n\
+             line 0x204 game.8o:4\n\
+             line 0x206 game.8o:4\n\
+             line 0x208 lib/util.8o:7\n",
+        )
+        .unwrap();
+
+        let lcov = coverage.lcov("game.ch8", &rom, 0x200, &symbols, Path::new("src"));
+        let game = Path::new("src").join("game.8o").display().to_string();
+        let util = Path::new("src").join("lib/util.8o").display().to_string();
+        assert_eq!(
+            lcov,
+            format!(
+                "TN:\n\
+                 SF:{}\nDA:3,1\nDA:4,1\nBRDA:4,0,0,1\nBRDA:4,0,1,0\n\
+                 BRF:2\nBRH:1\nLF:2\nLH:2\nend_of_record\n\
+                 SF:{}\nDA:7,1\nBRF:0\nBRH:0\nLF:1\nLH:1\nend_of_record\n",
+                game, util
+            )
+        );
+    }
+}
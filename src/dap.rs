@@ -0,0 +1,1114 @@
+use crate::cli::{InstrumentOptions, Options};
+use crate::constants::{FRAME_RATE, INSTRUCT_PER_SEC};
+use crate::machine;
+use chip_8::breakpoint::{Breakpoint, Hit};
+use chip_8::callstack;
+use chip_8::disasm;
+use chip_8::expr::{Expr, LogMessage};
+use chip_8::patch;
+use chip_8::snapshot::InputScript;
+use chip_8::symbols::Symbols;
+use chip_8::{Chip8, ExitReason, Font, Layout, Limits, Platform, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The machine is the only thread
+const THREAD_ID: u64 = 1;
+
+// Variable references for the scopes, memory pages come after them
+const REGISTERS: u64 = 1;
+const STACK: u64 = 2;
+const MEMORY: u64 = 3;
+const FRAMEBUFFER: u64 = 4;
+const MEMORY_PAGES: u64 = 0x100;
+
+const PAGE_SIZE: usize = 0x100;
+const ROW_SIZE: usize = 16;
+
+// What the program is doing between requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Stopped,
+    Running,
+    // Stepping over a call, until it returns to `pc` with the stack at `sp`
+    StepOver { pc: u16, sp: usize },
+    // Until the current subroutine returns
+    StepOut { sp: usize },
+}
+
+// A ROM launched for debugging
+struct Machine {
+    cpu: Chip8,
+    symbols: Symbols,
+    // Source files are named relative to the symbol file
+    source_dir: PathBuf,
+    script: InputScript,
+    frame: u32,
+    stop_on_entry: bool,
+    // Breakpoints set in each source file
+    breakpoints: HashMap<String, Vec<Breakpoint>>,
+    run: Run,
+    // Don't stop on the breakpoint we've just resumed from
+    resuming: bool,
+    next_frame: Instant,
+}
+
+// A Debug Adapter Protocol server, driving the machine for an editor
+struct Adapter<W> {
+    output: W,
+    seq: u64,
+    machine: Option<Machine>,
+}
+
+// Serves an editor over stdin and stdout, returning the process exit code
+pub fn run() -> i32 {
+    let messages = read_messages();
+    let mut adapter = Adapter {
+        output: io::stdout(),
+        seq: 0,
+        machine: None,
+    };
+    let frame_dt = Duration::new(0, 1_000_000_000u32 / FRAME_RATE);
+
+    loop {
+        // While running, wait for requests until the next frame is due
+        let message = match adapter.next_frame() {
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match messages.recv_timeout(wait) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            },
+        };
+
+        match message {
+            Some(message) => {
+                if !adapter.handle(&message) {
+                    break;
+                }
+            }
+            None => adapter.run_frame(frame_dt),
+        }
+    }
+
+    0
+}
+
+impl<W: Write> Adapter<W> {
+    fn next_frame(&self) -> Option<Instant> {
+        match &self.machine {
+            Some(machine) if machine.run != Run::Stopped => Some(machine.next_frame),
+            _ => None,
+        }
+    }
+
+    // Handles a request, returning false once the session is over
+    fn handle(&mut self, message: &Value) -> bool {
+        if message["type"] != "request" {
+            return true;
+        }
+        let arguments = &message["arguments"];
+
+        let command = message["command"].as_str().unwrap_or("");
+        let body = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsTerminateRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "evaluate" => self.with_machine(|machine| evaluate(machine, arguments)),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.with_machine(|machine| Ok(stack_trace(machine))),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY, "expensive": true },
+            ]})),
+            "variables" => {
+                let reference = arguments["variablesReference"].as_u64().unwrap_or(0);
+                self.with_machine(|machine| {
+                    Ok(json!({ "variables": variables(machine, reference) }))
+                })
+            }
+            "continue" => self.resume(Run::Running, true),
+            "next" => self
+                .with_machine(|machine| {
+                    let cpu = &machine.cpu;
+                    let instruction = instruction_at(cpu, cpu.pc());
+                    // Run a call until it returns, anything else is a single step
+                    Ok(if instruction >> 12 == 0x2 {
+                        Run::StepOver {
+                            pc: cpu.pc().wrapping_add(2),
+                            sp: cpu.sp(),
+                        }
+                    } else {
+                        Run::Stopped
+                    })
+                })
+                .and_then(|run| self.resume(run, false)),
+            "stepIn" => self.resume(Run::Stopped, false),
+            "stepOut" => self
+                .with_machine(|machine| match machine.cpu.sp() {
+                    // Stepping out of the top level runs to the end
+                    0 => Ok(Run::Running),
+                    sp => Ok(Run::StepOut { sp }),
+                })
+                .and_then(|run| self.resume(run, false)),
+            "pause" => Ok(json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(message, Ok(json!({})));
+                self.event("terminated", json!({}));
+                return false;
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+
+        let succeeded = body.is_ok();
+        self.respond(message, body);
+
+        // Events the request causes follow its response
+        let run = self.machine.as_ref().map(|machine| machine.run);
+        match (command, run) {
+            (_, None) => {}
+            ("configurationDone", Some(Run::Stopped)) => self.stopped("entry", None),
+            ("next", Some(Run::Stopped)) | ("stepIn", Some(Run::Stopped)) if succeeded => {
+                self.step_once()
+            }
+            ("pause", Some(run)) if run != Run::Stopped => self.stopped("pause", None),
+            _ => {}
+        }
+
+        true
+    }
+
+    fn with_machine<T>(
+        &mut self,
+        f: impl FnOnce(&mut Machine) -> Result<T, String>,
+    ) -> Result<T, String> {
+        match &mut self.machine {
+            Some(machine) => f(machine),
+            None => Err("No ROM has been launched".to_string()),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("Missing program to launch")?;
+        let path = Path::new(program);
+
+        // The same machine the command line would build with these options
+        let platform: Option<Platform> = match arguments["platform"].as_str() {
+            Some(name) => Some(name.parse()?),
+            None => None,
+        };
+        let no_patch = arguments["noPatch"].as_bool().unwrap_or(false);
+        let patch = match arguments["patch"].as_str() {
+            Some(_) if no_patch => {
+                return Err("patch and noPatch can't be used together".to_string())
+            }
+            Some(path) => Some(PathBuf::from(path)),
+            None if no_patch => None,
+            None => patch::find_patch(path),
+        };
+        let symbols = match arguments["symbols"].as_str() {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                Symbols::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => Symbols::default(),
+        };
+        let mut options = Options {
+            rom_path: path.to_path_buf(),
+            entry: None,
+            patch,
+            layout: platform.map_or_else(Layout::default, Layout::for_platform),
+            font: platform.map_or_else(Font::default, Font::for_platform),
+            limits: limits(arguments)?,
+            instruments: InstrumentOptions {
+                symbols,
+                ..InstrumentOptions::default()
+            },
+        };
+        let cpu = machine::build(&mut options, false).map_err(|e| e.to_string())?;
+
+        // Source files are named relative to the symbol file, or to Octo
+        // source assembled here
+        let source_dir = match arguments["symbols"].as_str() {
+            Some(symbols) => Path::new(symbols).parent(),
+            None => path.parent(),
+        };
+        let source_dir = source_dir.unwrap_or_else(|| Path::new("")).to_path_buf();
+        let symbols = options.instruments.symbols;
+
+        let script = match arguments["input"].as_str() {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                InputScript::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => InputScript::default(),
+        };
+
+        self.machine = Some(Machine {
+            cpu,
+            symbols,
+            source_dir,
+            script,
+            frame: 0,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            breakpoints: HashMap::new(),
+            run: Run::Stopped,
+            resuming: false,
+            next_frame: Instant::now(),
+        });
+        // Breakpoints can only be placed once the symbols are loaded
+        self.event("initialized", json!({}));
+
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["source"]["path"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        let requested = match arguments["breakpoints"].as_array() {
+            Some(breakpoints) => breakpoints.clone(),
+            None => Vec::new(),
+        };
+
+        self.with_machine(|machine| {
+            let mut placed = Vec::new();
+            let breakpoints: Vec<Value> = requested
+                .iter()
+                .map(|requested| {
+                    let line = requested["line"].as_u64().unwrap_or(0);
+                    let result = machine
+                        .symbols
+                        .address_of_line(&path, line as u32)
+                        .ok_or_else(|| "No code at or after this line".to_string())
+                        .and_then(|(line, address)| {
+                            let breakpoint = breakpoint(&machine.symbols, address, requested)?;
+                            Ok((line, breakpoint))
+                        });
+
+                    match result {
+                        Ok((line, breakpoint)) => {
+                            let address = breakpoint.address.unwrap_or(0);
+                            placed.push(breakpoint);
+                            json!({
+                                "verified": true,
+                                "line": line,
+                                "instructionReference": format!("{:#05X}", address),
+                            })
+                        }
+                        Err(message) => json!({
+                            "verified": false,
+                            "line": line,
+                            "message": message,
+                        }),
+                    }
+                })
+                .collect();
+
+            machine.breakpoints.insert(path, placed);
+            Ok(json!({ "breakpoints": breakpoints }))
+        })
+    }
+
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        self.with_machine(|machine| {
+            if !machine.stop_on_entry {
+                machine.run = Run::Running;
+                machine.next_frame = Instant::now();
+            }
+            Ok(json!({}))
+        })
+    }
+
+    // Resumes the program with `run`. Stopped steps a single instruction
+    // once the request has been answered.
+    fn resume(&mut self, run: Run, continuing: bool) -> Result<Value, String> {
+        let machine = self.machine.as_mut().ok_or("No ROM has been launched")?;
+
+        machine.run = run;
+        machine.resuming = true;
+        machine.next_frame = Instant::now();
+
+        Ok(if continuing {
+            json!({ "allThreadsContinued": true })
+        } else {
+            json!({})
+        })
+    }
+
+    fn step_once(&mut self) {
+        let machine = match &mut self.machine {
+            Some(machine) => machine,
+            None => return,
+        };
+
+        match machine.cpu.step() {
+            Ok(()) if finished(&machine.cpu) => self.finish(),
+            Ok(()) => self.stopped("step", None),
+            Err(e) => {
+                let message = machine.symbols.annotate_error(&e);
+                self.stopped("exception", Some(message))
+            }
+        }
+    }
+
+    // Runs a frame of the program, stopping where asked to
+    fn run_frame(&mut self, frame_dt: Duration) {
+        let machine = match &mut self.machine {
+            Some(machine) => machine,
+            None => return,
+        };
+
+        machine.frame += 1;
+        machine.script.apply(machine.frame, &mut machine.cpu);
+        let mut resuming = std::mem::replace(&mut machine.resuming, false);
+
+        for _ in 0..INSTRUCT_PER_SEC / FRAME_RATE {
+            if !std::mem::replace(&mut resuming, false) && self.check_breakpoints() {
+                self.stopped("breakpoint", None);
+                return;
+            }
+            // Log points send events, so borrow the machine again each step
+            let machine = match &mut self.machine {
+                Some(machine) => machine,
+                None => return,
+            };
+
+            if let Err(e) = machine.cpu.step() {
+                let message = machine.symbols.annotate_error(&e);
+                self.stopped("exception", Some(message));
+                return;
+            }
+            if finished(&machine.cpu) {
+                self.finish();
+                return;
+            }
+
+            let (pc, sp) = (machine.cpu.pc(), machine.cpu.sp());
+            let done = match machine.run {
+                Run::StepOver {
+                    pc: target,
+                    sp: depth,
+                } => pc == target && sp == depth,
+                Run::StepOut { sp: depth } => sp < depth,
+                _ => false,
+            };
+            if done {
+                self.stopped("step", None);
+                return;
+            }
+        }
+
+        let machine = match &mut self.machine {
+            Some(machine) => machine,
+            None => return,
+        };
+        machine.cpu.tick_timers();
+        machine.next_frame += frame_dt;
+        // Don't try to catch up after falling far behind
+        if machine.next_frame < Instant::now() {
+            machine.next_frame = Instant::now();
+        }
+    }
+
+    // Checks every breakpoint at PC, printing log points, and returns
+    // whether any should stop the program
+    fn check_breakpoints(&mut self) -> bool {
+        let machine = match &mut self.machine {
+            Some(machine) => machine,
+            None => return false,
+        };
+
+        let mut stop = false;
+        let mut logs = Vec::new();
+        for breakpoint in machine.breakpoints.values_mut().flatten() {
+            match breakpoint.check(&machine.cpu) {
+                Some(Hit::Stop) => stop = true,
+                Some(Hit::Log(message)) => logs.push(message),
+                None => {}
+            }
+        }
+        for message in logs {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", message) }),
+            );
+        }
+
+        stop
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        if let Some(machine) = &mut self.machine {
+            machine.run = Run::Stopped;
+        }
+
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            self.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{}\n", description) }),
+            );
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    // The program exited with 00FD
+    fn finish(&mut self) {
+        if let Some(machine) = &mut self.machine {
+            machine.run = Run::Stopped;
+        }
+
+        self.event("exited", json!({ "exitCode": 0 }));
+        self.event("terminated", json!({}));
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match body {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+
+        let content = message.to_string();
+        // The editor going away shows up as stdin closing
+        let _ = write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        );
+        let _ = self.output.flush();
+    }
+}
+
+// Limits from the launch request, as for the command line's --max-cycles,
+// --max-time, --detect-loops and --halt-on-self-modify
+fn limits(arguments: &Value) -> Result<Limits, String> {
+    let max_time = match arguments["maxTime"].as_f64() {
+        Some(seconds) => Some(
+            Duration::try_from_secs_f64(seconds)
+                .map_err(|_| format!("Invalid time {} for maxTime", seconds))?,
+        ),
+        None => None,
+    };
+
+    Ok(Limits {
+        max_cycles: arguments["maxCycles"].as_u64(),
+        max_time,
+        detect_infinite_loops: arguments["detectLoops"].as_bool().unwrap_or(false),
+        halt_on_self_modify: arguments["haltOnSelfModify"].as_bool().unwrap_or(false),
+    })
+}
+
+// A breakpoint at `address` with the condition, hit condition and log
+// message the editor asked for
+fn breakpoint(symbols: &Symbols, address: u16, requested: &Value) -> Result<Breakpoint, String> {
+    let condition = match requested["condition"].as_str() {
+        Some(text) if !text.trim().is_empty() => {
+            Some(Expr::parse(text, symbols).map_err(|e| format!("Invalid condition: {}", e))?)
+        }
+        _ => None,
+    };
+
+    // A bare count stops from that hit on, otherwise it's compared to the
+    // hit count, e.g. "% 10 == 0", or is a full expression
+    let hits = match requested["hitCondition"].as_str().map(str::trim) {
+        Some("") | None => None,
+        Some(text) => {
+            let text = if text.bytes().all(|b| b.is_ascii_digit()) {
+                format!("hit_count >= {}", text)
+            } else if text.starts_with(|c| "<>=!%".contains(c)) {
+                format!("hit_count {}", text)
+            } else {
+                text.to_string()
+            };
+            Some(Expr::parse(&text, symbols).map_err(|e| format!("Invalid hit count: {}", e))?)
+        }
+    };
+
+    let condition = match (condition, hits) {
+        (Some(condition), Some(hits)) => Some(condition.and(hits)),
+        (condition, hits) => condition.or(hits),
+    };
+    let log = match requested["logMessage"].as_str() {
+        Some(text) => Some(
+            LogMessage::parse(text, symbols).map_err(|e| format!("Invalid log message: {}", e))?,
+        ),
+        None => None,
+    };
+
+    Ok(Breakpoint::new(Some(address), condition, log))
+}
+
+// Evaluates a watch, hover or console expression
+fn evaluate(machine: &Machine, arguments: &Value) -> Result<Value, String> {
+    let text = arguments["expression"].as_str().unwrap_or("");
+    let expr = Expr::parse(text, &machine.symbols).map_err(|e| e.to_string())?;
+    let value = expr.evaluate(&machine.cpu, 0);
+
+    Ok(json!({
+        "result": if value >= 10 { format!("{} ({:#X})", value, value) } else { value.to_string() },
+        "variablesReference": 0,
+    }))
+}
+
+fn finished(cpu: &Chip8) -> bool {
+    matches!(cpu.exit_reason(), Some(ExitReason::Exit { .. }))
+}
+
+fn instruction_at(cpu: &Chip8, address: u16) -> u16 {
+    let memory = cpu.memory();
+    let address = address as usize;
+
+    match memory.get(address..address + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => 0,
+    }
+}
+
+// The current instruction, then each call on the stack from the innermost out
+fn stack_trace(machine: &Machine) -> Value {
+    let cpu = &machine.cpu;
+    // Show each caller at its call instruction rather than the return address
+    let callers = callstack::frames(cpu).into_iter().map(|frame| frame.call);
+
+    let frames: Vec<Value> = std::iter::once(cpu.pc())
+        .chain(callers)
+        .enumerate()
+        .map(|(id, address)| {
+            let symbols = &machine.symbols;
+            let location = symbols
+                .location(address)
+                .unwrap_or_else(|| format!("{:#05X}", address));
+            let instruction = disasm::disassemble_with(instruction_at(cpu, address), symbols);
+            let mut frame = json!({
+                "id": id,
+                "name": format!("{} {}", location, instruction),
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("{:#05X}", address),
+            });
+            if let Some(source) = machine.symbols.source_line(address) {
+                let path = machine.source_dir.join(&source.file);
+                frame["source"] = json!({
+                    "name": path.file_name().map(|name| name.to_string_lossy().into_owned()),
+                    "path": path.to_string_lossy(),
+                });
+                frame["line"] = json!(source.line);
+                frame["column"] = json!(1);
+            }
+            frame
+        })
+        .collect();
+
+    json!({ "stackFrames": frames, "totalFrames": frames.len() })
+}
+
+fn variables(machine: &Machine, reference: u64) -> Vec<Value> {
+    let cpu = &machine.cpu;
+
+    match reference {
+        REGISTERS => {
+            let mut variables: Vec<Value> = cpu
+                .registers()
+                .iter()
+                .enumerate()
+                .map(|(index, value)| variable(&format!("V{:X}", index), format!("{:#04X}", value)))
+                .collect();
+
+            let timers = cpu.timers();
+            variables.push(variable("I", format!("{:#05X}", cpu.i())));
+            variables.push(variable("PC", format!("{:#05X}", cpu.pc())));
+            variables.push(variable("SP", cpu.sp().to_string()));
+            variables.push(variable("DT", format!("{:#04X}", timers.delay)));
+            variables.push(variable("ST", format!("{:#04X}", timers.sound)));
+
+            let lit = cpu
+                .framebuffer()
+                .rows()
+                .flatten()
+                .filter(|&&pixel| pixel)
+                .count();
+            let mut framebuffer = variable(
+                "framebuffer",
+                format!("{}x{}, {} pixels lit", DISPLAY_WIDTH, DISPLAY_HEIGHT, lit),
+            );
+            framebuffer["variablesReference"] = json!(FRAMEBUFFER);
+            variables.push(framebuffer);
+
+            variables
+        }
+        STACK => {
+            let sp = cpu.sp().min(cpu.stack().len());
+            (0..sp)
+                .map(|slot| {
+                    let address = cpu.stack()[slot];
+                    let value = match machine.symbols.describe(address) {
+                        Some(location) => format!("{:#05X} ({})", address, location),
+                        None => format!("{:#05X}", address),
+                    };
+                    variable(&format!("[{}]", slot), value)
+                })
+                .collect()
+        }
+        MEMORY => (0..cpu.memory().len().div_ceil(PAGE_SIZE))
+            .map(|page| {
+                let start = page * PAGE_SIZE;
+                let end = (start + PAGE_SIZE).min(cpu.memory().len()) - 1;
+                let mut variable = variable(&format!("{:#05X}-{:#05X}", start, end), String::new());
+                variable["variablesReference"] = json!(MEMORY_PAGES + page as u64);
+                variable
+            })
+            .collect(),
+        FRAMEBUFFER => cpu
+            .framebuffer()
+            .rows()
+            .enumerate()
+            .map(|(row, pixels)| {
+                let line: String = pixels
+                    .iter()
+                    .map(|&pixel| if pixel { '#' } else { '.' })
+                    .collect();
+                variable(&format!("{:02}", row), line)
+            })
+            .collect(),
+        page if page >= MEMORY_PAGES => {
+            let memory = cpu.memory();
+            // Pages past the end of memory are empty, however far past
+            let start = usize::try_from(page - MEMORY_PAGES)
+                .ok()
+                .and_then(|page| page.checked_mul(PAGE_SIZE))
+                .unwrap_or(usize::MAX)
+                .min(memory.len());
+            let end = start.saturating_add(PAGE_SIZE).min(memory.len());
+
+            (start..end)
+                .step_by(ROW_SIZE)
+                .map(|row| {
+                    let bytes: Vec<String> = memory[row..(row + ROW_SIZE).min(end)]
+                        .iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect();
+                    variable(&format!("{:#05X}", row), bytes.join(" "))
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+// Reads messages from stdin on another thread, so the program keeps running
+// while waiting for requests
+fn read_messages() -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = BufReader::new(stdin.lock());
+
+        loop {
+            // Headers up to a blank line, of which only the length matters
+            let mut length = None;
+            loop {
+                let mut header = String::new();
+                match input.read_line(&mut header) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+
+            let mut content = vec![0; length.unwrap_or(0)];
+            if input.read_exact(&mut content).is_err() {
+                return;
+            }
+            // Skip anything that isn't JSON rather than ending the session
+            if let Ok(message) = serde_json::from_slice(&content) {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // main calls inner once, then spins
+    const SOURCE: &str = "\
+: main
+  v0 := 1
+  inner
+  v0 += 1
+  loop
+  again
+
+: inner
+  v1 := 2
+  v2 := 3
+;
+";
+
+    fn adapter() -> Adapter<Vec<u8>> {
+        Adapter {
+            output: Vec::new(),
+            seq: 0,
+            machine: None,
+        }
+    }
+
+    // Launches SOURCE, stopped on entry with the configuration done
+    fn launched(name: &str) -> Adapter<Vec<u8>> {
+        // Each test gets a directory, as the source is named by its file name
+        let dir = std::env::temp_dir().join(format!("chip8-dap-{}-{}", name, std::process::id()));
+        let path = dir.join("program.8o");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, SOURCE).unwrap();
+
+        let mut adapter = adapter();
+        let program = path.to_string_lossy();
+        let launch = request(
+            &mut adapter,
+            "launch",
+            json!({ "program": program, "stopOnEntry": true }),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(launch[0]["event"], "initialized");
+        assert_eq!(launch[1]["success"], true, "{}", launch[1]);
+
+        let done = request(&mut adapter, "configurationDone", json!({}));
+        assert_eq!(done[1]["body"]["reason"], "entry");
+        adapter
+    }
+
+    // Handles a request, returning the messages it sent
+    fn request(adapter: &mut Adapter<Vec<u8>>, command: &str, arguments: Value) -> Vec<Value> {
+        let message =
+            json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+        assert!(adapter.handle(&message));
+        sent(adapter)
+    }
+
+    fn sent(adapter: &mut Adapter<Vec<u8>>) -> Vec<Value> {
+        let output = String::from_utf8(std::mem::take(&mut adapter.output)).unwrap();
+        let mut rest = output.as_str();
+        let mut messages = Vec::new();
+
+        while let Some(header) = rest.strip_prefix("Content-Length: ") {
+            let (length, content) = header.split_once("\r\n\r\n").unwrap();
+            let (content, next) = content.split_at(length.parse().unwrap());
+            messages.push(serde_json::from_str(content).unwrap());
+            rest = next;
+        }
+        assert!(rest.is_empty());
+        messages
+    }
+
+    fn machine(adapter: &Adapter<Vec<u8>>) -> &Machine {
+        adapter.machine.as_ref().unwrap()
+    }
+
+    fn variables_of(adapter: &Adapter<Vec<u8>>, reference: u64) -> Vec<(String, String)> {
+        variables(machine(adapter), reference)
+            .iter()
+            .map(|variable| {
+                let name = variable["name"].as_str().unwrap().to_string();
+                (name, variable["value"].as_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn patches_next_to_the_rom_apply_unless_turned_off() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // LD V0, 1; JP 0x202, patched to load 5
+        let rom = dir.join("program.ch8");
+        fs::write(&rom, [0x60, 0x01, 0x12, 0x02]).unwrap();
+        fs::write(dir.join("program.ips"), b"PATCH\x00\x00\x01\x00\x01\x05EOF").unwrap();
+        let program = rom.to_string_lossy();
+
+        let launch = |arguments: Value| {
+            let mut adapter = adapter();
+            let response = request(&mut adapter, "launch", arguments);
+            (adapter, response)
+        };
+        let (patched, _) = launch(json!({ "program": program }));
+        assert_eq!(machine(&patched).cpu.memory()[0x201], 0x05);
+        let (unpatched, _) = launch(json!({ "program": program, "noPatch": true }));
+        assert_eq!(machine(&unpatched).cpu.memory()[0x201], 0x01);
+
+        // Patches are read like the command line reads them
+        let missing = dir.join("missing.ips");
+        let (_, response) = launch(json!({ "program": program, "patch": missing }));
+        assert_eq!(response[0]["success"], false);
+        assert!(response[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("Failed to read {}", missing.display())));
+        let (_, response) =
+            launch(json!({ "program": program, "patch": missing, "noPatch": true }));
+        assert_eq!(
+            response[0]["message"],
+            "patch and noPatch can't be used together"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn breakpoints_move_to_the_next_line_with_code() {
+        let mut adapter = launched("breakpoints");
+
+        let lines = json!([{ "line": 3 }, { "line": 7 }, { "line": 20 }]);
+        let response = request(
+            &mut adapter,
+            "setBreakpoints",
+            json!({ "source": { "path": "/elsewhere/program.8o" }, "breakpoints": lines }),
+        );
+        let breakpoints = &response[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["line"], 3);
+        assert_eq!(breakpoints[0]["instructionReference"], "0x202");
+        // The blank line and label before inner move onto its first instruction
+        assert_eq!(breakpoints[1]["verified"], true);
+        assert_eq!(breakpoints[1]["line"], 9);
+        assert_eq!(breakpoints[1]["instructionReference"], "0x208");
+        assert_eq!(breakpoints[2]["verified"], false);
+    }
+
+    #[test]
+    fn breakpoints_from_other_files_are_not_placed() {
+        let mut adapter = launched("other-file");
+
+        let response = request(
+            &mut adapter,
+            "setBreakpoints",
+            json!({ "source": { "path": "other.8o" }, "breakpoints": [{ "line": 3 }] }),
+        );
+        assert_eq!(response[0]["body"]["breakpoints"][0]["verified"], false);
+    }
+
+    #[test]
+    fn continuing_stops_at_a_breakpoint() {
+        let mut adapter = launched("continue");
+        request(
+            &mut adapter,
+            "setBreakpoints",
+            json!({ "source": { "path": "program.8o" }, "breakpoints": [{ "line": 9 }] }),
+        );
+
+        request(&mut adapter, "continue", json!({}));
+        adapter.run_frame(Duration::ZERO);
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "breakpoint");
+        assert_eq!(machine(&adapter).cpu.pc(), 0x208);
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut adapter = launched("next");
+
+        // Not a call, so a single step
+        let stepped = request(&mut adapter, "next", json!({}));
+        assert_eq!(stepped[1]["body"]["reason"], "step");
+        assert_eq!(machine(&adapter).cpu.pc(), 0x202);
+
+        // The call runs until it returns, without stopping inside it
+        let resumed = request(&mut adapter, "next", json!({}));
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(machine(&adapter).run, Run::StepOver { pc: 0x204, sp: 0 });
+        adapter.run_frame(Duration::ZERO);
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "step");
+        assert_eq!(machine(&adapter).cpu.pc(), 0x204);
+        assert_eq!(machine(&adapter).run, Run::Stopped);
+    }
+
+    #[test]
+    fn step_out_stops_after_the_return() {
+        let mut adapter = launched("step-out");
+        request(&mut adapter, "stepIn", json!({}));
+        request(&mut adapter, "stepIn", json!({}));
+        assert_eq!(machine(&adapter).cpu.pc(), 0x208);
+
+        request(&mut adapter, "stepOut", json!({}));
+        assert_eq!(machine(&adapter).run, Run::StepOut { sp: 1 });
+        adapter.run_frame(Duration::ZERO);
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "step");
+        assert_eq!(machine(&adapter).cpu.pc(), 0x204);
+
+        // Out of the top level there's nothing to return to, so it runs on
+        request(&mut adapter, "stepOut", json!({}));
+        assert_eq!(machine(&adapter).run, Run::Running);
+    }
+
+    #[test]
+    fn registers_include_the_framebuffer() {
+        let mut adapter = launched("registers");
+        request(&mut adapter, "stepIn", json!({}));
+
+        let registers = variables_of(&adapter, REGISTERS);
+        assert_eq!(registers[0], ("V0".to_string(), "0x01".to_string()));
+        assert_eq!(registers[17], ("PC".to_string(), "0x202".to_string()));
+        assert_eq!(registers[18], ("SP".to_string(), "0".to_string()));
+        assert_eq!(registers[21].0, "framebuffer");
+        assert_eq!(
+            variables(machine(&adapter), REGISTERS)[21]["variablesReference"],
+            FRAMEBUFFER
+        );
+
+        let framebuffer = variables_of(&adapter, FRAMEBUFFER);
+        assert_eq!(framebuffer.len(), DISPLAY_HEIGHT);
+        assert_eq!(framebuffer[0].1, ".".repeat(DISPLAY_WIDTH));
+    }
+
+    #[test]
+    fn stack_shows_return_addresses() {
+        let mut adapter = launched("stack");
+        assert!(variables_of(&adapter, STACK).is_empty());
+
+        request(&mut adapter, "stepIn", json!({}));
+        request(&mut adapter, "stepIn", json!({}));
+        let stack = variables_of(&adapter, STACK);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack[0].0, "[0]");
+        assert!(stack[0].1.starts_with("0x204"), "{:?}", stack);
+    }
+
+    #[test]
+    fn memory_is_shown_in_pages() {
+        let adapter = launched("memory");
+
+        let pages = variables(machine(&adapter), MEMORY);
+        assert_eq!(pages.len(), 16);
+        assert_eq!(pages[2]["name"], "0x200-0x2FF");
+        assert_eq!(pages[2]["variablesReference"], MEMORY_PAGES + 2);
+
+        let rows = variables_of(&adapter, MEMORY_PAGES + 2);
+        assert_eq!(rows.len(), PAGE_SIZE / ROW_SIZE);
+        assert_eq!(rows[0].0, "0x200");
+        assert!(rows[0].1.starts_with("60 01 22 08"), "{:?}", rows[0]);
+    }
+
+    #[test]
+    fn unknown_references_have_no_variables() {
+        let adapter = launched("unknown");
+
+        for reference in [0, 5, MEMORY_PAGES - 1, MEMORY_PAGES + 16, u64::MAX] {
+            assert!(
+                variables_of(&adapter, reference).is_empty(),
+                "{}",
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn hit_conditions_compare_the_hit_count() {
+        let symbols = Symbols::default();
+        let mut cpu = Chip8::new();
+        // 0x200: JP 0x200
+        cpu.load_rom(&[0x12, 0x00]).unwrap();
+        let stops = |hit_condition: &str| {
+            let requested = json!({ "hitCondition": hit_condition });
+            let mut breakpoint = breakpoint(&symbols, 0x200, &requested).unwrap();
+            (1..=6)
+                .filter(|_| breakpoint.check(&cpu) == Some(Hit::Stop))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(stops(""), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(stops("4"), [4, 5, 6]);
+        assert_eq!(stops("% 3 == 0"), [3, 6]);
+        assert_eq!(stops("== 2"), [2]);
+        assert_eq!(stops("hit_count < 3"), [1, 2]);
+    }
+
+    #[test]
+    fn hit_conditions_combine_with_conditions() {
+        let symbols = Symbols::default();
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0x12, 0x00]).unwrap();
+
+        let requested = json!({ "condition": "v0 == 0", "hitCondition": "2" });
+        let mut placed = breakpoint(&symbols, 0x200, &requested).unwrap();
+        assert_eq!(placed.check(&cpu), None);
+        assert_eq!(placed.check(&cpu), Some(Hit::Stop));
+
+        let requested = json!({ "condition": "v0 == 1", "hitCondition": "2" });
+        let mut placed = breakpoint(&symbols, 0x200, &requested).unwrap();
+        assert_eq!(placed.check(&cpu), None);
+        assert_eq!(placed.check(&cpu), None);
+
+        let invalid = json!({ "hitCondition": "% %" });
+        let error = breakpoint(&symbols, 0x200, &invalid).unwrap_err();
+        assert!(error.starts_with("Invalid hit count"), "{}", error);
+    }
+}
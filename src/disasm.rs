@@ -0,0 +1,118 @@
+//! Turning instructions back into assembly.
+//!
+//! Mnemonics follow Cowgod's CHIP-8 technical reference, the same names used
+//! in the comments of the interpreter, e.g. `LD V0, 0x2A` or `DRW V1, V2, 5`.
+
+use crate::symbols::Symbols;
+
+/// Disassembles a single instruction. Anything that isn't an instruction is
+/// shown as a data word, `DW 0x1234`.
+pub fn disassemble(instruction: u16) -> String {
+    let x = (instruction >> 8) & 0xF;
+    let y = (instruction >> 4) & 0xF;
+    let n = instruction & 0xF;
+    let nn = instruction & 0xFF;
+    let nnn = instruction & 0xFFF;
+
+    match (instruction >> 12, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => "CLS".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "RET".to_string(),
+        (0x0, 0x0, 0xF, 0xD) => "EXIT".to_string(),
+        (0x1, ..) => format!("JP {:#05X}", nnn),
+        (0x2, ..) => format!("CALL {:#05X}", nnn),
+        (0x3, ..) => format!("SE V{:X}, {:#04X}", x, nn),
+        (0x4, ..) => format!("SNE V{:X}, {:#04X}", x, nn),
+        (0x5, ..) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, ..) => format!("LD V{:X}, {:#04X}", x, nn),
+        (0x7, ..) => format!("ADD V{:X}, {:#04X}", x, nn),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}", x),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}", x),
+        (0x9, ..) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, ..) => format!("LD I, {:#05X}", nnn),
+        (0xB, ..) => format!("JP V0, {:#05X}", nnn),
+        (0xC, ..) => format!("RND V{:X}, {:#04X}", x, nn),
+        (0xD, ..) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xF, _, 0x3, 0x0) => format!("LD HF, V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW {:#06X}", instruction),
+    }
+}
+
+/// Disassembles an instruction like [`disassemble`], naming its target by
+/// label when `symbols` has one, e.g. `CALL draw_score`.
+pub fn disassemble_with(instruction: u16, symbols: &Symbols) -> String {
+    let text = disassemble(instruction);
+    let nnn = instruction & 0xFFF;
+
+    match (instruction >> 12, symbols.label(nnn)) {
+        (0x1 | 0x2 | 0xA | 0xB, Some(label)) => text.replace(&format!("{:#05X}", nnn), label),
+        _ => text,
+    }
+}
+
+/// The opcode pattern an instruction matches, such as `8xy4` or `Dxyn`, for
+/// grouping instructions by kind. `None` if it isn't an instruction.
+pub fn opcode_class(instruction: u16) -> Option<&'static str> {
+    let x = (instruction >> 8) & 0xF;
+    let y = (instruction >> 4) & 0xF;
+    let n = instruction & 0xF;
+
+    let class = match (instruction >> 12, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => "00E0",
+        (0x0, 0x0, 0xE, 0xE) => "00EE",
+        (0x0, 0x0, 0xF, 0xD) => "00FD",
+        (0x1, ..) => "1nnn",
+        (0x2, ..) => "2nnn",
+        (0x3, ..) => "3xnn",
+        (0x4, ..) => "4xnn",
+        (0x5, ..) => "5xy0",
+        (0x6, ..) => "6xnn",
+        (0x7, ..) => "7xnn",
+        (0x8, _, _, 0x0) => "8xy0",
+        (0x8, _, _, 0x1) => "8xy1",
+        (0x8, _, _, 0x2) => "8xy2",
+        (0x8, _, _, 0x3) => "8xy3",
+        (0x8, _, _, 0x4) => "8xy4",
+        (0x8, _, _, 0x5) => "8xy5",
+        (0x8, _, _, 0x6) => "8xy6",
+        (0x8, _, _, 0x7) => "8xy7",
+        (0x8, _, _, 0xE) => "8xyE",
+        (0x9, ..) => "9xy0",
+        (0xA, ..) => "Annn",
+        (0xB, ..) => "Bnnn",
+        (0xC, ..) => "Cxnn",
+        (0xD, ..) => "Dxyn",
+        (0xE, _, 0x9, 0xE) => "Ex9E",
+        (0xE, _, 0xA, 0x1) => "ExA1",
+        (0xF, _, 0x0, 0x7) => "Fx07",
+        (0xF, _, 0x0, 0xA) => "Fx0A",
+        (0xF, _, 0x1, 0x5) => "Fx15",
+        (0xF, _, 0x1, 0x8) => "Fx18",
+        (0xF, _, 0x1, 0xE) => "Fx1E",
+        (0xF, _, 0x2, 0x9) => "Fx29",
+        (0xF, _, 0x3, 0x0) => "Fx30",
+        (0xF, _, 0x3, 0x3) => "Fx33",
+        (0xF, _, 0x5, 0x5) => "Fx55",
+        (0xF, _, 0x6, 0x5) => "Fx65",
+        _ => return None,
+    };
+
+    Some(class)
+}
@@ -1,12 +1,12 @@
 extern crate sdl2;
 
+use chip_8::{Framebuffer, DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 
-
-
 pub enum ValidHex {
     Num0,
     Num1,
@@ -26,14 +26,31 @@ pub enum ValidHex {
     F,
 }
 
+// Events raised by the emulator hotkeys, processed by the execution loop
+pub enum DisplayEvent {
+    Quit,
+    TogglePause,
+    FrameAdvance,
+    FastForward(bool),
+    Unthrottle(bool),
+    ToggleSlowMotion,
+    SpeedUp,
+    SpeedDown,
+    ToggleMemoryView,
+    ScrollMemory(i32),
+    ToggleInspector,
+    CycleDraw(i32),
+    Click { x: i32, y: i32 },
+    HexDigit(u8),
+    ToggleCheat(usize),
+}
+
 pub struct Display {
     canvas: sdl2::render::WindowCanvas,
     event_pump: sdl2::EventPump,
 
     pixel_width: u32,
     pixel_height: u32,
-
-    buffer: [[bool; 64]; 32],
 }
 
 #[allow(dead_code)]
@@ -43,7 +60,7 @@ impl Display {
         let video_subsystem = sdl_context.video().unwrap();
 
         let window = video_subsystem
-            .window("Chip-8", width as u32, height as u32)
+            .window("Chip-8", width, height)
             .position_centered()
             .build()
             .unwrap();
@@ -56,25 +73,24 @@ impl Display {
 
         let event_pump = sdl_context.event_pump().unwrap();
 
-        let pixel_width = width / 64;
-        let pixel_height = height / 32;
-
-        let buffer = [[false; 64]; 32];
+        let pixel_width = width / DISPLAY_WIDTH as u32;
+        let pixel_height = height / DISPLAY_HEIGHT as u32;
 
-        Display {            canvas,
+        Display {
+            canvas,
             event_pump,
             pixel_width,
             pixel_height,
-            buffer,
         }
     }
 
-    pub fn draw_frame(&mut self) {
+    // Draws the display contents, call present() to show them
+    pub fn draw_frame(&mut self, framebuffer: &Framebuffer) {
         self.canvas.clear();
         self.canvas.set_draw_color(Color::RGB(0, 0, 0));
 
-        for (y, row) in self.buffer.iter_mut().enumerate() {
-            for (x, element) in row.iter_mut().enumerate() {
+        for (y, row) in framebuffer.rows().enumerate() {
+            for (x, element) in row.iter().enumerate() {
                 if *element {
                     self.canvas.set_draw_color(Color::RGB(255, 255, 255));
                 } else {
@@ -91,49 +107,134 @@ impl Display {
                     .unwrap();
             }
         }
+    }
 
+    pub fn present(&mut self) {
         self.canvas.present();
     }
 
-    pub fn flip_pixel(&mut self, x: u8, y: u8) -> bool {
-        let x = x as usize;
-        let y = y as usize;
-        self.buffer[y][x] = !self.buffer[y][x];
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rect(rect).unwrap();
+    }
+
+    pub fn outline_rect(&mut self, rect: Rect, color: Color) {
+        self.canvas.set_draw_color(color);
+        self.canvas.draw_rect(rect).unwrap();
+    }
+
+    // Size of a CHIP-8 pixel in the window
+    pub fn pixel_size(&self) -> (u32, u32) {
+        (self.pixel_width, self.pixel_height)
+    }
+
+    // The CHIP-8 pixel under a point in window coordinates
+    pub fn pixel_at(&self, x: i32, y: i32) -> (usize, usize) {
+        (
+            x.max(0) as usize / self.pixel_width as usize,
+            y.max(0) as usize / self.pixel_height as usize,
+        )
+    }
+
+    // Draws a 4 pixel wide font glyph, one byte per row, at window coordinates
+    pub fn draw_glyph(&mut self, x: i32, y: i32, glyph: &[u8], scale: u32, color: Color) {
+        self.canvas.set_draw_color(color);
 
-        // Return true if pixel was turned off (is now on)
-        !self.buffer[y][x]
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..4 {
+                if bits & (0x80 >> column) != 0 {
+                    self.canvas
+                        .fill_rect(Rect::new(
+                            x + column * scale as i32,
+                            y + row as i32 * scale as i32,
+                            scale,
+                            scale,
+                        ))
+                        .unwrap();
+                }
+            }
+        }
     }
 
-    pub fn set_pixel(&mut self, x: usize, y: usize, value: bool) {
-        self.buffer[y][x] = value;
+    pub fn set_title(&mut self, title: &str) {
+        // Only fails if the title contains a nul byte
+        self.canvas.window_mut().set_title(title).unwrap();
     }
 
-    // pub enum DisplayEvent {
-    //     Quit,
-    //     Keypress,
-    // }
-    // pub fn iter_events(&mut self) -> std::vec::IntoIter<DisplayEvent> {
-    //     // Polls all events in event loop and returns iterator containing events to be
-    //     // processed by chip-8
-    //     let mut event_queue: Vec<DisplayEvent> = Vec::new();
-
-    //     for event in self.event_pump.poll_iter() {
-    //         match event {
-    //             Event::Quit { .. }
-    //             | Event::KeyDown {
-    //                 keycode: Some(Keycode::Escape),
-    //                 ..
-    //             } => event_queue.push(DisplayEvent::Quit),
-    //             _ => {}
-    //         }
-    //     }
-
-    //     event_queue.into_iter()
-    // }
-
-    pub fn clear(&mut self) {
-        self.buffer = [[false; 64]; 32];
-        self.draw_frame();
+    pub fn poll_events(&mut self) -> Vec<DisplayEvent> {
+        // Polls all events in event loop and returns the hotkey events to be
+        // processed by the execution loop. Keypad state is read separately
+        // through check_key()
+        let mut event_queue: Vec<DisplayEvent> = Vec::new();
+
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => event_queue.push(DisplayEvent::Quit),
+
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat,
+                    ..
+                } => match keycode {
+                    Keycode::P if !repeat => event_queue.push(DisplayEvent::TogglePause),
+                    Keycode::N => event_queue.push(DisplayEvent::FrameAdvance),
+                    Keycode::Tab if !repeat => event_queue.push(DisplayEvent::FastForward(true)),
+                    Keycode::Backquote if !repeat => {
+                        event_queue.push(DisplayEvent::Unthrottle(true))
+                    }
+                    Keycode::L if !repeat => event_queue.push(DisplayEvent::ToggleSlowMotion),
+                    Keycode::Equals | Keycode::KpPlus => event_queue.push(DisplayEvent::SpeedUp),
+                    Keycode::Minus | Keycode::KpMinus => event_queue.push(DisplayEvent::SpeedDown),
+                    Keycode::M if !repeat => event_queue.push(DisplayEvent::ToggleMemoryView),
+                    Keycode::I if !repeat => event_queue.push(DisplayEvent::ToggleInspector),
+                    Keycode::LeftBracket => event_queue.push(DisplayEvent::CycleDraw(-1)),
+                    Keycode::RightBracket => event_queue.push(DisplayEvent::CycleDraw(1)),
+                    Keycode::Up => event_queue.push(DisplayEvent::ScrollMemory(-1)),
+                    Keycode::Down => event_queue.push(DisplayEvent::ScrollMemory(1)),
+                    Keycode::PageUp => event_queue.push(DisplayEvent::ScrollMemory(-16)),
+                    Keycode::PageDown => event_queue.push(DisplayEvent::ScrollMemory(16)),
+                    Keycode::F1 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(0)),
+                    Keycode::F2 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(1)),
+                    Keycode::F3 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(2)),
+                    Keycode::F4 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(3)),
+                    Keycode::F5 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(4)),
+                    Keycode::F6 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(5)),
+                    Keycode::F7 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(6)),
+                    Keycode::F8 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(7)),
+                    Keycode::F9 if !repeat => event_queue.push(DisplayEvent::ToggleCheat(8)),
+                    _ => {
+                        // Hex keys also drive the keypad, through check_key()
+                        if let Some(digit) = hex_digit(keycode) {
+                            event_queue.push(DisplayEvent::HexDigit(digit));
+                        }
+                    }
+                },
+
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => match keycode {
+                    Keycode::Tab => event_queue.push(DisplayEvent::FastForward(false)),
+                    Keycode::Backquote => event_queue.push(DisplayEvent::Unthrottle(false)),
+                    _ => {}
+                },
+
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => event_queue.push(DisplayEvent::Click { x, y }),
+
+                _ => {}
+            }
+        }
+
+        event_queue
     }
 
     pub fn check_key(&self, key: u8) -> bool{
@@ -185,20 +286,28 @@ impl Display {
 
         pressed_keys
     }
+}
 
-    pub fn check_quit(&mut self) -> bool {
-        // NOTE: Clears event_queue, only use if quit and esc are the
-        // only sdl events being used
-        for event in self.event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => return true,
-                _ => {}
-            }
-        }
-        false
-    }
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    let digit = match keycode {
+        Keycode::Num0 => 0x0,
+        Keycode::Num1 => 0x1,
+        Keycode::Num2 => 0x2,
+        Keycode::Num3 => 0x3,
+        Keycode::Num4 => 0x4,
+        Keycode::Num5 => 0x5,
+        Keycode::Num6 => 0x6,
+        Keycode::Num7 => 0x7,
+        Keycode::Num8 => 0x8,
+        Keycode::Num9 => 0x9,
+        Keycode::A => 0xA,
+        Keycode::B => 0xB,
+        Keycode::C => 0xC,
+        Keycode::D => 0xD,
+        Keycode::E => 0xE,
+        Keycode::F => 0xF,
+        _ => return None,
+    };
+
+    Some(digit)
 }
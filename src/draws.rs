@@ -0,0 +1,279 @@
+//! Recording what each `DRW` did to the display.
+//!
+//! A [`DrawRecorder`] watches the machine step by step and keeps a
+//! [`DrawEvent`] for every sprite drawn in the current and previous frames,
+//! along with the draw that last flipped each pixel, so glitches can be
+//! traced back to the instruction responsible.
+
+use crate::chip8::Chip8;
+use crate::framebuffer::{Framebuffer, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// A single `Dxyn`, as seen from outside the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrawEvent {
+    /// Instructions executed before the draw.
+    pub cycle: u64,
+    pub pc: u16,
+    pub i: u16,
+    /// Top left corner, after wrapping onto the display.
+    pub x: u8,
+    pub y: u8,
+    /// Rows in the sprite, `n` of the instruction.
+    pub height: u8,
+    /// Whether the draw turned any pixel off, the value left in VF.
+    pub collision: bool,
+    /// The sprite bytes at I, one per row. Shorter than `height` if the
+    /// sprite runs off the end of memory.
+    pub sprite: Vec<u8>,
+    /// Pixels the draw flipped.
+    pub flipped: usize,
+}
+
+impl DrawEvent {
+    /// Formats the event as a line of text, without the newline.
+    pub fn to_text(&self) -> String {
+        let sprite: Vec<String> = self.sprite.iter().map(|b| format!("{:02X}", b)).collect();
+
+        format!(
+            "{:>9} {:04X} I {:04X} X {:>2} Y {:>2} N {:>2} VF {} flipped {:>3} sprite {}",
+            self.cycle,
+            self.pc,
+            self.i,
+            self.x,
+            self.y,
+            self.height,
+            self.collision as u8,
+            self.flipped,
+            sprite.join(" ")
+        )
+    }
+
+    /// Whether the pixel at (`x`, `y`) is inside the sprite's rectangle.
+    pub fn covers(&self, x: usize, y: usize) -> bool {
+        let (left, top) = (self.x as usize, self.y as usize);
+
+        (left..left + 8).contains(&x) && (top..top + self.height as usize).contains(&y)
+    }
+}
+
+/// Records the draws a machine makes.
+///
+/// Call [`DrawRecorder::record`] before every [`Chip8::step`],
+/// [`DrawRecorder::finish_step`] after it and [`DrawRecorder::end_frame`]
+/// once per frame.
+pub struct DrawRecorder {
+    // A draw waiting to see what it changed, and the display before it
+    pending: Option<(DrawEvent, Framebuffer)>,
+    current: Vec<DrawEvent>,
+    previous: Vec<DrawEvent>,
+    // The draw that last flipped each pixel, row by row
+    flipped_by: Vec<Option<DrawEvent>>,
+}
+
+impl Default for DrawRecorder {
+    fn default() -> DrawRecorder {
+        DrawRecorder::new()
+    }
+}
+
+impl DrawRecorder {
+    /// Creates a recorder that hasn't seen any draws.
+    pub fn new() -> DrawRecorder {
+        DrawRecorder {
+            pending: None,
+            current: Vec::new(),
+            previous: Vec::new(),
+            flipped_by: vec![None; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+        }
+    }
+
+    /// Notes the instruction `cpu` is about to execute, if it's a draw.
+    pub fn record(&mut self, cpu: &Chip8) {
+        let pc = cpu.pc() as usize;
+        let memory = cpu.memory();
+        let instruction = match memory.get(pc..pc + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => return,
+        };
+        if instruction >> 12 != 0xD {
+            return;
+        }
+
+        let x = cpu.v(((instruction >> 8) & 0xF) as usize) as usize % DISPLAY_WIDTH;
+        let y = cpu.v(((instruction >> 4) & 0xF) as usize) as usize % DISPLAY_HEIGHT;
+        let height = (instruction & 0xF) as usize;
+        let start = (cpu.i() as usize).min(memory.len());
+        let end = (start + height).min(memory.len());
+
+        let event = DrawEvent {
+            cycle: cpu.cycles(),
+            pc: cpu.pc(),
+            i: cpu.i(),
+            x: x as u8,
+            y: y as u8,
+            height: height as u8,
+            collision: false,
+            sprite: memory[start..end].to_vec(),
+            flipped: 0,
+        };
+        self.pending = Some((event, cpu.framebuffer().clone()));
+    }
+
+    /// Completes the record of a draw once it has executed.
+    pub fn finish_step(&mut self, cpu: &Chip8) {
+        let (mut event, before) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        // The draw failed, or the machine was stepped without us
+        if cpu.cycles() != event.cycle + 1 {
+            return;
+        }
+
+        let after = cpu.framebuffer();
+        let mut flipped = Vec::new();
+        for y in 0..DISPLAY_HEIGHT {
+            for x in 0..DISPLAY_WIDTH {
+                if before.pixel(x, y) != after.pixel(x, y) {
+                    flipped.push(y * DISPLAY_WIDTH + x);
+                }
+            }
+        }
+
+        event.collision = cpu.v(0xF) == 1;
+        event.flipped = flipped.len();
+        for pixel in flipped {
+            self.flipped_by[pixel] = Some(event.clone());
+        }
+        self.current.push(event);
+    }
+
+    /// Starts a new frame, keeping the draws of the one just finished.
+    pub fn end_frame(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// Draws made so far in the current frame.
+    pub fn current_frame(&self) -> &[DrawEvent] {
+        &self.current
+    }
+
+    /// Draws made in the last complete frame.
+    pub fn last_frame(&self) -> &[DrawEvent] {
+        &self.previous
+    }
+
+    /// The draw that last flipped the pixel at (`x`, `y`), if any has.
+    pub fn flipped_by(&self, x: usize, y: usize) -> Option<&DrawEvent> {
+        if x >= DISPLAY_WIDTH || y >= DISPLAY_HEIGHT {
+            return None;
+        }
+
+        self.flipped_by[y * DISPLAY_WIDTH + x].as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // LD I, 0x20A; DRW V0, V1, 1; LD V0, 4; DRW V0, V1, 1; JP 0x208; sprite F0
+    const PROGRAM: [u16; 6] = [0xA20A, 0xD011, 0x6004, 0xD011, 0x1208, 0xF000];
+
+    fn run(recorder: &mut DrawRecorder, cpu: &mut Chip8, steps: usize) {
+        for _ in 0..steps {
+            recorder.record(cpu);
+            cpu.step().unwrap();
+            recorder.finish_step(cpu);
+        }
+    }
+
+    #[test]
+    fn records_each_draw() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 2);
+
+        let draw = &recorder.current_frame()[0];
+        assert_eq!(
+            (draw.pc, draw.i, draw.x, draw.y, draw.height),
+            (0x202, 0x20A, 0, 0, 1)
+        );
+        assert_eq!(draw.sprite, [0xF0]);
+        assert!(!draw.collision);
+        assert_eq!(draw.flipped, 4);
+        assert_eq!(
+            draw.to_text(),
+            "        1 0202 I 020A X  0 Y  0 N  1 VF 0 flipped   4 sprite F0"
+        );
+    }
+
+    #[test]
+    fn records_collisions() {
+        // LD I, 0x208; DRW V0, V1, 1 twice; JP 0x206; sprite F0
+        let mut cpu = cpu_with(&[0xA208, 0xD011, 0xD011, 0x1206, 0xF000]);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 3);
+
+        let erase = &recorder.current_frame()[1];
+        assert!(erase.collision);
+        assert_eq!(erase.flipped, 4);
+        assert_eq!(recorder.flipped_by(0, 0).unwrap().pc, 0x204);
+    }
+
+    #[test]
+    fn keeps_the_last_frame() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 2);
+        recorder.end_frame();
+        run(&mut recorder, &mut cpu, 3);
+
+        assert_eq!(recorder.last_frame().len(), 1);
+        assert_eq!(recorder.last_frame()[0].pc, 0x202);
+        assert_eq!(recorder.current_frame().len(), 1);
+        assert_eq!(recorder.current_frame()[0].x, 4);
+
+        recorder.end_frame();
+        assert_eq!(recorder.last_frame()[0].pc, 0x206);
+        assert!(recorder.current_frame().is_empty());
+    }
+
+    #[test]
+    fn pixels_name_the_draw_that_flipped_them() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 4);
+
+        // The second sprite sits just right of the first
+        assert_eq!(recorder.flipped_by(3, 0).unwrap().pc, 0x202);
+        assert_eq!(recorder.flipped_by(4, 0).unwrap().pc, 0x206);
+        assert!(recorder.flipped_by(8, 0).is_none());
+        assert!(recorder.flipped_by(DISPLAY_WIDTH, 0).is_none());
+    }
+
+    #[test]
+    fn draws_cover_their_rectangle() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 4);
+
+        let draw = &recorder.current_frame()[1];
+        assert!(draw.covers(4, 0) && draw.covers(11, 0));
+        assert!(!draw.covers(3, 0) && !draw.covers(12, 0) && !draw.covers(4, 1));
+    }
+
+    #[test]
+    fn draws_stepped_without_the_recorder_are_dropped() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut recorder = DrawRecorder::new();
+        run(&mut recorder, &mut cpu, 1);
+
+        recorder.record(&cpu);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        recorder.finish_step(&cpu);
+        assert!(recorder.current_frame().is_empty());
+    }
+}
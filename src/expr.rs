@@ -0,0 +1,664 @@
+//! A small expression language over machine state, for breakpoint
+//! conditions, watches and log messages.
+//!
+//! Expressions are C-like and evaluate to integers, with comparisons and
+//! logical operators giving 1 for true and 0 for false:
+//!
+//! ```text
+//! v3 == 0x10 && i > 0x300
+//! ram[0x3F0] != 0
+//! hit_count > 50
+//! pc == draw_score
+//! ```
+//!
+//! Names are the registers `v0` to `vf`, `i`, `pc`, `sp`, `dt`, `st`, the
+//! instructions executed so far as `cycles`, and `hit_count`, the times the
+//! breakpoint being checked has been reached. Labels from a symbol file stand
+//! for their address. `ram[ADDR]` reads a byte of memory and `stack[N]` a
+//! slot of the stack, giving 0 outside them. Numbers may be decimal, or hex
+//! or binary with a `0x` or `0b` prefix.
+//!
+//! The operators, from loosest to tightest binding, are `||`, `&&`, `|`,
+//! `^`, `&`, `==` `!=`, `<` `<=` `>` `>=`, `<<` `>>`, `+` `-`, `*` `/` `%`,
+//! then the unary `!` `-` `~`. Arithmetic wraps, and dividing by zero gives 0.
+
+use crate::chip8::Chip8;
+use crate::symbols::Symbols;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+
+/// Errors raised while parsing an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExprError {
+    /// The expression couldn't be parsed, at a column counted from 1.
+    Syntax { column: usize, message: String },
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Syntax { column, message } => write!(f, "Column {}: {}", column, message),
+        }
+    }
+}
+
+impl error::Error for ExprError {}
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Parses `text`, looking up names that aren't built in as labels in
+    /// `symbols`.
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Expr, ExprError> {
+        parse_at(text, 0, symbols)
+    }
+
+    /// An expression true when both `self` and `other` are.
+    pub fn and(self, other: Expr) -> Expr {
+        Expr {
+            source: format!("({}) && ({})", self.source, other.source),
+            root: Node::Binary(BinaryOp::And, Box::new(self.root), Box::new(other.root)),
+        }
+    }
+
+    /// The value of the expression for `cpu`, where the breakpoint being
+    /// checked has been reached `hit_count` times.
+    pub fn evaluate(&self, cpu: &Chip8, hit_count: u64) -> i64 {
+        self.root.evaluate(cpu, hit_count)
+    }
+
+    /// Whether the expression is non-zero.
+    pub fn is_true(&self, cpu: &Chip8, hit_count: u64) -> bool {
+        self.evaluate(cpu, hit_count) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Text with expressions in braces replaced by their values when rendered,
+/// such as `score {v3} at {pc}`. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogMessage {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Value(Expr),
+}
+
+impl LogMessage {
+    /// Parses `text`, with the expressions in it parsed as by
+    /// [`Expr::parse`].
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<LogMessage, ExprError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.char_indices().peekable();
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '{' if matches!(chars.peek(), Some((_, '{'))) => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if matches!(chars.peek(), Some((_, '}'))) => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let start = index + 1;
+                    let end = match text[start..].find('}') {
+                        Some(length) => start + length,
+                        None => return Err(syntax(text, index, "missing } after {")),
+                    };
+                    let expr = parse_at(&text[start..end], text[..start].chars().count(), symbols)?;
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Value(expr));
+                    while matches!(chars.peek(), Some(&(next, _)) if next <= end) {
+                        chars.next();
+                    }
+                }
+                '}' => return Err(syntax(text, index, "unmatched }, use }} for a brace")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+
+        Ok(LogMessage {
+            source: text.to_string(),
+            parts,
+        })
+    }
+
+    /// The message with each expression's value filled in, in decimal.
+    pub fn render(&self, cpu: &Chip8, hit_count: u64) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Value(expr) => expr.evaluate(cpu, hit_count).to_string(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+    Cycles,
+    HitCount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(i64),
+    Variable(Variable),
+    Ram(Box<Node>),
+    Stack(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn evaluate(&self, cpu: &Chip8, hit_count: u64) -> i64 {
+        match self {
+            Node::Number(value) => *value,
+            Node::Variable(variable) => match variable {
+                Variable::V(register) => cpu.v(*register).into(),
+                Variable::I => cpu.i().into(),
+                Variable::Pc => cpu.pc().into(),
+                Variable::Sp => cpu.sp() as i64,
+                Variable::Dt => cpu.timers().delay.into(),
+                Variable::St => cpu.timers().sound.into(),
+                Variable::Cycles => cpu.cycles() as i64,
+                Variable::HitCount => hit_count as i64,
+            },
+            Node::Ram(address) => {
+                let address = address.evaluate(cpu, hit_count);
+                usize::try_from(address)
+                    .ok()
+                    .and_then(|address| cpu.memory().get(address))
+                    .map_or(0, |&byte| byte.into())
+            }
+            Node::Stack(slot) => {
+                let slot = slot.evaluate(cpu, hit_count);
+                usize::try_from(slot)
+                    .ok()
+                    .and_then(|slot| cpu.stack().get(slot))
+                    .map_or(0, |&address| address.into())
+            }
+            Node::Unary(op, operand) => {
+                let value = operand.evaluate(cpu, hit_count);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Complement => !value,
+                }
+            }
+            Node::Binary(BinaryOp::And, left, right) => {
+                (left.evaluate(cpu, hit_count) != 0 && right.evaluate(cpu, hit_count) != 0) as i64
+            }
+            Node::Binary(BinaryOp::Or, left, right) => {
+                (left.evaluate(cpu, hit_count) != 0 || right.evaluate(cpu, hit_count) != 0) as i64
+            }
+            Node::Binary(op, left, right) => {
+                let (a, b) = (
+                    left.evaluate(cpu, hit_count),
+                    right.evaluate(cpu, hit_count),
+                );
+                let shift = u32::try_from(b).unwrap_or(u32::MAX);
+                match op {
+                    BinaryOp::BitOr => a | b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::Equal => (a == b) as i64,
+                    BinaryOp::NotEqual => (a != b) as i64,
+                    BinaryOp::Less => (a < b) as i64,
+                    BinaryOp::LessEqual => (a <= b) as i64,
+                    BinaryOp::Greater => (a > b) as i64,
+                    BinaryOp::GreaterEqual => (a >= b) as i64,
+                    BinaryOp::ShiftLeft => a.checked_shl(shift).unwrap_or(0),
+                    BinaryOp::ShiftRight => a.checked_shr(shift).unwrap_or(0),
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Subtract => a.wrapping_sub(b),
+                    BinaryOp::Multiply => a.wrapping_mul(b),
+                    BinaryOp::Divide => a.checked_div(b).unwrap_or(0),
+                    BinaryOp::Remainder => a.checked_rem(b).unwrap_or(0),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+// Punctuation, longest first so `<=` isn't read as `<`
+const SYMBOLS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "<", ">", "+", "-", "*", "/", "%", "&", "|",
+    "^", "!", "~", "(", ")", "[", "]",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+// Binding strength of binary operators, tightest highest
+fn binary_op(symbol: &str) -> Option<(u8, BinaryOp)> {
+    Some(match symbol {
+        "||" => (1, BinaryOp::Or),
+        "&&" => (2, BinaryOp::And),
+        "|" => (3, BinaryOp::BitOr),
+        "^" => (4, BinaryOp::BitXor),
+        "&" => (5, BinaryOp::BitAnd),
+        "==" => (6, BinaryOp::Equal),
+        "!=" => (6, BinaryOp::NotEqual),
+        "<" => (7, BinaryOp::Less),
+        "<=" => (7, BinaryOp::LessEqual),
+        ">" => (7, BinaryOp::Greater),
+        ">=" => (7, BinaryOp::GreaterEqual),
+        "<<" => (8, BinaryOp::ShiftLeft),
+        ">>" => (8, BinaryOp::ShiftRight),
+        "+" => (9, BinaryOp::Add),
+        "-" => (9, BinaryOp::Subtract),
+        "*" => (10, BinaryOp::Multiply),
+        "/" => (10, BinaryOp::Divide),
+        "%" => (10, BinaryOp::Remainder),
+        _ => return None,
+    })
+}
+
+// Parses an expression found `offset` characters into a longer text, so
+// errors point at the right column
+fn parse_at(text: &str, offset: usize, symbols: &Symbols) -> Result<Expr, ExprError> {
+    let tokens = tokenize(text, offset)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: offset + text.chars().count(),
+        symbols,
+    };
+
+    let root = parser.expression(0)?;
+    if let Some((token, column)) = parser.tokens.get(parser.position) {
+        return Err(ExprError::Syntax {
+            column: column + 1,
+            message: format!("unexpected {}", describe(token)),
+        });
+    }
+
+    Ok(Expr {
+        source: text.trim().to_string(),
+        root,
+    })
+}
+
+// Splits `text` into tokens, each with the column it starts at counted from 0
+fn tokenize(text: &str, offset: usize) -> Result<Vec<(Token, usize)>, ExprError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let c = chars[index];
+
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        if c.is_ascii_alphanumeric() || c == '_' {
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+            {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+
+            let token = if c.is_ascii_digit() {
+                let lower = word.to_ascii_lowercase();
+                let number = match (lower.strip_prefix("0x"), lower.strip_prefix("0b")) {
+                    (Some(hex), _) => i64::from_str_radix(hex, 16),
+                    (_, Some(binary)) => i64::from_str_radix(binary, 2),
+                    _ => lower.parse(),
+                };
+                match number {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => {
+                        return Err(ExprError::Syntax {
+                            column: offset + start + 1,
+                            message: format!("invalid number {}", word),
+                        })
+                    }
+                }
+            } else {
+                Token::Name(word)
+            };
+            tokens.push((token, offset + start));
+            continue;
+        }
+
+        let rest: String = chars[index..].iter().take(2).collect();
+        match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            Some(symbol) => {
+                tokens.push((Token::Symbol(symbol), offset + start));
+                index += symbol.len();
+            }
+            None => {
+                return Err(ExprError::Syntax {
+                    column: offset + start + 1,
+                    message: format!("unexpected {}", c),
+                })
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(number) => number.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Symbol(symbol) => symbol.to_string(),
+    }
+}
+
+// An error at byte `index` of `text`
+fn syntax(text: &str, index: usize, message: &str) -> ExprError {
+    ExprError::Syntax {
+        column: text[..index].chars().count() + 1,
+        message: message.to_string(),
+    }
+}
+
+// Recursive descent, climbing binary operators by binding strength
+struct Parser<'a> {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    // Column just past the expression, for errors at its end
+    end: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    // Binary operators binding at least as tightly as `strength`
+    fn expression(&mut self, strength: u8) -> Result<Node, ExprError> {
+        let mut left = self.unary()?;
+
+        while let Some((Token::Symbol(symbol), _)) = self.tokens.get(self.position) {
+            let (op_strength, op) = match binary_op(symbol) {
+                Some((op_strength, op)) if op_strength >= strength => (op_strength, op),
+                _ => break,
+            };
+            self.position += 1;
+
+            let right = self.expression(op_strength + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        let op = match self.tokens.get(self.position) {
+            Some((Token::Symbol("!"), _)) => UnaryOp::Not,
+            Some((Token::Symbol("-"), _)) => UnaryOp::Negate,
+            Some((Token::Symbol("~"), _)) => UnaryOp::Complement,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+        let (token, column) = match self.tokens.get(self.position) {
+            Some((token, column)) => (token.clone(), *column),
+            None => return Err(self.error_at(self.end, "expected a value")),
+        };
+        self.position += 1;
+
+        match token {
+            Token::Number(number) => Ok(Node::Number(number)),
+            Token::Symbol("(") => {
+                let node = self.expression(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Name(name) if name == "ram" || name == "stack" => {
+                self.expect("[")?;
+                let index = Box::new(self.expression(0)?);
+                self.expect("]")?;
+                Ok(if name == "ram" {
+                    Node::Ram(index)
+                } else {
+                    Node::Stack(index)
+                })
+            }
+            Token::Name(name) => match variable(&name) {
+                Some(variable) => Ok(Node::Variable(variable)),
+                None => match self.symbols.address_of(&name) {
+                    Some(address) => Ok(Node::Number(address.into())),
+                    None => Err(self.error_at(column, &format!("unknown name {}", name))),
+                },
+            },
+            token => Err(self.error_at(column, &format!("unexpected {}", describe(&token)))),
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ExprError> {
+        match self.tokens.get(self.position) {
+            Some((Token::Symbol(found), _)) if *found == symbol => {
+                self.position += 1;
+                Ok(())
+            }
+            Some((_, column)) => Err(self.error_at(*column, &format!("expected {}", symbol))),
+            None => Err(self.error_at(self.end, &format!("expected {}", symbol))),
+        }
+    }
+
+    fn error_at(&self, column: usize, message: &str) -> ExprError {
+        ExprError::Syntax {
+            column: column + 1,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn variable(name: &str) -> Option<Variable> {
+    let name = name.to_ascii_lowercase();
+
+    Some(match name.as_str() {
+        "i" => Variable::I,
+        "pc" => Variable::Pc,
+        "sp" => Variable::Sp,
+        "dt" => Variable::Dt,
+        "st" => Variable::St,
+        "cycles" => Variable::Cycles,
+        "hit_count" => Variable::HitCount,
+        _ => {
+            let register = name.strip_prefix('v')?;
+            if register.len() != 1 {
+                return None;
+            }
+            Variable::V(usize::from_str_radix(register, 16).ok()?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // Three steps in: V3 0x10, I 0x321 and inside a call from 0x204, with
+    // ram[score] holding 7
+    fn machine() -> (Chip8, Symbols) {
+        // LD V3, 0x10; LD I, 0x321; CALL 0x208; 0x208: JP 0x208
+        let mut cpu = cpu_with(&[0x6310, 0xA321, 0x2208, 0x0000, 0x1208]);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        cpu.memory_mut()[0x3F0] = 7;
+
+        (cpu, Symbols::parse("label 0x3F0 score").unwrap())
+    }
+
+    fn value(text: &str) -> i64 {
+        let (cpu, symbols) = machine();
+        Expr::parse(text, &symbols).unwrap().evaluate(&cpu, 51)
+    }
+
+    fn error(text: &str) -> String {
+        Expr::parse(text, &Symbols::default())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn reads_registers() {
+        assert_eq!(value("v3"), 0x10);
+        assert_eq!(value("V3 + vf"), 0x10);
+        assert_eq!(value("i"), 0x321);
+        assert_eq!(value("pc"), 0x208);
+        assert_eq!(value("sp"), 1);
+        assert_eq!(value("dt + st"), 0);
+        assert_eq!(value("cycles"), 3);
+        assert_eq!(value("hit_count"), 51);
+    }
+
+    #[test]
+    fn reads_memory_and_the_stack() {
+        assert_eq!(value("score"), 0x3F0);
+        assert_eq!(value("ram[score] * 2 + 1"), 15);
+        assert_eq!(value("ram[0x3f0 + 1]"), 0);
+        assert_eq!(value("stack[0]"), 0x206);
+        // Outside them reads as 0
+        assert_eq!(value("ram[0x10000]"), 0);
+        assert_eq!(value("ram[-1]"), 0);
+        assert_eq!(value("stack[16]"), 0);
+    }
+
+    #[test]
+    fn compares_to_0_or_1() {
+        assert_eq!(value("v3 == 0x10 && i > 0x300"), 1);
+        assert_eq!(value("ram[0x3f0] != 0"), 1);
+        assert_eq!(value("hit_count > 50 || dt"), 1);
+        assert_eq!(value("v3 <= 0b1111"), 0);
+        assert_eq!(value("!v3"), 0);
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(value("1 + 2 * 3 == 7 & 1"), 1);
+        assert_eq!(value("1 | 6 ^ 3 & 5"), 7);
+        assert_eq!(value("1 << 2 + 1"), 8);
+        assert_eq!(value("-(V3 >> 2) / 0b10"), -2);
+        assert_eq!(value("10 - 4 - 3"), 3);
+    }
+
+    #[test]
+    fn arithmetic_never_fails() {
+        assert_eq!(value("!pc | ~0 & 5 / 0"), 0);
+        assert_eq!(value("7 % 0"), 0);
+        assert_eq!(value("1 << 64"), 0);
+        assert_eq!(value("1 << -1"), 0);
+        assert_eq!(value("0x7FFFFFFFFFFFFFFF + 1"), i64::MIN);
+    }
+
+    #[test]
+    fn errors_give_the_column() {
+        assert_eq!(error("v3 == "), "Column 7: expected a value");
+        assert_eq!(error("ram[1"), "Column 6: expected ]");
+        assert_eq!(error("v3 = 1"), "Column 4: unexpected =");
+        assert_eq!(error("lives > 1"), "Column 1: unknown name lives");
+    }
+
+    #[test]
+    fn combined_expressions_need_both() {
+        let symbols = Symbols::default();
+        let (cpu, _) = machine();
+        let both = Expr::parse("v3 == 0x10", &symbols)
+            .unwrap()
+            .and(Expr::parse("hit_count > 2", &symbols).unwrap());
+
+        assert_eq!(both.to_string(), "(v3 == 0x10) && (hit_count > 2)");
+        assert!(!both.is_true(&cpu, 2));
+        assert!(both.is_true(&cpu, 3));
+    }
+
+    #[test]
+    fn log_messages_fill_in_values() {
+        let (cpu, symbols) = machine();
+
+        let message = LogMessage::parse("{{score}} {ram[score]} at {hit_count}", &symbols).unwrap();
+        assert_eq!(message.render(&cpu, 3), "{score} 7 at 3");
+        assert_eq!(
+            LogMessage::parse("x {v3 +}", &symbols)
+                .unwrap_err()
+                .to_string(),
+            "Column 8: expected a value"
+        );
+    }
+}
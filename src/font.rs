@@ -0,0 +1,302 @@
+use crate::chip8::Error;
+use crate::layout::Platform;
+
+/// Size of the 16 small 4x5 glyphs, 5 bytes each.
+pub const SMALL_FONT_SIZE: usize = 16 * 5;
+
+/// Size of one big 8x10 glyph.
+pub const BIG_GLYPH_SIZE: usize = 10;
+
+/// Names of the built-in fonts, for use with [`Font::builtin`].
+pub const BUILTIN_FONTS: [&str; 6] = ["chip48", "vip", "dream6800", "eti660", "schip", "xochip"];
+
+// 4x5 sprites for the hex digits 0 - F, as used by CHIP-48 and most modern interpreters
+static CHIP48_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// The font in the COSMAC VIP's interpreter ROM
+static VIP_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+// CHIPOS on the DREAM 6800 used narrower 3x5 glyphs
+static DREAM6800_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x40, 0x40, 0x40, 0x40, 0x40, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// The ETI-660's 3x5 glyphs, with lowercase-style b and d
+static ETI660_FONT: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+    0x20, 0x20, 0x20, 0x20, 0x20, // 1
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+    0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// SUPER-CHIP 1.1 8x10 glyphs, digits 0 - 9 only
+static SCHIP_BIG_FONT: [u8; 10 * BIG_GLYPH_SIZE] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+];
+
+// XO-CHIP (Octo) 8x10 glyphs for all of 0 - F
+static XOCHIP_BIG_FONT: [u8; 16 * BIG_GLYPH_SIZE] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Glyphs for the hex digits, used by `Fx29` and the SUPER-CHIP `Fx30`.
+///
+/// Every font has 16 small 4x5 glyphs. Fonts can also carry big 8x10 glyphs,
+/// either just the digits 0 - 9 like SUPER-CHIP or all 16 like XO-CHIP. In
+/// memory the big glyphs directly follow the small ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Font {
+    small: Vec<u8>,
+    big: Vec<u8>,
+}
+
+impl Default for Font {
+    fn default() -> Font {
+        Font::builtin("chip48").expect("Default font is built in")
+    }
+}
+
+impl Font {
+    /// Looks up one of the [`BUILTIN_FONTS`] by name.
+    pub fn builtin(name: &str) -> Option<Font> {
+        let (small, big): (&[u8], &[u8]) = match name.to_ascii_lowercase().as_str() {
+            "chip48" => (&CHIP48_FONT, &[]),
+            "vip" => (&VIP_FONT, &[]),
+            "dream6800" => (&DREAM6800_FONT, &[]),
+            "eti660" => (&ETI660_FONT, &[]),
+            "schip" => (&CHIP48_FONT, &SCHIP_BIG_FONT),
+            "xochip" => (&CHIP48_FONT, &XOCHIP_BIG_FONT),
+            _ => return None,
+        };
+
+        Some(Font {
+            small: small.to_vec(),
+            big: big.to_vec(),
+        })
+    }
+
+    /// The font shipped with `platform`'s interpreter.
+    pub fn for_platform(platform: Platform) -> Font {
+        let name = match platform {
+            Platform::CosmacVip => "vip",
+            Platform::Eti660 => "eti660",
+            Platform::Dream6800 => "dream6800",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        };
+
+        Font::builtin(name).expect("Platform fonts are built in")
+    }
+
+    /// Parses a custom font.
+    ///
+    /// The data holds the 80 bytes of small glyphs, optionally followed by
+    /// 100 bytes of big digits or 160 bytes of big hex glyphs.
+    pub fn from_bytes(data: &[u8]) -> Result<Font, Error> {
+        match data.len() {
+            SMALL_FONT_SIZE => {}
+            n if n == SMALL_FONT_SIZE + 10 * BIG_GLYPH_SIZE => {}
+            n if n == SMALL_FONT_SIZE + 16 * BIG_GLYPH_SIZE => {}
+            n => return Err(Error::InvalidFontSize(n)),
+        }
+
+        let (small, big) = data.split_at(SMALL_FONT_SIZE);
+
+        Ok(Font {
+            small: small.to_vec(),
+            big: big.to_vec(),
+        })
+    }
+
+    /// The 4x5 glyphs, 5 bytes per digit.
+    pub fn small(&self) -> &[u8] {
+        &self.small
+    }
+
+    /// The 8x10 glyphs, 10 bytes per digit. Empty if the font has none.
+    pub fn big(&self) -> &[u8] {
+        &self.big
+    }
+
+    /// Number of bytes the font takes up in memory.
+    pub fn size(&self) -> usize {
+        self.small.len() + self.big.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::layout::{Layout, MEMORY_SIZE};
+
+    #[test]
+    fn builtin_fonts_are_found_by_name() {
+        for name in &BUILTIN_FONTS {
+            let font = Font::builtin(name).unwrap();
+            assert_eq!(font.small().len(), SMALL_FONT_SIZE);
+        }
+        assert_eq!(Font::builtin("VIP"), Font::builtin("vip"));
+        assert_eq!(Font::builtin("comic-sans"), None);
+    }
+
+    #[test]
+    fn big_glyphs_follow_the_platform() {
+        assert!(Font::builtin("vip").unwrap().big().is_empty());
+        assert_eq!(
+            Font::builtin("schip").unwrap().big().len(),
+            10 * BIG_GLYPH_SIZE
+        );
+        assert_eq!(
+            Font::builtin("xochip").unwrap().size(),
+            SMALL_FONT_SIZE + 16 * BIG_GLYPH_SIZE
+        );
+    }
+
+    #[test]
+    fn custom_fonts_have_small_glyphs_and_optional_big_ones() {
+        for &big in &[0, 10, 16] {
+            let data = vec![0xAA; SMALL_FONT_SIZE + big * BIG_GLYPH_SIZE];
+            let font = Font::from_bytes(&data).unwrap();
+            assert_eq!(font.small(), &data[..SMALL_FONT_SIZE]);
+            assert_eq!(font.big().len(), big * BIG_GLYPH_SIZE);
+        }
+    }
+
+    #[test]
+    fn custom_fonts_of_other_sizes_are_rejected() {
+        for &size in &[0, SMALL_FONT_SIZE - 1, SMALL_FONT_SIZE + 1, 300] {
+            assert_eq!(
+                Font::from_bytes(&vec![0; size]),
+                Err(Error::InvalidFontSize(size))
+            );
+        }
+    }
+
+    #[test]
+    fn fonts_must_fit_in_memory() {
+        let layout = Layout {
+            font_address: (MEMORY_SIZE - SMALL_FONT_SIZE) as u16,
+            entry_point: 0x200,
+            ..Layout::default()
+        };
+        let mut cpu = Chip8::with_layout(layout).unwrap();
+        assert_eq!(cpu.set_font(Font::builtin("vip").unwrap()), Ok(()));
+        assert_eq!(
+            cpu.set_font(Font::builtin("schip").unwrap()),
+            Err(Error::FontOutsideMemory)
+        );
+    }
+
+    #[test]
+    fn roms_cant_overlap_big_glyphs() {
+        // Loads right after the small glyphs
+        let layout = Layout {
+            load_address: 0x0A0,
+            entry_point: 0x0A0,
+            ..Layout::default()
+        };
+        let mut cpu = Chip8::with_layout(layout).unwrap();
+        cpu.load_rom(&[0x12, 0xA0]).unwrap();
+
+        let mut cpu = Chip8::with_layout(layout).unwrap();
+        cpu.set_font(Font::builtin("schip").unwrap()).unwrap();
+        assert_eq!(cpu.load_rom(&[0x12, 0xA0]), Err(Error::RomOverlapsFont));
+    }
+
+    #[test]
+    fn switching_fonts_clears_old_big_glyphs() {
+        let mut cpu = Chip8::new();
+        cpu.set_font(Font::builtin("xochip").unwrap()).unwrap();
+        cpu.set_font(Font::builtin("vip").unwrap()).unwrap();
+
+        let start = Layout::default().font_address as usize + SMALL_FONT_SIZE;
+        let big = &cpu.memory()[start..start + 16 * BIG_GLYPH_SIZE];
+        assert!(big.iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,61 @@
+/// Width of the display in pixels.
+pub const DISPLAY_WIDTH: usize = 64;
+
+/// Height of the display in pixels.
+pub const DISPLAY_HEIGHT: usize = 32;
+
+/// Monochrome display contents, `true` for a lit pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    buffer: [[bool; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
+
+impl Framebuffer {
+    /// Creates a framebuffer with every pixel off.
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            buffer: [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+        }
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> usize {
+        DISPLAY_WIDTH
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> usize {
+        DISPLAY_HEIGHT
+    }
+
+    /// Whether the pixel at (`x`, `y`) is lit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the coordinates are outside the display.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.buffer[y][x]
+    }
+
+    /// Iterates over the rows of the display from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[bool]> {
+        self.buffer.iter().map(|row| &row[..])
+    }
+
+    pub(crate) fn flip_pixel(&mut self, x: usize, y: usize) -> bool {
+        self.buffer[y][x] = !self.buffer[y][x];
+
+        // Return true if pixel was turned off (is now on)
+        !self.buffer[y][x]
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.buffer = [[false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+    }
+}
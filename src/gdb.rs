@@ -0,0 +1,492 @@
+//! The GDB remote serial protocol, for attaching debuggers to a machine.
+//!
+//! [`PacketReader`] splits the bytes arriving from a debugger into packets
+//! and [`GdbStub`] answers them from the machine state. Running the machine
+//! is left to the caller: the stub only says when to continue or step, and
+//! which addresses have breakpoints.
+//!
+//! The registers are numbered V0 to VF (0 - 15), I (16), PC (17), SP (18),
+//! DT (19) and ST (20), described to the debugger by a target description.
+//! Register values are sent little endian, as GDB expects.
+
+use crate::chip8::Chip8;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Stop signal for a breakpoint or completed step.
+pub const SIGTRAP: u8 = 5;
+/// Stop signal when the debugger interrupts a running program.
+pub const SIGINT: u8 = 2;
+/// Stop signal when the program fails, e.g. with an unknown instruction.
+pub const SIGSEGV: u8 = 11;
+
+const REGISTER_COUNT: usize = 21;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Something sent by the debugger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incoming {
+    /// A packet with a valid checksum, to be acknowledged with `+`.
+    Packet(String),
+    /// A packet with a bad checksum, to be asked for again with `-`.
+    BadChecksum,
+    /// A request to stop the running program, sent as a lone `0x03` byte.
+    Interrupt,
+}
+
+/// Splits the bytes from a debugger into packets.
+#[derive(Debug, Default)]
+pub struct PacketReader {
+    buffer: Vec<u8>,
+}
+
+impl PacketReader {
+    pub fn new() -> PacketReader {
+        PacketReader::default()
+    }
+
+    /// Adds bytes received from the debugger.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete message, if one has arrived.
+    pub fn next_incoming(&mut self) -> Option<Incoming> {
+        loop {
+            match self.buffer.first()? {
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Incoming::Interrupt);
+                }
+                b'$' => break,
+                // Acknowledgements and noise between packets
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+
+        // A packet is $payload#xx
+        let end = self.buffer.iter().position(|&b| b == b'#')?;
+        if self.buffer.len() < end + 3 {
+            return None;
+        }
+
+        let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+        let payload = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+        if checksum != Some(checksum_of(payload)) {
+            return Some(Incoming::BadChecksum);
+        }
+        Some(Incoming::Packet(
+            String::from_utf8_lossy(payload).into_owned(),
+        ))
+    }
+}
+
+/// Frames `payload` as a packet, escaping the characters the protocol reserves.
+pub fn encode_packet(payload: &str) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(payload.len());
+    for &byte in payload.as_bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(byte);
+        }
+    }
+
+    let mut packet = Vec::with_capacity(escaped.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum_of(&escaped)).as_bytes());
+    packet
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// The reply telling the debugger the program stopped with `signal`.
+pub fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+/// The reply telling the debugger the program finished with `code`.
+pub fn exit_reply(code: u8) -> String {
+    format!("W{:02x}", code)
+}
+
+/// What to do after handling a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Send this reply and wait for the next packet.
+    Reply(String),
+    /// Run until a breakpoint or interrupt, then send a stop reply.
+    Continue,
+    /// Execute one instruction, then send a stop reply.
+    Step,
+    /// The debugger is detaching, send `OK` and carry on without it.
+    Detach,
+    /// The debugger wants the program killed, without a reply.
+    Kill,
+}
+
+/// Answers debugger requests about a machine.
+#[derive(Debug, Default)]
+pub struct GdbStub {
+    breakpoints: BTreeSet<u16>,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub::default()
+    }
+
+    /// Whether there's a breakpoint on `address`.
+    pub fn is_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    /// Handles one packet while the program is stopped.
+    pub fn handle(&mut self, cpu: &mut Chip8, packet: &str) -> Response {
+        let reply = |text: &str| Response::Reply(text.to_string());
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+
+        match command {
+            "?" => Response::Reply(stop_reply(SIGTRAP)),
+            "g" => {
+                let values = registers(cpu);
+                let encoded = (0..REGISTER_COUNT).map(|n| encode_register(n, values[n]));
+                Response::Reply(encoded.collect())
+            }
+            "G" => match decode_registers(arguments) {
+                Some(values) => {
+                    for (number, value) in values.into_iter().enumerate() {
+                        set_register(cpu, number, value);
+                    }
+                    reply("OK")
+                }
+                None => reply("E01"),
+            },
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(number) if number < REGISTER_COUNT => {
+                    Response::Reply(encode_register(number, registers(cpu)[number]))
+                }
+                _ => reply("E01"),
+            },
+            "P" => match parse_register_write(arguments) {
+                Some((number, value)) => {
+                    set_register(cpu, number, value);
+                    reply("OK")
+                }
+                None => reply("E01"),
+            },
+            "m" => match parse_range(arguments).and_then(|r| cpu.memory().get(r)) {
+                Some(bytes) => Response::Reply(hex(bytes)),
+                None => reply("E01"),
+            },
+            "M" => match write_memory(cpu, arguments) {
+                Some(()) => reply("OK"),
+                None => reply("E01"),
+            },
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                // Software and hardware breakpoints are the same thing here
+                Some((kind, address)) if kind <= 1 => {
+                    if command == "Z" {
+                        self.breakpoints.insert(address);
+                    } else {
+                        self.breakpoints.remove(&address);
+                    }
+                    reply("OK")
+                }
+                // Watchpoints aren't supported
+                _ => reply(""),
+            },
+            "c" => resume(cpu, arguments, Response::Continue),
+            "s" => resume(cpu, arguments, Response::Step),
+            "D" => Response::Detach,
+            "k" => Response::Kill,
+            "H" | "T" => reply("OK"),
+            _ => handle_query(packet),
+        }
+    }
+}
+
+fn resume(cpu: &mut Chip8, address: &str, response: Response) -> Response {
+    // c and s may give an address to resume from
+    if !address.is_empty() {
+        match u16::from_str_radix(address, 16) {
+            Ok(address) => cpu.set_pc(address),
+            Err(_) => return Response::Reply("E01".to_string()),
+        }
+    }
+
+    response
+}
+
+fn handle_query(packet: &str) -> Response {
+    let reply = |text: &str| Response::Reply(text.to_string());
+
+    if packet.starts_with("qSupported") {
+        reply("PacketSize=1000;qXfer:features:read+")
+    } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        match parse_range(range) {
+            Some(range) if range.start <= TARGET_XML.len() => {
+                let end = range.end.min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                Response::Reply(format!("{}{}", more, &TARGET_XML[range.start..end]))
+            }
+            _ => reply("E01"),
+        }
+    } else if packet == "vCont?" {
+        reply("vCont;c;s")
+    } else if let Some(action) = packet.strip_prefix("vCont;") {
+        // Only one thread, so the first action applies
+        match action.as_bytes().first() {
+            Some(b'c') => Response::Continue,
+            Some(b's') => Response::Step,
+            _ => reply("E01"),
+        }
+    } else if packet == "qAttached" {
+        reply("1")
+    } else if packet == "qC" {
+        reply("QC1")
+    } else if packet == "qfThreadInfo" {
+        reply("m1")
+    } else if packet == "qsThreadInfo" {
+        reply("l")
+    } else if packet.starts_with("qOffsets") {
+        reply("Text=0;Data=0;Bss=0")
+    } else {
+        // An empty reply means the packet isn't supported
+        reply("")
+    }
+}
+
+// Register values in the order the debugger numbers them
+fn registers(cpu: &Chip8) -> [u16; REGISTER_COUNT] {
+    let mut registers = [0; REGISTER_COUNT];
+    for (register, &value) in registers.iter_mut().zip(cpu.registers()) {
+        *register = value as u16;
+    }
+
+    let timers = cpu.timers();
+    registers[16] = cpu.i();
+    registers[17] = cpu.pc();
+    registers[18] = cpu.sp() as u16;
+    registers[19] = timers.delay as u16;
+    registers[20] = timers.sound as u16;
+    registers
+}
+
+fn register_size(number: usize) -> usize {
+    match number {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+fn set_register(cpu: &mut Chip8, number: usize, value: u16) {
+    let mut timers = cpu.timers();
+
+    match number {
+        0..=15 => cpu.set_v(number, value as u8),
+        16 => cpu.set_i(value),
+        17 => cpu.set_pc(value),
+        18 => cpu.set_sp(value as usize),
+        19 => timers.delay = value as u8,
+        20 => timers.sound = value as u8,
+        _ => {}
+    }
+    cpu.set_timers(timers);
+}
+
+fn encode_register(number: usize, value: u16) -> String {
+    hex(&value.to_le_bytes()[..register_size(number)])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Little endian register value of `size` bytes
+fn decode_register(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u16)
+}
+
+fn decode_registers(text: &str) -> Option<Vec<u16>> {
+    let bytes = unhex(text)?;
+    let mut values = Vec::with_capacity(REGISTER_COUNT);
+
+    let mut offset = 0;
+    for number in 0..REGISTER_COUNT {
+        let size = register_size(number);
+        values.push(decode_register(bytes.get(offset..offset + size)?));
+        offset += size;
+    }
+
+    Some(values)
+}
+
+// NN=VALUE
+fn parse_register_write(text: &str) -> Option<(usize, u16)> {
+    let (number, value) = text.split_once('=')?;
+    let number = usize::from_str_radix(number, 16).ok()?;
+    if number >= REGISTER_COUNT {
+        return None;
+    }
+
+    let bytes = unhex(value)?;
+    if bytes.len() > 2 {
+        return None;
+    }
+    Some((number, decode_register(&bytes)))
+}
+
+// ADDR,LENGTH as a range of addresses
+fn parse_range(text: &str) -> Option<std::ops::Range<usize>> {
+    let (start, length) = text.split_once(',')?;
+    let start = usize::from_str_radix(start, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+
+    Some(start..start.checked_add(length)?)
+}
+
+// ADDR,LENGTH:BYTES
+fn write_memory(cpu: &mut Chip8, text: &str) -> Option<()> {
+    let (range, data) = text.split_once(':')?;
+    let range = parse_range(range)?;
+    let bytes = unhex(data)?;
+    if bytes.len() != range.len() {
+        return None;
+    }
+
+    cpu.memory_mut().get_mut(range)?.copy_from_slice(&bytes);
+    Some(())
+}
+
+// TYPE,ADDR,KIND
+fn parse_breakpoint(text: &str) -> Option<(u8, u16)> {
+    let mut fields = text.split(',');
+    let kind = fields.next()?.parse().ok()?;
+    let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+
+    Some((kind, address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(stub: &mut GdbStub, cpu: &mut Chip8, packet: &str) -> String {
+        match stub.handle(cpu, packet) {
+            Response::Reply(reply) => reply,
+            response => panic!("Expected a reply to {}, got {:?}", packet, response),
+        }
+    }
+
+    #[test]
+    fn reads_packets() {
+        let mut reader = PacketReader::new();
+        reader.push(b"+$g#67$m200,2");
+        assert_eq!(
+            reader.next_incoming(),
+            Some(Incoming::Packet("g".to_string()))
+        );
+        assert_eq!(reader.next_incoming(), None);
+
+        reader.push(b"#00\x03");
+        assert_eq!(reader.next_incoming(), Some(Incoming::BadChecksum));
+        assert_eq!(reader.next_incoming(), Some(Incoming::Interrupt));
+
+        assert_eq!(encode_packet("OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode_packet("a#"), b"$a}\x03#e1".to_vec());
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0x60, 0x2A, 0x12, 0x02]).unwrap();
+        cpu.set_i(0x345);
+        let mut stub = GdbStub::new();
+
+        let registers = reply(&mut stub, &mut cpu, "g");
+        assert_eq!(registers.len(), 2 * 23);
+        assert!(registers.ends_with("45030002000000"));
+        assert_eq!(reply(&mut stub, &mut cpu, "p11"), "0002");
+
+        assert_eq!(reply(&mut stub, &mut cpu, "P3=7f"), "OK");
+        assert_eq!(cpu.v(0x3), 0x7F);
+        assert_eq!(reply(&mut stub, &mut cpu, "P10=3412"), "OK");
+        assert_eq!(cpu.i(), 0x1234);
+
+        assert_eq!(reply(&mut stub, &mut cpu, "m200,4"), "602a1202");
+        assert_eq!(reply(&mut stub, &mut cpu, "M300,2:beef"), "OK");
+        assert_eq!(&cpu.memory()[0x300..0x302], &[0xBE, 0xEF]);
+        assert_eq!(reply(&mut stub, &mut cpu, "mfff,2"), "E01");
+
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,202,2"), "OK");
+        assert!(stub.is_breakpoint(0x202));
+        assert_eq!(reply(&mut stub, &mut cpu, "z0,202,2"), "OK");
+        assert!(!stub.is_breakpoint(0x202));
+        assert_eq!(reply(&mut stub, &mut cpu, "Z2,300,1"), "");
+
+        assert_eq!(stub.handle(&mut cpu, "c"), Response::Continue);
+        assert_eq!(stub.handle(&mut cpu, "s204"), Response::Step);
+        assert_eq!(cpu.pc(), 0x204);
+        assert_eq!(stub.handle(&mut cpu, "vCont;s:1"), Response::Step);
+
+        let xml = reply(&mut stub, &mut cpu, "qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::instruments::Instruments;
+use chip_8::gdb::{self, GdbStub, Incoming, PacketReader, Response};
+use chip_8::Chip8;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::Duration;
+
+// What the debugger wants after serving its packets
+pub enum Session {
+    Attached,
+    Detached,
+    Killed,
+}
+
+// A debugger attached over TCP. The program starts stopped, waiting for the
+// debugger to continue or step it.
+pub struct GdbServer {
+    stream: TcpStream,
+    reader: PacketReader,
+    stub: GdbStub,
+    halted: bool,
+    // Don't stop on the breakpoint we've just continued from
+    resuming: bool,
+}
+
+impl GdbServer {
+    // Waits for a debugger to connect to localhost on `port`
+    pub fn listen(port: u16) -> Result<GdbServer, String> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| format!("Failed to listen on port {}: {}", port, e))?;
+        eprintln!("Waiting for a debugger on {}:{}", Ipv4Addr::LOCALHOST, port);
+
+        GdbServer::attach(&listener)
+    }
+
+    // Waits for a debugger to connect through `listener`
+    fn attach(listener: &TcpListener) -> Result<GdbServer, String> {
+        let (stream, address) = listener
+            .accept()
+            .map_err(|e| format!("Failed to accept a debugger: {}", e))?;
+        // Packets are small and latency matters more than throughput
+        let _ = stream.set_nodelay(true);
+        eprintln!("Debugger attached from {}", address);
+
+        Ok(GdbServer {
+            stream,
+            reader: PacketReader::new(),
+            stub: GdbStub::new(),
+            halted: true,
+            resuming: false,
+        })
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Call before each step while running, true if the program should stop
+    // for a breakpoint or an interrupt from the debugger
+    pub fn should_stop(&mut self, cpu: &Chip8) -> bool {
+        if !self.halted && self.receive(Duration::from_secs(0)).is_err() {
+            // Lost the debugger, the next call to serve() notices
+            return false;
+        }
+        while let Some(incoming) = self.reader.next_incoming() {
+            if incoming == Incoming::Interrupt {
+                self.stop(gdb::SIGINT);
+            }
+        }
+
+        if self.halted {
+            return true;
+        }
+        if self.resuming {
+            self.resuming = false;
+            return false;
+        }
+        if self.stub.is_breakpoint(cpu.pc()) {
+            self.stop(gdb::SIGTRAP);
+            return true;
+        }
+
+        false
+    }
+
+    // Stops the program, telling the debugger why
+    pub fn stop(&mut self, signal: u8) {
+        self.halted = true;
+        self.send(&gdb::stop_reply(signal));
+    }
+
+    // Tells the debugger the program has finished
+    pub fn finish(&mut self, code: u8) {
+        self.send(&gdb::exit_reply(code));
+    }
+
+    // Handles packets from the debugger while the program is stopped, waiting
+    // up to `wait` for more until the debugger resumes the program
+    pub fn serve(
+        &mut self,
+        cpu: &mut Chip8,
+        instruments: &mut Instruments,
+        wait: Duration,
+    ) -> Session {
+        loop {
+            while let Some(incoming) = self.reader.next_incoming() {
+                let packet = match incoming {
+                    Incoming::Packet(packet) => packet,
+                    Incoming::BadChecksum => {
+                        self.send_raw(b"-");
+                        continue;
+                    }
+                    Incoming::Interrupt => {
+                        if !self.halted {
+                            self.stop(gdb::SIGINT);
+                        }
+                        continue;
+                    }
+                };
+                self.send_raw(b"+");
+                // While running, the debugger should only interrupt
+                if !self.halted {
+                    continue;
+                }
+
+                match self.stub.handle(cpu, &packet) {
+                    Response::Reply(reply) => self.send(&reply),
+                    Response::Continue => {
+                        self.halted = false;
+                        self.resuming = true;
+                    }
+                    Response::Step => match instruments.step(cpu) {
+                        Ok(()) => self.stop(gdb::SIGTRAP),
+                        Err(e) => {
+                            eprintln!("{}", instruments.symbols().annotate_error(&e));
+                            self.stop(gdb::SIGSEGV);
+                        }
+                    },
+                    Response::Detach => {
+                        self.send("OK");
+                        eprintln!("Debugger detached");
+                        return Session::Detached;
+                    }
+                    Response::Kill => return Session::Killed,
+                }
+            }
+
+            if !self.halted {
+                return Session::Attached;
+            }
+            match self.receive(wait) {
+                Ok(true) => {}
+                Ok(false) => return Session::Attached,
+                Err(e) => {
+                    eprintln!("Lost the debugger: {}", e);
+                    return Session::Detached;
+                }
+            }
+        }
+    }
+
+    // Reads whatever the debugger has sent, waiting up to `wait` for
+    // something to arrive. False if nothing did.
+    fn receive(&mut self, wait: Duration) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+
+        // A zero timeout isn't allowed, don't block at all instead
+        if wait == Duration::from_secs(0) {
+            self.stream.set_nonblocking(true)?;
+        } else {
+            self.stream.set_read_timeout(Some(wait))?;
+        }
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                self.reader.push(&buffer[..read]);
+                Ok(true)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, payload: &str) {
+        self.send_raw(&gdb::encode_packet(payload));
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        // A dead connection shows up as an error on the next read
+        let _ = self.stream.write_all(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::InstrumentOptions;
+
+    const WAIT: Duration = Duration::from_millis(100);
+
+    // 0x200: ADD V0, 1; JP 0x200
+    const LOOP: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+    // The debugger's end of the connection
+    struct Client {
+        stream: TcpStream,
+        reader: PacketReader,
+    }
+
+    impl Client {
+        fn send(&mut self, payload: &str) {
+            self.stream.write_all(&gdb::encode_packet(payload)).unwrap();
+        }
+
+        fn interrupt(&mut self) {
+            self.stream.write_all(&[0x03]).unwrap();
+        }
+
+        // The next packet from the server, skipping acknowledgements
+        fn reply(&mut self) -> String {
+            let mut buffer = [0; 4096];
+            loop {
+                if let Some(Incoming::Packet(packet)) = self.reader.next_incoming() {
+                    return packet;
+                }
+                let read = self.stream.read(&mut buffer).unwrap();
+                assert!(read > 0, "The server hung up");
+                self.reader.push(&buffer[..read]);
+            }
+        }
+    }
+
+    // A server attached to a client over a loopback socket
+    fn connect() -> (GdbServer, Client) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let server = GdbServer::attach(&listener).unwrap();
+
+        let client = Client {
+            stream,
+            reader: PacketReader::new(),
+        };
+        (server, client)
+    }
+
+    fn machine() -> (Chip8, Instruments) {
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&LOOP).unwrap();
+        let instruments = Instruments::new(&InstrumentOptions::default(), &mut cpu).unwrap();
+        (cpu, instruments)
+    }
+
+    // Runs the program until the server stops it
+    fn run_until_stopped(server: &mut GdbServer, cpu: &mut Chip8) {
+        for _ in 0..1000 {
+            if server.should_stop(cpu) {
+                return;
+            }
+            cpu.step().unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("The program never stopped");
+    }
+
+    #[test]
+    fn serves_packets_while_stopped() {
+        let (mut server, mut client) = connect();
+        let (mut cpu, mut instruments) = machine();
+        assert!(server.is_halted());
+
+        client.send("m200,4");
+        client.send("s");
+        assert!(matches!(
+            server.serve(&mut cpu, &mut instruments, WAIT),
+            Session::Attached
+        ));
+        assert_eq!(client.reply(), "70011200");
+        assert_eq!(client.reply(), "S05");
+        assert_eq!(cpu.pc(), 0x202);
+        assert!(server.is_halted());
+    }
+
+    #[test]
+    fn continuing_stops_at_the_next_breakpoint() {
+        let (mut server, mut client) = connect();
+        let (mut cpu, mut instruments) = machine();
+
+        // The breakpoint the program is stopped at doesn't stop it again
+        client.send("Z0,200,2");
+        client.send("c");
+        server.serve(&mut cpu, &mut instruments, WAIT);
+        assert_eq!(client.reply(), "OK");
+        assert!(!server.is_halted());
+
+        run_until_stopped(&mut server, &mut cpu);
+        assert_eq!(client.reply(), "S05");
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(cpu.v(0), 1);
+    }
+
+    #[test]
+    fn interrupts_stop_the_running_program() {
+        let (mut server, mut client) = connect();
+        let (mut cpu, mut instruments) = machine();
+
+        client.send("c");
+        server.serve(&mut cpu, &mut instruments, WAIT);
+        assert!(!server.should_stop(&cpu));
+
+        client.interrupt();
+        run_until_stopped(&mut server, &mut cpu);
+        assert_eq!(client.reply(), "S02");
+        assert!(server.is_halted());
+    }
+
+    #[test]
+    fn detaching_ends_the_session() {
+        let (mut server, mut client) = connect();
+        let (mut cpu, mut instruments) = machine();
+
+        client.send("D");
+        assert!(matches!(
+            server.serve(&mut cpu, &mut instruments, WAIT),
+            Session::Detached
+        ));
+        assert_eq!(client.reply(), "OK");
+    }
+
+    #[test]
+    fn killing_ends_the_session() {
+        let (mut server, mut client) = connect();
+        let (mut cpu, mut instruments) = machine();
+
+        client.send("k");
+        assert!(matches!(
+            server.serve(&mut cpu, &mut instruments, WAIT),
+            Session::Killed
+        ));
+    }
+
+    #[test]
+    fn hanging_up_detaches() {
+        let (mut server, client) = connect();
+        let (mut cpu, mut instruments) = machine();
+
+        drop(client);
+        assert!(matches!(
+            server.serve(&mut cpu, &mut instruments, WAIT),
+            Session::Detached
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::cli::{HeadlessOptions, SnapshotFormat, SnapshotOptions};
+use crate::gdbserver::{GdbServer, Session};
+use crate::instruments::Instruments;
+use chip_8::callstack;
+use chip_8::gdb;
+use chip_8::snapshot::{self, InputScript, Snapshot};
+use chip_8::symbols::Symbols;
+use chip_8::Chip8;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Name goldens are recorded under, the ROM's file name without extensions
+pub fn rom_name(path: &Path) -> String {
+    if path == Path::new("-") {
+        return "stdin".to_string();
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "rom".to_string());
+
+    match name.split_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    }
+}
+
+// Records or verifies snapshots, returning the process exit code
+pub fn snapshot(cpu: &mut Chip8, rom_name: &str, options: &SnapshotOptions) -> i32 {
+    cpu.set_seed(options.seed);
+
+    let script = match read_script(&options.input) {
+        Ok(script) => script,
+        Err(message) => {
+            eprintln!("{}", message);
+            return 2;
+        }
+    };
+
+    let snapshots = match snapshot::capture(
+        cpu,
+        &script,
+        &options.frames,
+        options.instructions_per_frame,
+    ) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    let extensions: &[&str] = match options.format {
+        SnapshotFormat::Text => &["txt"],
+        SnapshotFormat::Png => &["png"],
+        SnapshotFormat::Both => &["txt", "png"],
+    };
+
+    if !options.verify {
+        if let Err(e) = fs::create_dir_all(&options.dir) {
+            eprintln!("Failed to create {}: {}", options.dir.display(), e);
+            return 1;
+        }
+    }
+
+    let mut failures = 0;
+
+    for (frame, snapshot) in options.frames.iter().zip(&snapshots) {
+        for extension in extensions {
+            let path = options
+                .dir
+                .join(format!("{}.{}.{}", rom_name, frame, extension));
+
+            if options.verify {
+                match read_golden(&path) {
+                    Ok(golden) => match snapshot.diff(&golden) {
+                        None => println!("ok   {}", path.display()),
+                        Some(diff) => {
+                            println!("FAIL {}: {}", path.display(), diff);
+                            failures += 1;
+                        }
+                    },
+                    Err(e) => {
+                        println!("FAIL {}: {}", path.display(), e);
+                        failures += 1;
+                    }
+                }
+            } else {
+                let data = match *extension {
+                    "png" => snapshot.to_png(),
+                    _ => snapshot.to_text().into_bytes(),
+                };
+
+                if let Err(e) = fs::write(&path, data) {
+                    eprintln!("Failed to write {}: {}", path.display(), e);
+                    return 1;
+                }
+                println!("wrote {}", path.display());
+            }
+        }
+    }
+
+    if failures > 0 {
+        println!("{} snapshots didn't match", failures);
+        1
+    } else {
+        0
+    }
+}
+
+fn read_golden(path: &Path) -> Result<Snapshot, String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    let golden = if path.extension() == Some("png".as_ref()) {
+        Snapshot::from_png(&data)
+    } else {
+        Snapshot::from_text(&String::from_utf8_lossy(&data))
+    };
+
+    golden.map_err(|e| e.to_string())
+}
+
+// Runs until the program finishes, returning the process exit code
+pub fn run(
+    cpu: &mut Chip8,
+    instruments: &mut Instruments,
+    debugger: &mut Option<GdbServer>,
+    options: &HeadlessOptions,
+) -> i32 {
+    cpu.set_seed(options.seed);
+
+    let script = match read_script(&options.input) {
+        Ok(script) => script,
+        Err(message) => {
+            eprintln!("{}", message);
+            return 2;
+        }
+    };
+
+    // Step one instruction at a time to stop as soon as the program finishes
+    let mut frame = 0;
+    let result = 'frames: loop {
+        if let Some(reason) = cpu.exit_reason() {
+            break Ok(reason);
+        }
+        if frame == options.frames {
+            break Err(format!("Still running after {} frames", frame));
+        }
+
+        frame += 1;
+        script.apply(frame, cpu);
+
+        for _ in 0..options.instructions_per_frame {
+            if cpu.exit_reason().is_some() {
+                continue 'frames;
+            }
+            if !serve_debugger(debugger, cpu, instruments) {
+                break 'frames Err("Killed by the debugger".to_string());
+            }
+            if let Err(e) = instruments.step(cpu) {
+                match debugger {
+                    // Stop and let the debugger look at what went wrong
+                    Some(server) => {
+                        eprintln!("{}", instruments.symbols().annotate_error(&e));
+                        server.stop(gdb::SIGSEGV);
+                    }
+                    None => break 'frames Err(instruments.symbols().annotate_error(&e)),
+                }
+            }
+        }
+        instruments.end_frame(cpu);
+    };
+
+    match result {
+        Ok(reason) => {
+            println!("Finished: {}", reason);
+            print_state(cpu, frame, instruments.symbols());
+
+            let code = match options.exit_register {
+                Some(register) => cpu.v(register),
+                None => 0,
+            };
+            if let Some(server) = debugger {
+                server.finish(code);
+            }
+            code.into()
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            print_state(cpu, frame, instruments.symbols());
+            1
+        }
+    }
+}
+
+// Stops for any breakpoint at PC and serves the debugger until it resumes
+// the program. False if the debugger killed it.
+fn serve_debugger(
+    debugger: &mut Option<GdbServer>,
+    cpu: &mut Chip8,
+    instruments: &mut Instruments,
+) -> bool {
+    let server = match debugger {
+        Some(server) => server,
+        None => return true,
+    };
+    if !server.should_stop(cpu) {
+        return true;
+    }
+
+    while server.is_halted() {
+        match server.serve(cpu, instruments, Duration::from_secs(1)) {
+            Session::Attached => {}
+            Session::Detached => {
+                *debugger = None;
+                break;
+            }
+            Session::Killed => return false,
+        }
+    }
+
+    true
+}
+
+fn print_state(cpu: &Chip8, frames: u32, symbols: &Symbols) {
+    println!("Cycles: {}, frames: {}", cpu.cycles(), frames);
+
+    for (half, registers) in cpu.registers().chunks(8).enumerate() {
+        let line: Vec<String> = registers
+            .iter()
+            .enumerate()
+            .map(|(index, value)| format!("V{:X} {:02X}", half * 8 + index, value))
+            .collect();
+        println!("{}", line.join("  "));
+    }
+
+    let timers = cpu.timers();
+    println!(
+        "I {:03X}  PC {:03X}  SP {:X}  DT {:02X}  ST {:02X}",
+        cpu.i(),
+        cpu.pc(),
+        cpu.sp(),
+        timers.delay,
+        timers.sound
+    );
+
+    if cpu.sp() > 0 {
+        print!("Call stack:\n{}", callstack::backtrace(cpu, symbols));
+    }
+}
+
+fn read_script(input: &Option<PathBuf>) -> Result<InputScript, String> {
+    let path = match input {
+        Some(path) => path,
+        None => return Ok(InputScript::default()),
+    };
+
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    InputScript::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::InstrumentOptions;
+
+    fn options(frames: u32, instructions_per_frame: u32) -> HeadlessOptions {
+        HeadlessOptions {
+            frames,
+            input: None,
+            seed: 0,
+            instructions_per_frame,
+            exit_register: None,
+        }
+    }
+
+    // Runs `program` headlessly, returning the exit code and the machine
+    fn run_program(program: &[u16], options: &HeadlessOptions) -> (i32, Chip8) {
+        let rom: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&rom).unwrap();
+        let mut instruments = Instruments::new(&InstrumentOptions::default(), &mut cpu).unwrap();
+
+        let code = run(&mut cpu, &mut instruments, &mut None, options);
+        (code, cpu)
+    }
+
+    #[test]
+    fn finishing_exits_with_zero() {
+        // LD V3, 7; EXIT
+        let (code, cpu) = run_program(&[0x6307, 0x00FD], &options(10, 10));
+        assert_eq!(code, 0);
+        assert_eq!(cpu.cycles(), 1);
+
+        // LD V0, 1; JP 0x202
+        let (code, cpu) = run_program(&[0x6001, 0x1202], &options(10, 10));
+        assert_eq!(code, 0);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn exit_register_gives_the_exit_code() {
+        let options = HeadlessOptions {
+            exit_register: Some(3),
+            ..options(10, 10)
+        };
+
+        let (code, _) = run_program(&[0x6307, 0x00FD], &options);
+        assert_eq!(code, 7);
+        let (code, _) = run_program(&[0x63FF, 0x00FD], &options);
+        assert_eq!(code, 255);
+    }
+
+    #[test]
+    fn stops_as_soon_as_the_program_finishes() {
+        // Three instructions into the first frame
+        let (code, cpu) = run_program(&[0x6001, 0x6102, 0x6203, 0x00FD], &options(10, 100));
+        assert_eq!(code, 0);
+        assert_eq!(cpu.cycles(), 3);
+    }
+
+    #[test]
+    fn running_out_of_frames_fails() {
+        // ADD V0, 1; JP 0x200
+        let (code, cpu) = run_program(&[0x7001, 0x1200], &options(3, 4));
+        assert_eq!(code, 1);
+        assert_eq!(cpu.cycles(), 12);
+        assert_eq!(cpu.v(0), 6);
+    }
+
+    #[test]
+    fn errors_fail() {
+        // LD V0, 1; an instruction that doesn't exist
+        let (code, cpu) = run_program(&[0x6001, 0xF0FF], &options(10, 10));
+        assert_eq!(code, 1);
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn unreadable_input_is_a_usage_error() {
+        let options = HeadlessOptions {
+            input: Some(PathBuf::from("/nonexistent/input.txt")),
+            ..options(10, 10)
+        };
+
+        let (code, cpu) = run_program(&[0x00FD], &options);
+        assert_eq!(code, 2);
+        assert_eq!(cpu.cycles(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::display::Display;
+use chip_8::draws::{DrawEvent, DrawRecorder};
+use chip_8::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+
+// Size of each sprite pixel in the bitmap panel
+const BITMAP_SCALE: u32 = 8;
+const PANEL_MARGIN: i32 = 8;
+
+// Shows the last frame's draws over the display, with the bytes of a
+// selected sprite as a bitmap. Needs the draws recorded with --draw-log.
+#[derive(Default)]
+pub struct SpriteInspector {
+    visible: bool,
+    selected: Option<DrawEvent>,
+    // Position in the last frame's draws when stepping through them
+    index: Option<usize>,
+}
+
+impl SpriteInspector {
+    pub fn toggle(&mut self, draws: Option<&DrawRecorder>) {
+        if draws.is_none() {
+            println!("Run with --draw-log to record draws for the sprite inspector");
+            return;
+        }
+
+        self.visible = !self.visible;
+    }
+
+    // Selects the draw that last flipped the pixel under a click
+    pub fn click(&mut self, display: &Display, draws: Option<&DrawRecorder>, x: i32, y: i32) {
+        let draws = match draws {
+            Some(draws) if self.visible => draws,
+            _ => return,
+        };
+
+        let (pixel_x, pixel_y) = display.pixel_at(x, y);
+        match draws.flipped_by(pixel_x, pixel_y) {
+            Some(draw) => {
+                println!(
+                    "Pixel ({}, {}) last flipped by DRW at {:#05X} on cycle {}:",
+                    pixel_x, pixel_y, draw.pc, draw.cycle
+                );
+                println!("{}", draw.to_text());
+                self.selected = Some(draw.clone());
+                self.index = None;
+            }
+            None => println!("Pixel ({}, {}) hasn't been drawn", pixel_x, pixel_y),
+        }
+    }
+
+    // Steps through the last frame's draws in the order they were made
+    pub fn cycle(&mut self, draws: Option<&DrawRecorder>, offset: i32) {
+        let frame = match draws {
+            Some(draws) if self.visible && !draws.last_frame().is_empty() => draws.last_frame(),
+            _ => return,
+        };
+
+        let count = frame.len() as i32;
+        let index = match self.index {
+            Some(index) => (index as i32 + offset).rem_euclid(count),
+            None if offset < 0 => count - 1,
+            None => 0,
+        } as usize;
+
+        let draw = &frame[index];
+        println!("Draw {} of {}: {}", index + 1, count, draw.to_text());
+        self.selected = Some(draw.clone());
+        self.index = Some(index);
+    }
+
+    pub fn draw(&self, display: &mut Display, draws: Option<&DrawRecorder>) {
+        let draws = match draws {
+            Some(draws) if self.visible => draws,
+            _ => return,
+        };
+
+        // Outline every sprite drawn last frame, red where it collided
+        for draw in draws.last_frame() {
+            let color = if draw.collision {
+                Color::RGB(200, 40, 40)
+            } else {
+                Color::RGB(40, 120, 200)
+            };
+            display.outline_rect(sprite_rect(display, draw), color);
+        }
+
+        let selected = match &self.selected {
+            Some(selected) => selected,
+            None => return,
+        };
+        display.outline_rect(sprite_rect(display, selected), Color::RGB(255, 220, 0));
+
+        // The sprite bytes as a bitmap in the top right corner
+        let width = 8 * BITMAP_SCALE;
+        let height = selected.sprite.len().max(1) as u32 * BITMAP_SCALE;
+        let left = (display.pixel_size().0 * DISPLAY_WIDTH as u32) as i32
+            - width as i32
+            - 2 * PANEL_MARGIN;
+        display.fill_rect(
+            Rect::new(
+                left - PANEL_MARGIN,
+                PANEL_MARGIN,
+                width + 2 * PANEL_MARGIN as u32,
+                height + 2 * PANEL_MARGIN as u32,
+            ),
+            Color::RGB(24, 24, 24),
+        );
+        for (row, bits) in selected.sprite.iter().enumerate() {
+            for column in 0..8 {
+                let color = if bits & (0x80 >> column) != 0 {
+                    Color::RGB(255, 220, 0)
+                } else {
+                    Color::RGB(60, 60, 60)
+                };
+                let rect = Rect::new(
+                    left + column * BITMAP_SCALE as i32,
+                    2 * PANEL_MARGIN + row as i32 * BITMAP_SCALE as i32,
+                    BITMAP_SCALE - 1,
+                    BITMAP_SCALE - 1,
+                );
+                display.fill_rect(rect, color);
+            }
+        }
+    }
+}
+
+// The rectangle a sprite covers in window coordinates, clipped to the display
+fn sprite_rect(display: &Display, draw: &DrawEvent) -> Rect {
+    let (pixel_width, pixel_height) = display.pixel_size();
+    let width = 8.min(DISPLAY_WIDTH as u32 - draw.x as u32);
+    let height = (draw.height as u32)
+        .min(DISPLAY_HEIGHT as u32 - draw.y as u32)
+        .max(1);
+
+    Rect::new(
+        draw.x as i32 * pixel_width as i32,
+        draw.y as i32 * pixel_height as i32,
+        width * pixel_width,
+        height * pixel_height,
+    )
+}
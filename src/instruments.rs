@@ -0,0 +1,373 @@
+use crate::cli::{CoverageFormat, CoverageOptions, InstrumentOptions};
+use chip_8::callstack::StackChecker;
+use chip_8::cheats::{self, Cheat, CheatFile};
+use chip_8::coverage::Coverage;
+use chip_8::draws::{DrawEvent, DrawRecorder};
+use chip_8::profile::Profiler;
+use chip_8::script::{Script, ScriptError};
+use chip_8::symbols::Symbols;
+use chip_8::trace::{self, TraceRecord, Tracer};
+use chip_8::{Chip8, Error};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Tools watching every instruction the machine runs
+pub struct Instruments {
+    tracer: Option<Tracer<BufWriter<File>>>,
+    // Written out to the path when the program finishes
+    profiler: Option<(Profiler, PathBuf)>,
+    coverage: Option<(Coverage, CoverageOptions)>,
+    // Each frame's draws are logged once it ends
+    draws: Option<(DrawRecorder, BufWriter<File>)>,
+    script: Option<Script>,
+    // Codes for every ROM, saved back to the path as they change
+    cheats: CheatFile,
+    cheats_path: Option<PathBuf>,
+    // Whether the user has been told edits aren't saved without --cheats
+    warned_unsaved: bool,
+    rom_hash: u32,
+    // Addresses held at a value every frame until unfrozen
+    frozen: BTreeMap<u16, u8>,
+    stack_checks: Option<StackChecker>,
+    frame: u64,
+    symbols: Symbols,
+}
+
+impl Instruments {
+    // Scripts run straight away, so get the machine they're loaded into
+    pub fn new(options: &InstrumentOptions, cpu: &mut Chip8) -> Result<Instruments, String> {
+        let tracer = match &options.trace {
+            Some(trace) => {
+                let file = File::create(&trace.path)
+                    .map_err(|e| format!("Failed to create {}: {}", trace.path.display(), e))?;
+                let mut tracer =
+                    Tracer::new(BufWriter::new(file), trace.format, trace.filter.clone())
+                        .map_err(|e| format!("Failed to write {}: {}", trace.path.display(), e))?;
+                tracer.set_symbols(options.symbols.clone());
+                Some(tracer)
+            }
+            None => None,
+        };
+
+        let profiler = options
+            .profile
+            .as_ref()
+            .map(|path| (Profiler::new(), path.clone()));
+
+        let coverage = options
+            .coverage
+            .as_ref()
+            .map(|coverage| (Coverage::new(), coverage.clone()));
+
+        let draws = match &options.draw_log {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+                Some((DrawRecorder::new(), BufWriter::new(file)))
+            }
+            None => None,
+        };
+
+        let cheats = match &options.cheats {
+            Some(path) => read_cheats(path)?,
+            None => CheatFile::default(),
+        };
+        let start = cpu.layout().load_address as usize;
+        let rom_hash = cheats::rom_hash(&cpu.memory()[start..start + cpu.rom_size()]);
+
+        let script = match &options.script {
+            Some(path) => {
+                let source = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let script =
+                    Script::load(&source, cpu).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Some(script)
+            }
+            None => None,
+        };
+
+        Ok(Instruments {
+            tracer,
+            profiler,
+            coverage,
+            draws,
+            script,
+            cheats,
+            cheats_path: options.cheats.clone(),
+            warned_unsaved: false,
+            rom_hash,
+            frozen: BTreeMap::new(),
+            stack_checks: options.stack_checks.then(StackChecker::new),
+            frame: 0,
+            symbols: options.symbols.clone(),
+        })
+    }
+
+    // Executes one instruction, letting each tool see it first
+    pub fn step(&mut self, cpu: &mut Chip8) -> Result<(), Error> {
+        // Hooks may change the machine, so run them before anything records it
+        self.run_script(cpu, Script::before_step);
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.record(cpu) {
+                // Carry on without the trace rather than stopping the program
+                eprintln!("Failed to write trace, stopping tracing: {}", e);
+                self.tracer = None;
+            }
+        }
+        if let Some((profiler, _)) = &mut self.profiler {
+            profiler.record(cpu);
+        }
+        if let Some((coverage, _)) = &mut self.coverage {
+            coverage.record(cpu);
+        }
+        if let Some((draws, _)) = &mut self.draws {
+            draws.record(cpu);
+        }
+        if let Some(checker) = &mut self.stack_checks {
+            if let Some(warning) = checker.check(cpu) {
+                match self.symbols.describe(warning.address()) {
+                    Some(location) => eprintln!("Warning: {} ({})", warning, location),
+                    None => eprintln!("Warning: {}", warning),
+                }
+            }
+        }
+
+        let result = cpu.step();
+        if let Some((draws, _)) = &mut self.draws {
+            draws.finish_step(cpu);
+        }
+        if result.is_ok() {
+            self.run_script(cpu, Script::after_step);
+        }
+
+        result
+    }
+
+    // Ticks the timers at the end of each frame
+    pub fn end_frame(&mut self, cpu: &mut Chip8) {
+        cpu.tick_timers();
+        cheats::apply(cpu, self.cheats.cheats(self.rom_hash), &self.frozen);
+        if let Some((profiler, _)) = &mut self.profiler {
+            profiler.end_frame();
+        }
+
+        self.frame += 1;
+        let frame = self.frame;
+        self.run_script(cpu, |script, cpu| script.end_frame(cpu, frame));
+        if let Some((draws, log)) = &mut self.draws {
+            if let Err(e) = write_draws(log, self.frame, draws.current_frame()) {
+                eprintln!("Failed to write draw log, stopping logging: {}", e);
+                self.draws = None;
+                return;
+            }
+            draws.end_frame();
+        }
+    }
+
+    // Sets whether the player holds `key`, keeping it down while the script
+    // holds it
+    pub fn set_key(&self, cpu: &mut Chip8, key: u8, pressed: bool) {
+        let held = self.script.as_ref().is_some_and(|script| script.holds(key));
+        cpu.set_key(key, pressed || held);
+    }
+
+    // Runs script hooks, carrying on without the script if they fail
+    fn run_script(
+        &mut self,
+        cpu: &mut Chip8,
+        hooks: impl FnOnce(&mut Script, &mut Chip8) -> Result<(), ScriptError>,
+    ) {
+        if let Some(script) = &mut self.script {
+            if let Err(e) = hooks(script, cpu) {
+                eprintln!("{}\nStopping the script", e);
+                self.script = None;
+            }
+        }
+    }
+
+    // The loaded ROM's patch codes
+    pub fn cheats(&self) -> &[Cheat] {
+        self.cheats.cheats(self.rom_hash)
+    }
+
+    // Changes the loaded ROM's patch codes, saving them to --cheats. The
+    // change is kept for this run even if it can't be saved.
+    pub fn edit_cheats<T>(&mut self, edit: impl FnOnce(&mut Vec<Cheat>) -> T) -> T {
+        let result = edit(self.cheats.cheats_mut(self.rom_hash));
+
+        match &self.cheats_path {
+            Some(path) => {
+                if let Err(e) = fs::write(path, self.cheats.to_text()) {
+                    eprintln!("Warning: failed to write {}: {}", path.display(), e);
+                }
+            }
+            None if !self.warned_unsaved => {
+                eprintln!("Warning: give a cheat file with --cheats to keep codes");
+                self.warned_unsaved = true;
+            }
+            None => {}
+        }
+
+        result
+    }
+
+    // Turns the loaded ROM's code `index` on or off, e.g. from the window
+    pub fn toggle_cheat(&mut self, index: usize) {
+        if index >= self.cheats().len() {
+            return;
+        }
+
+        let message = self.edit_cheats(|cheats| {
+            let cheat = &mut cheats[index];
+            cheat.enabled = !cheat.enabled;
+            format!(
+                "Cheat {} {}: {}",
+                index + 1,
+                if cheat.enabled { "on" } else { "off" },
+                cheat.name
+            )
+        });
+        println!("{}", message);
+    }
+
+    // Addresses held at a value every frame
+    pub fn frozen_mut(&mut self) -> &mut BTreeMap<u16, u8> {
+        &mut self.frozen
+    }
+
+    // Draws recorded for the sprite inspector, when --draw-log is given
+    pub fn draws(&self) -> Option<&DrawRecorder> {
+        self.draws.as_ref().map(|(draws, _)| draws)
+    }
+
+    // Names for addresses, empty without --symbols
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Flushes anything the tools have buffered up and writes their reports
+    pub fn finish(self, cpu: &Chip8) {
+        if let Some(mut tracer) = self.tracer {
+            if let Err(e) = tracer.flush() {
+                eprintln!("Failed to write trace: {}", e);
+            }
+        }
+        if let Some((draws, mut log)) = self.draws {
+            // Log the draws of the frame that was cut short
+            let result = write_draws(&mut log, self.frame + 1, draws.current_frame())
+                .and_then(|_| log.flush());
+            if let Err(e) = result {
+                eprintln!("Failed to write draw log: {}", e);
+            }
+        }
+        if let Some((profiler, path)) = self.profiler {
+            if let Err(e) = fs::write(&path, profiler.report(cpu.memory(), &self.symbols)) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+        if let Some((coverage, options)) = self.coverage {
+            // Report on the ROM as it is in memory now
+            let start = cpu.layout().load_address as usize;
+            let rom = &cpu.memory()[start..start + cpu.rom_size()];
+            let report = match options.format {
+                CoverageFormat::Listing => coverage.listing(rom, start as u16, &self.symbols),
+                CoverageFormat::Lcov => coverage.lcov(
+                    &options.rom_path.display().to_string(),
+                    rom,
+                    start as u16,
+                    &self.symbols,
+                    &options.source_dir,
+                ),
+            };
+            if let Err(e) = fs::write(&options.path, report) {
+                eprintln!("Failed to write {}: {}", options.path.display(), e);
+            }
+        }
+    }
+}
+
+// A missing cheat file has no codes yet
+fn read_cheats(path: &Path) -> Result<CheatFile, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    CheatFile::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Logs a frame's draws under a "frame N" heading, leaving out frames without any
+fn write_draws(log: &mut impl Write, frame: u64, draws: &[DrawEvent]) -> io::Result<()> {
+    if draws.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(log, "frame {}", frame)?;
+    for draw in draws {
+        writeln!(log, "{}", draw.to_text())?;
+    }
+
+    Ok(())
+}
+
+// Prints a binary trace as text, returning the process exit code
+pub fn dump_trace(path: &Path, symbols: &Symbols) -> i32 {
+    match write_trace_text(path, symbols) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+fn write_trace_text(path: &Path, symbols: &Symbols) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    trace::read_binary_header(&mut reader)?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    while let Some(record) = TraceRecord::read_binary(&mut reader)? {
+        writeln!(out, "{}", record.to_text_with(symbols))?;
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_keys_survive_latching_the_keypad() {
+        let mut cpu = Chip8::new();
+        // LD V1, 5; SKP V1; JP 0x202; JP 0x206
+        cpu.load_rom(&[0x61, 0x05, 0xE1, 0x9E, 0x12, 0x02, 0x12, 0x06])
+            .unwrap();
+        let mut instruments = Instruments::new(&InstrumentOptions::default(), &mut cpu).unwrap();
+        let source = "
+            on_frame(|frame| if frame == 1 { press(5) });
+            on_key(|key, pressed| set_v(3, v(3) + 1));
+        ";
+        instruments.script = Some(Script::load(source, &mut cpu).unwrap());
+
+        for _ in 0..2 {
+            // As the window does, with the player holding nothing
+            for key in 0x0..=0xF {
+                instruments.set_key(&mut cpu, key, false);
+            }
+            for _ in 0..4 {
+                instruments.step(&mut cpu).unwrap();
+            }
+            instruments.end_frame(&mut cpu);
+        }
+
+        // The program saw the key and no hook took it for the player
+        assert!(cpu.key(5));
+        assert_eq!(cpu.pc(), 0x206);
+        assert_eq!(cpu.v(3), 0);
+    }
+}
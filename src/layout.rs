@@ -0,0 +1,293 @@
+use crate::chip8::Error;
+use crate::font::SMALL_FONT_SIZE;
+use std::fmt;
+use std::str::FromStr;
+
+/// Size of the addressable memory in bytes, in the default layout.
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Address programs are loaded at and start executing from, in the default layout.
+pub const PROGRAM_START: u16 = 0x200;
+
+/// Address of the built-in hexadecimal font, in the default layout.
+pub const FONT_ADDRESS: u16 = 0x050;
+
+/// Largest ROM that fits between [`PROGRAM_START`] and the end of memory.
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - PROGRAM_START as usize;
+
+// Largest memory addressable through the 16-bit index register
+const MAX_MEMORY_SIZE: usize = 0x10000;
+
+/// Historical CHIP-8 interpreters, used to pick layout presets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter.
+    CosmacVip,
+    /// The ETI-660, which loads programs at 0x600.
+    Eti660,
+    /// CHIPOS on the DREAM 6800.
+    Dream6800,
+    /// SUPER-CHIP on the HP48 calculators.
+    SuperChip,
+    /// Octo's XO-CHIP extension, with 64kb of memory.
+    XoChip,
+}
+
+impl Platform {
+    /// Every supported platform.
+    pub const ALL: [Platform; 5] = [
+        Platform::CosmacVip,
+        Platform::Eti660,
+        Platform::Dream6800,
+        Platform::SuperChip,
+        Platform::XoChip,
+    ];
+
+    /// Short name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::CosmacVip => "vip",
+            Platform::Eti660 => "eti660",
+            Platform::Dream6800 => "dream6800",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        Platform::ALL
+            .iter()
+            .copied()
+            .find(|platform| platform.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown platform {}", s))
+    }
+}
+
+/// Where programs and the font live in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Total size of memory in bytes.
+    pub memory_size: usize,
+    /// Address ROMs are copied to.
+    pub load_address: u16,
+    /// Initial value of the program counter.
+    pub entry_point: u16,
+    /// Address of the hex font used by `Fx29`, big glyphs follow the small ones.
+    pub font_address: u16,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::for_platform(Platform::CosmacVip)
+    }
+}
+
+impl Layout {
+    /// The layout used by `platform`.
+    pub fn for_platform(platform: Platform) -> Layout {
+        match platform {
+            Platform::CosmacVip | Platform::Dream6800 | Platform::SuperChip => Layout {
+                memory_size: MEMORY_SIZE,
+                load_address: PROGRAM_START,
+                entry_point: PROGRAM_START,
+                font_address: FONT_ADDRESS,
+            },
+            Platform::Eti660 => Layout {
+                memory_size: MEMORY_SIZE,
+                load_address: 0x600,
+                entry_point: 0x600,
+                font_address: FONT_ADDRESS,
+            },
+            Platform::XoChip => Layout {
+                memory_size: MAX_MEMORY_SIZE,
+                load_address: PROGRAM_START,
+                entry_point: PROGRAM_START,
+                font_address: FONT_ADDRESS,
+            },
+        }
+    }
+
+    /// Largest ROM that fits between the load address and the end of memory.
+    pub fn max_rom_size(&self) -> usize {
+        self.memory_size.saturating_sub(self.load_address as usize)
+    }
+
+    /// Checks that every region fits in memory.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.memory_size > MAX_MEMORY_SIZE {
+            return Err(Error::InvalidLayout("memory is larger than 64kb"));
+        }
+        if self.font_address as usize + SMALL_FONT_SIZE > self.memory_size {
+            return Err(Error::InvalidLayout("font doesn't fit in memory"));
+        }
+        if self.load_address as usize >= self.memory_size {
+            return Err(Error::InvalidLayout("load address is outside memory"));
+        }
+        // Instructions are two bytes long
+        if self.entry_point as usize + 1 >= self.memory_size {
+            return Err(Error::InvalidLayout("entry point is outside memory"));
+        }
+
+        Ok(())
+    }
+
+    // Checks that a ROM of `size` bytes can be loaded without clobbering a font
+    // of `font_size` bytes
+    pub(crate) fn validate_rom(&self, size: usize, font_size: usize) -> Result<(), Error> {
+        if size == 0 {
+            return Err(Error::EmptyRom);
+        }
+        if size > self.max_rom_size() {
+            return Err(Error::RomTooLarge {
+                size,
+                max: self.max_rom_size(),
+            });
+        }
+
+        let rom_start = self.load_address as usize;
+        let rom_end = rom_start + size;
+        let font_start = self.font_address as usize;
+        let font_end = font_start + font_size;
+
+        if rom_start < font_end && font_start < rom_end {
+            return Err(Error::RomOverlapsFont);
+        }
+        if !(rom_start..rom_end).contains(&(self.entry_point as usize)) {
+            return Err(Error::EntryPointOutsideRom(self.entry_point));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(layout: Layout) -> Error {
+        layout.validate().unwrap_err()
+    }
+
+    #[test]
+    fn every_platform_is_valid() {
+        for &platform in &Platform::ALL {
+            assert_eq!(Layout::for_platform(platform).validate(), Ok(()));
+            assert_eq!(platform.name().parse(), Ok(platform));
+        }
+        assert_eq!("VIP".parse(), Ok(Platform::CosmacVip));
+        assert_eq!(
+            "c64".parse::<Platform>(),
+            Err("Unknown platform c64".to_string())
+        );
+    }
+
+    #[test]
+    fn memory_is_at_most_64kb() {
+        let layout = Layout {
+            memory_size: MAX_MEMORY_SIZE + 1,
+            ..Layout::default()
+        };
+        assert_eq!(
+            invalid(layout),
+            Error::InvalidLayout("memory is larger than 64kb")
+        );
+    }
+
+    #[test]
+    fn font_must_fit_in_memory() {
+        let layout = Layout {
+            font_address: (MEMORY_SIZE - SMALL_FONT_SIZE + 1) as u16,
+            ..Layout::default()
+        };
+        assert_eq!(
+            invalid(layout),
+            Error::InvalidLayout("font doesn't fit in memory")
+        );
+    }
+
+    #[test]
+    fn load_address_must_be_in_memory() {
+        let layout = Layout {
+            load_address: MEMORY_SIZE as u16,
+            ..Layout::default()
+        };
+        assert_eq!(
+            invalid(layout),
+            Error::InvalidLayout("load address is outside memory")
+        );
+    }
+
+    #[test]
+    fn entry_point_needs_a_whole_instruction() {
+        let layout = Layout {
+            entry_point: (MEMORY_SIZE - 1) as u16,
+            ..Layout::default()
+        };
+        assert_eq!(
+            invalid(layout),
+            Error::InvalidLayout("entry point is outside memory")
+        );
+
+        let layout = Layout {
+            entry_point: (MEMORY_SIZE - 2) as u16,
+            ..Layout::default()
+        };
+        assert_eq!(layout.validate(), Ok(()));
+    }
+
+    #[test]
+    fn roms_must_fit_after_the_load_address() {
+        let layout = Layout::default();
+        assert_eq!(
+            layout.validate_rom(0, SMALL_FONT_SIZE),
+            Err(Error::EmptyRom)
+        );
+        assert_eq!(
+            layout.validate_rom(MAX_ROM_SIZE + 1, SMALL_FONT_SIZE),
+            Err(Error::RomTooLarge {
+                size: MAX_ROM_SIZE + 1,
+                max: MAX_ROM_SIZE
+            })
+        );
+        assert_eq!(layout.validate_rom(MAX_ROM_SIZE, SMALL_FONT_SIZE), Ok(()));
+    }
+
+    #[test]
+    fn roms_cant_overlap_the_font() {
+        let layout = Layout {
+            load_address: FONT_ADDRESS + SMALL_FONT_SIZE as u16,
+            entry_point: FONT_ADDRESS + SMALL_FONT_SIZE as u16,
+            ..Layout::default()
+        };
+        assert_eq!(layout.validate_rom(2, SMALL_FONT_SIZE), Ok(()));
+        // Big glyphs follow the small ones
+        assert_eq!(
+            layout.validate_rom(2, SMALL_FONT_SIZE + 1),
+            Err(Error::RomOverlapsFont)
+        );
+    }
+
+    #[test]
+    fn entry_point_must_be_in_the_rom() {
+        let layout = Layout {
+            entry_point: 0x300,
+            ..Layout::default()
+        };
+        assert_eq!(
+            layout.validate_rom(0x100, SMALL_FONT_SIZE),
+            Err(Error::EntryPointOutsideRom(0x300))
+        );
+        assert_eq!(layout.validate_rom(0x101, SMALL_FONT_SIZE), Ok(()));
+    }
+}
@@ -0,0 +1,49 @@
+//! A CHIP-8 interpreter core.
+//!
+//! [`Chip8`] holds the whole machine state and knows nothing about windows,
+//! audio or real time, so it can be embedded in frontends and tools or run
+//! headlessly:
+//!
+//! ```
+//! use chip_8::Chip8;
+//!
+//! let mut cpu = Chip8::new();
+//!
+//! // LD V0, 0x2A; JP 0x202
+//! cpu.load_rom(&[0x60, 0x2A, 0x12, 0x02]).unwrap();
+//! cpu.run_frame(10).unwrap();
+//!
+//! assert_eq!(cpu.v(0x0), 0x2A);
+//! assert_eq!(cpu.pc(), 0x202);
+//! ```
+
+pub mod breakpoint;
+pub mod callstack;
+pub mod cheats;
+mod chip8;
+pub mod coverage;
+pub mod disasm;
+pub mod draws;
+pub mod expr;
+mod font;
+mod framebuffer;
+pub mod gdb;
+mod layout;
+mod limits;
+pub mod memview;
+pub mod octo;
+pub mod patch;
+pub mod profile;
+pub mod rom;
+pub mod script;
+pub mod snapshot;
+pub mod symbols;
+pub mod trace;
+
+pub use crate::chip8::{Chip8, Error, ExitReason, Timers};
+pub use crate::font::{Font, BIG_GLYPH_SIZE, BUILTIN_FONTS, SMALL_FONT_SIZE};
+pub use crate::framebuffer::{Framebuffer, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+pub use crate::layout::{
+    Layout, Platform, FONT_ADDRESS, MAX_ROM_SIZE, MEMORY_SIZE, PROGRAM_START,
+};
+pub use crate::limits::{Limits, StopReason};
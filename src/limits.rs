@@ -0,0 +1,53 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Bounds on how a program may run, for executing untrusted ROMs.
+///
+/// Every limit is off by default. A program that hits one stops with
+/// [`Error::Stopped`](crate::Error::Stopped), leaving the machine as it was
+/// before the instruction that would have gone over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Stop after executing this many instructions.
+    pub max_cycles: Option<u64>,
+    /// Stop once this much wall-clock time has passed since the limits were set.
+    pub max_time: Option<Duration>,
+    /// Stop on a `1nnn` jumping to itself while both timers are stopped, since
+    /// nothing can change after it.
+    pub detect_infinite_loops: bool,
+    /// Stop when an instruction writes into the memory the ROM was loaded into.
+    pub halt_on_self_modify: bool,
+}
+
+/// Why a program stopped under [`Limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StopReason {
+    /// Executed [`Limits::max_cycles`] instructions.
+    CycleLimit(u64),
+    /// Ran for longer than [`Limits::max_time`].
+    TimeLimit(Duration),
+    /// The instruction at `address` jumps to itself with the timers stopped.
+    InfiniteLoop { address: u16 },
+    /// The instruction at `address` would write to `target` inside the ROM.
+    SelfModifyingWrite { address: u16, target: u16 },
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::CycleLimit(cycles) => write!(f, "cycle limit of {} reached", cycles),
+            StopReason::TimeLimit(time) => {
+                write!(f, "time limit of {:.1}s reached", time.as_secs_f64())
+            }
+            StopReason::InfiniteLoop { address } => {
+                write!(f, "infinite loop at {:#05X}", address)
+            }
+            StopReason::SelfModifyingWrite { address, target } => write!(
+                f,
+                "instruction at {:#05X} writes to {:#05X} inside the ROM",
+                address, target
+            ),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::cli::Options;
+use chip_8::octo;
+use chip_8::patch;
+use chip_8::rom::{self, RomError};
+use chip_8::Chip8;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Why the machine couldn't be built
+pub enum SetupError {
+    // The ROM, its source or its patch couldn't be read or loaded
+    Rom(String),
+    // The layout or font is invalid
+    Machine(String),
+}
+
+impl SetupError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SetupError::Rom(_) => 1,
+            SetupError::Machine(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetupError::Rom(message) | SetupError::Machine(message) => f.write_str(message),
+        }
+    }
+}
+
+// Builds the machine described by `options` and loads its ROM, for the
+// window, the headless runners and the debug adapter alike. Octo source is
+// assembled first, its symbols replacing any that weren't given. Only asks
+// which ROM of a pack to run when `interactive`.
+pub fn build(options: &mut Options, interactive: bool) -> Result<Chip8, SetupError> {
+    let rom = if octo::is_source(&options.rom_path) {
+        assemble_source(options).map_err(SetupError::Rom)?
+    } else {
+        load_rom(options, interactive).map_err(|e| {
+            SetupError::Rom(format!(
+                "Failed to read {}: {}",
+                options.rom_path.display(),
+                e
+            ))
+        })?
+    };
+    let rom = match &options.patch {
+        Some(path) => patch_rom(&rom, path).map_err(SetupError::Rom)?,
+        None => rom,
+    };
+
+    let mut cpu =
+        Chip8::with_layout(options.layout).map_err(|e| SetupError::Machine(e.to_string()))?;
+    cpu.set_font(options.font.clone())
+        .map_err(|e| SetupError::Machine(e.to_string()))?;
+    cpu.load_rom(&rom).map_err(|e| {
+        SetupError::Rom(format!(
+            "Failed to load {}: {}",
+            options.rom_path.display(),
+            e
+        ))
+    })?;
+    cpu.set_limits(options.limits);
+
+    Ok(cpu)
+}
+
+fn load_rom(options: &Options, interactive: bool) -> Result<Vec<u8>, RomError> {
+    let data = rom::read_source(&options.rom_path)?;
+
+    match rom::extract_rom(&data, options.entry.as_deref()) {
+        // Let the user pick from a ROM pack, unless stdin is already taken by the ROM
+        Err(RomError::MultipleRoms(names)) if interactive && options.rom_path != Path::new("-") => {
+            let entry = pick_entry(&names)?;
+            rom::extract_rom(&data, Some(&entry))
+        }
+        result => result,
+    }
+}
+
+fn pick_entry(names: &[String]) -> io::Result<String> {
+    println!("Archive contains several ROMs:");
+    for (index, name) in names.iter().enumerate() {
+        println!("{:>4}: {}", index + 1, name);
+    }
+
+    loop {
+        print!("Select a ROM [1-{}]: ", names.len());
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "No ROM selected",
+            ));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(choice) if (1..=names.len()).contains(&choice) => {
+                return Ok(names[choice - 1].clone())
+            }
+            _ => println!("Enter a number between 1 and {}", names.len()),
+        }
+    }
+}
+
+// Applies the patch at `path`, which may have been found next to the ROM
+fn patch_rom(rom: &[u8], path: &Path) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let rom = patch::apply(rom, &data)
+        .map_err(|e| format!("Failed to apply {}: {}", path.display(), e))?;
+
+    eprintln!("Applied {}", path.display());
+    Ok(rom)
+}
+
+// Assembles Octo source for the machine's load address, taking its symbols
+// unless some were given
+fn assemble_source(options: &mut Options) -> Result<Vec<u8>, String> {
+    let path = &options.rom_path;
+    let program = read_and_assemble(path, options.layout.load_address)?;
+
+    if options.instruments.symbols.is_empty() {
+        options.instruments.symbols = program.symbols;
+    }
+    Ok(program.rom)
+}
+
+pub fn read_and_assemble(path: &Path, load_address: u16) -> Result<octo::Program, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    // Source lines are named relative to the source's directory, like the
+    // symbol files assemblers write alongside their ROMs
+    let file = path.file_name().unwrap_or_default().to_string_lossy();
+
+    octo::assemble(&source, &file, load_address).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -1,663 +1,407 @@
+mod cli;
 mod constants;
+mod dap;
 mod display;
-
-extern crate rand;
-
+mod gdbserver;
+mod headless;
+mod inspector;
+mod instruments;
+mod machine;
+mod monitor;
+
+use crate::cli::{AssembleOptions, Command, Options};
 use crate::constants::*;
-use crate::display::Display;
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
+use crate::display::{Display, DisplayEvent};
+use crate::gdbserver::{GdbServer, Session};
+use crate::inspector::SpriteInspector;
+use crate::instruments::Instruments;
+use crate::monitor::MemoryEditor;
+use chip_8::gdb;
+use chip_8::{Chip8, Error, ExitReason};
+use std::fs;
 use std::path::Path;
+use std::process;
+use std::thread;
 use std::time::{Duration, Instant};
 
-#[allow(dead_code)]
-
-struct Chip8 {
-    // 4kb RAM
-    pub ram: [u8; 4096],
-
-    // General purpose registers
-    v: [u8; 16],
-
-    // Index register
-    i: u16,
-
-    // Program counter
-    pc: u16,
-
-    // Stack and stack pointer
-    stack: [u16; 16],
-    sp: usize,
-
-    // Delay and sound timers
-    dt: u8,
-    st: u8,
-
-    // Display
-    display: Display,
-}
-
-impl Chip8 {
-    fn new() -> Chip8 {
-        // Initialise empty Chip8
-        let mut cpu = Chip8 {
-            ram: [0; 4096],
-            v: [0; 16],
-            i: 0,
-            pc: 0x200,
-            stack: [0; 16],
-            sp: 0,
-            dt: 0,
-            st: 0,
-            display: Display::new(WINDOW_WIDTH, WINDOW_HEIGHT),
-        };
-
-        // Write font to 0x050 - 0x09F
-        static FONT: [u8; 80] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ];
-
-        cpu.write_ram(&FONT, 0x050);
-
-        // Return cpu
-        cpu
-    }
-
-    fn write_ram(&mut self, array: &[u8], start_address: u16) {
-        let mut index = start_address;
-        for byte in array {
-            self.ram[index as usize] = *byte;
-            index += 1;
-        }
-    }
-
-    fn read_instruction(&self, start_address: usize) -> u16 {
-        let upper = self.ram[start_address] as u16;
-        let lower = self.ram[start_address + 1] as u16;
-
-        (upper << 8) | lower
-    }
-
-    fn load_ram_from_file(&mut self, path: &Path) -> io::Result<()> {
-        let mut buffer = Vec::new();
-        let mut file = File::open(path)?;
-
-        // Check file length is less than 4096 - 512 = 0xDFF bytes
-        match file.read_to_end(&mut buffer) {
-            Ok(1..=3583) => self.write_ram(&buffer, 0x200),
-            Ok(0) => panic!("Input file appears empty"),
-            Ok(_) => panic!("Input file is too large, max size is 0xDFF bytes"),
-            Err(m) => return Err(m),
-        }
-
-        Ok(())
-    }
-
-    fn execute_loop(&mut self) {
-        let mut start_time: Instant;
-        let mut since_last_step = Duration::new(0, 0);
-        let mut since_last_frame = Duration::new(0, 0);
-
-        let step_dt = Duration::new(0, 1_000_000_000u32 / INSTRUCT_PER_SEC);
-        let frame_dt = Duration::new(0, 1_000_000_000u32 / FRAME_RATE);
-
-        // // Draw checkerboard
-        // let mut toggle = false;
-        // for x in 0..64 {
-        //     for y in 0..32 {
-        //         self.display.set_pixel(x, y, toggle);
-        //         toggle = !toggle;
-        //     }
-        //     toggle = !toggle;
-        // }
-
-        'execution: loop {
-            start_time = Instant::now();
-
-            if self.display.check_quit() {
-                break 'execution;
-            };
-
-            since_last_step += start_time.elapsed();
-
-            if since_last_step >= step_dt {
-                self.step_cpu();
-                since_last_step -= step_dt;
-            }
-
-            since_last_frame += start_time.elapsed();
-
-            if since_last_frame >= frame_dt {
-                // // Flip checkerboard
-                // for x in 0..64 {
-                //     for y in 0..32 {
-                //         self.display.flip_pixel(x, y);
-                //     }
-                // }
-                self.display.draw_frame();
-                since_last_frame -= frame_dt;
+fn execute_loop(
+    cpu: &mut Chip8,
+    display: &mut Display,
+    instruments: &mut Instruments,
+    memory: &mut MemoryEditor,
+    debugger: &mut Option<GdbServer>,
+) -> Result<(), Error> {
+    let mut instructions_per_frame = INSTRUCT_PER_SEC / FRAME_RATE;
+    let mut paused = false;
+    let mut fast_forward = false;
+    let mut unthrottled = false;
+    let mut slow_motion = false;
+    let mut inspector = SpriteInspector::default();
+
+    let frame_dt = Duration::new(0, 1_000_000_000u32 / FRAME_RATE);
+    let mut last_frame = Instant::now();
+    let mut title_changed = true;
+
+    'execution: loop {
+        // The program asked to quit with 00FD
+        if let Some(ExitReason::Exit { .. }) = cpu.exit_reason() {
+            if let Some(server) = debugger {
+                server.finish(0);
             }
+            break 'execution;
         }
-    }
 
-    fn step_cpu(&mut self) {
-        // Fetch
-        let instruction = self.read_instruction(self.pc.into());
-        self.pc += 2;
-
-        // Get instruction arguments
-        let op = (instruction & 0b1111_0000_0000_0000) >> 12;
-        let x = ((instruction & 0b0000_1111_0000_0000) >> 8) as usize;
-        let y = ((instruction & 0b0000_0000_1111_0000) >> 4) as usize;
-        let n = instruction & 0b0000_0000_0000_1111;
-        let nn = (instruction & 0b0000_0000_1111_1111) as u8;
-        let nnn = instruction & 0b0000_1111_1111_1111;
-
-        // Decode
-        match op {
-            0x0 => {
-                match instruction {
-                    0x00E0 => {
-                        // CLS
-                        self.cls();
+        for event in display.poll_events() {
+            match event {
+                DisplayEvent::Quit => break 'execution,
+                DisplayEvent::TogglePause => paused = !paused,
+                DisplayEvent::FrameAdvance => {
+                    // Only step frame by frame while paused
+                    if paused {
+                        run_frame(
+                            cpu,
+                            display,
+                            instruments,
+                            memory,
+                            debugger,
+                            instructions_per_frame,
+                        )?;
+                        redraw(cpu, display, instruments, memory, &inspector);
                     }
-
-                    0x00EE => {
-                        // RET
-                        self.ret();
+                }
+                DisplayEvent::FastForward(held) => fast_forward = held,
+                DisplayEvent::Unthrottle(held) => unthrottled = held,
+                DisplayEvent::ToggleSlowMotion => slow_motion = !slow_motion,
+                DisplayEvent::SpeedUp => {
+                    instructions_per_frame =
+                        (instructions_per_frame + 1).min(MAX_INSTRUCT_PER_FRAME)
+                }
+                DisplayEvent::SpeedDown => {
+                    instructions_per_frame = (instructions_per_frame - 1).max(1)
+                }
+                DisplayEvent::ToggleMemoryView => memory.toggle(),
+                DisplayEvent::ScrollMemory(rows) => memory.scroll(cpu, rows),
+                DisplayEvent::ToggleInspector => inspector.toggle(instruments.draws()),
+                DisplayEvent::CycleDraw(offset) => inspector.cycle(instruments.draws(), offset),
+                DisplayEvent::Click { x, y } => {
+                    // The memory overlay sits on top of the inspector
+                    if memory.is_visible() {
+                        memory.click(cpu, x, y)
+                    } else {
+                        inspector.click(display, instruments.draws(), x, y)
                     }
-                    _ => panic!("Reached unimplemented instruction {:#04X}", instruction),
                 }
+                DisplayEvent::HexDigit(digit) => memory.type_digit(cpu, digit, paused),
+                DisplayEvent::ToggleCheat(index) => instruments.toggle_cheat(index),
             }
+            title_changed = true;
+        }
 
-            0x1 => {
-                // JP
-                self.jp(nnn);
-            }
-
-            0x2 => {
-                // CALL
-                self.call(nnn);
-            }
-
-            0x3 => {
-                // SE immediate
-                self.se_imm(x, nn);
-            }
-
-            0x4 => {
-                // SNE immediate
-                self.sne_imm(x, nn);
-            }
-
-            0x5 => {
-                // SE
-                self.se(x, y)
-            }
-
-            0x6 => {
-                // LD immediate
-                self.ld_imm(x, nn);
-            }
-
-            0x7 => {
-                // ADD immediate
-                self.add_imm(x, nn);
-            }
-
-            0x8 => {
-                match n {
-                    0x0 => {
-                        // LD
-                        self.ld(x, y);
-                    }
-
-                    0x1 => {
-                        // OR
-                        self.or(x, y);
-                    }
-
-                    0x2 => {
-                        // AND
-                        self.and(x, y);
-                    }
-
-                    0x3 => {
-                        // XOR
-                        self.xor(x, y);
-                    }
-
-                    0x4 => {
-                        // ADD
-                        self.add(x, y);
-                    }
-
-                    0x5 => {
-                        // SUB
-                        self.sub(x, y);
-                    }
-
-                    0x6 => {
-                        // SHR
-                        self.shr(x);
-                    }
-
-                    0x7 => {
-                        // SUBN
-                        self.subn(x, y);
-                    }
-
-                    0xE => {
-                        // SHL
-                        self.shl(x);
+        memory.poll_terminal(cpu, instruments, paused);
+
+        // While the debugger has the program stopped, wait on it rather than sleeping
+        let halted = match debugger {
+            Some(server) => {
+                let wait = if server.is_halted() {
+                    frame_dt
+                } else {
+                    Duration::from_secs(0)
+                };
+                match server.serve(cpu, instruments, wait) {
+                    Session::Attached => server.is_halted(),
+                    Session::Detached => {
+                        *debugger = None;
+                        false
                     }
-
-                    _ => panic!("Reached unimplemented instruction {:#04X}", instruction),
+                    Session::Killed => break 'execution,
                 }
             }
+            None => false,
+        };
 
-            0x9 => {
-                // SNE
-                self.sne(x, y);
-            }
+        if title_changed {
+            let title = speed_title(
+                instructions_per_frame,
+                paused,
+                fast_forward,
+                unthrottled,
+                slow_motion,
+            );
+            display.set_title(&title);
+            title_changed = false;
+        }
 
-            0xA => {
-                // LD Index
-                self.ld_i_imm(nnn);
-            }
+        if paused || halted {
+            // Keep the memory overlay up to date with edits
+            redraw(cpu, display, instruments, memory, &inspector);
 
-            0xB => {
-                // JP to nnn + v0
-                self.jp_offset(nnn);
+            // Don't spin while waiting for hotkeys
+            if !halted {
+                thread::sleep(frame_dt);
             }
+            last_frame = Instant::now();
+            continue;
+        }
 
-            0xC => {
-                // RAND
-                self.rand(x, nn);
-            }
+        // Slow motion stretches each emulated frame over several real frames
+        let target_dt = if slow_motion {
+            frame_dt * SLOW_MOTION_FACTOR
+        } else {
+            frame_dt
+        };
 
-            0xD => {
-                // DRW
-                self.drw(x, y, n);
+        if unthrottled {
+            // Run as fast as possible, only presenting at the normal frame rate
+            if run_frame(
+                cpu,
+                display,
+                instruments,
+                memory,
+                debugger,
+                instructions_per_frame,
+            )? {
+                paused = true;
+                title_changed = true;
             }
 
-            0xE => match nn {
-                0x9E => {
-                    // SKP
-                    self.skp(x);
-                }
+            if last_frame.elapsed() >= frame_dt {
+                redraw(cpu, display, instruments, memory, &inspector);
+                last_frame = Instant::now();
+            }
+            continue;
+        }
 
-                0xA1 => {
-                    // SKNP
-                    self.sknp(x);
+        let since_last_frame = last_frame.elapsed();
+
+        if since_last_frame >= target_dt {
+            let frames = if fast_forward { FAST_FORWARD_FACTOR } else { 1 };
+
+            for _ in 0..frames {
+                if run_frame(
+                    cpu,
+                    display,
+                    instruments,
+                    memory,
+                    debugger,
+                    instructions_per_frame,
+                )? {
+                    paused = true;
+                    title_changed = true;
+                    break;
                 }
-                _ => panic!("Reached unimplemented instruction {:#04X}", instruction),
-            },
-
-            0xF => {
-                match nn {
-                    0x1E => {
-                        // ADD I
-                        self.add_i(x);
-                    }
-
-                    0x29 => {
-                        // LD Font
-                        self.ld_f(x);
-                    }
-
-                    0x33 => {
-                        // LD bcd
-                        self.ld_bcd(x);
-                    }
-
-                    0x55 => {
-                        // LD into I
-                        self.ld_into_i(x);
-                    }
+            }
 
-                    0x65 => {
-                        // LD from I
-                        self.ld_from_i(x);
-                    }
+            redraw(cpu, display, instruments, memory, &inspector);
+            last_frame += target_dt;
 
-                    _ => panic!("Reached unimplemented instruction {:#04X}", instruction),
-                }
+            // Don't try to catch up after falling far behind (e.g. leaving unthrottled mode)
+            if last_frame.elapsed() > target_dt {
+                last_frame = Instant::now();
             }
-
-            _ => panic!("Reached unimplemented instruction {:#04X}", instruction),
+        } else {
+            thread::sleep(target_dt - since_last_frame);
         }
-
-        // Execute
     }
 
-    fn cls(&mut self) {
-        // 00E0 - CLS
-        // Clears the display
-        self.display.clear();
-    }
-
-    fn ret(&mut self) {
-        // 00EE - RET
-        // Set PC to top value in Stack, Decrement SP
-        self.pc = self.stack[self.sp];
-        self.sp -= 1;
-    }
-
-    fn jp(&mut self, address: u16) {
-        // 1nnn - JP nnn
-        // Sets program counter to nnn
-        self.pc = address;
-    }
-
-    fn call(&mut self, address: u16) {
-        // 1nnn - CALL nnn
-        // Increment SP, Push current PC to stack, Set program counter to nnn
-        self.sp += 1;
-        self.stack[self.sp] = self.pc;
-        self.pc = address;
-    }
+    Ok(())
+}
 
-    fn se_imm(&mut self, target_register: usize, imm_value: u8) {
-        // 3xnn - SE Vx, nn
-        // Skip next instruction if Vx = nn
-        if self.v[target_register] == imm_value {
-            self.pc += 2;
+fn run_frame(
+    cpu: &mut Chip8,
+    display: &Display,
+    instruments: &mut Instruments,
+    memory: &mut MemoryEditor,
+    debugger: &mut Option<GdbServer>,
+    instructions_per_frame: u32,
+) -> Result<bool, Error> {
+    // Latch the keypad once per frame
+    for key in 0x0..=0xF {
+        instruments.set_key(cpu, key, display.check_key(key));
+    }
+
+    // Whether the frame stopped at a breakpoint set from the monitor
+    let mut hit_breakpoint = false;
+    for _ in 0..instructions_per_frame {
+        if memory.should_break(cpu) {
+            hit_breakpoint = true;
+            break;
         }
-    }
-
-    fn sne_imm(&mut self, target_register: usize, imm_value: u8) {
-        // 4xnn - SNE Vx, nn
-        // Skip next instruction if Vx != nn
-        if self.v[target_register] != imm_value {
-            self.pc += 2;
+        if let Some(server) = debugger {
+            if server.should_stop(cpu) {
+                break;
+            }
         }
-    }
 
-    fn se(&mut self, x_register: usize, y_register: usize) {
-        // 5xy0 - SE Vx, Vy
-        // Skip next instruction if Vx = Vy
-        if self.v[x_register] == self.v[y_register] {
-            self.pc += 2;
+        if let Err(e) = instruments.step(cpu) {
+            match debugger {
+                // Stop and let the debugger look at what went wrong
+                Some(server) => {
+                    eprintln!("{}", instruments.symbols().annotate_error(&e));
+                    server.stop(gdb::SIGSEGV);
+                    break;
+                }
+                None => return Err(e),
+            }
         }
     }
+    instruments.end_frame(cpu);
+    memory.end_frame(cpu);
 
-    fn ld_imm(&mut self, target_register: usize, imm_value: u8) {
-        // 6xnn - LD Vx, nn
-        // Loads the immediate value nn into register Vx
-        self.v[target_register] = imm_value;
-    }
-
-    fn add_imm(&mut self, x_register: usize, imm_value: u8) {
-        // 7xnn - ADD Vx, nn
-        // Adds the value nn to register Vx and stores it in Vx
-        // Note: doesn't affect overflow flag
-        self.v[x_register] = self.v[x_register].wrapping_add(imm_value);
-    }
-
-    fn ld(&mut self, x_register: usize, y_register: usize) {
-        // 8xy0 - LD Vx, Vy
-        // Set Vx = Vy
-        self.v[x_register] = self.v[y_register];
-    }
-
-    fn or(&mut self, x_register: usize, y_register: usize) {
-        // 8xy1 - OR Vx, Vy
-        // Set Vx = Vx OR Vy
-        self.v[x_register] |= self.v[y_register];
-    }
+    Ok(hit_breakpoint)
+}
 
-    fn and(&mut self, x_register: usize, y_register: usize) {
-        // 8xy2 - AND Vx, Vy
-        // Set Vx = Vx AND Vy
-        self.v[x_register] &= self.v[y_register];
-    }
+fn redraw(
+    cpu: &Chip8,
+    display: &mut Display,
+    instruments: &Instruments,
+    memory: &MemoryEditor,
+    inspector: &SpriteInspector,
+) {
+    display.draw_frame(cpu.framebuffer());
+    inspector.draw(display, instruments.draws());
+    memory.draw(display, cpu);
+    display.present();
+}
 
-    fn xor(&mut self, x_register: usize, y_register: usize) {
-        // 8xy3 - XOR Vx, Vy
-        // Set Vx = Vx XOR Vy
-        self.v[x_register] ^= self.v[y_register];
-    }
+fn speed_title(
+    instructions_per_frame: u32,
+    paused: bool,
+    fast_forward: bool,
+    unthrottled: bool,
+    slow_motion: bool,
+) -> String {
+    // Window title showing the current speed, e.g. "Chip-8 - 11 IPF (x4)"
+    let mut title = format!("Chip-8 - {} IPF", instructions_per_frame);
+
+    if paused {
+        title.push_str(" [Paused]");
+    } else if unthrottled {
+        title.push_str(" (Unthrottled)");
+    } else if fast_forward {
+        title.push_str(&format!(" (x{})", FAST_FORWARD_FACTOR));
+    } else if slow_motion {
+        title.push_str(&format!(" (x1/{})", SLOW_MOTION_FACTOR));
+    }
+
+    title
+}
 
-    fn add(&mut self, x_register: usize, y_register: usize) {
-        // 8xy4 - ADD Vx, Vy
-        // Set Vx = Vx + Vy
-        // Set VF = carry
-        let (value, overflow) = self.v[x_register].overflowing_add(self.v[y_register]);
+// Writes Octo source out as a ROM, returning the process exit code
+fn assemble(options: &AssembleOptions) -> i32 {
+    let result =
+        machine::read_and_assemble(&options.source, options.load_address).and_then(|program| {
+            let write = |path: &Path, data: &[u8]| {
+                fs::write(path, data)
+                    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+            };
+            write(&options.output, &program.rom)?;
+            if let Some(path) = &options.symbols {
+                write(path, program.symbols.to_text().as_bytes())?;
+            }
+            Ok(program.rom.len())
+        });
 
-        if overflow {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
+    match result {
+        Ok(size) => {
+            println!("Wrote {} bytes to {}", size, options.output.display());
+            0
         }
-
-        self.v[x_register] = value;
-    }
-
-    fn sub(&mut self, x_register: usize, y_register: usize) {
-        // 8xy5 - SUB Vx, Vy
-        // Set Vx = Vx - Vy
-        // Set VF = Not borrow (Vx <= Vy)
-        if self.v[x_register] > self.v[y_register] {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
+        Err(message) => {
+            eprintln!("{}", message);
+            1
         }
-
-        self.v[x_register] = self.v[x_register].wrapping_sub(self.v[y_register]);
     }
+}
 
-    fn shr(&mut self, x_register: usize) {
-        // 8xy6 - SHR Vx
-        // Set Vx = Vx >> 1
-        // Set VF = LSB of X = 1
-
-        if (self.v[x_register] & 0b0000_0001) != 0 {
-            self.v[0xF] = 1
-        } else {
-            self.v[0xF] = 0;
+// Builds the machine described by `options` and loads its ROM, exiting on
+// failure
+fn setup_machine(options: &mut Options) -> Chip8 {
+    match machine::build(options, true) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
         }
-
-        self.v[x_register] >>= 1;
     }
+}
 
-    fn subn(&mut self, x_register: usize, y_register: usize) {
-        // 8xy7 - SUBN Vx, Vy
-        // Set Vx = Vy - Vx
-        // Set VF = Not borrow (Vx <= Vy)
-
-        if self.v[y_register] > self.v[x_register] {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
+fn setup_instruments(options: &Options, cpu: &mut Chip8) -> Instruments {
+    match Instruments::new(&options.instruments, cpu) {
+        Ok(instruments) => instruments,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
         }
-
-        self.v[x_register] = self.v[y_register].wrapping_sub(self.v[x_register]);
     }
+}
 
-    fn shl(&mut self, x_register: usize) {
-        // 8xy7 - SHL Vx
-        // Set Vx = Vx << 1
-        // Set VF = MSB of X = 1
-
-        if (self.v[x_register] & 0b1000_0000) != 0 {
-            self.v[0xF] = 1
-        } else {
-            self.v[0xF] = 0;
-        }
-
-        self.v[x_register] >>= 1;
-    }
+// Waits for a debugger if asked for one, exiting on failure
+fn setup_debugger(options: &Options) -> Option<GdbServer> {
+    let port = options.instruments.gdb_port?;
 
-    fn sne(&mut self, x_register: usize, y_register: usize) {
-        // 9xy0 - SNE Vx, Vy
-        // Skip next instruction if Vx != Vy
-        if self.v[x_register] != self.v[y_register] {
-            self.pc += 2;
+    match GdbServer::listen(port) {
+        Ok(server) => Some(server),
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
         }
     }
+}
 
-    fn ld_i_imm(&mut self, address: u16) {
-        // Annn - LD I, addr
-        // Sets Index register to addr
-        self.i = address;
-    }
-
-    fn jp_offset(&mut self, address: u16) {
-        // Bnnn - JP to nnn + V0
-        // Set PC to nnn + V0
-        self.pc = address + (self.v[0x0] as u16);
-    }
-
-    fn rand(&mut self, x_register: usize, imm_value: u8) {
-        // Cxnn - RAND Vx, nn
-        // Set Vx to a random 8-bit number ANDed with nn
-        let rand_val: u8 = rand::random();
-        self.v[x_register] = rand_val & imm_value;
-    }
-
-    fn drw(&mut self, x_register: usize, y_register: usize, n_bytes: u16) {
-        // Dxyn - DRW Vx, Vy, n
-        // Display n-byte sprite starting at memory location I at (Vx, Vy)
-        // Set VF = collision
-
-        // Get starting coordinates with appropriate wrapping
-        let x_coord = self.v[x_register] % 64;
-        let y_coord = self.v[y_register] % 32;
-
-        // Reset VF
-        self.v[0xF] = 0;
-        let mut set_vf = false;
-
-        // For every row of sprite data
-        for n in 0..n_bytes {
-            // Load data from ram
-            let sprite_data: u8 = self.ram[(self.i + n) as usize];
-
-            // For each bit in sprite row
-            for bit in 0..8 {
-                // Mask MSB and if set, flip pixel on display
-                if (sprite_data << bit) & 0b1000_0000 != 0 {
-                    // set_vf will be true if any writes turned a pixel off
-                    set_vf |= self.display.flip_pixel(x_coord + bit, y_coord + n as u8);
-                }
-
-                // Check that sprite doesn't go off side of display
-                if x_coord + bit > 63 {
-                    break;
-                }
-            }
-
-            // Check that sprite doesn't go off bottom of display
-            if y_coord + (n as u8) > 31 {
-                break;
-            }
+fn main() {
+    let command = match cli::parse_args() {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, cli::USAGE);
+            process::exit(2);
         }
-
-        // If needed, set VF
-        if set_vf {
-            self.v[0xF] = 1;
+    };
+
+    let (mut options, run) = match command {
+        Command::Run(options, run) => (options, run),
+        Command::Snapshot(mut options, snapshot) => {
+            let rom_name = headless::rom_name(&options.rom_path);
+            let mut cpu = setup_machine(&mut options);
+            process::exit(headless::snapshot(&mut cpu, &rom_name, &snapshot));
         }
-    }
-
-    fn skp(&mut self, x_register: usize) {
-        // Ex9E - SKP Vx
-        // Skip next instruction if key with value Vx is pressed
-        if self.display.check_key(self.v[x_register]) {
-            self.pc += 2;
+        Command::Headless(mut options, headless) => {
+            let mut cpu = setup_machine(&mut options);
+            let mut instruments = setup_instruments(&options, &mut cpu);
+            let mut debugger = setup_debugger(&options);
+            let code = headless::run(&mut cpu, &mut instruments, &mut debugger, &headless);
+            instruments.finish(&cpu);
+            process::exit(code);
         }
-    }
-
-    fn sknp(&mut self, x_register: usize) {
-        // ExA1 - SKNP Vx
-        // Skip next instruction if key with value Vx is not pressed
-        if !self.display.check_key(self.v[x_register]) {
-            self.pc += 2;
+        Command::DumpTrace(path, symbols) => {
+            process::exit(instruments::dump_trace(&path, &symbols))
         }
-    }
-
-    fn add_i(&mut self, x_register: usize) {
-        // Fx1E - ADD I, Vx
-        // Set I = I + Vx
-
-        self.i += self.v[x_register] as u16;
-    }
-
-    fn ld_f(&mut self, x_register: usize) {
-        // Fx29 - LD F, Vx
-        // Set I = location of sprite for digit Vx
-
-        // Index = base(0x050) + Vx * offset(0x5)
-        self.i = 0x050 + (self.v[x_register] as u16) * 0x005;
-    }
-
-    fn ld_bcd(&mut self, x_register: usize) {
-        // Fx33 - LD B, Vx
-        // Store BCD representation of Vx in memory locations I, I+1 and I+2
-
-        let mut value = self.v[x_register];
-
-        // Store least significant digit in I+2
-        self.ram[self.i as usize + 2] = value % 10;
-        value /= 10;
-
-        // Store second digit in I+1
-        self.ram[self.i as usize + 1] = value % 10;
-        value /= 10;
-
-        // Store most significant digit in I
-        self.ram[self.i as usize] = value % 10;
-    }
-
-    fn ld_into_i(&mut self, x_register: usize) {
-        // Fx55 - LD [I], Vx
-        // Stores registers V0 to Vx into memory starting at I
-
-        let start_address = self.i as usize;
-
-        for i in 0..=x_register {
-            self.ram[start_address + i] = self.v[i];
+        Command::Assemble(options) => process::exit(assemble(&options)),
+        Command::Dap => process::exit(dap::run()),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return;
         }
+    };
+
+    let mut cpu = setup_machine(&mut options);
+    let mut instruments = setup_instruments(&options, &mut cpu);
+    let mut display = Display::new(WINDOW_WIDTH, WINDOW_HEIGHT);
+    let symbols = options.instruments.symbols.clone();
+    let mut memory = MemoryEditor::new(&cpu, run.monitor, symbols);
+    let mut debugger = setup_debugger(&options);
+
+    let result = execute_loop(
+        &mut cpu,
+        &mut display,
+        &mut instruments,
+        &mut memory,
+        &mut debugger,
+    )
+    .map_err(|e| instruments.symbols().annotate_error(&e));
+    instruments.finish(&cpu);
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
     }
-
-    fn ld_from_i(&mut self, x_register: usize) {
-        // Fx65 - LD Vx, [I]
-        // Reads registers V0 to Vx from memory starting at I
-
-        let start_address = self.i as usize;
-
-        for i in 0..=x_register {
-            self.v[i] = self.ram[start_address + i];
-        }
-    }
-}
-
-fn main() {
-    let mut cpu = Chip8::new();
-
-    // let program_path = Path::new("ibm_logo.ch8");
-    // let program_path = Path::new("BC_test.ch8");
-    let program_path = Path::new("test_opcode.ch8");
-
-    cpu.load_ram_from_file(program_path)
-        .expect("Failed to read file");
-    cpu.execute_loop();
-
-    // println!("{:x?}", cpu.ram);
 }
@@ -0,0 +1,207 @@
+//! Looking at memory while a program runs.
+//!
+//! A [`MemoryView`] remembers which bytes the program has written recently
+//! and classifies each address, so frontends can show a hex dump with PC, I,
+//! the return addresses on the stack, the font and fresh writes picked out.
+
+use crate::chip8::Chip8;
+use std::fmt::Write;
+
+/// Frames a write stays highlighted for.
+pub const RECENT_FRAMES: u8 = 30;
+
+/// Bytes shown on each row of a hex dump.
+pub const BYTES_PER_ROW: usize = 16;
+
+/// Why an address stands out, in order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    /// Part of the instruction at PC.
+    Pc,
+    /// The byte I points at.
+    I,
+    /// Part of an instruction a subroutine will return to.
+    Stack,
+    /// Part of the hex font.
+    Font,
+    /// Written in the last [`RECENT_FRAMES`] frames.
+    Written,
+    None,
+}
+
+impl Highlight {
+    /// The ANSI SGR code used to show the highlight in a terminal.
+    pub fn ansi_code(self) -> Option<&'static str> {
+        match self {
+            Highlight::Pc => Some("41"),
+            Highlight::I => Some("42"),
+            Highlight::Stack => Some("43"),
+            Highlight::Font => Some("44"),
+            Highlight::Written => Some("45"),
+            Highlight::None => None,
+        }
+    }
+}
+
+/// Tracks recent writes to a machine's memory.
+///
+/// Call [`MemoryView::update`] once per frame. Writes are found by comparing
+/// memory with the previous frame, so a byte changed and changed back within
+/// a frame isn't noticed.
+pub struct MemoryView {
+    previous: Vec<u8>,
+    // Frames since each byte was last written, up to RECENT_FRAMES
+    ages: Vec<u8>,
+}
+
+impl MemoryView {
+    /// Starts tracking `cpu`, treating its current memory as unwritten.
+    pub fn new(cpu: &Chip8) -> MemoryView {
+        MemoryView {
+            previous: cpu.memory().to_vec(),
+            ages: vec![RECENT_FRAMES; cpu.memory().len()],
+        }
+    }
+
+    /// Notes the bytes written since the last update.
+    pub fn update(&mut self, cpu: &Chip8) {
+        let memory = cpu.memory();
+
+        for (address, age) in self.ages.iter_mut().enumerate() {
+            if memory[address] != self.previous[address] {
+                *age = 0;
+            } else if *age < RECENT_FRAMES {
+                *age += 1;
+            }
+        }
+
+        self.previous.copy_from_slice(memory);
+    }
+
+    /// Whether `address` was written in the last [`RECENT_FRAMES`] frames.
+    pub fn recently_written(&self, address: usize) -> bool {
+        matches!(self.ages.get(address), Some(&age) if age < RECENT_FRAMES)
+    }
+
+    /// How `address` should be highlighted.
+    pub fn highlight(&self, cpu: &Chip8, address: usize) -> Highlight {
+        let pc = cpu.pc() as usize;
+        let font_start = cpu.layout().font_address as usize;
+        // The return addresses are in stack[..sp]
+        let returns = &cpu.stack()[..cpu.sp().min(cpu.stack().len())];
+
+        if address == pc || address == pc + 1 {
+            Highlight::Pc
+        } else if address == cpu.i() as usize {
+            Highlight::I
+        } else if returns
+            .iter()
+            .any(|&r| address == r as usize || address == r as usize + 1)
+        {
+            Highlight::Stack
+        } else if (font_start..font_start + cpu.font().size()).contains(&address) {
+            Highlight::Font
+        } else if self.recently_written(address) {
+            Highlight::Written
+        } else {
+            Highlight::None
+        }
+    }
+
+    /// A hex dump of `rows` rows from `start`, coloured with ANSI escapes.
+    pub fn hex_dump(&self, cpu: &Chip8, start: usize, rows: usize) -> String {
+        let memory = cpu.memory();
+        let start = start - start % BYTES_PER_ROW;
+        let end = (start + rows * BYTES_PER_ROW).min(memory.len());
+
+        let mut dump = String::new();
+        for row in (start..end).step_by(BYTES_PER_ROW) {
+            let _ = write!(dump, "{:04X} ", row);
+
+            let bytes = &memory[row..(row + BYTES_PER_ROW).min(end)];
+            for (address, byte) in (row..).zip(bytes) {
+                match self.highlight(cpu, address).ansi_code() {
+                    Some(code) => {
+                        let _ = write!(dump, " \x1b[{}m{:02X}\x1b[0m", code, byte);
+                    }
+                    None => {
+                        let _ = write!(dump, " {:02X}", byte);
+                    }
+                }
+            }
+
+            // Printable ASCII alongside, as in most hex editors
+            dump.push_str("  ");
+            for &byte in bytes {
+                dump.push(if byte.is_ascii_graphic() {
+                    byte as char
+                } else {
+                    '.'
+                });
+            }
+            dump.push('\n');
+        }
+
+        dump
+    }
+}
+
+/// Writes `bytes` to memory starting at `address`, failing without writing
+/// anything if they don't fit.
+pub fn poke(cpu: &mut Chip8, address: usize, bytes: &[u8]) -> Result<(), String> {
+    let memory = cpu.memory_mut();
+
+    match memory.get_mut(address..address + bytes.len()) {
+        Some(target) => {
+            target.copy_from_slice(bytes);
+            Ok(())
+        }
+        None => Err(format!(
+            "{:#X} bytes at {:#05X} don't fit in {:#X} bytes of memory",
+            bytes.len(),
+            address,
+            memory.len()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_state_and_recent_writes() {
+        // LD I, 0x300; CALL 0x206; JP 0x204; LD V0, 0x2A; LD [I], V0
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0xA3, 0x00, 0x22, 0x06, 0x12, 0x04, 0x60, 0x2A, 0xF0, 0x55])
+            .unwrap();
+        let mut view = MemoryView::new(&cpu);
+
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        view.update(&cpu);
+
+        assert_eq!(view.highlight(&cpu, 0x208), Highlight::Pc);
+        assert_eq!(view.highlight(&cpu, 0x300), Highlight::I);
+        assert_eq!(view.highlight(&cpu, 0x204), Highlight::Stack);
+        assert_eq!(view.highlight(&cpu, 0x050), Highlight::Font);
+        assert_eq!(view.highlight(&cpu, 0x301), Highlight::None);
+
+        cpu.step().unwrap();
+        poke(&mut cpu, 0x301, &[0xFF]).unwrap();
+        view.update(&cpu);
+        assert_eq!(cpu.memory()[0x300], 0x2A);
+        assert_eq!(view.highlight(&cpu, 0x301), Highlight::Written);
+
+        for _ in 0..RECENT_FRAMES {
+            view.update(&cpu);
+        }
+        assert!(!view.recently_written(0x301));
+
+        assert!(poke(&mut cpu, 0xFFF, &[0, 0]).is_err());
+        assert!(view
+            .hex_dump(&cpu, 0x305, 1)
+            .starts_with("0300  \x1b[42m2A\x1b[0m FF"));
+    }
+}
@@ -0,0 +1,1030 @@
+use crate::cli::parse_number;
+use crate::display::Display;
+use crate::instruments::Instruments;
+use chip_8::breakpoint::{Breakpoint, Hit};
+use chip_8::callstack;
+use chip_8::cheats::{Cheat, Comparison, Search};
+use chip_8::expr::{Expr, LogMessage};
+use chip_8::memview::{self, Highlight, MemoryView, BYTES_PER_ROW};
+use chip_8::symbols::Symbols;
+use chip_8::Chip8;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+pub const MONITOR_HELP: &str = "\
+Commands:
+    mem [ADDR] [ROWS]     Hex dump of memory, from the row holding PC by default
+    poke ADDR BYTE...     Write bytes to memory, only while paused
+    break [ADDR] [if EXPR] [log MESSAGE]
+                          Pause when PC reaches ADDR and EXPR holds, or print
+                          MESSAGE instead. Without ADDR, pause each time EXPR
+                          starts to hold. Lists breakpoints without arguments
+    delete ADDR|#N        Remove the breakpoints at ADDR, or breakpoint N
+    watch [EXPR]          Print EXPR each time the program pauses, or print
+                          every watch now
+    unwatch N             Remove watch N
+    print EXPR            Print the value of EXPR
+    stack                 List the subroutine calls waiting to return
+    search [new|= N|changed|unchanged|up|down]
+                          Find where a value is kept: start a search of memory,
+                          then keep the bytes equal to N or that changed,
+                          stayed the same, went up or down since last time
+    freeze ADDR BYTE      Hold a byte at a value every frame. Lists frozen
+                          bytes without arguments
+    unfreeze ADDR         Let the program change a frozen byte again
+    cheat [add CODE NAME|on N|off N|delete N]
+                          Keep a patch code for this ROM in the --cheats file,
+                          e.g. cheat add 3F0:09 Lives, or turn one on or off.
+                          Lists the ROM's codes without arguments
+    help                  Print this message
+
+Addresses may be labels from --symbols, optionally with an offset, e.g. main+4.
+Expressions read the machine like v3 == 0x10 && ram[i] != 0 or hit_count > 50,
+and log messages show their values in braces, e.g. score {v3}.
+
+Highlights: \x1b[41mPC\x1b[0m \x1b[42mI\x1b[0m \x1b[43mreturn addresses\x1b[0m \
+\x1b[44mfont\x1b[0m \x1b[45mwritten in the last half second\x1b[0m";
+
+// Rows shown by the overlay and by mem without a row count
+const ROWS: usize = 16;
+// Candidates listed after narrowing down a search
+const SEARCH_RESULTS: usize = 20;
+
+// Overlay layout, in characters of the scaled up hex font
+const SCALE: i32 = 2;
+const CHAR_WIDTH: i32 = 5 * SCALE;
+const CHAR_HEIGHT: i32 = 7 * SCALE;
+const BYTES_COLUMN: i32 = 6;
+const LEFT: i32 = 50;
+const TOP: i32 = 48;
+
+// Inspecting and editing memory while running in a window, through an
+// overlay on the display and optionally commands typed into the terminal
+pub struct MemoryEditor {
+    view: MemoryView,
+    terminal: Option<Receiver<String>>,
+
+    visible: bool,
+    // First address shown by the overlay, always the start of a row
+    top: usize,
+    selected: Option<usize>,
+    // First digit typed for the selected byte
+    high_nibble: Option<u8>,
+
+    symbols: Symbols,
+    // Numbered in the order they were set
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_breakpoint: usize,
+    watches: Vec<Expr>,
+    search: Option<Search>,
+    // Where we paused and the number of the breakpoint that paused there,
+    // so resuming carries on with the breakpoints after it
+    stopped_at: Option<(u16, usize)>,
+}
+
+impl MemoryEditor {
+    pub fn new(cpu: &Chip8, terminal: bool, symbols: Symbols) -> MemoryEditor {
+        let terminal = if terminal {
+            print!("{}\n> ", MONITOR_HELP);
+            let _ = io::stdout().flush();
+            Some(read_lines())
+        } else {
+            None
+        };
+
+        MemoryEditor {
+            view: MemoryView::new(cpu),
+            terminal,
+            visible: false,
+            top: row_start(cpu.pc() as usize),
+            selected: None,
+            high_nibble: None,
+            symbols,
+            breakpoints: Vec::new(),
+            next_breakpoint: 1,
+            watches: Vec::new(),
+            search: None,
+            stopped_at: None,
+        }
+    }
+
+    // Call before each step, true if the program should pause at a breakpoint
+    pub fn should_break(&mut self, cpu: &Chip8) -> bool {
+        let pc = cpu.pc();
+        // Those up to the one we paused at were checked before pausing
+        let checked = match self.stopped_at.take() {
+            Some((address, number)) if address == pc => number,
+            _ => 0,
+        };
+
+        // Check them in order so every hit is counted once, up to the first
+        // that stops. Numbers only go up, so later ones are still to check.
+        let mut stopped = None;
+        for (index, (number, breakpoint)) in self.breakpoints.iter_mut().enumerate() {
+            if *number <= checked {
+                continue;
+            }
+            match breakpoint.check(cpu) {
+                Some(Hit::Stop) => {
+                    stopped = Some(index);
+                    break;
+                }
+                Some(Hit::Log(message)) => {
+                    print!("\r{}\n> ", message);
+                    let _ = io::stdout().flush();
+                }
+                None => {}
+            }
+        }
+        let (number, breakpoint) = match stopped {
+            Some(index) => &self.breakpoints[index],
+            None => return false,
+        };
+
+        println!("\n{}", self.describe(*number, breakpoint));
+        print!("{}> ", self.show_watches(cpu));
+        let _ = io::stdout().flush();
+        self.stopped_at = Some((pc, *number));
+        true
+    }
+
+    pub fn end_frame(&mut self, cpu: &Chip8) {
+        self.view.update(cpu);
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        self.high_nibble = None;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn scroll(&mut self, cpu: &Chip8, rows: i32) {
+        let last_top = row_start(cpu.memory().len() - 1).saturating_sub((ROWS - 1) * BYTES_PER_ROW);
+        let top = self.top as i64 + rows as i64 * BYTES_PER_ROW as i64;
+
+        self.top = top.max(0).min(last_top as i64) as usize;
+    }
+
+    // Selects the byte under a click in window coordinates
+    pub fn click(&mut self, cpu: &Chip8, x: i32, y: i32) {
+        if !self.visible || x < LEFT || y < TOP {
+            return;
+        }
+
+        let column = (x - LEFT) / CHAR_WIDTH - BYTES_COLUMN;
+        let row = ((y - TOP) / CHAR_HEIGHT) as usize;
+        // Each byte takes two characters and a space
+        if column < 0 || column % 3 == 2 || column / 3 >= BYTES_PER_ROW as i32 || row >= ROWS {
+            return;
+        }
+
+        let address = self.top + row * BYTES_PER_ROW + (column / 3) as usize;
+        if address < cpu.memory().len() {
+            self.selected = Some(address);
+            self.high_nibble = None;
+        }
+    }
+
+    // Types a hex digit into the selected byte, moving on once both are in
+    pub fn type_digit(&mut self, cpu: &mut Chip8, digit: u8, paused: bool) {
+        let address = match self.selected {
+            Some(address) if self.visible && paused => address,
+            _ => return,
+        };
+
+        match self.high_nibble.take() {
+            None => self.high_nibble = Some(digit),
+            Some(high) => {
+                cpu.memory_mut()[address] = high << 4 | digit;
+                self.view.update(cpu);
+                if address + 1 < cpu.memory().len() {
+                    self.selected = Some(address + 1);
+                }
+            }
+        }
+    }
+
+    // Runs any commands typed into the terminal since the last call
+    pub fn poll_terminal(&mut self, cpu: &mut Chip8, instruments: &mut Instruments, paused: bool) {
+        loop {
+            let line = match self.terminal.as_ref().map(Receiver::try_recv) {
+                Some(Ok(line)) => line,
+                Some(Err(TryRecvError::Disconnected)) => {
+                    // Stdin was closed, carry on without the terminal
+                    self.terminal = None;
+                    return;
+                }
+                Some(Err(TryRecvError::Empty)) | None => return,
+            };
+
+            match self.run_command(cpu, instruments, &line, paused) {
+                Ok(output) => print!("{}", output),
+                Err(message) => println!("{}", message),
+            }
+            print!("> ");
+            let _ = io::stdout().flush();
+        }
+    }
+
+    fn run_command(
+        &mut self,
+        cpu: &mut Chip8,
+        instruments: &mut Instruments,
+        line: &str,
+        paused: bool,
+    ) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+
+        match words.next() {
+            Some("mem") | Some("m") => {
+                let start = match words.next() {
+                    Some(word) => self.parse_address(word, "mem")?,
+                    None => cpu.pc() as usize,
+                };
+                let rows = match words.next() {
+                    Some(word) => parse_number(word, "mem")?,
+                    None => ROWS,
+                };
+                if start >= cpu.memory().len() {
+                    return Err(format!("{:#05X} is outside memory", start));
+                }
+
+                Ok(self.view.hex_dump(cpu, start, rows))
+            }
+            Some("poke") | Some("p") => {
+                if !paused {
+                    return Err("Pause with P before poking memory".to_string());
+                }
+
+                let address = self.parse_address(words.next().ok_or("Missing address")?, "poke")?;
+                let bytes = words
+                    .map(|word| match parse_number(word, "poke")? {
+                        byte if byte <= 0xFF => Ok(byte as u8),
+                        _ => Err(format!("{} doesn't fit in a byte", word)),
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+                if bytes.is_empty() {
+                    return Err("Missing bytes to write".to_string());
+                }
+
+                memview::poke(cpu, address, &bytes)?;
+                self.view.update(cpu);
+
+                Ok(self.view.hex_dump(cpu, address, 1))
+            }
+            Some("break") | Some("b") => {
+                let arguments = arguments(line);
+                if arguments.is_empty() {
+                    return Ok(self.list_breakpoints());
+                }
+
+                let (arguments, log) = split_keyword(arguments, "log");
+                let (address, condition) = split_keyword(arguments, "if");
+                let address = match address {
+                    "" => None,
+                    word => {
+                        let address = self.parse_address(word, "break")?;
+                        if address >= cpu.memory().len() {
+                            return Err(format!("{:#05X} is outside memory", address));
+                        }
+                        Some(address as u16)
+                    }
+                };
+                let condition = match condition {
+                    Some(text) => Some(
+                        Expr::parse(text, &self.symbols)
+                            .map_err(|e| format!("Invalid condition: {}", e))?,
+                    ),
+                    None if address.is_none() => return Err("Missing address".to_string()),
+                    None => None,
+                };
+                let log = match log {
+                    Some(text) => Some(
+                        LogMessage::parse(text, &self.symbols)
+                            .map_err(|e| format!("Invalid log message: {}", e))?,
+                    ),
+                    None => None,
+                };
+
+                let number = self.next_breakpoint;
+                self.next_breakpoint += 1;
+                let breakpoint = Breakpoint::new(address, condition, log);
+                let output = format!("{}\n", self.describe(number, &breakpoint));
+                self.breakpoints.push((number, breakpoint));
+
+                Ok(output)
+            }
+            Some("delete") | Some("d") => {
+                let word = words.next().ok_or("Missing address")?;
+                let count = self.breakpoints.len();
+                match word.strip_prefix('#') {
+                    Some(number) => {
+                        let number = parse_number(number, "delete")?;
+                        self.breakpoints.retain(|(n, _)| *n != number);
+                    }
+                    None => {
+                        let address = self.parse_address(word, "delete")?;
+                        self.breakpoints.retain(|(_, breakpoint)| {
+                            breakpoint.address.map(usize::from) != Some(address)
+                        });
+                    }
+                }
+                if self.breakpoints.len() == count {
+                    return Err(format!("No breakpoint at {}", word));
+                }
+
+                Ok(String::new())
+            }
+            Some("watch") | Some("w") => {
+                let text = arguments(line);
+                if text.is_empty() {
+                    if self.watches.is_empty() {
+                        return Ok("No watches\n".to_string());
+                    }
+                    return Ok(self.show_watches(cpu));
+                }
+
+                let expr = Expr::parse(text, &self.symbols).map_err(|e| e.to_string())?;
+                let output = format!(
+                    "#{} {} = {}\n",
+                    self.watches.len() + 1,
+                    expr,
+                    show_value(expr.evaluate(cpu, 0))
+                );
+                self.watches.push(expr);
+
+                Ok(output)
+            }
+            Some("unwatch") => {
+                let word = words.next().ok_or("Missing watch number")?;
+                let number = parse_number(word.trim_start_matches('#'), "unwatch")?;
+                if number == 0 || number > self.watches.len() {
+                    return Err(format!("No watch {}", word));
+                }
+
+                self.watches.remove(number - 1);
+                Ok(String::new())
+            }
+            Some("print") => {
+                let expr =
+                    Expr::parse(arguments(line), &self.symbols).map_err(|e| e.to_string())?;
+                Ok(format!("{}\n", show_value(expr.evaluate(cpu, 0))))
+            }
+            Some("stack") | Some("bt") => Ok(callstack::backtrace(cpu, &self.symbols)),
+            Some("search") | Some("s") => {
+                let comparison = match (words.next(), words.next()) {
+                    (None, _) | (Some("new"), _) => {
+                        self.search = Some(Search::new(cpu));
+                        return Ok(format!("Searching {} bytes\n", cpu.memory().len()));
+                    }
+                    (Some("="), Some(word)) => match parse_number(word, "search")? {
+                        value if value <= 0xFF => Comparison::Equal(value as u8),
+                        _ => return Err(format!("{} doesn't fit in a byte", word)),
+                    },
+                    (Some("changed"), _) => Comparison::Changed,
+                    (Some("unchanged"), _) => Comparison::Unchanged,
+                    (Some("up"), _) => Comparison::Increased,
+                    (Some("down"), _) => Comparison::Decreased,
+                    _ => {
+                        return Err("Expected new, = N, changed, unchanged, up or down".to_string())
+                    }
+                };
+                let mut search = self.search.take().unwrap_or_else(|| Search::new(cpu));
+                // Filtering moves the search on to memory as it is now
+                let before: Vec<u8> = (0..cpu.memory().len())
+                    .map(|address| search.previous(address as u16))
+                    .collect();
+
+                let count = search.filter(cpu, comparison);
+                let mut output = format!("{} candidates\n", count);
+                for &address in search.candidates().iter().take(SEARCH_RESULTS) {
+                    output += &format!(
+                        "  {}  {:#04X}, was {:#04X}\n",
+                        self.name(address),
+                        cpu.memory()[address as usize],
+                        before[address as usize]
+                    );
+                }
+                self.search = Some(search);
+
+                Ok(output)
+            }
+            Some("freeze") => {
+                let frozen = instruments.frozen_mut();
+                let word = match words.next() {
+                    Some(word) => word,
+                    None if frozen.is_empty() => return Ok("No frozen bytes\n".to_string()),
+                    None => {
+                        let lines: Vec<String> = frozen
+                            .iter()
+                            .map(|(&address, value)| {
+                                format!("{} = {:#04X}\n", self.name(address), value)
+                            })
+                            .collect();
+                        return Ok(lines.concat());
+                    }
+                };
+                let address = self.parse_address(word, "freeze")?;
+                if address >= cpu.memory().len() {
+                    return Err(format!("{:#05X} is outside memory", address));
+                }
+                let value = words.next().ok_or("Missing byte to hold")?;
+                let value = match parse_number(value, "freeze")? {
+                    byte if byte <= 0xFF => byte as u8,
+                    _ => return Err(format!("{} doesn't fit in a byte", value)),
+                };
+
+                frozen.insert(address as u16, value);
+                cpu.memory_mut()[address] = value;
+                Ok(String::new())
+            }
+            Some("unfreeze") => {
+                let word = words.next().ok_or("Missing address")?;
+                let address = self.parse_address(word, "unfreeze")?;
+                match instruments.frozen_mut().remove(&(address as u16)) {
+                    Some(_) => Ok(String::new()),
+                    None => Err(format!("{} isn't frozen", word)),
+                }
+            }
+            Some("cheat") | Some("c") => self.cheat_command(instruments, line),
+            Some("help") => Ok(format!("{}\n", MONITOR_HELP)),
+            Some(command) => Err(format!("Unknown command {}, try help", command)),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn cheat_command(&self, instruments: &mut Instruments, line: &str) -> Result<String, String> {
+        let mut words = arguments(line).splitn(3, char::is_whitespace);
+
+        let index = |word: Option<&str>, count: usize| {
+            let word = word.ok_or("Missing cheat number")?;
+            match parse_number(word.trim_start_matches('#'), "cheat")? {
+                number if number >= 1 && number <= count => Ok(number - 1),
+                _ => Err(format!("No cheat {}", word)),
+            }
+        };
+        let count = instruments.cheats().len();
+
+        match words.next() {
+            None | Some("") => {
+                if count == 0 {
+                    return Ok("No cheats for this ROM\n".to_string());
+                }
+                let lines: Vec<String> = instruments
+                    .cheats()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, cheat)| {
+                        let enabled = if cheat.enabled { "on " } else { "off" };
+                        format!(
+                            "#{} {} {}  {}\n",
+                            index + 1,
+                            enabled,
+                            cheat.code(),
+                            cheat.name
+                        )
+                    })
+                    .collect();
+                Ok(lines.concat())
+            }
+            Some("add") => {
+                let code = words.next().ok_or("Missing code")?;
+                let patches = Cheat::parse_code(code).map_err(|e| e.to_string())?;
+                let name = words.next().unwrap_or("").trim().to_string();
+                let cheat = Cheat {
+                    name,
+                    patches,
+                    enabled: true,
+                };
+                let number = instruments.edit_cheats(|cheats| {
+                    cheats.push(cheat);
+                    cheats.len()
+                });
+                Ok(format!("Added cheat {}\n", number))
+            }
+            Some(command @ "on") | Some(command @ "off") => {
+                let index = index(words.next(), count)?;
+                instruments.edit_cheats(|cheats| cheats[index].enabled = command == "on");
+                Ok(String::new())
+            }
+            Some("delete") => {
+                let index = index(words.next(), count)?;
+                instruments.edit_cheats(|cheats| cheats.remove(index));
+                Ok(String::new())
+            }
+            Some(command) => Err(format!("Unknown cheat command {}, try help", command)),
+        }
+    }
+
+    // A number, or a label with an optional offset such as main+4
+    fn parse_address(&self, word: &str, command: &str) -> Result<usize, String> {
+        let (label, offset) = match word.split_once('+') {
+            Some((label, offset)) => (label, parse_number(offset, command)?),
+            None => (word, 0),
+        };
+
+        match self.symbols.address_of(label) {
+            Some(address) => Ok(address as usize + offset),
+            None if label.starts_with(|c: char| c.is_ascii_digit()) => parse_number(word, command),
+            None => Err(format!("Unknown label {}", label)),
+        }
+    }
+
+    // e.g. Breakpoint #2 at 0x204 <main+0x4> if v0 == 3
+    fn describe(&self, number: usize, breakpoint: &Breakpoint) -> String {
+        let mut text = format!("Breakpoint #{}", number);
+        if let Some(address) = breakpoint.address {
+            text += &format!(" at {}", self.name(address));
+        }
+        if let Some(condition) = &breakpoint.condition {
+            text += &format!(" if {}", condition);
+        }
+        if let Some(log) = &breakpoint.log {
+            text += &format!(" log {}", log);
+        }
+
+        text
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints\n".to_string();
+        }
+
+        self.breakpoints
+            .iter()
+            .map(|(number, breakpoint)| {
+                format!(
+                    "{}, hit {} times\n",
+                    self.describe(*number, breakpoint),
+                    breakpoint.hits()
+                )
+            })
+            .collect()
+    }
+
+    // Each watch with its value, one per line
+    fn show_watches(&self, cpu: &Chip8) -> String {
+        self.watches
+            .iter()
+            .enumerate()
+            .map(|(index, expr)| {
+                format!(
+                    "#{} {} = {}\n",
+                    index + 1,
+                    expr,
+                    show_value(expr.evaluate(cpu, 0))
+                )
+            })
+            .collect()
+    }
+
+    // An address with its label, e.g. 0x204 <main+0x4>
+    fn name(&self, address: u16) -> String {
+        match self.symbols.location(address) {
+            Some(location) => format!("{:#05X} <{}>", address, location),
+            None => format!("{:#05X}", address),
+        }
+    }
+
+    // Draws the overlay over the display, if it's open
+    pub fn draw(&self, display: &mut Display, cpu: &Chip8) {
+        if !self.visible {
+            return;
+        }
+
+        let width = (BYTES_COLUMN + 3 * BYTES_PER_ROW as i32) * CHAR_WIDTH;
+        let height = ROWS as i32 * CHAR_HEIGHT;
+        display.fill_rect(
+            Rect::new(LEFT - 8, TOP - 8, width as u32 + 16, height as u32 + 12),
+            Color::RGB(24, 24, 24),
+        );
+
+        let memory = cpu.memory();
+        let glyphs = cpu.font().small();
+        for row in 0..ROWS {
+            let row_address = self.top + row * BYTES_PER_ROW;
+            if row_address >= memory.len() {
+                break;
+            }
+            let y = TOP + row as i32 * CHAR_HEIGHT;
+
+            let address_text = format!("{:04X}", row_address);
+            draw_text(
+                display,
+                glyphs,
+                LEFT,
+                y,
+                &address_text,
+                Color::RGB(160, 160, 160),
+            );
+
+            for offset in 0..BYTES_PER_ROW {
+                let address = row_address + offset;
+                if address >= memory.len() {
+                    break;
+                }
+                let x = LEFT + (BYTES_COLUMN + 3 * offset as i32) * CHAR_WIDTH;
+
+                let background = if self.selected == Some(address) {
+                    Some(Color::RGB(255, 255, 255))
+                } else {
+                    highlight_color(self.view.highlight(cpu, address))
+                };
+                if let Some(color) = background {
+                    let rect = Rect::new(x - 1, y - 2, 2 * CHAR_WIDTH as u32, CHAR_HEIGHT as u32);
+                    display.fill_rect(rect, color);
+                }
+
+                let text = match self.high_nibble {
+                    // Show the digit typed so far in place of the old value
+                    Some(high) if self.selected == Some(address) => format!("{:X} ", high),
+                    _ => format!("{:02X}", memory[address]),
+                };
+                let color = if self.selected == Some(address) {
+                    Color::RGB(0, 0, 0)
+                } else {
+                    Color::RGB(255, 255, 255)
+                };
+                draw_text(display, glyphs, x, y, &text, color);
+            }
+        }
+    }
+}
+
+fn highlight_color(highlight: Highlight) -> Option<Color> {
+    match highlight {
+        Highlight::Pc => Some(Color::RGB(170, 0, 0)),
+        Highlight::I => Some(Color::RGB(0, 130, 0)),
+        Highlight::Stack => Some(Color::RGB(150, 120, 0)),
+        Highlight::Font => Some(Color::RGB(0, 60, 170)),
+        Highlight::Written => Some(Color::RGB(140, 0, 140)),
+        _ => None,
+    }
+}
+
+// Draws hex digits with the machine's own font, skipping anything else
+fn draw_text(display: &mut Display, glyphs: &[u8], x: i32, y: i32, text: &str, color: Color) {
+    for (index, c) in text.chars().enumerate() {
+        if let Some(digit) = c.to_digit(16) {
+            let glyph = &glyphs[digit as usize * 5..digit as usize * 5 + 5];
+            display.draw_glyph(x + index as i32 * CHAR_WIDTH, y, glyph, SCALE as u32, color);
+        }
+    }
+}
+
+// The text after a command's name
+fn arguments(line: &str) -> &str {
+    match line.trim().split_once(char::is_whitespace) {
+        Some((_, arguments)) => arguments.trim(),
+        None => "",
+    }
+}
+
+// Splits `text` around the first word `keyword`, e.g. the if in main if v0 == 1
+fn split_keyword<'a>(text: &'a str, keyword: &str) -> (&'a str, Option<&'a str>) {
+    let is_boundary = |c: Option<char>| !matches!(c, Some(c) if !c.is_whitespace());
+
+    for (index, _) in text.match_indices(keyword) {
+        let after = index + keyword.len();
+        if is_boundary(text[..index].chars().next_back())
+            && is_boundary(text[after..].chars().next())
+        {
+            return (text[..index].trim(), Some(text[after..].trim()));
+        }
+    }
+
+    (text.trim(), None)
+}
+
+// A value in decimal, and in hex too when that reads differently
+fn show_value(value: i64) -> String {
+    match value {
+        0..=9 => value.to_string(),
+        10..=i64::MAX => format!("{} ({:#X})", value, value),
+        _ => value.to_string(),
+    }
+}
+
+fn row_start(address: usize) -> usize {
+    address - address % BYTES_PER_ROW
+}
+
+// Reads stdin on another thread so the window keeps running while waiting
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            // Stop once stdin closes or the window has gone
+            let sent = match line {
+                Ok(line) => sender.send(line).is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::InstrumentOptions;
+
+    // 0x200: LD V0, 1; ADD V0, 1; JP 0x202
+    const ROM: [u8; 6] = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+
+    fn setup(symbols: &str) -> (Chip8, Instruments, MemoryEditor) {
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&ROM).unwrap();
+        let instruments = Instruments::new(&InstrumentOptions::default(), &mut cpu).unwrap();
+        let editor = MemoryEditor::new(&cpu, false, Symbols::parse(symbols).unwrap());
+
+        (cpu, instruments, editor)
+    }
+
+    fn run(
+        (cpu, instruments, editor): &mut (Chip8, Instruments, MemoryEditor),
+        line: &str,
+    ) -> Result<String, String> {
+        editor.run_command(cpu, instruments, line, true)
+    }
+
+    // Steps like the window does, returning the address of each pause
+    fn run_until_paused(cpu: &mut Chip8, editor: &mut MemoryEditor, steps: usize) -> Vec<u16> {
+        let mut pauses = Vec::new();
+        for _ in 0..steps {
+            if editor.should_break(cpu) {
+                pauses.push(cpu.pc());
+            }
+            // Resuming straight away, as if continued
+            cpu.step().unwrap();
+        }
+        pauses
+    }
+
+    #[test]
+    fn mem_dumps_rows() {
+        let mut machine = setup("");
+        machine.0.memory_mut()[0x300..0x304].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let dump = run(&mut machine, "mem 0x302 2").unwrap();
+        assert!(dump.starts_with("0300  DE AD BE EF 00"), "{}", dump);
+        assert_eq!(dump.lines().count(), 2);
+        assert_eq!(
+            run(&mut machine, "mem 0x1000"),
+            Err("0x1000 is outside memory".to_string())
+        );
+    }
+
+    #[test]
+    fn poke_only_writes_while_paused() {
+        let (mut cpu, mut instruments, mut editor) = setup("");
+
+        let running = editor.run_command(&mut cpu, &mut instruments, "poke 0x300 1 2", false);
+        assert_eq!(
+            running,
+            Err("Pause with P before poking memory".to_string())
+        );
+        assert_eq!(cpu.memory()[0x300], 0);
+
+        editor
+            .run_command(&mut cpu, &mut instruments, "poke 0x300 1 0xFF", true)
+            .unwrap();
+        assert_eq!(&cpu.memory()[0x300..0x302], [0x01, 0xFF]);
+
+        let mut machine = (cpu, instruments, editor);
+        assert_eq!(
+            run(&mut machine, "poke 0x300 0x100"),
+            Err("0x100 doesn't fit in a byte".to_string())
+        );
+        assert_eq!(
+            run(&mut machine, "poke 0x300"),
+            Err("Missing bytes to write".to_string())
+        );
+    }
+
+    #[test]
+    fn breakpoints_are_listed_and_deleted() {
+        let mut machine = setup("label 0x202 add");
+
+        assert_eq!(run(&mut machine, "break").unwrap(), "No breakpoints\n");
+        assert_eq!(
+            run(&mut machine, "break add if v0 == 3").unwrap(),
+            "Breakpoint #1 at 0x202 <add> if v0 == 3\n"
+        );
+        assert_eq!(
+            run(&mut machine, "b 0x204 log v0 is {v0}").unwrap(),
+            "Breakpoint #2 at 0x204 <add+0x2> log v0 is {v0}\n"
+        );
+        assert_eq!(
+            run(&mut machine, "break").unwrap(),
+            "Breakpoint #1 at 0x202 <add> if v0 == 3, hit 0 times\n\
+             Breakpoint #2 at 0x204 <add+0x2> log v0 is {v0}, hit 0 times\n"
+        );
+
+        run(&mut machine, "delete #2").unwrap();
+        assert_eq!(
+            run(&mut machine, "delete add+2"),
+            Err("No breakpoint at add+2".to_string())
+        );
+        run(&mut machine, "delete add").unwrap();
+        assert_eq!(run(&mut machine, "break").unwrap(), "No breakpoints\n");
+    }
+
+    #[test]
+    fn break_rejects_bad_arguments() {
+        let mut machine = setup("");
+
+        assert_eq!(
+            run(&mut machine, "break nowhere"),
+            Err("Unknown label nowhere".to_string())
+        );
+        assert_eq!(
+            run(&mut machine, "break 0x1000"),
+            Err("0x1000 is outside memory".to_string())
+        );
+        assert_eq!(
+            run(&mut machine, "break log v0 is {v0}"),
+            Err("Missing address".to_string())
+        );
+        assert!(run(&mut machine, "break 0x202 if v0 ==")
+            .unwrap_err()
+            .starts_with("Invalid condition"));
+    }
+
+    #[test]
+    fn breakpoints_pause_when_conditions_hold() {
+        let mut machine = setup("");
+        run(&mut machine, "break 0x202 if v0 == 3").unwrap();
+        // Without an address, pauses as the condition starts to hold
+        run(&mut machine, "break if v0 == 5").unwrap();
+        let (cpu, _, editor) = &mut machine;
+
+        // V0 is 1 at the first visit to 0x202, then 2, 3, 4...
+        assert_eq!(run_until_paused(cpu, editor, 9), [0x202, 0x204]);
+        assert!(run(&mut machine, "break").unwrap().contains("hit 4 times"));
+    }
+
+    #[test]
+    fn resuming_checks_the_other_breakpoints_at_pc() {
+        let mut machine = setup("");
+        run(&mut machine, "break 0x202").unwrap();
+        run(&mut machine, "break 0x202 log v0 is {v0}").unwrap();
+        run(&mut machine, "break 0x202 if v0 == 1").unwrap();
+        let (cpu, _, editor) = &mut machine;
+        cpu.step().unwrap();
+
+        // The first pauses, then resuming runs into the third
+        assert!(editor.should_break(cpu));
+        assert!(editor.should_break(cpu));
+        assert!(!editor.should_break(cpu));
+
+        let list = run(&mut machine, "break").unwrap();
+        let hits: Vec<&str> = list.lines().map(|line| &line[line.len() - 11..]).collect();
+        assert_eq!(hits, ["hit 1 times"; 3]);
+    }
+
+    #[test]
+    fn watches_are_added_and_removed() {
+        let mut machine = setup("");
+
+        assert_eq!(run(&mut machine, "watch").unwrap(), "No watches\n");
+        assert_eq!(
+            run(&mut machine, "watch pc").unwrap(),
+            "#1 pc = 512 (0x200)\n"
+        );
+        assert_eq!(run(&mut machine, "w v0 + 1").unwrap(), "#2 v0 + 1 = 1\n");
+        assert_eq!(
+            run(&mut machine, "watch").unwrap(),
+            "#1 pc = 512 (0x200)\n#2 v0 + 1 = 1\n"
+        );
+
+        run(&mut machine, "unwatch #1").unwrap();
+        assert_eq!(run(&mut machine, "watch").unwrap(), "#1 v0 + 1 = 1\n");
+        assert_eq!(
+            run(&mut machine, "unwatch 2"),
+            Err("No watch 2".to_string())
+        );
+    }
+
+    #[test]
+    fn search_narrows_down_candidates() {
+        let mut machine = setup("");
+
+        assert_eq!(
+            run(&mut machine, "search").unwrap(),
+            "Searching 4096 bytes\n"
+        );
+        machine.0.memory_mut()[0x300] = 7;
+        machine.0.memory_mut()[0x301] = 7;
+        assert!(run(&mut machine, "search changed")
+            .unwrap()
+            .starts_with("2 candidates\n"));
+
+        machine.0.memory_mut()[0x301] = 8;
+        assert_eq!(
+            run(&mut machine, "search up").unwrap(),
+            "1 candidates\n  0x301  0x08, was 0x07\n"
+        );
+        assert!(run(&mut machine, "search = 0x100").is_err());
+        assert!(run(&mut machine, "search sideways").is_err());
+    }
+
+    #[test]
+    fn freeze_holds_bytes() {
+        let mut machine = setup("label 0x300 lives");
+
+        assert_eq!(run(&mut machine, "freeze").unwrap(), "No frozen bytes\n");
+        run(&mut machine, "freeze lives+1 9").unwrap();
+        assert_eq!(machine.0.memory()[0x301], 9);
+        assert_eq!(
+            run(&mut machine, "freeze").unwrap(),
+            "0x301 <lives+0x1> = 0x09\n"
+        );
+        assert_eq!(
+            run(&mut machine, "freeze lives 0x100"),
+            Err("0x100 doesn't fit in a byte".to_string())
+        );
+
+        run(&mut machine, "unfreeze 0x301").unwrap();
+        assert_eq!(
+            run(&mut machine, "unfreeze 0x301"),
+            Err("0x301 isn't frozen".to_string())
+        );
+    }
+
+    #[test]
+    fn cheats_are_added_and_toggled() {
+        let mut machine = setup("");
+
+        assert_eq!(
+            run(&mut machine, "cheat").unwrap(),
+            "No cheats for this ROM\n"
+        );
+        assert_eq!(
+            run(&mut machine, "cheat add 3F0:09 Infinite lives").unwrap(),
+            "Added cheat 1\n"
+        );
+        run(&mut machine, "cheat off 1").unwrap();
+        assert_eq!(
+            run(&mut machine, "cheat").unwrap(),
+            "#1 off 3F0:09  Infinite lives\n"
+        );
+        assert_eq!(
+            run(&mut machine, "cheat on 2"),
+            Err("No cheat 2".to_string())
+        );
+        assert!(run(&mut machine, "cheat add nonsense").is_err());
+
+        run(&mut machine, "cheat delete #1").unwrap();
+        assert_eq!(machine.1.cheats().len(), 0);
+    }
+
+    #[test]
+    fn addresses_may_be_labels_with_offsets() {
+        let (_, _, editor) = setup("label 0x204 loop");
+
+        assert_eq!(editor.parse_address("loop", "mem"), Ok(0x204));
+        assert_eq!(editor.parse_address("loop+4", "mem"), Ok(0x208));
+        assert_eq!(editor.parse_address("loop+0x10", "mem"), Ok(0x214));
+        assert_eq!(editor.parse_address("0x300", "mem"), Ok(0x300));
+        assert_eq!(editor.parse_address("768", "mem"), Ok(768));
+        assert_eq!(
+            editor.parse_address("jump+4", "mem"),
+            Err("Unknown label jump".to_string())
+        );
+        assert_eq!(
+            editor.parse_address("loop+x", "mem"),
+            Err("Invalid number x for mem".to_string())
+        );
+    }
+
+    #[test]
+    fn keywords_split_on_whole_words() {
+        assert_eq!(
+            split_keyword("main if v0 == 1", "if"),
+            ("main", Some("v0 == 1"))
+        );
+        assert_eq!(split_keyword("if v0 == 1", "if"), ("", Some("v0 == 1")));
+        assert_eq!(split_keyword("main", "if"), ("main", None));
+        // Not inside a label
+        assert_eq!(split_keyword("diff if v0", "if"), ("diff", Some("v0")));
+        assert_eq!(
+            split_keyword("main log {v0} if odd", "log"),
+            ("main", Some("{v0} if odd"))
+        );
+        assert_eq!(split_keyword("main if", "if"), ("main", Some("")));
+    }
+}
@@ -0,0 +1,1225 @@
+//! An assembler for Octo, the language most CHIP-8 programs are now written
+//! in.
+//!
+//! Octo source is a stream of tokens separated by whitespace, with `#`
+//! starting a comment. Instructions are written as assignments and
+//! structured control flow, and bare numbers are written out as bytes, which
+//! is how sprites are drawn:
+//!
+//! ```text
+//! : main
+//!   i := ball
+//!   loop
+//!     sprite v0 v1 4
+//!     v0 += 1
+//!     if v0 == 60 then v0 := 0
+//!   again
+//!
+//! : ball 0x60 0xF0 0xF0 0x60
+//! ```
+//!
+//! This supports labels (`:` and `:next`), `:const`, `:alias`, `:calc`,
+//! `:macro`, `:byte`, `:org`, `:pointer`, `:call` and `:unpack`, `if ... then`
+//! and `if ... begin ... else ... end`, and `loop ... while ... again`. Of
+//! the SCHIP and XO-CHIP instructions only `exit` and `i := bighex` are
+//! assembled, the others are rejected as the interpreter doesn't run them.
+//! A label on its own calls it as a subroutine. As in Octo, programs start
+//! at `main` when they have one, with a jump to it at the load address
+//! unless it comes first, and `:calc` evaluates right to left without
+//! operator precedence, so `2 * x + 1` is `2 * (x + 1)`.
+
+use crate::symbols::{SourceLine, Symbols};
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::f64::consts;
+use std::fmt;
+use std::path::Path;
+
+// The most memory a layout allows
+const ADDRESS_SPACE: usize = 0x10000;
+
+// Stops a macro that expands into itself
+const MAX_EXPANSIONS: usize = 100_000;
+
+// Words that can't be used as names
+const KEYWORDS: &str = "
+    := += -= =- |= &= ^= >>= <<= == != < > <= >= key -key hex bighex random delay buzzer
+    pitch long i if then begin else end loop again while return clear bcd save load saveflags
+    loadflags sprite jump jump0 native hires lores exit scroll-down scroll-up scroll-left
+    scroll-right audio plane { } ;
+";
+
+const UNARY_OPERATORS: [&str; 14] = [
+    "-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor", "@",
+];
+
+const BINARY_OPERATORS: [&str; 19] = [
+    "-", "+", "*", "/", "%", "&", "|", "^", "<<", ">>", "pow", "min", "max", "<", "<=", "==", "!=",
+    ">=", ">",
+];
+
+/// Errors raised while assembling Octo source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AssembleError {
+    /// A statement couldn't be assembled.
+    Invalid { line: usize, message: String },
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssembleError::Invalid { line, message } => {
+                write!(f, "Source line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl error::Error for AssembleError {}
+
+/// An assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The ROM image, to be loaded at the address it was assembled for.
+    pub rom: Vec<u8>,
+    /// The program's labels, and the source line of each instruction.
+    pub symbols: Symbols,
+}
+
+/// Whether `path` names Octo source, going by its `.8o` extension.
+pub fn is_source(path: &Path) -> bool {
+    matches!(path.extension(), Some(extension) if extension.eq_ignore_ascii_case("8o"))
+}
+
+/// Assembles `source` into a ROM to be loaded at `origin`. Source lines in
+/// the program's symbols are given as being in `file`.
+pub fn assemble(source: &str, file: &str, origin: u16) -> Result<Program, AssembleError> {
+    Assembler::new(source, file, origin).run()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+// Splits source into tokens, with braces and parentheses standing alone even
+// without spaces around them
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        let mut push = |text: &str| {
+            tokens.push_back(Token {
+                text: text.to_string(),
+                line: index + 1,
+            })
+        };
+
+        for word in code.split_whitespace() {
+            let mut start = 0;
+            for (offset, c) in word.char_indices() {
+                if "{}()".contains(c) {
+                    if start < offset {
+                        push(&word[start..offset]);
+                    }
+                    push(&word[offset..offset + 1]);
+                    start = offset + 1;
+                }
+            }
+            if start < word.len() {
+                push(&word[start..]);
+            }
+        }
+    }
+
+    tokens
+}
+
+// A decimal, 0x hex or 0b binary literal, optionally negative
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value } as f64)
+}
+
+// v0 to vF
+fn register_number(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    match digit.len() {
+        1 => u8::from_str_radix(digit, 16).ok(),
+        _ => None,
+    }
+}
+
+// Whether `text` could name a label, constant or macro
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == ':')
+        && !KEYWORDS.split_whitespace().any(|keyword| keyword == text)
+}
+
+// Where a reference to a label is written
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    // The low 12 bits of an instruction
+    Address,
+    // Two bytes
+    Wide,
+    // The low nibble of a byte, holding the top of a 12 bit address
+    HighNibble,
+    LowByte,
+}
+
+// A reference to a label that hadn't been defined yet
+struct Fixup {
+    at: usize,
+    field: Field,
+    name: String,
+    line: usize,
+}
+
+// Control flow waiting to be closed, to patch its jumps
+enum Block {
+    // The jump past an if's body or else branch
+    Branch {
+        jump: usize,
+        has_else: bool,
+        line: usize,
+    },
+    // Where again jumps back to, and the jumps out of each while
+    Loop {
+        start: usize,
+        exits: Vec<usize>,
+        line: usize,
+    },
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+// How a condition compares its register
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+    None,
+}
+
+struct Assembler<'a> {
+    file: &'a str,
+    origin: usize,
+    tokens: VecDeque<Token>,
+    // Line of the statement being assembled, for errors
+    line: usize,
+
+    memory: Vec<u8>,
+    written: Vec<bool>,
+    here: usize,
+    // One past the highest address written
+    end: usize,
+
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    // Whether the program has a main that the first code or data doesn't start
+    jump_to_main: bool,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    symbols: Symbols,
+}
+
+impl<'a> Assembler<'a> {
+    fn new(source: &str, file: &'a str, origin: u16) -> Assembler<'a> {
+        Assembler {
+            file,
+            origin: origin as usize,
+            tokens: tokenize(source),
+            line: 1,
+            memory: vec![0; ADDRESS_SPACE],
+            written: vec![false; ADDRESS_SPACE],
+            here: origin as usize,
+            end: origin as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            jump_to_main: false,
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            symbols: Symbols::default(),
+        }
+    }
+
+    fn run(mut self) -> Result<Program, AssembleError> {
+        let tokens = &self.tokens;
+        self.jump_to_main = tokens
+            .iter()
+            .zip(tokens.iter().skip(1))
+            .any(|(a, b)| a.text == ":" && b.text == "main");
+
+        while let Some(token) = self.tokens.pop_front() {
+            self.line = token.line;
+            self.statement(&token.text)?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            let (line, message) = match block {
+                Block::Branch { line, .. } => (*line, "This if begin is missing its end"),
+                Block::Loop { line, .. } => (*line, "This loop is missing its again"),
+            };
+            return Err(AssembleError::Invalid {
+                line,
+                message: message.to_string(),
+            });
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let address = match self.labels.get(&fixup.name) {
+                Some(&address) => address,
+                None => return Err(self.error(format!("Undefined name {}", fixup.name))),
+            };
+            self.patch(fixup.at, fixup.field, address as f64)?;
+        }
+
+        Ok(Program {
+            rom: self.memory[self.origin..self.end.max(self.origin)].to_vec(),
+            symbols: self.symbols,
+        })
+    }
+
+    fn error(&self, message: String) -> AssembleError {
+        AssembleError::Invalid {
+            line: self.line,
+            message,
+        }
+    }
+
+    // An SCHIP or XO-CHIP instruction the interpreter has no opcode for
+    fn unsupported(&self, instruction: &str) -> AssembleError {
+        self.error(format!(
+            "{} is an SCHIP or XO-CHIP instruction, which the interpreter doesn't run",
+            instruction
+        ))
+    }
+
+    fn next(&mut self) -> Result<String, AssembleError> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token.text),
+            None => Err(self.error("Unexpected end of source".to_string())),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), AssembleError> {
+        match self.next()? {
+            token if token == text => Ok(()),
+            token => Err(self.error(format!("Expected {}, found {}", text, token))),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn statement(&mut self, token: &str) -> Result<(), AssembleError> {
+        match token {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(&name, 0)
+            }
+            ":next" => {
+                // Names the second byte of the next instruction, to modify it
+                let name = self.name()?;
+                self.define_label(&name, 1)
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.known_value(&value)?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    self.next()?;
+                    let value = self.calc()?;
+                    self.fit_byte(value)?
+                } else {
+                    self.byte()?
+                };
+                self.emit(byte)
+            }
+            ":org" => {
+                let value = self.next()?;
+                let value = self.known_value(&value)?;
+                if !(0.0..ADDRESS_SPACE as f64).contains(&value) {
+                    return Err(self.error(format!("{} is outside memory", value)));
+                }
+                self.here = value as usize;
+                Ok(())
+            }
+            ":pointer" => {
+                let name = self.next()?;
+                let at = self.here;
+                self.emit(0)?;
+                self.emit(0)?;
+                self.refer(&name, Field::Wide, at)
+            }
+            ":call" => {
+                let name = self.next()?;
+                self.jump(0x2000, &name)
+            }
+            ":unpack" => {
+                // v0 := nibble and the top of the address, v1 := the rest
+                let nibble = self.nibble()?;
+                let name = self.next()?;
+                let at = self.here;
+                self.instruction(0x6000 | (nibble as u16) << 4)?;
+                self.instruction(0x6100)?;
+                self.refer(&name, Field::HighNibble, at + 1)?;
+                self.refer(&name, Field::LowByte, at + 3)
+            }
+            ":breakpoint" | ":monitor" => {
+                // Octo's debugger commands. Set breakpoints with the monitor
+                // or a debugger instead
+                self.next()?;
+                if token == ":monitor" {
+                    self.next()?;
+                }
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            "return" | ";" => self.instruction(0x00EE),
+            "clear" => self.instruction(0x00E0),
+            "scroll-down" | "scroll-up" | "scroll-right" | "scroll-left" | "lores" | "hires"
+            | "audio" | "plane" | "saveflags" | "loadflags" | "pitch" => {
+                Err(self.unsupported(token))
+            }
+            "exit" => self.instruction(0x00FD),
+            "bcd" => self.register_instruction(0xF033),
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                if self.peek() == Some("-") {
+                    return Err(self.unsupported(&format!("{} with a range of registers", token)));
+                }
+                let opcode = if token == "save" { 0xF055 } else { 0xF065 };
+                self.instruction(opcode | x << 8)
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()? as u16;
+                self.instruction(0xD000 | x << 8 | y << 4 | n)
+            }
+            "jump" | "jump0" | "native" => {
+                let name = self.next()?;
+                let opcode = match token {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.jump(opcode, &name)
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let opcode = if token == "delay" { 0xF015 } else { 0xF018 };
+                self.register_instruction(opcode)
+            }
+            "i" => self.assign_i(),
+            "if" => self.conditional(),
+            "else" => match self.blocks.pop() {
+                Some(Block::Branch {
+                    jump,
+                    has_else: false,
+                    line,
+                }) => {
+                    let skip = self.here;
+                    self.instruction(0x1000)?;
+                    self.patch(jump, Field::Address, self.here as f64)?;
+                    self.blocks.push(Block::Branch {
+                        jump: skip,
+                        has_else: true,
+                        line,
+                    });
+                    Ok(())
+                }
+                _ => Err(self.error("else without if begin".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::Branch { jump, .. }) => {
+                    self.patch(jump, Field::Address, self.here as f64)
+                }
+                _ => Err(self.error("end without if begin".to_string())),
+            },
+            "loop" => {
+                self.start(None)?;
+                self.blocks.push(Block::Loop {
+                    start: self.here,
+                    exits: Vec::new(),
+                    line: self.line,
+                });
+                Ok(())
+            }
+            "while" => {
+                let condition = self.condition()?;
+                // Skip the jump out while the condition holds
+                self.skip_unless(condition, true)?;
+                let exit = self.here;
+                self.instruction(0x1000)?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| matches!(block, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { exits, .. }) => {
+                        exits.push(exit);
+                        Ok(())
+                    }
+                    _ => Err(self.error("while outside a loop".to_string())),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits, .. }) => {
+                    let at = self.here;
+                    self.instruction(0x1000)?;
+                    self.patch(at, Field::Address, start as f64)?;
+                    for exit in exits {
+                        self.patch(exit, Field::Address, self.here as f64)?;
+                    }
+                    Ok(())
+                }
+                _ => Err(self.error("again without loop".to_string())),
+            },
+            _ => {
+                if let Some(x) = self.register_named(token) {
+                    self.assign_register(x)
+                } else if self.macros.contains_key(token) {
+                    self.expand_macro(token)
+                } else if let Some(value) =
+                    number(token).or_else(|| self.constants.get(token).copied())
+                {
+                    // Bare numbers are data, such as sprites, and bare labels calls
+                    let byte = self.fit_byte(value)?;
+                    self.emit(byte)
+                } else if is_name(token) {
+                    self.jump(0x2000, token)
+                } else {
+                    Err(self.error(format!("Unexpected {}", token)))
+                }
+            }
+        }
+    }
+
+    fn assign_register(&mut self, x: u8) -> Result<(), AssembleError> {
+        let x = x as u16;
+        let operator = self.next()?;
+        let source = self.next()?;
+        let y = self.register_named(&source).map(u16::from);
+
+        // Operators that only take a register
+        let logic = match operator.as_str() {
+            "|=" => Some(0x8001),
+            "&=" => Some(0x8002),
+            "^=" => Some(0x8003),
+            "=-" => Some(0x8007),
+            ">>=" => Some(0x8006),
+            "<<=" => Some(0x800E),
+            _ => None,
+        };
+        if let Some(opcode) = logic {
+            let y =
+                y.ok_or_else(|| self.error(format!("Expected a register, found {}", source)))?;
+            return self.instruction(opcode | x << 8 | y << 4);
+        }
+
+        match (operator.as_str(), y) {
+            (":=", Some(y)) => self.instruction(0x8000 | x << 8 | y << 4),
+            ("+=", Some(y)) => self.instruction(0x8004 | x << 8 | y << 4),
+            ("-=", Some(y)) => self.instruction(0x8005 | x << 8 | y << 4),
+            (":=", None) => match source.as_str() {
+                "random" => {
+                    let mask = self.byte()?;
+                    self.instruction(0xC000 | x << 8 | mask as u16)
+                }
+                "delay" => self.instruction(0xF007 | x << 8),
+                "key" => self.instruction(0xF00A | x << 8),
+                _ => {
+                    let byte = self.byte_named(&source)?;
+                    self.instruction(0x6000 | x << 8 | byte as u16)
+                }
+            },
+            ("+=", None) => {
+                let byte = self.byte_named(&source)?;
+                self.instruction(0x7000 | x << 8 | byte as u16)
+            }
+            ("-=", None) => {
+                let byte = self.byte_named(&source)?;
+                self.instruction(0x7000 | x << 8 | byte.wrapping_neg() as u16)
+            }
+            _ => Err(self.error(format!("Unknown operator {}", operator))),
+        }
+    }
+
+    fn assign_i(&mut self) -> Result<(), AssembleError> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" => match self.next()?.as_str() {
+                "hex" => self.register_instruction(0xF029),
+                "bighex" => self.register_instruction(0xF030),
+                "long" => Err(self.unsupported("i := long")),
+                name => {
+                    let name = name.to_string();
+                    self.jump(0xA000, &name)
+                }
+            },
+            "+=" => self.register_instruction(0xF01E),
+            _ => Err(self.error(format!("Unknown operator {}", operator))),
+        }
+    }
+
+    fn conditional(&mut self) -> Result<(), AssembleError> {
+        let condition = self.condition()?;
+
+        match self.next()?.as_str() {
+            "then" => self.skip_unless(condition, false),
+            "begin" => {
+                // Skip the jump past the body when the condition holds
+                self.skip_unless(condition, true)?;
+                self.blocks.push(Block::Branch {
+                    jump: self.here,
+                    has_else: false,
+                    line: self.line,
+                });
+                self.instruction(0x1000)
+            }
+            token => Err(self.error(format!("Expected then or begin, found {}", token))),
+        }
+    }
+
+    // A register, comparison and operand, e.g. v0 != 5 or v3 -key
+    fn condition(&mut self) -> Result<(u8, String, Operand), AssembleError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+
+        let operand = match operator.as_str() {
+            "key" | "-key" => Operand::None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let token = self.next()?;
+                match self.register_named(&token) {
+                    Some(y) => Operand::Register(y),
+                    None => Operand::Byte(self.byte_named(&token)?),
+                }
+            }
+            _ => return Err(self.error(format!("Unknown comparison {}", operator))),
+        };
+
+        Ok((x, operator, operand))
+    }
+
+    // Skips the next instruction unless the condition holds, or if it holds
+    // when `negate` is set. Ordering comparisons go through vf
+    fn skip_unless(
+        &mut self,
+        (x, operator, operand): (u8, String, Operand),
+        negate: bool,
+    ) -> Result<(), AssembleError> {
+        let operator = match (operator.as_str(), negate) {
+            (operator, false) => operator,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("key", true) => "-key",
+            ("-key", true) => "key",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            (_, true) => ">",
+        };
+        // < and > hold when vf is left clear, see below
+        let vf_skip = if matches!(operator, "<" | ">") {
+            0x4F00
+        } else {
+            0x3F00
+        };
+        let x = x as u16;
+
+        match (operator, operand) {
+            ("==", Operand::Register(y)) => self.instruction(0x9000 | x << 8 | (y as u16) << 4),
+            ("!=", Operand::Register(y)) => self.instruction(0x5000 | x << 8 | (y as u16) << 4),
+            ("==", Operand::Byte(byte)) => self.instruction(0x4000 | x << 8 | byte as u16),
+            ("!=", Operand::Byte(byte)) => self.instruction(0x3000 | x << 8 | byte as u16),
+            // The ordering comparisons leave vf set when x >= y for < and >=,
+            // or when y >= x for > and <=
+            (operator, Operand::Register(y)) => {
+                let y = y as u16;
+                let (first, second) = if matches!(operator, "<" | ">=") {
+                    (x, y)
+                } else {
+                    (y, x)
+                };
+                self.instruction(0x8F00 | first << 4)?;
+                self.instruction(0x8F05 | second << 4)?;
+                self.instruction(vf_skip)
+            }
+            (operator, Operand::Byte(byte)) => {
+                let subtract = if matches!(operator, "<" | ">=") {
+                    0x8F07
+                } else {
+                    0x8F05
+                };
+                self.instruction(0x6F00 | byte as u16)?;
+                self.instruction(subtract | x << 4)?;
+                self.instruction(vf_skip)
+            }
+            ("key", Operand::None) => self.instruction(0xE0A1 | x << 8),
+            (_, Operand::None) => self.instruction(0xE09E | x << 8),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssembleError> {
+        let line = self.line;
+        let name = self.name()?;
+        let mut parameters = Vec::new();
+        loop {
+            match self.next()? {
+                token if token == "{" => break,
+                token => parameters.push(token),
+            }
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.tokens.pop_front().ok_or(AssembleError::Invalid {
+                line,
+                message: format!("Macro {} is missing its closing }}", name),
+            })?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), AssembleError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(format!(
+                "Too many macro expansions, does {} use itself?",
+                name
+            )));
+        }
+
+        let count = self.macros[name].parameters.len();
+        let mut arguments = HashMap::new();
+        for index in 0..count {
+            let argument = self.next()?;
+            arguments.insert(self.macros[name].parameters[index].clone(), argument);
+        }
+
+        // The expansion counts as being on the line that used the macro
+        for token in self.macros[name].body.iter().rev() {
+            let text = arguments.get(&token.text).unwrap_or(&token.text);
+            self.tokens.push_front(Token {
+                text: text.clone(),
+                line: self.line,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Evaluates a :calc expression after its opening brace
+    fn calc(&mut self) -> Result<f64, AssembleError> {
+        let line = self.line;
+        let mut tokens = Vec::new();
+        loop {
+            match self.tokens.pop_front() {
+                Some(token) if token.text == "}" => break,
+                Some(token) => tokens.push(token.text),
+                None => {
+                    return Err(AssembleError::Invalid {
+                        line,
+                        message: "Expression is missing its closing }".to_string(),
+                    })
+                }
+            }
+        }
+
+        let mut position = 0;
+        let value = self.expression(&tokens, &mut position)?;
+        match tokens.get(position) {
+            None => Ok(value),
+            Some(token) => Err(self.error(format!("Unexpected {} in expression", token))),
+        }
+    }
+
+    fn expression(&self, tokens: &[String], position: &mut usize) -> Result<f64, AssembleError> {
+        let left = self.term(tokens, position)?;
+
+        match tokens.get(*position) {
+            Some(operator) if BINARY_OPERATORS.contains(&operator.as_str()) => {
+                *position += 1;
+                let right = self.expression(tokens, position)?;
+                Ok(binary(operator, left, right))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn term(&self, tokens: &[String], position: &mut usize) -> Result<f64, AssembleError> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| self.error("Incomplete expression".to_string()))?;
+        *position += 1;
+
+        if token == "(" {
+            let value = self.expression(tokens, position)?;
+            if tokens.get(*position).map(String::as_str) != Some(")") {
+                return Err(self.error("Expected ) in expression".to_string()));
+            }
+            *position += 1;
+            return Ok(value);
+        }
+        if UNARY_OPERATORS.contains(&token.as_str()) {
+            let value = self.term(tokens, position)?;
+            return Ok(match token.as_str() {
+                "@" => match self.memory.get(value as usize) {
+                    Some(&byte) if value >= 0.0 => byte as f64,
+                    _ => 0.0,
+                },
+                operator => unary(operator, value),
+            });
+        }
+
+        match self.value(token) {
+            Some(value) => Ok(value),
+            None => match token.as_str() {
+                "HERE" => Ok(self.here as f64),
+                "PI" => Ok(consts::PI),
+                "E" => Ok(consts::E),
+                _ => Err(self.unknown(token)),
+            },
+        }
+    }
+
+    // A number, constant or label that's already been defined
+    fn value(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|&address| address as f64))
+    }
+
+    fn known_value(&self, text: &str) -> Result<f64, AssembleError> {
+        self.value(text).ok_or_else(|| self.unknown(text))
+    }
+
+    fn unknown(&self, text: &str) -> AssembleError {
+        if is_name(text) {
+            self.error(format!("Undefined name {}", text))
+        } else {
+            self.error(format!("Expected a number, found {}", text))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.byte_named(&token)
+    }
+
+    fn byte_named(&self, text: &str) -> Result<u8, AssembleError> {
+        let value = self.known_value(text)?;
+        self.fit_byte(value)
+    }
+
+    fn fit_byte(&self, value: f64) -> Result<u8, AssembleError> {
+        match value.floor() {
+            value if (-128.0..=255.0).contains(&value) => Ok(value as i16 as u8),
+            _ => Err(self.error(format!("{} doesn't fit in a byte", value))),
+        }
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        match self.known_value(&token)?.floor() {
+            value if (0.0..=15.0).contains(&value) => Ok(value as u8),
+            _ => Err(self.error(format!("{} doesn't fit in 4 bits", token))),
+        }
+    }
+
+    fn register_named(&self, text: &str) -> Option<u8> {
+        self.aliases
+            .get(text)
+            .copied()
+            .or_else(|| register_number(text))
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.register_named(&token)
+            .ok_or_else(|| self.error(format!("Expected a register, found {}", token)))
+    }
+
+    fn name(&mut self) -> Result<String, AssembleError> {
+        let token = self.next()?;
+        // Aliases may be given again, to point at another register
+        if !is_name(&token) || register_number(&token).is_some() {
+            return Err(self.error(format!("{} can't be used as a name", token)));
+        }
+
+        Ok(token)
+    }
+
+    // Names the address `offset` bytes on from here
+    fn define_label(&mut self, name: &str, offset: usize) -> Result<(), AssembleError> {
+        if self.labels.contains_key(name) {
+            return Err(self.error(format!("Label {} is defined twice", name)));
+        }
+        self.start(Some(name).filter(|_| offset == 0))?;
+
+        let address = self.here + offset;
+        if address >= ADDRESS_SPACE {
+            return Err(self.error(format!("Label {} is outside memory", name)));
+        }
+
+        self.labels.insert(name.to_string(), address as u16);
+        if self.symbols.label(address as u16).is_none() {
+            self.symbols.add_label(address as u16, name);
+        }
+        Ok(())
+    }
+
+    // An instruction whose low 12 bits are the address of `name`
+    fn jump(&mut self, opcode: u16, name: &str) -> Result<(), AssembleError> {
+        let at = self.here;
+        self.instruction(opcode)?;
+        self.refer(name, Field::Address, at)
+    }
+
+    // An instruction taking a register in its second nibble
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), AssembleError> {
+        let x = self.register()? as u16;
+        self.instruction(opcode | x << 8)
+    }
+
+    fn instruction(&mut self, opcode: u16) -> Result<(), AssembleError> {
+        if self.here < ADDRESS_SPACE {
+            let source = SourceLine {
+                file: self.file.to_string(),
+                line: self.line as u32,
+            };
+            self.symbols.add_line(self.here as u16, source);
+        }
+
+        self.word(opcode)
+    }
+
+    fn word(&mut self, word: u16) -> Result<(), AssembleError> {
+        self.emit((word >> 8) as u8)?;
+        self.emit(word as u8)
+    }
+
+    // Execution starts at main, so jump there from the load address unless
+    // main is the first label or byte placed there
+    fn start(&mut self, label: Option<&str>) -> Result<(), AssembleError> {
+        if !self.jump_to_main {
+            return Ok(());
+        }
+        self.jump_to_main = false;
+        if label == Some("main") && self.here == self.origin {
+            return Ok(());
+        }
+
+        let here = self.here;
+        self.here = self.origin;
+        self.word(0x1000)?;
+        self.refer("main", Field::Address, self.origin)?;
+        // After an :org, carry on from where it set
+        if here != self.origin {
+            self.here = here;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), AssembleError> {
+        self.start(None)?;
+        if self.here < self.origin {
+            return Err(self.error(format!(
+                "{:#05X} is before the load address {:#05X}",
+                self.here, self.origin
+            )));
+        }
+        if self.here >= ADDRESS_SPACE {
+            return Err(self.error("The program doesn't fit in memory".to_string()));
+        }
+        if self.written[self.here] {
+            return Err(self.error(format!("{:#05X} has already been assembled", self.here)));
+        }
+
+        self.memory[self.here] = byte;
+        self.written[self.here] = true;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    // Writes the address of `name` at `at` now, or once it's defined
+    fn refer(&mut self, name: &str, field: Field, at: usize) -> Result<(), AssembleError> {
+        match self.value(name) {
+            Some(value) => self.patch(at, field, value),
+            None if is_name(name) => {
+                self.fixups.push(Fixup {
+                    at,
+                    field,
+                    name: name.to_string(),
+                    line: self.line,
+                });
+                Ok(())
+            }
+            None => Err(self.error(format!("Expected an address, found {}", name))),
+        }
+    }
+
+    fn patch(&mut self, at: usize, field: Field, value: f64) -> Result<(), AssembleError> {
+        let value = value.floor();
+        let limit = match field {
+            Field::Address | Field::HighNibble => 0xFFF,
+            Field::Wide | Field::LowByte => 0xFFFF,
+        };
+        if !(0.0..=limit as f64).contains(&value) {
+            let bits = if limit == 0xFFF { 12 } else { 16 };
+            return Err(self.error(format!("Address {} doesn't fit in {} bits", value, bits)));
+        }
+
+        let value = value as u16;
+        let [high, low] = value.to_be_bytes();
+        match field {
+            Field::Address => {
+                self.memory[at] = self.memory[at] & 0xF0 | high;
+                self.memory[at + 1] = low;
+            }
+            Field::Wide => {
+                self.memory[at] = high;
+                self.memory[at + 1] = low;
+            }
+            Field::HighNibble => self.memory[at] = self.memory[at] & 0xF0 | high,
+            Field::LowByte => self.memory[at] = low,
+        }
+        Ok(())
+    }
+}
+
+fn unary(operator: &str, value: f64) -> f64 {
+    match operator {
+        "-" => -value,
+        "~" => !(value as i64) as f64,
+        "!" => (value == 0.0) as u8 as f64,
+        "sin" => value.sin(),
+        "cos" => value.cos(),
+        "tan" => value.tan(),
+        "exp" => value.exp(),
+        "log" => value.ln(),
+        "abs" => value.abs(),
+        "sqrt" => value.sqrt(),
+        "sign" => value.signum(),
+        "ceil" => value.ceil(),
+        _ => value.floor(),
+    }
+}
+
+fn binary(operator: &str, left: f64, right: f64) -> f64 {
+    let (a, b) = (left as i64, right as i64);
+    let truth = |holds: bool| holds as u8 as f64;
+
+    match operator {
+        "-" => left - right,
+        "+" => left + right,
+        "*" => left * right,
+        "/" => left / right,
+        "%" => left % right,
+        "&" => (a & b) as f64,
+        "|" => (a | b) as f64,
+        "^" => (a ^ b) as f64,
+        "<<" => a.wrapping_shl(b as u32) as f64,
+        ">>" => a.wrapping_shr(b as u32) as f64,
+        "pow" => left.powf(right),
+        "min" => left.min(right),
+        "max" => left.max(right),
+        "<" => truth(left < right),
+        "<=" => truth(left <= right),
+        "==" => truth(left == right),
+        "!=" => truth(left != right),
+        ">=" => truth(left >= right),
+        _ => truth(left > right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Exercises constants, aliases, macros, calls and every kind of block
+    const SOURCE: &str = "\
+:const SPEED 2
+:alias x v1
+:calc WIDTH { 2 * SPEED + 1 }     # right to left, so 6
+
+:macro bump register amount { register += amount }
+
+: draw
+  i := ball
+  sprite x v2 4
+;
+
+: main
+  x := 0
+  loop
+    bump x SPEED
+    if x == 60 then x := 0
+    if x < v2 begin
+      draw
+    else
+      vf := key
+    end
+    while v3 != 1
+  again
+
+: ball
+  0b01100000 0xF0 0xF0 0x60
+:byte { WIDTH }
+";
+
+    fn error(source: &str) -> String {
+        assemble(source, "bad.8o", 0x200).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn assembles_octo_programs() {
+        let program = assemble(SOURCE, "ball.8o", 0x200).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x12, 0x08,             // jump main
+            0xA2, 0x24,             // draw: i := ball
+            0xD1, 0x24,             // sprite x v2 4
+            0x00, 0xEE,             // ;
+            0x61, 0x00,             // main: x := 0
+            0x71, 0x02,             // loop, bump x SPEED
+            0x41, 0x3C, 0x61, 0x00, // if x == 60 then x := 0
+            0x8F, 0x10, 0x8F, 0x25, // if x < v2: vf := x, vf -= v2
+            0x3F, 0x00, 0x12, 0x1C, // skip the jump to else when vf is clear
+            0x22, 0x02,             // draw
+            0x12, 0x1E,             // else, jump to end
+            0xFF, 0x0A,             // vf := key
+            0x43, 0x01, 0x12, 0x24, // while v3 != 1, skip the jump out
+            0x12, 0x0A,             // again
+            0x60, 0xF0, 0xF0, 0x60, // ball
+            0x06,                   // WIDTH
+        ];
+        assert_eq!(program.rom, expected);
+    }
+
+    #[test]
+    fn constants_and_aliases_are_substituted() {
+        let program = assemble(":const N 5\n:alias x v1\nx := N\n", "a.8o", 0x200).unwrap();
+        assert_eq!(program.rom, [0x61, 0x05]);
+    }
+
+    #[test]
+    fn calc_evaluates_right_to_left() {
+        let program = assemble(":calc W { 2 * 3 + 1 }\nv0 := W\n", "a.8o", 0x200).unwrap();
+        assert_eq!(program.rom, [0x60, 0x08]);
+    }
+
+    #[test]
+    fn labels_become_symbols() {
+        let symbols = assemble(SOURCE, "ball.8o", 0x200).unwrap().symbols;
+        assert_eq!(symbols.label(0x208), Some("main"));
+        assert_eq!(symbols.address_of("ball"), Some(0x224));
+        assert_eq!(&Symbols::parse(&symbols.to_text()).unwrap(), &symbols);
+    }
+
+    #[test]
+    fn instructions_map_to_their_source_line() {
+        let symbols = assemble(SOURCE, "ball.8o", 0x200).unwrap().symbols;
+        // Macros count as the line using them
+        assert_eq!(
+            symbols.source_line(0x20A).unwrap().to_string(),
+            "ball.8o:15"
+        );
+        // Data has no line
+        assert_eq!(symbols.source_line(0x224), None);
+    }
+
+    #[test]
+    fn names_must_be_defined() {
+        assert_eq!(
+            error(": main\n  jump done\n"),
+            "Source line 2: Undefined name done"
+        );
+        assert_eq!(error(": v3"), "Source line 1: v3 can't be used as a name");
+    }
+
+    #[test]
+    fn blocks_must_be_closed() {
+        assert_eq!(
+            error("loop\n  v0 += 1\n"),
+            "Source line 1: This loop is missing its again"
+        );
+    }
+
+    #[test]
+    fn values_must_fit() {
+        assert_eq!(
+            error("v0 := 256"),
+            "Source line 1: 256 doesn't fit in a byte"
+        );
+    }
+
+    #[test]
+    fn extended_instructions_are_rejected() {
+        assert_eq!(
+            error(": main\n  save v0 - v3\n"),
+            "Source line 2: save with a range of registers is an SCHIP or XO-CHIP \
+             instruction, which the interpreter doesn't run"
+        );
+    }
+}
@@ -0,0 +1,406 @@
+//! Applying IPS and BPS patches to ROM images.
+//!
+//! Fixes and translations of ROMs are usually shared as patches against the
+//! original file. The format is recognised by the patch's magic bytes, and
+//! BPS patches are only applied to the ROM they were made from: their CRC-32s
+//! of the original ROM, the patched ROM and the patch itself must all match.
+//!
+//! Patches are applied to the ROM's bytes before it's loaded into memory, so
+//! offsets in the patch are from the start of the ROM file rather than
+//! addresses in memory.
+
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+// Extensions looked for next to a ROM, in order of preference
+const PATCH_EXTENSIONS: [&str; 2] = ["bps", "ips"];
+
+// The largest memory a layout allows, so no ROM can be bigger. Stops a
+// corrupt patch from growing a ROM without bound
+const MAX_PATCHED_SIZE: usize = 0x10000;
+
+/// Errors raised while applying a patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PatchError {
+    /// The patch is neither IPS nor BPS.
+    UnknownFormat,
+    /// The patch ended in the middle of a record.
+    Truncated,
+    /// The patch describes a ROM too big for memory.
+    TooLarge(usize),
+    /// A BPS patch read or copied outside the ROM it's building from.
+    OutOfBounds,
+    /// A BPS patch was made for a ROM of a different size.
+    SizeMismatch { expected: usize, actual: usize },
+    /// A BPS checksum didn't match, `of` naming what was checked.
+    ChecksumMismatch {
+        of: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::TooLarge(size) => {
+                write!(
+                    f,
+                    "Patched ROM would be {} bytes, too large for memory",
+                    size
+                )
+            }
+            PatchError::OutOfBounds => write!(f, "Patch reads outside the ROM"),
+            PatchError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Patch is for a {} byte ROM but this one is {} bytes",
+                expected, actual
+            ),
+            PatchError::ChecksumMismatch {
+                of,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "CRC-32 of the {} is {:08X}, the patch expects {:08X}",
+                of, actual, expected
+            ),
+        }
+    }
+}
+
+impl error::Error for PatchError {}
+
+/// Returns `rom` with `patch` applied, working out the patch's format from
+/// its magic bytes.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, &patch[IPS_MAGIC.len()..])
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Finds a patch next to the ROM at `rom_path`, with the same name but a
+/// `.bps` or `.ips` extension, so `pong.ch8` is patched by `pong.bps`.
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+// Reads a patch from start to end, failing if it runs out
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, offset: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset == self.data.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        let end = self
+            .offset
+            .checked_add(count)
+            .ok_or(PatchError::Truncated)?;
+        let bytes = self
+            .data
+            .get(self.offset..end)
+            .ok_or(PatchError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    // A big endian number `count` bytes long, as used by IPS
+    fn big_endian(&mut self, count: usize) -> Result<usize, PatchError> {
+        let bytes = self.bytes(count)?;
+        Ok(bytes.iter().fold(0, |n, &byte| n << 8 | byte as usize))
+    }
+
+    // A BPS variable length number: seven bits per byte, least significant
+    // first, with the top bit marking the last byte
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut number: usize = 0;
+        let mut shift: usize = 1;
+
+        loop {
+            let byte = self.bytes(1)?[0];
+            let digit = ((byte & 0x7F) as usize).checked_mul(shift);
+            number = digit
+                .and_then(|digit| number.checked_add(digit))
+                .ok_or(PatchError::Truncated)?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Truncated)?;
+            number = number.checked_add(shift).ok_or(PatchError::Truncated)?;
+        }
+    }
+}
+
+// An IPS patch after its magic: records of a 3 byte offset and 2 byte length
+// followed by the bytes to write, or a zero length, 2 byte count and a byte
+// to repeat. Ends with EOF and optionally a 3 byte length to truncate to
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut output = rom.to_vec();
+    let mut reader = Reader::new(patch);
+
+    loop {
+        if reader.bytes(3)? == IPS_EOF {
+            break;
+        }
+        reader.offset -= 3;
+
+        let offset = reader.big_endian(3)?;
+        let data = match reader.big_endian(2)? {
+            0 => {
+                let count = reader.big_endian(2)?;
+                vec![reader.bytes(1)?[0]; count]
+            }
+            length => reader.bytes(length)?.to_vec(),
+        };
+
+        let end = offset + data.len();
+        if end > MAX_PATCHED_SIZE {
+            return Err(PatchError::TooLarge(end));
+        }
+        if end > output.len() {
+            output.resize(end, 0);
+        }
+        output[offset..end].copy_from_slice(&data);
+    }
+
+    if !reader.is_empty() {
+        let length = reader.big_endian(3)?;
+        output.truncate(length);
+    }
+
+    Ok(output)
+}
+
+// A BPS patch: the sizes of the original and patched ROMs and some metadata,
+// then actions building the patched ROM from the original, the patch and the
+// output so far. Ends with the CRC-32s of the original, patched ROM and patch
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    // Checksums are little endian
+    let checksum = |offset: usize| {
+        let bytes = &patch[patch.len() - offset..][..4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    };
+    let check = |of, expected, data: &[u8]| match crc32fast::hash(data) {
+        actual if actual == expected => Ok(()),
+        actual => Err(PatchError::ChecksumMismatch {
+            of,
+            expected,
+            actual,
+        }),
+    };
+
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err(PatchError::Truncated);
+    }
+    check("patch", checksum(4), &patch[..patch.len() - 4])?;
+
+    let actions = &patch[..patch.len() - 12];
+    let mut reader = Reader::new(actions);
+    reader.bytes(BPS_MAGIC.len())?;
+
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return Err(PatchError::SizeMismatch {
+            expected: source_size,
+            actual: rom.len(),
+        });
+    }
+    check("ROM", checksum(12), rom)?;
+    if target_size > MAX_PATCHED_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+
+    let mut output = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while !reader.is_empty() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if output.len() + length > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+
+        match action & 3 {
+            // Copy from the original ROM at the same offset
+            0 => {
+                let start = output.len();
+                let bytes = rom
+                    .get(start..start + length)
+                    .ok_or(PatchError::OutOfBounds)?;
+                output.extend_from_slice(bytes);
+            }
+            // Copy from the patch
+            1 => output.extend_from_slice(reader.bytes(length)?),
+            // Copy from the original ROM or the output so far, starting a
+            // relative distance from where the last copy of its kind ended
+            kind => {
+                let distance = reader.number()?;
+                let offset = if kind == 2 {
+                    &mut source_offset
+                } else {
+                    &mut target_offset
+                };
+                *offset = if distance & 1 == 0 {
+                    offset.checked_add(distance >> 1)
+                } else {
+                    offset.checked_sub(distance >> 1)
+                }
+                .ok_or(PatchError::OutOfBounds)?;
+
+                for _ in 0..length {
+                    // Output copies may overlap what they're writing
+                    let byte = if kind == 2 {
+                        rom.get(*offset)
+                    } else {
+                        output.get(*offset)
+                    };
+                    let byte = *byte.ok_or(PatchError::OutOfBounds)?;
+                    output.push(byte);
+                    *offset += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check("patched ROM", checksum(8), &output)?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(mut n: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                bytes.push(byte | 0x80);
+                return bytes;
+            }
+            bytes.push(byte);
+            n -= 1;
+        }
+    }
+
+    fn bps(rom: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(number(rom.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(2));
+        patch.extend_from_slice(b"{}");
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32fast::hash(rom).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let checksum = crc32fast::hash(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        patch
+    }
+
+    const ROM: &[u8] = b"ABCDEFGH";
+
+    // A BPS patch turning ROM into its target using every kind of action
+    fn example() -> (&'static [u8], Vec<u8>) {
+        let target = b"ABCDxyABABABFG";
+        let mut actions = number(3 << 2); // Original ROM, 4 bytes
+        actions.extend(number(1 | 1 << 2)); // Patch, 2 bytes
+        actions.extend_from_slice(b"xy");
+        actions.extend(number(2 | 1 << 2)); // Original ROM from 0, 2 bytes
+        actions.extend(number(0));
+        actions.extend(number(3 | 3 << 2)); // Output from 6, 4 bytes, overlapping
+        actions.extend(number(6 << 1));
+        actions.extend(number(2 | 1 << 2)); // Original ROM from 5, 2 bytes
+        actions.extend(number(3 << 1));
+        (target, bps(ROM, target, &actions))
+    }
+
+    #[test]
+    fn ips_writes_runs_and_truncates() {
+        // Write "xy" at 1, five Zs at 6, then truncate to 10 bytes
+        let ips = b"PATCH\x00\x00\x01\x00\x02xy\x00\x00\x06\x00\x00\x00\x05ZEOF\x00\x00\x0A";
+        assert_eq!(apply(ROM, ips).unwrap(), b"AxyDEFZZZZ");
+    }
+
+    #[test]
+    fn truncated_ips_is_rejected() {
+        assert_eq!(
+            apply(ROM, b"PATCH\x00\x00\x01\x00\x02x"),
+            Err(PatchError::Truncated)
+        );
+    }
+
+    #[test]
+    fn bps_copies_from_rom_patch_and_output() {
+        let (target, patch) = example();
+        assert_eq!(apply(ROM, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_checks_the_rom() {
+        let (_, patch) = example();
+        assert_eq!(
+            apply(b"ABCDEFGX", &patch).unwrap_err().to_string(),
+            format!(
+                "CRC-32 of the ROM is {:08X}, the patch expects {:08X}",
+                crc32fast::hash(b"ABCDEFGX"),
+                crc32fast::hash(ROM)
+            )
+        );
+        assert_eq!(
+            apply(b"ABC", &patch),
+            Err(PatchError::SizeMismatch {
+                expected: 8,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn bps_checks_itself() {
+        let (_, mut patch) = example();
+        patch[10] ^= 1;
+        assert!(matches!(
+            apply(ROM, &patch),
+            Err(PatchError::ChecksumMismatch { of: "patch", .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        assert_eq!(apply(ROM, b"nonsense"), Err(PatchError::UnknownFormat));
+    }
+}
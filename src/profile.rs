@@ -0,0 +1,332 @@
+//! Profiling where a program spends its time.
+//!
+//! A [`Profiler`] counts how often each address and each kind of instruction
+//! is executed, how many instructions are spent inside each subroutine
+//! (following `CALL` and `RET`) and how often `DRW` runs. Time is measured in
+//! instructions, so the profile is the same however fast the machine runs.
+
+use crate::chip8::Chip8;
+use crate::disasm::{disassemble_with, opcode_class};
+use crate::symbols::Symbols;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Rows shown in each table of the report
+const REPORT_ROWS: usize = 20;
+
+/// Instructions spent in one subroutine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubroutineStats {
+    /// Times the subroutine was called.
+    pub calls: u64,
+    /// Instructions executed between entering and returning, including the
+    /// `RET` and any subroutines it calls.
+    pub inclusive: u64,
+    /// Instructions executed by the subroutine itself.
+    pub exclusive: u64,
+}
+
+// A subroutine that hasn't returned yet
+struct ActiveCall {
+    entry: u16,
+    started: u64,
+    in_callees: u64,
+}
+
+/// Collects execution statistics for a machine.
+///
+/// Call [`Profiler::record`] before every [`Chip8::step`] and
+/// [`Profiler::end_frame`] once per frame.
+#[derive(Default)]
+pub struct Profiler {
+    instructions: u64,
+    frames: u64,
+    by_address: BTreeMap<u16, u64>,
+    by_class: BTreeMap<&'static str, u64>,
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    active_calls: Vec<ActiveCall>,
+    draws: BTreeMap<u16, u64>,
+}
+
+impl Profiler {
+    /// Creates an empty profile.
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Counts the instruction `cpu` is about to execute.
+    pub fn record(&mut self, cpu: &Chip8) {
+        let pc = cpu.pc();
+        let instruction = match cpu.memory().get(pc as usize..pc as usize + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            // Past the end of memory, the step will fail
+            None => return,
+        };
+
+        self.instructions += 1;
+        *self.by_address.entry(pc).or_insert(0) += 1;
+        *self
+            .by_class
+            .entry(opcode_class(instruction).unwrap_or("unknown"))
+            .or_insert(0) += 1;
+
+        match instruction >> 12 {
+            0x2 => self.active_calls.push(ActiveCall {
+                entry: instruction & 0xFFF,
+                // The subroutine's time starts after the CALL
+                started: self.instructions,
+                in_callees: 0,
+            }),
+            0xD => *self.draws.entry(pc).or_insert(0) += 1,
+            _ if instruction == 0x00EE => self.record_return(),
+            _ => {}
+        }
+    }
+
+    fn record_return(&mut self) {
+        // Unbalanced RETs have nothing to attribute time to
+        let call = match self.active_calls.pop() {
+            Some(call) => call,
+            None => return,
+        };
+
+        let inclusive = self.instructions - call.started;
+        let stats = self.subroutines.entry(call.entry).or_default();
+        stats.calls += 1;
+        stats.inclusive += inclusive;
+        stats.exclusive += inclusive - call.in_callees;
+
+        if let Some(caller) = self.active_calls.last_mut() {
+            caller.in_callees += inclusive;
+        }
+    }
+
+    /// Marks the end of a frame.
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    /// Instructions executed.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Frames run.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Times the instruction at `address` was executed.
+    pub fn hits(&self, address: u16) -> u64 {
+        self.by_address.get(&address).copied().unwrap_or(0)
+    }
+
+    /// Instructions executed of each kind, keyed by opcode pattern such as `8xy4`.
+    pub fn classes(&self) -> &BTreeMap<&'static str, u64> {
+        &self.by_class
+    }
+
+    /// Statistics for each subroutine that has returned, keyed by entry address.
+    pub fn subroutines(&self) -> &BTreeMap<u16, SubroutineStats> {
+        &self.subroutines
+    }
+
+    /// `DRW` instructions executed at each address.
+    pub fn draws(&self) -> &BTreeMap<u16, u64> {
+        &self.draws
+    }
+
+    /// Total `DRW` instructions executed.
+    pub fn total_draws(&self) -> u64 {
+        self.draws.values().sum()
+    }
+
+    /// A human readable summary of the profile, with the hottest entries of
+    /// each table first. `memory` is used to disassemble hot addresses, and
+    /// addresses are named by label where `symbols` has them.
+    pub fn report(&self, memory: &[u8], symbols: &Symbols) -> String {
+        let mut report = String::new();
+        let location = |address: u16| match symbols.location(address) {
+            Some(location) => format!("  <{}>", location),
+            None => String::new(),
+        };
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+
+        let draws = self.total_draws();
+        let _ = writeln!(
+            report,
+            "{} instructions over {} frames, {} draws ({:.1} per frame)",
+            self.instructions,
+            self.frames,
+            draws,
+            draws as f64 / self.frames.max(1) as f64
+        );
+
+        let _ = writeln!(report, "\nHot addresses:");
+        for (address, count) in hottest(&self.by_address) {
+            let instruction = match memory.get(address as usize..address as usize + 2) {
+                Some(bytes) => disassemble_with(u16::from_be_bytes([bytes[0], bytes[1]]), symbols),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                report,
+                "  {:#05X} {:>10} {:>5.1}%  {}{}",
+                address,
+                count,
+                percent(count),
+                instruction,
+                location(address)
+            );
+        }
+
+        let _ = writeln!(report, "\nInstructions by kind:");
+        for (class, count) in hottest(&self.by_class) {
+            let _ = writeln!(
+                report,
+                "  {:<7} {:>10} {:>5.1}%",
+                class,
+                count,
+                percent(count)
+            );
+        }
+
+        let _ = writeln!(report, "\nSubroutines:");
+        let _ = writeln!(
+            report,
+            "  {:<5} {:>8} {:>10} {:>6} {:>10} {:>6}",
+            "entry", "calls", "inclusive", "", "self", ""
+        );
+        let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.inclusive));
+        for (entry, stats) in subroutines.into_iter().take(REPORT_ROWS) {
+            let _ = writeln!(
+                report,
+                "  {:#05X} {:>8} {:>10} {:>5.1}% {:>10} {:>5.1}%{}",
+                entry,
+                stats.calls,
+                stats.inclusive,
+                percent(stats.inclusive),
+                stats.exclusive,
+                percent(stats.exclusive),
+                location(*entry)
+            );
+        }
+
+        let _ = writeln!(report, "\nDraws by address:");
+        for (address, count) in hottest(&self.draws) {
+            let _ = writeln!(
+                report,
+                "  {:#05X} {:>10}{}",
+                address,
+                count,
+                location(address)
+            );
+        }
+
+        report
+    }
+}
+
+// The largest counts, biggest first
+fn hottest<K: Copy + Ord>(counts: &BTreeMap<K, u64>) -> Vec<(K, u64)> {
+    let mut counts: Vec<(K, u64)> = counts.iter().map(|(&key, &count)| (key, count)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts.truncate(REPORT_ROWS);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // 0x200: CALL 0x204; JP 0x202
+    // 0x204: CALL 0x20A; DRW V0, V0, 1; RET
+    // 0x20A: ADD V0, 1; RET
+    const PROGRAM: [u16; 7] = [0x2204, 0x1202, 0x220A, 0xD001, 0x00EE, 0x7001, 0x00EE];
+
+    // Profiles `steps` instructions of `program` as one frame
+    fn profile(program: &[u16], steps: usize) -> (Profiler, Chip8) {
+        let mut cpu = cpu_with(program);
+        let mut profiler = Profiler::new();
+        for _ in 0..steps {
+            profiler.record(&cpu);
+            cpu.step().unwrap();
+        }
+        profiler.end_frame();
+
+        (profiler, cpu)
+    }
+
+    #[test]
+    fn counts_addresses_and_kinds() {
+        let (profiler, _) = profile(&PROGRAM, 10);
+
+        assert_eq!(profiler.instructions(), 10);
+        assert_eq!(profiler.frames(), 1);
+        assert_eq!(profiler.hits(0x200), 1);
+        assert_eq!(profiler.hits(0x202), 4);
+        assert_eq!(profiler.hits(0x20C), 1);
+        assert_eq!(profiler.classes()["2nnn"], 2);
+        assert_eq!(profiler.classes()["00EE"], 2);
+        assert_eq!(profiler.classes()["1nnn"], 4);
+    }
+
+    #[test]
+    fn times_nested_subroutines() {
+        let (profiler, _) = profile(&PROGRAM, 10);
+
+        // Inner: ADD, RET. Outer: CALL, DRW, RET plus the inner two
+        assert_eq!(
+            profiler.subroutines()[&0x20A],
+            SubroutineStats {
+                calls: 1,
+                inclusive: 2,
+                exclusive: 2
+            }
+        );
+        assert_eq!(
+            profiler.subroutines()[&0x204],
+            SubroutineStats {
+                calls: 1,
+                inclusive: 5,
+                exclusive: 3
+            }
+        );
+    }
+
+    #[test]
+    fn subroutines_are_only_timed_once_they_return() {
+        // Stopped inside the inner subroutine
+        let (profiler, _) = profile(&PROGRAM, 3);
+        assert!(profiler.subroutines().is_empty());
+
+        // A RET without a CALL has nothing to attribute time to
+        let mut profiler = Profiler::new();
+        profiler.record(&cpu_with(&[0x00EE]));
+        assert!(profiler.subroutines().is_empty());
+        assert_eq!(profiler.instructions(), 1);
+    }
+
+    #[test]
+    fn counts_draws_by_address() {
+        let (profiler, _) = profile(&PROGRAM, 10);
+
+        assert_eq!(profiler.total_draws(), 1);
+        assert_eq!(profiler.draws()[&0x206], 1);
+    }
+
+    #[test]
+    fn report_names_hot_addresses() {
+        let (profiler, cpu) = profile(&PROGRAM, 10);
+        let symbols = Symbols::parse("label 0x202 idle\nlabel 0x204 draw").unwrap();
+        let report = profiler.report(cpu.memory(), &symbols);
+
+        assert!(report.starts_with("10 instructions over 1 frames, 1 draws (1.0 per frame)\n"));
+        // Hottest first
+        let hot = report.lines().nth(3).unwrap();
+        assert_eq!(hot, "  0x202          4  40.0%  JP idle  <idle>");
+        assert!(report.contains("  0x204        1          5  50.0%          3  30.0%  <draw>\n"));
+        assert!(report.contains("Draws by address:\n  0x206          1  <draw+0x2>\n"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Reading ROM images from files, stdin and compressed archives.
+//!
+//! ROMs are often distributed in packs, so besides plain `.ch8` files this
+//! accepts gzip streams and zip archives. Archives are recognised by their
+//! magic bytes rather than their file extension so they can also be piped in
+//! through stdin.
+
+use flate2::read::GzDecoder;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+// File extensions used by CHIP-8 ROMs inside archives
+const ROM_EXTENSIONS: [&str; 5] = ["ch8", "c8", "rom", "sc8", "xo8"];
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+// Largest ROM any memory layout can hold, decompression stops past this
+const MAX_ROM_BYTES: usize = 0x10000;
+
+/// Errors raised while reading a ROM image.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RomError {
+    /// The ROM or archive couldn't be read.
+    Io(io::Error),
+    /// The data looked like an archive but couldn't be decoded.
+    InvalidArchive(String),
+    /// The archive doesn't contain any files.
+    EmptyArchive,
+    /// The archive holds several ROMs and none was selected, lists their names.
+    MultipleRoms(Vec<String>),
+    /// The selected entry isn't in the archive.
+    EntryNotFound(String),
+    /// The unpacked ROM is larger than any memory layout.
+    TooLarge,
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::InvalidArchive(message) => write!(f, "Invalid archive: {}", message),
+            RomError::EmptyArchive => write!(f, "Archive doesn't contain any files"),
+            RomError::MultipleRoms(names) => {
+                write!(f, "Archive contains several ROMs: {}", names.join(", "))
+            }
+            RomError::EntryNotFound(name) => write!(f, "No entry named {} in archive", name),
+            RomError::TooLarge => write!(f, "Unpacked ROM is larger than {} bytes", MAX_ROM_BYTES),
+        }
+    }
+}
+
+impl error::Error for RomError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RomError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
+impl From<zip::result::ZipError> for RomError {
+    fn from(e: zip::result::ZipError) -> RomError {
+        match e {
+            zip::result::ZipError::Io(e) => RomError::Io(e),
+            e => RomError::InvalidArchive(e.to_string()),
+        }
+    }
+}
+
+/// Reads the raw contents of `path`, or of stdin if `path` is `-`.
+pub fn read_source(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+
+    if path == Path::new("-") {
+        io::stdin().read_to_end(&mut buffer)?;
+    } else {
+        buffer = fs::read(path)?;
+    }
+
+    Ok(buffer)
+}
+
+/// Reads a ROM from `path` (or stdin for `-`), unpacking it if it's an archive.
+///
+/// See [`extract_rom`] for how `entry` is used.
+pub fn load_file(path: &Path, entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let data = read_source(path)?;
+    extract_rom(&data, entry)
+}
+
+/// Returns the ROM held in `data`.
+///
+/// Gzip data is decompressed. For zip archives the file named `entry` is
+/// returned, matching either its full path in the archive or just its file
+/// name. Without an `entry` the archive must hold a single ROM, otherwise
+/// [`RomError::MultipleRoms`] lists the candidates to choose from. Anything
+/// else is assumed to already be a raw ROM and returned unchanged.
+///
+/// Unpacking stops with [`RomError::TooLarge`] once the ROM outgrows the
+/// largest memory, so archives can't expand without bound.
+pub fn extract_rom(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        read_bounded(GzDecoder::new(data))
+    } else if data.starts_with(&ZIP_MAGIC) {
+        extract_zip_entry(data, entry)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+fn read_bounded<R: Read>(reader: R) -> Result<Vec<u8>, RomError> {
+    let mut rom = Vec::new();
+    reader
+        .take(MAX_ROM_BYTES as u64 + 1)
+        .read_to_end(&mut rom)?;
+
+    if rom.len() > MAX_ROM_BYTES {
+        return Err(RomError::TooLarge);
+    }
+
+    Ok(rom)
+}
+
+fn extract_zip_entry(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+
+    let files: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !is_metadata(name))
+        .map(String::from)
+        .collect();
+
+    let name = match entry {
+        Some(entry) => files
+            .iter()
+            .find(|name| name.as_str() == entry || file_name(name) == entry)
+            .cloned()
+            .ok_or_else(|| RomError::EntryNotFound(entry.to_string()))?,
+        None => {
+            // Ignore readmes and the like when there are files that look like ROMs
+            let roms: Vec<String> = files.iter().filter(|name| is_rom(name)).cloned().collect();
+            let mut candidates = if roms.is_empty() { files } else { roms };
+
+            match candidates.len() {
+                0 => return Err(RomError::EmptyArchive),
+                1 => candidates.remove(0),
+                _ => {
+                    candidates.sort();
+                    return Err(RomError::MultipleRoms(candidates));
+                }
+            }
+        }
+    };
+
+    let file = archive.by_name(&name)?;
+    read_bounded(file)
+}
+
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn is_metadata(name: &str) -> bool {
+    // Resource forks and dotfiles added by archivers
+    name.starts_with("__MACOSX/") || file_name(name).starts_with('.')
+}
+
+fn is_rom(name: &str) -> bool {
+    match file_name(name).rsplit_once('.') {
+        Some((_, extension)) => ROM_EXTENSIONS
+            .iter()
+            .any(|rom_extension| extension.eq_ignore_ascii_case(rom_extension)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // A zip of `files`, where names ending in / are directories
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, data) in files {
+            match name.strip_suffix('/') {
+                Some(directory) => writer.add_directory(directory, options).unwrap(),
+                None => {
+                    writer.start_file(*name, options).unwrap();
+                    writer.write_all(data).unwrap();
+                }
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn gzip_is_unpacked_up_to_the_largest_memory() {
+        let rom = vec![0xA5; MAX_ROM_BYTES];
+        assert_eq!(extract_rom(&gzip(&rom), None).unwrap(), rom);
+
+        // A byte more is refused, however well it compresses
+        let bomb = gzip(&vec![0; MAX_ROM_BYTES + 1]);
+        assert!(bomb.len() < 1024);
+        assert!(matches!(extract_rom(&bomb, None), Err(RomError::TooLarge)));
+
+        // Raw ROMs pass through unchanged
+        assert_eq!(extract_rom(&[0x12, 0x00], None).unwrap(), [0x12, 0x00]);
+    }
+
+    #[test]
+    fn zip_with_one_rom_needs_no_entry() {
+        let archive = zip(&[
+            ("readme.txt", b"Press 5 to start"),
+            ("roms/", b""),
+            ("roms/pong.ch8", &[0x12, 0x00]),
+        ]);
+        assert_eq!(extract_rom(&archive, None).unwrap(), [0x12, 0x00]);
+
+        // Without anything that looks like a ROM, the only file will do
+        let archive = zip(&[("program", &[0x00, 0xE0])]);
+        assert_eq!(extract_rom(&archive, None).unwrap(), [0x00, 0xE0]);
+    }
+
+    #[test]
+    fn zip_with_several_roms_lists_them() {
+        let archive = zip(&[("tetris.c8", &[1]), ("games/pong.ch8", &[2])]);
+
+        match extract_rom(&archive, None) {
+            Err(RomError::MultipleRoms(names)) => {
+                assert_eq!(names, ["games/pong.ch8", "tetris.c8"]);
+            }
+            result => panic!("Expected MultipleRoms, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn zip_entries_are_found_by_path_or_file_name() {
+        let archive = zip(&[("tetris.c8", &[1]), ("games/pong.ch8", &[2])]);
+
+        assert_eq!(extract_rom(&archive, Some("games/pong.ch8")).unwrap(), [2]);
+        assert_eq!(extract_rom(&archive, Some("pong.ch8")).unwrap(), [2]);
+        assert_eq!(extract_rom(&archive, Some("tetris.c8")).unwrap(), [1]);
+        assert!(matches!(
+            extract_rom(&archive, Some("games/tetris.c8")),
+            Err(RomError::EntryNotFound(name)) if name == "games/tetris.c8"
+        ));
+    }
+
+    #[test]
+    fn zip_metadata_is_skipped() {
+        let archive = zip(&[
+            ("__MACOSX/._pong.ch8", &[0xFF]),
+            ("games/.pong.ch8.swp", &[0xFF]),
+            (".DS_Store", &[0xFF]),
+            ("games/pong.ch8", &[2]),
+        ]);
+        assert_eq!(extract_rom(&archive, None).unwrap(), [2]);
+        assert!(matches!(
+            extract_rom(&archive, Some(".DS_Store")),
+            Err(RomError::EntryNotFound(_))
+        ));
+
+        let archive = zip(&[("__MACOSX/._pong.ch8", &[0xFF]), ("empty/", b"")]);
+        assert!(matches!(
+            extract_rom(&archive, None),
+            Err(RomError::EmptyArchive)
+        ));
+    }
+
+    #[test]
+    fn zip_entries_are_unpacked_up_to_the_largest_memory() {
+        let rom = vec![0xA5; MAX_ROM_BYTES];
+        assert_eq!(extract_rom(&zip(&[("big.ch8", &rom)]), None).unwrap(), rom);
+
+        let bomb = zip(&[("bomb.ch8", &vec![0; MAX_ROM_BYTES + 1])]);
+        assert!(bomb.len() < 1024);
+        assert!(matches!(extract_rom(&bomb, None), Err(RomError::TooLarge)));
+    }
+}
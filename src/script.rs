@@ -0,0 +1,498 @@
+//! Rhai scripts hooked into a running machine, for automating play-throughs
+//! and writing bots without recompiling.
+//!
+//! A script runs once when it's loaded, registering closures to be called
+//! on events:
+//!
+//! ```text
+//! on_frame(|frame| if frame % 60 == 0 { screenshot(`frame${frame}.png`) });
+//! on_exec(0x2F0, |pc| print(`score ${v(3)}`));
+//! on_write(0x3F0, |address, value| if value > 5 { write(address, 5) });
+//! on_key(|key, pressed| print(`key ${key} ${pressed}`));
+//! ```
+//!
+//! `on_exec` hooks run before the instruction at the address, and
+//! `on_write(ADDR, f)` or `on_write(START, END, f)` after an instruction
+//! writes to memory there. `on_key` sees keys pressed and released by the
+//! player but not by scripts.
+//!
+//! Scripts and hooks can read and change the machine with `v(n)`,
+//! `set_v(n, value)`, `i()`, `set_i(value)`, `pc()`, `set_pc(value)`,
+//! `sp()`, `dt()`, `set_dt(value)`, `st()`, `set_st(value)`, `cycles()`,
+//! `read(address)` and `write(address, value)`, hold keys down with
+//! `press(key)` and `release(key)`, check them with `is_pressed(key)` and
+//! save the display as a PNG with `screenshot(path)`. Keys a script presses
+//! stay down until it releases them, whatever the player does, see
+//! [`Script::holds`].
+
+use crate::chip8::Chip8;
+use crate::snapshot::Snapshot;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, AST, INT};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::ops::{Range, RangeInclusive};
+use std::rc::Rc;
+
+// Enough for any reasonable hook, while stopping one stuck in a loop
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Errors raised by a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ScriptError {
+    /// The script couldn't be parsed.
+    Syntax(String),
+    /// The script or one of its hooks failed while running.
+    Runtime(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Syntax(message) => write!(f, "Script syntax error: {}", message),
+            ScriptError::Runtime(message) => write!(f, "Script error: {}", message),
+        }
+    }
+}
+
+impl error::Error for ScriptError {}
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Closures registered by the script for each event
+#[derive(Default)]
+struct Hooks {
+    frame: Vec<FnPtr>,
+    exec: BTreeMap<u16, Vec<FnPtr>>,
+    write: Vec<(RangeInclusive<usize>, FnPtr)>,
+    key: Vec<FnPtr>,
+}
+
+/// A loaded script and the hooks it registered.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    // The machine is swapped in here while the script runs, for the
+    // functions registered with the engine to reach
+    machine: Rc<RefCell<Chip8>>,
+    hooks: Rc<RefCell<Hooks>>,
+    // Keys held down with press(), for frontends to keep when they latch
+    // the keypad
+    held: Rc<RefCell<[bool; 16]>>,
+    // Keys as last seen, to notice the player pressing them
+    keys: [bool; 16],
+    // Memory the instruction being executed writes to
+    writes: Option<Range<usize>>,
+}
+
+impl Script {
+    /// Compiles `source` and runs it on `cpu`, letting it register hooks.
+    pub fn load(source: &str, cpu: &mut Chip8) -> Result<Script, ScriptError> {
+        let machine = Rc::new(RefCell::new(Chip8::new()));
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let held = Rc::new(RefCell::new([false; 16]));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        register_hooks(&mut engine, &hooks);
+        register_machine(&mut engine, &machine, &held);
+
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError::Syntax(e.to_string()))?;
+
+        let mut script = Script {
+            engine,
+            ast,
+            machine,
+            hooks,
+            held,
+            keys: [false; 16],
+            writes: None,
+        };
+        script.with_machine(cpu, |engine, ast| engine.run_ast(ast))?;
+
+        Ok(script)
+    }
+
+    /// Whether the script holds `key` (0x0 - 0xF) down.
+    ///
+    /// Frontends that set every key from the keyboard each frame should keep
+    /// these pressed, or the program never sees them.
+    pub fn holds(&self, key: u8) -> bool {
+        self.held.borrow()[(key & 0xF) as usize]
+    }
+
+    /// Runs the hooks for keys the player changed and for the instruction
+    /// `cpu` is about to execute. Call before every [`Chip8::step`].
+    pub fn before_step(&mut self, cpu: &mut Chip8) -> Result<(), ScriptError> {
+        let changed: Vec<(u8, bool)> = (0..16u8)
+            .map(|key| (key, cpu.key(key)))
+            .filter(|&(key, pressed)| pressed != self.keys[key as usize])
+            .collect();
+        for &(key, pressed) in &changed {
+            self.keys[key as usize] = pressed;
+        }
+        for (key, pressed) in changed {
+            let hooks = self.hooks.borrow().key.clone();
+            self.call(cpu, &hooks, (key as INT, pressed))?;
+        }
+
+        let pc = cpu.pc();
+        let hooks = self.hooks.borrow().exec.get(&pc).cloned();
+        if let Some(hooks) = hooks {
+            self.call(cpu, &hooks, (pc as INT,))?;
+        }
+
+        self.writes = written(cpu);
+        Ok(())
+    }
+
+    /// Runs the hooks for memory written by the instruction just executed.
+    /// Call after every [`Chip8::step`] that succeeds.
+    pub fn after_step(&mut self, cpu: &mut Chip8) -> Result<(), ScriptError> {
+        let writes = match self.writes.take() {
+            Some(writes) => writes,
+            None => return Ok(()),
+        };
+
+        for address in writes {
+            let hooks: Vec<FnPtr> = self
+                .hooks
+                .borrow()
+                .write
+                .iter()
+                .filter(|(range, _)| range.contains(&address))
+                .map(|(_, hook)| hook.clone())
+                .collect();
+            let value = match cpu.memory().get(address) {
+                Some(&value) if !hooks.is_empty() => value,
+                _ => continue,
+            };
+            self.call(cpu, &hooks, (address as INT, value as INT))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the hooks for the end of frame `frame`, counted from 1.
+    pub fn end_frame(&mut self, cpu: &mut Chip8, frame: u64) -> Result<(), ScriptError> {
+        let hooks = self.hooks.borrow().frame.clone();
+
+        self.call(cpu, &hooks, (frame as INT,))
+    }
+
+    fn call(
+        &mut self,
+        cpu: &mut Chip8,
+        hooks: &[FnPtr],
+        args: impl FuncArgs + Clone,
+    ) -> Result<(), ScriptError> {
+        for hook in hooks {
+            self.with_machine(cpu, |engine, ast| {
+                hook.call::<Dynamic>(engine, ast, args.clone()).map(|_| ())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Lends `cpu` to the script while `run` runs
+    fn with_machine<T>(
+        &mut self,
+        cpu: &mut Chip8,
+        run: impl FnOnce(&Engine, &AST) -> RhaiResult<T>,
+    ) -> Result<T, ScriptError> {
+        std::mem::swap(cpu, &mut *self.machine.borrow_mut());
+        let result = run(&self.engine, &self.ast);
+        std::mem::swap(cpu, &mut *self.machine.borrow_mut());
+
+        // Keys the script pressed aren't the player's
+        for (key, pressed) in self.keys.iter_mut().enumerate() {
+            *pressed = cpu.key(key as u8);
+        }
+
+        result.map_err(|e| ScriptError::Runtime(e.to_string()))
+    }
+}
+
+// The memory the instruction at PC will write to
+fn written(cpu: &Chip8) -> Option<Range<usize>> {
+    let pc = cpu.pc() as usize;
+    let instruction = match cpu.memory().get(pc..pc + 2) {
+        Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+        None => return None,
+    };
+    let start = cpu.i() as usize;
+
+    match instruction & 0xF0FF {
+        // BCD
+        0xF033 => Some(start..start + 3),
+        // Store V0 to Vx
+        0xF055 => Some(start..start + ((instruction >> 8) & 0xF) as usize + 1),
+        _ => None,
+    }
+}
+
+fn register_hooks(engine: &mut Engine, hooks: &Rc<RefCell<Hooks>>) {
+    let shared = Rc::clone(hooks);
+    engine.register_fn("on_frame", move |hook: FnPtr| {
+        shared.borrow_mut().frame.push(hook)
+    });
+
+    let shared = Rc::clone(hooks);
+    engine.register_fn(
+        "on_exec",
+        move |address: INT, hook: FnPtr| -> RhaiResult<()> {
+            let address = u16::try_from(address).map_err(|_| out_of_range("address", address))?;
+            shared
+                .borrow_mut()
+                .exec
+                .entry(address)
+                .or_default()
+                .push(hook);
+            Ok(())
+        },
+    );
+
+    let shared = Rc::clone(hooks);
+    engine.register_fn(
+        "on_write",
+        move |start: INT, end: INT, hook: FnPtr| -> RhaiResult<()> {
+            let start = usize::try_from(start).map_err(|_| out_of_range("address", start))?;
+            let end = usize::try_from(end).map_err(|_| out_of_range("address", end))?;
+            shared.borrow_mut().write.push((start..=end, hook));
+            Ok(())
+        },
+    );
+
+    let shared = Rc::clone(hooks);
+    engine.register_fn(
+        "on_write",
+        move |address: INT, hook: FnPtr| -> RhaiResult<()> {
+            let address = usize::try_from(address).map_err(|_| out_of_range("address", address))?;
+            shared.borrow_mut().write.push((address..=address, hook));
+            Ok(())
+        },
+    );
+
+    let shared = Rc::clone(hooks);
+    engine.register_fn("on_key", move |hook: FnPtr| {
+        shared.borrow_mut().key.push(hook)
+    });
+}
+
+fn register_machine(
+    engine: &mut Engine,
+    machine: &Rc<RefCell<Chip8>>,
+    held: &Rc<RefCell<[bool; 16]>>,
+) {
+    let cpu = Rc::clone(machine);
+    engine.register_fn("v", move |register: INT| -> RhaiResult<INT> {
+        Ok(cpu.borrow().v(nibble("register", register)?).into())
+    });
+    let cpu = Rc::clone(machine);
+    engine.register_fn(
+        "set_v",
+        move |register: INT, value: INT| -> RhaiResult<()> {
+            cpu.borrow_mut()
+                .set_v(nibble("register", register)?, byte(value)?);
+            Ok(())
+        },
+    );
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("i", move || -> INT { cpu.borrow().i().into() });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("set_i", move |value: INT| -> RhaiResult<()> {
+        let value = u16::try_from(value).map_err(|_| out_of_range("I", value))?;
+        cpu.borrow_mut().set_i(value);
+        Ok(())
+    });
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("pc", move || -> INT { cpu.borrow().pc().into() });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("set_pc", move |value: INT| -> RhaiResult<()> {
+        let mut cpu = cpu.borrow_mut();
+        let address = address(&cpu, value)?;
+        cpu.set_pc(address as u16);
+        Ok(())
+    });
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("sp", move || -> INT { cpu.borrow().sp() as INT });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("cycles", move || -> INT { cpu.borrow().cycles() as INT });
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("dt", move || -> INT { cpu.borrow().timers().delay.into() });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("set_dt", move |value: INT| -> RhaiResult<()> {
+        let mut cpu = cpu.borrow_mut();
+        let mut timers = cpu.timers();
+        timers.delay = byte(value)?;
+        cpu.set_timers(timers);
+        Ok(())
+    });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("st", move || -> INT { cpu.borrow().timers().sound.into() });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("set_st", move |value: INT| -> RhaiResult<()> {
+        let mut cpu = cpu.borrow_mut();
+        let mut timers = cpu.timers();
+        timers.sound = byte(value)?;
+        cpu.set_timers(timers);
+        Ok(())
+    });
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("read", move |value: INT| -> RhaiResult<INT> {
+        let cpu = cpu.borrow();
+        Ok(cpu.memory()[address(&cpu, value)?].into())
+    });
+    let cpu = Rc::clone(machine);
+    engine.register_fn(
+        "write",
+        move |address_value: INT, value: INT| -> RhaiResult<()> {
+            let mut cpu = cpu.borrow_mut();
+            let address = address(&cpu, address_value)?;
+            cpu.memory_mut()[address] = byte(value)?;
+            Ok(())
+        },
+    );
+
+    let (cpu, keys) = (Rc::clone(machine), Rc::clone(held));
+    engine.register_fn("press", move |key: INT| -> RhaiResult<()> {
+        let key = nibble("key", key)?;
+        keys.borrow_mut()[key] = true;
+        cpu.borrow_mut().set_key(key as u8, true);
+        Ok(())
+    });
+    let (cpu, keys) = (Rc::clone(machine), Rc::clone(held));
+    engine.register_fn("release", move |key: INT| -> RhaiResult<()> {
+        let key = nibble("key", key)?;
+        keys.borrow_mut()[key] = false;
+        cpu.borrow_mut().set_key(key as u8, false);
+        Ok(())
+    });
+    let cpu = Rc::clone(machine);
+    engine.register_fn("is_pressed", move |key: INT| -> RhaiResult<bool> {
+        Ok(cpu.borrow().key(nibble("key", key)? as u8))
+    });
+
+    let cpu = Rc::clone(machine);
+    engine.register_fn("screenshot", move |path: &str| -> RhaiResult<()> {
+        let png = Snapshot::from_framebuffer(cpu.borrow().framebuffer()).to_png();
+        fs::write(path, png).map_err(|e| format!("Failed to write {}: {}", path, e).into())
+    });
+}
+
+fn out_of_range(what: &str, value: INT) -> Box<EvalAltResult> {
+    format!("{} is out of range for {}", value, what).into()
+}
+
+// A register or key number
+fn nibble(what: &str, value: INT) -> RhaiResult<usize> {
+    match value {
+        0..=15 => Ok(value as usize),
+        _ => Err(out_of_range(what, value)),
+    }
+}
+
+fn byte(value: INT) -> RhaiResult<u8> {
+    u8::try_from(value).map_err(|_| out_of_range("a byte", value))
+}
+
+fn address(cpu: &Chip8, value: INT) -> RhaiResult<usize> {
+    match usize::try_from(value) {
+        Ok(address) if address < cpu.memory().len() => Ok(address),
+        _ => Err(format!("{:#05X} is outside memory", value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::cpu_with;
+
+    // LD V0, 0x7B; LD I, 0x300; BCD V0; JP 0x206
+    const PROGRAM: [u16; 4] = [0x607B, 0xA300, 0xF033, 0x1206];
+
+    fn run(script: &mut Script, cpu: &mut Chip8, steps: usize) {
+        for _ in 0..steps {
+            script.before_step(cpu).unwrap();
+            cpu.step().unwrap();
+            script.after_step(cpu).unwrap();
+        }
+    }
+
+    #[test]
+    fn top_level_code_runs_on_load() {
+        let mut cpu = cpu_with(&PROGRAM);
+        Script::load("set_v(3, 7);", &mut cpu).unwrap();
+        assert_eq!(cpu.v(3), 7);
+    }
+
+    #[test]
+    fn exec_hooks_run_before_the_instruction() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut script =
+            Script::load("on_exec(0x202, |pc| set_v(1, v(0) + 1));", &mut cpu).unwrap();
+        run(&mut script, &mut cpu, 3);
+        assert_eq!(cpu.v(1), 0x7C);
+    }
+
+    #[test]
+    fn write_hooks_see_each_byte_in_range() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let source =
+            "on_write(0x300, 0x302, |address, value| write(0x3F0 + address - 0x300, value * 2));";
+        let mut script = Script::load(source, &mut cpu).unwrap();
+        run(&mut script, &mut cpu, 3);
+        assert_eq!(&cpu.memory()[0x3F0..0x3F3], &[2, 4, 6]);
+    }
+
+    #[test]
+    fn key_hooks_run_when_keys_change() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let mut script =
+            Script::load("on_key(|key, pressed| set_v(2, v(2) + key));", &mut cpu).unwrap();
+        cpu.set_key(0xA, true);
+        run(&mut script, &mut cpu, 3);
+        assert_eq!(cpu.v(2), 0xA);
+    }
+
+    #[test]
+    fn keys_pressed_by_the_script_skip_key_hooks() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let source = "
+            on_frame(|frame| if frame == 2 { press(5) });
+            on_key(|key, pressed| set_v(2, v(2) + key));
+        ";
+        let mut script = Script::load(source, &mut cpu).unwrap();
+        script.end_frame(&mut cpu, 1).unwrap();
+        assert!(!script.holds(5));
+
+        script.end_frame(&mut cpu, 2).unwrap();
+        script.before_step(&mut cpu).unwrap();
+        assert!(cpu.key(5));
+        assert!(script.holds(5));
+        assert_eq!(cpu.v(2), 0);
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        let mut cpu = cpu_with(&PROGRAM);
+        let error = Script::load("set_v(16, 0)", &mut cpu).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("16 is out of range for register"));
+        let error = Script::load("on_frame(", &mut cpu).err().unwrap();
+        assert!(matches!(error, ScriptError::Syntax(_)));
+    }
+}
@@ -0,0 +1,430 @@
+//! Recording display snapshots and comparing them against goldens.
+//!
+//! A ROM is run for a number of frames while an [`InputScript`] presses and
+//! releases keys, and the display is captured at chosen frames. Snapshots
+//! are stored either as text, one line per row with `#` for lit pixels and
+//! `.` for unlit ones, or as greyscale PNGs at one image pixel per display
+//! pixel.
+
+use crate::chip8::{Chip8, Error};
+use crate::framebuffer::Framebuffer;
+use std::error;
+use std::fmt;
+
+/// Errors raised while parsing scripts or stored snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    /// A line of an input script couldn't be parsed.
+    InvalidScript { line: usize, message: String },
+    /// A text snapshot is malformed.
+    InvalidText(String),
+    /// A PNG snapshot couldn't be decoded.
+    InvalidPng(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::InvalidScript { line, message } => {
+                write!(f, "Input script line {}: {}", line, message)
+            }
+            SnapshotError::InvalidText(message) => write!(f, "Invalid text snapshot: {}", message),
+            SnapshotError::InvalidPng(message) => write!(f, "Invalid PNG snapshot: {}", message),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+/// A key press or release at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u32,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Scripted keypad input.
+///
+/// Each line of a script reads `<frame> down|up <key>`, with the key as a
+/// single hex digit. Events are applied before the frame runs, frames are
+/// counted from 1. Blank lines and anything after a `#` are ignored:
+///
+/// ```text
+/// # Hold 5 for a second to start the game
+/// 30 down 5
+/// 90 up 5
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    /// Parses a script in the format described above.
+    pub fn parse(text: &str) -> Result<InputScript, SnapshotError> {
+        let mut events = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: &str| SnapshotError::InvalidScript {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(invalid("expected <frame> down|up <key>"));
+            }
+
+            let frame = fields[0]
+                .parse()
+                .map_err(|_| invalid("frame isn't a number"))?;
+            let pressed = match fields[1] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(invalid("expected down or up")),
+            };
+            let key = match u8::from_str_radix(fields[2], 16) {
+                Ok(key) if key <= 0xF => key,
+                _ => return Err(invalid("key must be a hex digit")),
+            };
+
+            events.push(InputEvent {
+                frame,
+                key,
+                pressed,
+            });
+        }
+
+        // Keep events for the same frame in script order
+        events.sort_by_key(|event| event.frame);
+
+        Ok(InputScript { events })
+    }
+
+    /// The events in frame order.
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    /// Applies the events scheduled for `frame` to the keypad.
+    pub fn apply(&self, frame: u32, cpu: &mut Chip8) {
+        for event in self.events.iter().filter(|event| event.frame == frame) {
+            cpu.set_key(event.key, event.pressed);
+        }
+    }
+}
+
+/// Runs `cpu` until the last of `frames`, capturing the display after each
+/// of them. Snapshots are returned in the order of `frames`, frame 0 being
+/// the display before anything has run.
+pub fn capture(
+    cpu: &mut Chip8,
+    script: &InputScript,
+    frames: &[u32],
+    instructions_per_frame: u32,
+) -> Result<Vec<Snapshot>, Error> {
+    let last_frame = frames.iter().copied().max().unwrap_or(0);
+    let mut captured = vec![None; frames.len()];
+
+    for frame in 0..=last_frame {
+        if frame > 0 {
+            script.apply(frame, cpu);
+            cpu.run_frame(instructions_per_frame)?;
+        }
+
+        for (slot, _) in captured
+            .iter_mut()
+            .zip(frames)
+            .filter(|(_, &wanted)| wanted == frame)
+        {
+            *slot = Some(Snapshot::from_framebuffer(cpu.framebuffer()));
+        }
+    }
+
+    Ok(captured.into_iter().flatten().collect())
+}
+
+/// A captured display, independent of the machine it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Snapshot {
+    /// Copies the current contents of `framebuffer`.
+    pub fn from_framebuffer(framebuffer: &Framebuffer) -> Snapshot {
+        Snapshot {
+            width: framebuffer.width(),
+            height: framebuffer.height(),
+            pixels: framebuffer.rows().flatten().copied().collect(),
+        }
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Whether the pixel at (`x`, `y`) is lit.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width + x]
+    }
+
+    /// Renders the snapshot as text, `#` for lit pixels and `.` for unlit ones.
+    pub fn to_text(&self) -> String {
+        let mut text = String::with_capacity((self.width + 1) * self.height);
+
+        for row in self.pixels.chunks(self.width) {
+            text.extend(row.iter().map(|&lit| if lit { '#' } else { '.' }));
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Parses a text snapshot written by [`Snapshot::to_text`].
+    pub fn from_text(text: &str) -> Result<Snapshot, SnapshotError> {
+        let rows: Vec<&str> = text.lines().collect();
+        let width = rows.first().map_or(0, |row| row.chars().count());
+
+        if width == 0 {
+            return Err(SnapshotError::InvalidText("snapshot is empty".to_string()));
+        }
+
+        let mut pixels = Vec::with_capacity(width * rows.len());
+        for (y, row) in rows.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(SnapshotError::InvalidText(format!(
+                    "row {} isn't {} pixels wide",
+                    y, width
+                )));
+            }
+
+            for c in row.chars() {
+                match c {
+                    '#' => pixels.push(true),
+                    '.' => pixels.push(false),
+                    _ => {
+                        return Err(SnapshotError::InvalidText(format!(
+                            "unexpected character {:?} in row {}",
+                            c, y
+                        )))
+                    }
+                }
+            }
+        }
+
+        Ok(Snapshot {
+            width,
+            height: rows.len(),
+            pixels,
+        })
+    }
+
+    /// Encodes the snapshot as an 8-bit greyscale PNG.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = Vec::new();
+
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|&lit| if lit { 0xFF } else { 0x00 })
+            .collect();
+
+        // Writing to a Vec can't fail and the data always matches the header
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&data).unwrap();
+        writer.finish().unwrap();
+
+        png
+    }
+
+    /// Decodes a PNG, treating any pixel brighter than mid-grey as lit.
+    pub fn from_png(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let invalid = |e: png::DecodingError| SnapshotError::InvalidPng(e.to_string());
+
+        let mut decoder = png::Decoder::new(data);
+        // Expand palettes and low bit depths to 8-bit samples
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+        let mut reader = decoder.read_info().map_err(invalid)?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).map_err(invalid)?;
+
+        let channels = info.color_type.samples();
+        let pixels = buffer[..info.buffer_size()]
+            .chunks(channels)
+            .map(|pixel| {
+                // Average the colour channels, ignoring alpha
+                let colour = match info.color_type {
+                    png::ColorType::GrayscaleAlpha | png::ColorType::Rgba => &pixel[..channels - 1],
+                    _ => pixel,
+                };
+                let brightness = colour.iter().map(|&c| c as usize).sum::<usize>() / colour.len();
+                brightness >= 0x80
+            })
+            .collect();
+
+        Ok(Snapshot {
+            width: info.width as usize,
+            height: info.height as usize,
+            pixels,
+        })
+    }
+
+    /// Compares against `expected`, describing the mismatch if there is one.
+    ///
+    /// The description marks pixels that should be lit with `+`, pixels that
+    /// should be unlit with `-` and lists the first differing coordinates.
+    pub fn diff(&self, expected: &Snapshot) -> Option<String> {
+        if self.width != expected.width || self.height != expected.height {
+            return Some(format!(
+                "size differs: expected {}x{}, got {}x{}",
+                expected.width, expected.height, self.width, self.height
+            ));
+        }
+        if self.pixels == expected.pixels {
+            return None;
+        }
+
+        let mut picture = String::new();
+        let mut differences = Vec::new();
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (actual, wanted) = (self.pixel(x, y), expected.pixel(x, y));
+                picture.push(match (actual, wanted) {
+                    (false, true) => '+',
+                    (true, false) => '-',
+                    (true, true) => '#',
+                    (false, false) => '.',
+                });
+                if actual != wanted {
+                    differences.push((x, y));
+                }
+            }
+            picture.push('\n');
+        }
+
+        let listed: Vec<String> = differences
+            .iter()
+            .take(10)
+            .map(|(x, y)| format!("({}, {})", x, y))
+            .collect();
+        let more = if differences.len() > listed.len() {
+            ", ..."
+        } else {
+            ""
+        };
+
+        Some(format!(
+            "{} pixels differ at {}{}\n(+ should be lit, - should be unlit)\n{}",
+            differences.len(),
+            listed.join(", "),
+            more,
+            picture
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> Snapshot {
+        let text: String = (0..4)
+            .map(|y| {
+                let row: String = (0..6)
+                    .map(|x| if (x + y) % 2 == 0 { '#' } else { '.' })
+                    .collect();
+                row + "\n"
+            })
+            .collect();
+        Snapshot::from_text(&text).unwrap()
+    }
+
+    #[test]
+    fn parses_input_scripts() {
+        let script = InputScript::parse("# comment\n\n20 up A\n10 down a # start\n").unwrap();
+
+        assert_eq!(
+            script.events(),
+            &[
+                InputEvent {
+                    frame: 10,
+                    key: 0xA,
+                    pressed: true
+                },
+                InputEvent {
+                    frame: 20,
+                    key: 0xA,
+                    pressed: false
+                },
+            ]
+        );
+        assert_eq!(
+            InputScript::parse("10 down 5\n10 held 5"),
+            Err(SnapshotError::InvalidScript {
+                line: 2,
+                message: "expected down or up".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn text_and_png_round_trip() {
+        let snapshot = checkerboard();
+
+        assert_eq!(Snapshot::from_text(&snapshot.to_text()).unwrap(), snapshot);
+        assert_eq!(Snapshot::from_png(&snapshot.to_png()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn diff_marks_differing_pixels() {
+        let expected = checkerboard();
+        let mut actual = expected.clone();
+        actual.pixels[1] = true;
+        actual.pixels[0] = false;
+
+        assert_eq!(expected.diff(&expected), None);
+
+        let diff = actual.diff(&expected).unwrap();
+        assert!(diff.starts_with("2 pixels differ at (0, 0), (1, 0)"));
+        assert!(diff.contains("\n+-#.#.\n"));
+    }
+
+    #[test]
+    fn capture_runs_scripted_frames() {
+        // 0x200: LD V0, K; LD F, V0; DRW V1, V1, 5; JP 0x206
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x06])
+            .unwrap();
+        let script = InputScript::parse("2 down 1\n3 up 1").unwrap();
+
+        let snapshots = capture(&mut cpu, &script, &[3, 0, 2], 10).unwrap();
+
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(snapshots[1], snapshots[2]);
+        // The "1" glyph is drawn once the key is pressed and released
+        assert!(snapshots[0].pixel(2, 0));
+        assert!(!snapshots[2].pixel(2, 0));
+    }
+}
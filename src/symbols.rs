@@ -0,0 +1,301 @@
+//! Symbol files mapping ROM addresses back to assembly source.
+//!
+//! A symbol file is plain text with one record per line. A `label` record
+//! names an address, and a `line` record ties the instruction at an address
+//! to the source line it was assembled from, given as `<file>:<line>`. Blank
+//! lines and anything after a `#` are ignored:
+//!
+//! ```text
+//! # pong.ch8
+//! label 0x200 main
+//! line 0x200 pong.8o:12
+//! line 0x202 pong.8o:13
+//! label 0x2F0 draw_score
+//! ```
+//!
+//! Addresses are then shown by the nearest label at or before them, such as
+//! `draw_score` or `main+0x2`.
+
+use crate::chip8::Error;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::path::Path;
+
+/// Errors raised while parsing a symbol file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SymbolError {
+    /// A record couldn't be parsed.
+    InvalidRecord { line: usize, message: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::InvalidRecord { line, message } => {
+                write!(f, "Symbol file line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl error::Error for SymbolError {}
+
+/// A line of assembly source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// The file as named in the symbol file, usually relative to it.
+    pub file: String,
+    /// Counted from 1.
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// The contents of a symbol file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+impl Symbols {
+    /// Parses a symbol file in the format described above.
+    pub fn parse(text: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: &str| SymbolError::InvalidRecord {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (address, rest) = rest
+                .trim()
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected <kind> <address> <value>"))?;
+            let address = parse_address(address).ok_or_else(|| invalid("invalid address"))?;
+
+            match kind {
+                "label" => {
+                    let name = rest.trim();
+                    if name.contains(char::is_whitespace) {
+                        return Err(invalid("labels can't contain spaces"));
+                    }
+                    symbols.labels.insert(address, name.to_string());
+                }
+                "line" => {
+                    // Split on the last colon so file names may hold them
+                    let (file, number) = rest
+                        .trim()
+                        .rsplit_once(':')
+                        .ok_or_else(|| invalid("expected <file>:<line>"))?;
+                    let number = match number.parse() {
+                        Ok(number) if number > 0 && !file.is_empty() => number,
+                        _ => return Err(invalid("expected <file>:<line>")),
+                    };
+
+                    symbols.lines.insert(
+                        address,
+                        SourceLine {
+                            file: file.to_string(),
+                            line: number,
+                        },
+                    );
+                }
+                _ => return Err(invalid(&format!("unknown record {}", kind))),
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    /// The file as text, in the format [`Symbols::parse`] reads.
+    pub fn to_text(&self) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(address, name)| (address, format!("label {:#05X} {}", address, name)));
+        let lines = self
+            .lines
+            .iter()
+            .map(|(address, source)| (address, format!("line {:#05X} {}", address, source)));
+        let mut records: Vec<(&u16, String)> = labels.chain(lines).collect();
+        // Labels come before the lines they name, as they'd be written by hand
+        records.sort_by_key(|(address, _)| **address);
+
+        records
+            .into_iter()
+            .map(|(_, record)| record + "\n")
+            .collect()
+    }
+
+    /// Names `address`, replacing any label it had.
+    pub fn add_label(&mut self, address: u16, name: &str) {
+        self.labels.insert(address, name.to_string());
+    }
+
+    /// Records the source line the instruction at `address` came from.
+    pub fn add_line(&mut self, address: u16, source: SourceLine) {
+        self.lines.insert(address, source);
+    }
+
+    /// The label naming exactly `address`.
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// The address of the label `name`.
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, label)| label.as_str() == name)
+            .map(|(&address, _)| address)
+    }
+
+    /// `address` relative to the nearest label at or before it, such as
+    /// `main+0x4`, if there is one.
+    pub fn location(&self, address: u16) -> Option<String> {
+        let (&start, label) = self.labels.range(..=address).next_back()?;
+
+        Some(match address - start {
+            0 => label.clone(),
+            offset => format!("{}+{:#X}", label, offset),
+        })
+    }
+
+    /// Everything known about `address`: its location and source line, such
+    /// as `main+0x4, pong.8o:14`.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let parts: Vec<String> = self
+            .location(address)
+            .into_iter()
+            .chain(self.source_line(address).map(SourceLine::to_string))
+            .collect();
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+
+    /// The message for `error`, followed by where it happened when it was
+    /// raised by an instruction the symbols know about.
+    pub fn annotate_error(&self, error: &Error) -> String {
+        match error.address().and_then(|address| self.describe(address)) {
+            Some(location) => format!("{} ({})", error, location),
+            None => error.to_string(),
+        }
+    }
+
+    /// The source line the instruction at `address` was assembled from.
+    pub fn source_line(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    /// The first line at or after `line` in `file` that has code, and the
+    /// lowest address assembled from it. `file` may be a longer path than
+    /// the symbol file gives, e.g. an absolute path from an editor.
+    pub fn address_of_line(&self, file: &str, line: u32) -> Option<(u32, u16)> {
+        self.lines
+            .iter()
+            .filter(|(_, source)| source.line >= line && same_file(file, &source.file))
+            .map(|(&address, source)| (source.line, address))
+            .min()
+    }
+
+    /// Whether the file holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+}
+
+// Whether two paths name the same file, when one may be relative
+fn same_file(a: &str, b: &str) -> bool {
+    let (a, b) = (Path::new(a), Path::new(b));
+
+    a.ends_with(b) || b.ends_with(a)
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_lines_and_addresses() {
+        let symbols = Symbols::parse(
+            "# pong\n\
+             line 0x200 src/pong.8o:12\n\
+             line 0x202 src/pong.8o:12  # second half of an if\n\
+             line 516 src/pong.8o:15\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            symbols.source_line(0x204),
+            Some(&SourceLine {
+                file: "src/pong.8o".to_string(),
+                line: 15
+            })
+        );
+        assert_eq!(symbols.source_line(0x206), None);
+
+        assert_eq!(
+            symbols.address_of_line("/home/me/game/src/pong.8o", 12),
+            Some((12, 0x200))
+        );
+        // Lines without code move on to the next line that has some
+        assert_eq!(symbols.address_of_line("pong.8o", 13), Some((15, 0x204)));
+        assert_eq!(symbols.address_of_line("pong.8o", 16), None);
+        assert_eq!(symbols.address_of_line("other.8o", 12), None);
+
+        let error = Symbols::parse("line 0x200 pong.8o\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Symbol file line 1: expected <file>:<line>"
+        );
+    }
+
+    #[test]
+    fn names_addresses_by_label() {
+        let symbols = Symbols::parse(
+            "label 0x200 main\n\
+             line 0x204 pong.8o:14\n\
+             label 0x2F0 draw_score\n",
+        )
+        .unwrap();
+
+        assert_eq!(symbols.address_of("draw_score"), Some(0x2F0));
+        assert_eq!(symbols.address_of("missing"), None);
+        assert_eq!(symbols.label(0x2F0), Some("draw_score"));
+        assert_eq!(symbols.location(0x1FE), None);
+        assert_eq!(symbols.location(0x2F0).unwrap(), "draw_score");
+        assert_eq!(symbols.describe(0x204).unwrap(), "main+0x4, pong.8o:14");
+        assert_eq!(symbols.describe(0x100), None);
+
+        let error = Error::StackUnderflow { address: 0x2F2 };
+        assert_eq!(
+            symbols.annotate_error(&error),
+            "Stack underflow, RET at 0x2F2 with an empty stack (draw_score+0x2)"
+        );
+    }
+}
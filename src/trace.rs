@@ -0,0 +1,300 @@
+//! Per-instruction execution traces.
+//!
+//! A [`Tracer`] records the machine state just before each instruction runs:
+//! the cycle count, PC, the instruction, V0 to VF, I, SP and the timers. Text
+//! traces hold one record per line in fixed width columns, with the
+//! disassembly after a `;` so it can be cut off when diffing against traces
+//! from emulators that use other mnemonics:
+//!
+//! ```text
+//!         0 0200 00E0 V 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 0000 SP 0 DT 00 ST 00 ; CLS
+//! ```
+//!
+//! Binary traces start with the 8 byte header `CH8TRC\0\x01` followed by 33
+//! byte records: the cycle as a little endian `u64`, PC, the instruction and
+//! I as little endian `u16`s, then SP, DT, ST and V0 to VF as single bytes.
+
+use crate::chip8::Chip8;
+use crate::disasm::disassemble_with;
+use crate::symbols::Symbols;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+const BINARY_HEADER: &[u8; 8] = b"CH8TRC\x00\x01";
+const BINARY_RECORD_SIZE: usize = 33;
+
+/// The machine state before an instruction was executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl TraceRecord {
+    /// Records the state of `cpu` before it executes the instruction at PC.
+    pub fn capture(cpu: &Chip8) -> TraceRecord {
+        let pc = cpu.pc() as usize;
+        let memory = cpu.memory();
+
+        // PC can point past the end of memory, the step will fail
+        let instruction = match (memory.get(pc), memory.get(pc + 1)) {
+            (Some(&upper), Some(&lower)) => u16::from_be_bytes([upper, lower]),
+            _ => 0,
+        };
+
+        let timers = cpu.timers();
+        TraceRecord {
+            cycle: cpu.cycles(),
+            pc: cpu.pc(),
+            instruction,
+            v: *cpu.registers(),
+            i: cpu.i(),
+            sp: cpu.sp() as u8,
+            delay: timers.delay,
+            sound: timers.sound,
+        }
+    }
+
+    /// Formats the record as a line of a text trace, without the newline.
+    pub fn to_text(&self) -> String {
+        self.to_text_with(&Symbols::default())
+    }
+
+    /// Formats the record like [`TraceRecord::to_text`], naming addresses
+    /// by label and following the disassembly with where PC is in the source.
+    pub fn to_text_with(&self, symbols: &Symbols) -> String {
+        let registers: Vec<String> = self.v.iter().map(|v| format!("{:02X}", v)).collect();
+
+        let text = format!(
+            "{:>9} {:04X} {:04X} V {} I {:04X} SP {:X} DT {:02X} ST {:02X} ; {}",
+            self.cycle,
+            self.pc,
+            self.instruction,
+            registers.join(" "),
+            self.i,
+            self.sp,
+            self.delay,
+            self.sound,
+            disassemble_with(self.instruction, symbols)
+        );
+
+        match symbols.describe(self.pc) {
+            Some(location) => format!("{}  @ {}", text, location),
+            None => text,
+        }
+    }
+
+    /// Writes the record in the binary format.
+    pub fn write_binary(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut record = [0; BINARY_RECORD_SIZE];
+
+        record[0..8].copy_from_slice(&self.cycle.to_le_bytes());
+        record[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        record[10..12].copy_from_slice(&self.instruction.to_le_bytes());
+        record[12..14].copy_from_slice(&self.i.to_le_bytes());
+        record[14] = self.sp;
+        record[15] = self.delay;
+        record[16] = self.sound;
+        record[17..].copy_from_slice(&self.v);
+
+        writer.write_all(&record)
+    }
+
+    /// Reads the next record of a binary trace whose header has already been
+    /// read, returning `None` at the end of the trace.
+    pub fn read_binary(reader: &mut impl Read) -> io::Result<Option<TraceRecord>> {
+        let mut record = [0; BINARY_RECORD_SIZE];
+
+        // Distinguish a clean end of the trace from a truncated record
+        let mut filled = 0;
+        while filled < record.len() {
+            match reader.read(&mut record[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => filled += read,
+            }
+        }
+
+        let u16_at = |offset: usize| u16::from_le_bytes([record[offset], record[offset + 1]]);
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&record[0..8]);
+        let mut v = [0; 16];
+        v.copy_from_slice(&record[17..]);
+
+        Ok(Some(TraceRecord {
+            cycle: u64::from_le_bytes(cycle),
+            pc: u16_at(8),
+            instruction: u16_at(10),
+            i: u16_at(12),
+            sp: record[14],
+            delay: record[15],
+            sound: record[16],
+            v,
+        }))
+    }
+}
+
+/// Reads the header of a binary trace, failing if it isn't one.
+pub fn read_binary_header(reader: &mut impl Read) -> io::Result<()> {
+    let mut header = [0; BINARY_HEADER.len()];
+    reader.read_exact(&mut header)?;
+
+    if &header != BINARY_HEADER {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a binary CHIP-8 trace",
+        ));
+    }
+
+    Ok(())
+}
+
+/// How trace records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+/// Which instructions are traced. An empty filter traces everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only trace instructions at these addresses.
+    pub addresses: Option<RangeInclusive<u16>>,
+    /// Only trace instructions executed at these cycle counts.
+    pub cycles: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    /// Whether the instruction at `pc`, run at `cycle`, should be traced.
+    pub fn matches(&self, pc: u16, cycle: u64) -> bool {
+        let address_matches = match &self.addresses {
+            Some(addresses) => addresses.contains(&pc),
+            None => true,
+        };
+        let cycle_matches = match &self.cycles {
+            Some(cycles) => cycles.contains(&cycle),
+            None => true,
+        };
+
+        address_matches && cycle_matches
+    }
+}
+
+/// Writes a trace of the instructions a machine executes.
+///
+/// Call [`Tracer::record`] before every [`Chip8::step`].
+pub struct Tracer<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    symbols: Symbols,
+}
+
+impl<W: Write> Tracer<W> {
+    /// Starts a trace, writing the header of binary traces straight away.
+    pub fn new(mut writer: W, format: TraceFormat, filter: TraceFilter) -> io::Result<Tracer<W>> {
+        if format == TraceFormat::Binary {
+            writer.write_all(BINARY_HEADER)?;
+        }
+
+        Ok(Tracer {
+            writer,
+            format,
+            filter,
+            symbols: Symbols::default(),
+        })
+    }
+
+    /// Names addresses in text traces with `symbols`.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Records the instruction `cpu` is about to execute, if the filter allows it.
+    pub fn record(&mut self, cpu: &Chip8) -> io::Result<()> {
+        if !self.filter.matches(cpu.pc(), cpu.cycles()) {
+            return Ok(());
+        }
+
+        let record = TraceRecord::capture(cpu);
+        match self.format {
+            TraceFormat::Text => writeln!(self.writer, "{}", record.to_text_with(&self.symbols)),
+            TraceFormat::Binary => record.write_binary(&mut self.writer),
+        }
+    }
+
+    /// Flushes the trace to the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Finishes the trace, returning the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_records_round_trip() {
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0x60, 0x2A, 0xA2, 0x34, 0x12, 0x04]).unwrap();
+
+        let mut tracer =
+            Tracer::new(Vec::new(), TraceFormat::Binary, TraceFilter::default()).unwrap();
+        let mut expected = Vec::new();
+        for _ in 0..3 {
+            tracer.record(&cpu).unwrap();
+            expected.push(TraceRecord::capture(&cpu));
+            cpu.step().unwrap();
+        }
+
+        let data = tracer.into_inner().unwrap();
+        let mut reader = &data[..];
+        read_binary_header(&mut reader).unwrap();
+
+        let mut records = Vec::new();
+        while let Some(record) = TraceRecord::read_binary(&mut reader).unwrap() {
+            records.push(record);
+        }
+        assert_eq!(records, expected);
+        assert_eq!(records[1].v[0], 0x2A);
+        assert_eq!(records[2].i, 0x234);
+    }
+
+    #[test]
+    fn filter_limits_text_trace() {
+        // LD V0, 0x2A; JP 0x202
+        let mut cpu = Chip8::new();
+        cpu.load_rom(&[0x60, 0x2A, 0x12, 0x02]).unwrap();
+
+        let filter = TraceFilter {
+            addresses: Some(0x202..=0x202),
+            cycles: Some(0..=2),
+        };
+        let mut tracer = Tracer::new(Vec::new(), TraceFormat::Text, filter).unwrap();
+        for _ in 0..5 {
+            tracer.record(&cpu).unwrap();
+            cpu.step().unwrap();
+        }
+
+        let text = String::from_utf8(tracer.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "        1 0202 1202 V 2A 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 0000 SP 0 DT 00 ST 00 ; JP 0x202"
+        );
+    }
+}
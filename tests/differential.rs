@@ -0,0 +1,389 @@
+//! Differential tests against a reference model of the CHIP-8 instruction set.
+//!
+//! The model below is written to be obviously correct rather than fast: every
+//! instruction computes its results from the old state before writing any of
+//! them back, so flag ordering bugs such as VF being clobbered when it is also
+//! the destination register show up as divergences. Random machine states and
+//! instruction sequences are run through both the model and [`Chip8`], and any
+//! difference in registers, memory or the display is reported.
+
+use chip_8::{Chip8, Error, Timers, DISPLAY_HEIGHT, DISPLAY_WIDTH, FONT_ADDRESS};
+use proptest::prelude::*;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Model {
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    sp: usize,
+    stack: [u16; 16],
+    dt: u8,
+    st: u8,
+    ram: Vec<u8>,
+    display: Vec<Vec<bool>>,
+    keys: [bool; 16],
+    pending_key: Option<u8>,
+}
+
+impl Model {
+    fn from_machine(cpu: &Chip8) -> Model {
+        Model {
+            v: *cpu.registers(),
+            i: cpu.i(),
+            pc: cpu.pc(),
+            sp: cpu.sp(),
+            stack: *cpu.stack(),
+            dt: cpu.timers().delay,
+            st: cpu.timers().sound,
+            ram: cpu.memory().to_vec(),
+            display: cpu.framebuffer().rows().map(|row| row.to_vec()).collect(),
+            keys: {
+                let mut keys = [false; 16];
+                for (key, pressed) in keys.iter_mut().enumerate() {
+                    *pressed = cpu.key(key as u8);
+                }
+                keys
+            },
+            pending_key: None,
+        }
+    }
+
+    // Executes one instruction. `random` stands in for the byte Cxnn draws.
+    fn step(&mut self, random: u8) -> Result<(), Error> {
+        let pc = self.pc;
+        let opcode = (self.ram[pc as usize] as u16) << 8 | self.ram[pc as usize + 1] as u16;
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = opcode & 0xF;
+        let nn = (opcode & 0xFF) as u8;
+        let nnn = opcode & 0xFFF;
+        let (vx, vy) = (self.v[x], self.v[y]);
+        let next = pc + 2;
+        let skip = |condition: bool| if condition { pc + 4 } else { next };
+
+        self.pc = next;
+
+        match (opcode >> 12, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => {
+                self.display = vec![vec![false; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+            }
+            (0x0, 0x0, 0xE, 0xE) => {
+                self.sp -= 1;
+                self.pc = self.stack[self.sp];
+            }
+            (0x0, 0x0, 0xF, 0xD) => self.pc = pc,
+            (0x1, ..) => self.pc = nnn,
+            (0x2, ..) => {
+                self.stack[self.sp] = next;
+                self.sp += 1;
+                self.pc = nnn;
+            }
+            (0x3, ..) => self.pc = skip(vx == nn),
+            (0x4, ..) => self.pc = skip(vx != nn),
+            (0x5, _, _, _) => self.pc = skip(vx == vy),
+            (0x6, ..) => self.v[x] = nn,
+            (0x7, ..) => self.v[x] = vx.wrapping_add(nn),
+            (0x8, _, _, 0x0) => self.v[x] = vy,
+            (0x8, _, _, 0x1) => self.v[x] = vx | vy,
+            (0x8, _, _, 0x2) => self.v[x] = vx & vy,
+            (0x8, _, _, 0x3) => self.v[x] = vx ^ vy,
+            (0x8, _, _, 0x4) => {
+                self.set_with_flag(x, vx.wrapping_add(vy), vx as u16 + vy as u16 > 0xFF)
+            }
+            (0x8, _, _, 0x5) => self.set_with_flag(x, vx.wrapping_sub(vy), vx >= vy),
+            (0x8, _, _, 0x6) => self.set_with_flag(x, vx >> 1, vx & 0x01 != 0),
+            (0x8, _, _, 0x7) => self.set_with_flag(x, vy.wrapping_sub(vx), vy >= vx),
+            (0x8, _, _, 0xE) => self.set_with_flag(x, vx << 1, vx & 0x80 != 0),
+            (0x9, _, _, _) => self.pc = skip(vx != vy),
+            (0xA, ..) => self.i = nnn,
+            (0xB, ..) => self.pc = nnn + self.v[0] as u16,
+            (0xC, ..) => self.v[x] = random & nn,
+            (0xD, ..) => {
+                let mut collision = false;
+                for row in 0..n as usize {
+                    let sprite = self.ram[self.i as usize + row];
+                    for col in 0..8 {
+                        let px = vx as usize % DISPLAY_WIDTH + col;
+                        let py = vy as usize % DISPLAY_HEIGHT + row;
+                        if px < DISPLAY_WIDTH && py < DISPLAY_HEIGHT && sprite & (0x80 >> col) != 0
+                        {
+                            collision |= self.display[py][px];
+                            self.display[py][px] ^= true;
+                        }
+                    }
+                }
+                self.v[0xF] = collision as u8;
+            }
+            (0xE, _, 0x9, 0xE) => self.pc = skip(self.keys[(vx & 0xF) as usize]),
+            (0xE, _, 0xA, 0x1) => self.pc = skip(!self.keys[(vx & 0xF) as usize]),
+            (0xF, _, 0x0, 0x7) => self.v[x] = self.dt,
+            // Taken once the key first seen down is released
+            (0xF, _, 0x0, 0xA) => match self.pending_key {
+                Some(key) if !self.keys[key as usize] => {
+                    self.v[x] = key;
+                    self.pending_key = None;
+                }
+                Some(_) => self.pc = pc,
+                None => {
+                    self.pending_key = self
+                        .keys
+                        .iter()
+                        .position(|&pressed| pressed)
+                        .map(|key| key as u8);
+                    self.pc = pc;
+                }
+            },
+            (0xF, _, 0x1, 0x5) => self.dt = vx,
+            (0xF, _, 0x1, 0x8) => self.st = vx,
+            (0xF, _, 0x1, 0xE) => self.i += vx as u16,
+            (0xF, _, 0x2, 0x9) => self.i = FONT_ADDRESS + (vx & 0xF) as u16 * 5,
+            (0xF, _, 0x3, 0x3) => {
+                let i = self.i as usize;
+                self.ram[i..i + 3].copy_from_slice(&[vx / 100, vx / 10 % 10, vx % 10]);
+            }
+            (0xF, _, 0x5, 0x5) => {
+                let i = self.i as usize;
+                self.ram[i..=i + x].copy_from_slice(&self.v[..=x]);
+            }
+            (0xF, _, 0x6, 0x5) => {
+                let i = self.i as usize;
+                let values = self.ram[i..=i + x].to_vec();
+                self.v[..=x].copy_from_slice(&values);
+            }
+            _ => {
+                self.pc = pc;
+                return Err(Error::UnknownInstruction {
+                    address: pc,
+                    instruction: opcode,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the result before the flag, so VF holds the flag when x is F
+    fn set_with_flag(&mut self, x: usize, value: u8, flag: bool) {
+        self.v[x] = value;
+        self.v[0xF] = flag as u8;
+    }
+
+    // Describes every difference from `actual`
+    fn divergences(&self, actual: &Model) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        for register in 0..16 {
+            if self.v[register] != actual.v[register] {
+                differences.push(format!(
+                    "V{:X}: expected {:#04X}, got {:#04X}",
+                    register, self.v[register], actual.v[register]
+                ));
+            }
+        }
+
+        let scalars = [
+            ("I", self.i as usize, actual.i as usize),
+            ("PC", self.pc as usize, actual.pc as usize),
+            ("SP", self.sp, actual.sp),
+            ("DT", self.dt as usize, actual.dt as usize),
+            ("ST", self.st as usize, actual.st as usize),
+        ];
+        for (name, expected, got) in scalars.iter() {
+            if expected != got {
+                differences.push(format!(
+                    "{}: expected {:#X}, got {:#X}",
+                    name, expected, got
+                ));
+            }
+        }
+
+        if self.stack != actual.stack {
+            differences.push(format!(
+                "stack: expected {:X?}, got {:X?}",
+                self.stack, actual.stack
+            ));
+        }
+
+        for (address, (expected, got)) in self.ram.iter().zip(&actual.ram).enumerate() {
+            if expected != got {
+                differences.push(format!(
+                    "ram[{:#05X}]: expected {:#04X}, got {:#04X}",
+                    address, expected, got
+                ));
+            }
+        }
+
+        for (y, (expected, got)) in self.display.iter().zip(&actual.display).enumerate() {
+            for x in 0..DISPLAY_WIDTH {
+                if expected[x] != got[x] {
+                    differences.push(format!(
+                        "pixel ({}, {}): expected {}, got {}",
+                        x, y, expected[x], got[x]
+                    ));
+                }
+            }
+        }
+
+        differences
+    }
+}
+
+// An instruction with random operands, weighted towards valid encodings
+fn instruction() -> impl Strategy<Value = u16> {
+    let x = || 0u16..16;
+    prop_oneof![
+        Just(0x00E0u16),
+        Just(0x00EE),
+        Just(0x00FD),
+        (0u16..0x1000).prop_map(|nnn| 0x1000 | nnn),
+        (0u16..0x1000).prop_map(|nnn| 0x2000 | nnn),
+        (0u16..0x1000).prop_map(|xnn| 0x3000 | xnn),
+        (0u16..0x1000).prop_map(|xnn| 0x4000 | xnn),
+        (x(), x()).prop_map(|(x, y)| 0x5000 | x << 8 | y << 4),
+        (0u16..0x1000).prop_map(|xnn| 0x6000 | xnn),
+        (0u16..0x1000).prop_map(|xnn| 0x7000 | xnn),
+        (
+            x(),
+            x(),
+            prop::sample::select(vec![0x0u16, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE])
+        )
+            .prop_map(|(x, y, n)| 0x8000 | x << 8 | y << 4 | n),
+        (x(), x()).prop_map(|(x, y)| 0x9000 | x << 8 | y << 4),
+        (0u16..0x1000).prop_map(|nnn| 0xA000 | nnn),
+        (0u16..0x1000).prop_map(|nnn| 0xB000 | nnn),
+        (0u16..0x1000).prop_map(|xnn| 0xC000 | xnn),
+        (0u16..0x1000).prop_map(|xyn| 0xD000 | xyn),
+        (x(), prop::sample::select(vec![0x9Eu16, 0xA1])).prop_map(|(x, nn)| 0xE000 | x << 8 | nn),
+        (
+            x(),
+            prop::sample::select(vec![
+                0x07u16, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x30, 0x33, 0x55, 0x65
+            ])
+        )
+            .prop_map(|(x, nn)| 0xF000 | x << 8 | nn),
+        any::<u16>(),
+    ]
+}
+
+#[derive(Debug, Clone)]
+struct Scenario {
+    v: [u8; 16],
+    i: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    timers: Timers,
+    keys: [bool; 16],
+    data: Vec<u8>,
+    program: Vec<u16>,
+}
+
+fn scenario() -> impl Strategy<Value = Scenario> {
+    (
+        any::<[u8; 16]>(),
+        // Keep I far enough from the end of memory for the largest access
+        0u16..0xF00,
+        (0x100u16..0x700).prop_map(|pc| pc * 2),
+        prop::collection::vec((0x100u16..0x700).prop_map(|address| address * 2), 1..=16),
+        (any::<u8>(), any::<u8>()),
+        any::<[bool; 16]>(),
+        prop::collection::vec(any::<u8>(), 64),
+        prop::collection::vec(instruction(), 1..16),
+    )
+        .prop_map(
+            |(v, i, pc, stack, (delay, sound), keys, data, program)| Scenario {
+                v,
+                i,
+                pc,
+                stack,
+                timers: Timers { delay, sound },
+                keys,
+                data,
+                program,
+            },
+        )
+}
+
+fn build_machine(scenario: &Scenario) -> Chip8 {
+    let mut cpu = Chip8::new();
+
+    for (register, &value) in scenario.v.iter().enumerate() {
+        cpu.set_v(register, value);
+    }
+    cpu.set_i(scenario.i);
+    cpu.set_pc(scenario.pc);
+    cpu.set_timers(scenario.timers);
+    for (key, &pressed) in scenario.keys.iter().enumerate() {
+        cpu.set_key(key as u8, pressed);
+    }
+
+    cpu.stack_mut()[..scenario.stack.len()].copy_from_slice(&scenario.stack);
+    cpu.set_sp(scenario.stack.len());
+
+    // Sprite and register data around I, then the program at PC
+    let memory = cpu.memory_mut();
+    let i = scenario.i as usize;
+    memory[i..i + scenario.data.len()].copy_from_slice(&scenario.data);
+    for (offset, opcode) in scenario.program.iter().enumerate() {
+        let address = scenario.pc as usize + offset * 2;
+        memory[address..address + 2].copy_from_slice(&opcode.to_be_bytes());
+    }
+
+    cpu
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2000))]
+
+    #[test]
+    fn machine_matches_reference_model(scenario in scenario()) {
+        let mut cpu = build_machine(&scenario);
+        let mut model = Model::from_machine(&cpu);
+
+        for _ in 0..scenario.program.len() {
+            // PC and I must stay inside memory and the stack is only 16 deep,
+            // those faults aren't part of the instruction semantics under test
+            let pc = model.pc as usize;
+            if pc + 1 >= model.ram.len() {
+                break;
+            }
+
+            let opcode = (model.ram[pc] as u16) << 8 | model.ram[pc + 1] as u16;
+            let stack_fault = (opcode == 0x00EE && model.sp == 0)
+                || (opcode & 0xF000 == 0x2000 && model.sp == 16);
+            let accesses_i = opcode & 0xF000 == 0xD000
+                || [0xF033, 0xF055, 0xF065].contains(&(opcode & 0xF0FF));
+            if stack_fault || (accesses_i && model.i as usize + 16 > model.ram.len()) {
+                break;
+            }
+
+            let actual_result = cpu.step();
+            let actual = Model::from_machine(&cpu);
+
+            // The model can't predict Cxnn, so borrow its random byte from the
+            // machine, checking it was masked properly
+            let mut random = 0;
+            if opcode & 0xF000 == 0xC000 {
+                let x = (opcode >> 8 & 0xF) as usize;
+                let nn = (opcode & 0xFF) as u8;
+                prop_assert_eq!(actual.v[x] & !nn, 0, "Cxnn result isn't masked by nn");
+                random = actual.v[x];
+            }
+
+            let expected_result = model.step(random);
+            prop_assert_eq!(&actual_result, &expected_result, "result of {:#06X}", opcode);
+
+            let divergences = model.divergences(&actual);
+            prop_assert!(
+                divergences.is_empty(),
+                "{:#06X} at {:#05X} diverged from the reference model:\n{}",
+                opcode,
+                pc,
+                divergences.join("\n")
+            );
+
+            if actual_result.is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Runs the bundled test ROMs headlessly and compares the final display to
+//! golden snapshots in `tests/golden`.
+//!
+//! After an intended change in behaviour, regenerate the snapshots with
+//! `UPDATE_GOLDEN=1 cargo test --test roms` and review the diff.
+
+use chip_8::snapshot::Snapshot;
+use chip_8::Chip8;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Every bundled ROM finishes well within this and then spins on a jump to itself
+const CYCLES: usize = 5000;
+
+fn check_rom(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let rom = fs::read(root.join(format!("{}.ch8", name))).unwrap();
+
+    let mut cpu = Chip8::new();
+    cpu.load_rom(&rom).unwrap();
+    for _ in 0..CYCLES {
+        cpu.step().unwrap();
+    }
+
+    let actual = Snapshot::from_framebuffer(cpu.framebuffer());
+    let golden_path = root.join("tests/golden").join(format!("{}.txt", name));
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&golden_path, actual.to_text()).unwrap();
+        return;
+    }
+
+    let expected = Snapshot::from_text(&fs::read_to_string(&golden_path).unwrap()).unwrap();
+    if let Some(diff) = actual.diff(&expected) {
+        panic!("{} doesn't match its golden snapshot: {}", name, diff);
+    }
+}
+
+#[test]
+fn ibm_logo() {
+    check_rom("ibm_logo");
+}
+
+#[test]
+fn test_opcode() {
+    check_rom("test_opcode");
+}
+
+#[test]
+fn bc_test() {
+    // Displays "BON" when every check passes, or an error number
+    check_rom("BC_test");
+}